@@ -0,0 +1,482 @@
+//! Actions staff can take on other users' accounts.
+
+use crate::audit;
+use crate::auth::{User, current_user, normalize_email};
+use crate::config::app_config;
+use crate::forms::fields::TrimmedEmail;
+use crate::forms::form_from_request;
+use crate::forms::login::landing_redirect;
+use crate::https;
+use crate::invites;
+use crate::mail_queue;
+use cot::auth::Auth;
+use cot::common_types::Email;
+use cot::db::Database;
+use cot::email::{Email as EmailService, EmailMessage};
+use cot::form::{Form, FormResult};
+use cot::json::Json;
+use cot::request::Request;
+use cot::request::extractors::Path;
+use cot::response::{IntoResponse, Response, ResponseExt};
+use cot::router::Urls;
+use cot::session::Session;
+use cot::{Method, StatusCode};
+use serde::Serialize;
+
+/// The session entry holding the id of the staff user impersonating the
+/// session's user.
+const IMPERSONATOR_KEY: &str = "impersonator_id";
+
+fn forbidden(message: &'static str) -> cot::Error {
+    cot::Error::with_status(message, StatusCode::FORBIDDEN)
+}
+
+fn require_post(request: &Request) -> cot::Result<()> {
+    if request.method() == Method::POST {
+        Ok(())
+    } else {
+        Err(cot::Error::with_status(
+            "only POST is allowed",
+            StatusCode::METHOD_NOT_ALLOWED,
+        ))
+    }
+}
+
+fn no_content() -> Response {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(cot::Body::empty())
+        .expect("the status is valid")
+}
+
+/// The id of the staff user impersonating the session's user, if any.
+pub(crate) async fn impersonator_id(session: &Session) -> cot::Result<Option<i64>> {
+    session
+        .get(IMPERSONATOR_KEY)
+        .await
+        .map_err(cot::Error::wrap)
+}
+
+/// The user making a request for a staff action.
+///
+/// Refuses while the session impersonates someone: the action would be taken
+/// with the impersonated account's rights, and logged under its name.
+async fn acting_user(auth: &Auth, db: &Database, session: &Session) -> cot::Result<Option<User>> {
+    if impersonator_id(session).await?.is_some() {
+        return Err(forbidden("stop impersonating before taking staff actions"));
+    }
+    current_user(auth, db).await
+}
+
+/// Logs the staff user making the request in as user `id`, remembering who
+/// they are so [`stop_impersonating`] can switch back.
+///
+/// Other staff can only be impersonated when `app.allow_impersonating_staff`
+/// is set, and superusers and deactivated accounts never can.
+pub(crate) async fn impersonate(
+    urls: Urls,
+    auth: Auth,
+    db: Database,
+    session: Session,
+    Path(id): Path<i64>,
+    request: Request,
+) -> cot::Result<Response> {
+    require_post(&request)?;
+    let Some(staff) = acting_user(&auth, &db, &session)
+        .await?
+        .filter(User::is_staff)
+    else {
+        return Err(forbidden("only staff can impersonate users"));
+    };
+    let staff_id = staff.id().expect("user loaded from the database has an id");
+    if id == staff_id {
+        return Err(forbidden("staff can't impersonate themselves"));
+    }
+
+    let Some(target) = User::get_by_id(&db, id).await? else {
+        return Err(cot::error::NotFound::new().into());
+    };
+    if target.is_superuser() {
+        return Err(forbidden("superuser accounts can't be impersonated"));
+    }
+    if target.is_staff() && !app_config().allow_impersonating_staff {
+        return Err(forbidden("staff accounts can't be impersonated"));
+    }
+    if !target.is_active() {
+        return Err(forbidden("deactivated accounts can't be impersonated"));
+    }
+
+    audit::record(&db, staff_id, "impersonate_start", Some(id)).await?;
+    let redirect = landing_redirect(&urls, Some(&target))?;
+    auth.login(Box::new(target)).await?;
+    session
+        .insert(IMPERSONATOR_KEY, staff_id)
+        .await
+        .map_err(cot::Error::wrap)?;
+    Ok(redirect)
+}
+
+/// Switches an impersonated session back to the staff user who started it.
+pub(crate) async fn stop_impersonating(
+    urls: Urls,
+    auth: Auth,
+    db: Database,
+    session: Session,
+    request: Request,
+) -> cot::Result<Response> {
+    require_post(&request)?;
+    let Some(staff_id) = impersonator_id(&session).await? else {
+        return Err(forbidden("this session isn't impersonating anyone"));
+    };
+    let target_id = current_user(&auth, &db).await?.and_then(|user| user.id());
+    session
+        .remove::<i64>(IMPERSONATOR_KEY)
+        .await
+        .map_err(cot::Error::wrap)?;
+
+    // the staff account may have lost its rights in the meantime
+    let Some(staff) = User::get_by_id(&db, staff_id)
+        .await?
+        .filter(|staff| staff.is_staff() && staff.is_active())
+    else {
+        auth.logout().await?;
+        return Ok(cot::reverse_redirect!(urls, "login")?);
+    };
+
+    audit::record(&db, staff_id, "impersonate_stop", target_id).await?;
+    let redirect = landing_redirect(&urls, Some(&staff))?;
+    auth.login(Box::new(staff)).await?;
+    Ok(redirect)
+}
+
+#[derive(Debug, Form)]
+pub(crate) struct InviteForm {
+    email: TrimmedEmail,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatedInvite {
+    email: String,
+    signup_url: String,
+}
+
+/// Sends a signup invite to the posted `email` and answers `201 Created` with
+/// the signup link, so staff can pass it on themselves if the email doesn't
+/// arrive.
+pub(crate) async fn create_invite(
+    urls: Urls,
+    auth: Auth,
+    db: Database,
+    session: Session,
+    email_sender: EmailService,
+    mut request: Request,
+) -> cot::Result<Response> {
+    require_post(&request)?;
+    let Some(staff) = acting_user(&auth, &db, &session)
+        .await?
+        .filter(User::is_staff)
+    else {
+        return Err(forbidden("only staff can invite users"));
+    };
+    let FormResult::Ok(form) = form_from_request::<InviteForm>(&mut request).await? else {
+        return Err(cot::Error::with_status(
+            "a valid email is required",
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    let email = normalize_email(&form.email.into_email());
+    let staff_id = staff.id().expect("user loaded from the database has an id");
+
+    let token = invites::create(&db, &email, staff_id).await?;
+    let signup_url = format!(
+        "{}{}?invite={token}",
+        https::base_url(request.headers()).trim_end_matches('/'),
+        cot::reverse!(urls, "signup")?,
+    );
+    let message = EmailMessage::builder()
+        .from(Email::try_from("no-reply@example.com").unwrap())
+        .to(vec![email.clone()])
+        .subject(format!("You're invited to {}", app_config().site_name))
+        .body(format!("sign up with this link:\n\n{signup_url}\n"))
+        .build()?;
+    mail_queue::send(email_sender, message).await?;
+    audit::record(&db, staff_id, "invite_create", None).await?;
+
+    Json(CreatedInvite {
+        email: email.as_str().to_owned(),
+        signup_url,
+    })
+    .with_status(StatusCode::CREATED)
+    .into_response()
+}
+
+/// Anonymizes user `id` with [`User::anonymize`] and answers `204 No Content`.
+///
+/// Staff accounts have to lose their staff status first, so one can't be
+/// scrubbed by mistake.
+pub(crate) async fn anonymize(
+    auth: Auth,
+    db: Database,
+    session: Session,
+    Path(id): Path<i64>,
+    request: Request,
+) -> cot::Result<Response> {
+    require_post(&request)?;
+    let Some(staff) = acting_user(&auth, &db, &session)
+        .await?
+        .filter(User::is_staff)
+    else {
+        return Err(forbidden("only staff can anonymize users"));
+    };
+    let Some(mut target) = User::get_by_id(&db, id).await? else {
+        return Err(cot::error::NotFound::new().into());
+    };
+    if target.is_staff() {
+        return Err(forbidden("staff accounts can't be anonymized"));
+    }
+
+    target.anonymize(&db).await?;
+    let staff_id = staff.id().expect("user loaded from the database has an id");
+    audit::record(&db, staff_id, "anonymize", Some(id)).await?;
+    Ok(no_content())
+}
+
+#[derive(Debug, Form)]
+pub(crate) struct StaffForm {
+    /// `true` to grant staff status, `false` to revoke it.
+    is_staff: bool,
+}
+
+/// Grants or revokes the staff status of user `id` with [`User::set_staff`]
+/// and answers `204 No Content`.
+///
+/// Only superusers can, and not on their own account or another
+/// superuser's: those are changed from the command line.
+pub(crate) async fn set_staff(
+    auth: Auth,
+    db: Database,
+    session: Session,
+    Path(id): Path<i64>,
+    mut request: Request,
+) -> cot::Result<Response> {
+    require_post(&request)?;
+    let Some(superuser) = acting_user(&auth, &db, &session)
+        .await?
+        .filter(User::is_superuser)
+    else {
+        return Err(forbidden("only superusers can change staff status"));
+    };
+    let superuser_id = superuser
+        .id()
+        .expect("user loaded from the database has an id");
+    if id == superuser_id {
+        return Err(forbidden("superusers can't change their own staff status"));
+    }
+    let FormResult::Ok(form) = form_from_request::<StaffForm>(&mut request).await? else {
+        return Err(cot::Error::with_status(
+            "is_staff must be true or false",
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    let Some(mut target) = User::get_by_id(&db, id).await? else {
+        return Err(cot::error::NotFound::new().into());
+    };
+    if target.is_superuser() {
+        return Err(forbidden("superusers can't be changed here"));
+    }
+
+    target.set_staff(form.is_staff, &db, superuser_id).await?;
+    Ok(no_content())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::db::Model;
+
+    /// Saves `username` as staff, and a superuser if `superuser` is set.
+    async fn create_staff(app: &TestApp, username: &str, superuser: bool) -> i64 {
+        let mut user = app.create_user(username, PASSWORD).await;
+        user.set_staff_unaudited(true).set_superuser(superuser);
+        user.save(app.db()).await.unwrap();
+        user.id().unwrap()
+    }
+
+    async fn impersonate(app: &mut TestApp, id: i64) -> StatusCode {
+        app.post(&format!("/admin/users/{id}/impersonate"), &[])
+            .await
+            .status
+    }
+
+    fn allowing_staff(config: &mut crate::config::AppConfig) {
+        config.allow_impersonating_staff = true;
+    }
+
+    #[cot::test]
+    async fn superusers_cannot_be_impersonated() {
+        let mut app = TestApp::with_config(allowing_staff).await;
+        create_staff(&app, "imp_staff", false).await;
+        let superuser_id = create_staff(&app, "imp_superuser", true).await;
+        app.login("imp_staff", PASSWORD).await;
+
+        assert_eq!(
+            impersonate(&mut app, superuser_id).await,
+            StatusCode::FORBIDDEN
+        );
+        assert!(app.get("/home").await.body.contains("imp_staff"));
+    }
+
+    #[cot::test]
+    async fn deactivated_accounts_cannot_be_impersonated() {
+        let mut app = TestApp::new().await;
+        create_staff(&app, "imp_admin", false).await;
+        let mut target = app.create_user("imp_deactivated", PASSWORD).await;
+        target.deactivate().save(app.db()).await.unwrap();
+        app.login("imp_admin", PASSWORD).await;
+
+        assert_eq!(
+            impersonate(&mut app, target.id().unwrap()).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[cot::test]
+    async fn staff_can_anonymize_a_user() {
+        let mut app = TestApp::new().await;
+        let staff_id = create_staff(&app, "anon_staff", false).await;
+        let target_id = app.create_user("anon_target", PASSWORD).await.id().unwrap();
+        app.login("anon_staff", PASSWORD).await;
+
+        let response = app
+            .post(&format!("/admin/users/{target_id}/anonymize"), &[])
+            .await;
+
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        let target = User::get_by_id(app.db(), target_id).await.unwrap().unwrap();
+        assert!(target.username().starts_with("deleted-"));
+        assert!(!target.is_active());
+        assert_eq!(
+            audit::entries(app.db()).await.unwrap(),
+            [(staff_id, "anonymize".to_owned(), Some(target_id))]
+        );
+    }
+
+    #[cot::test]
+    async fn staff_actions_are_refused_while_impersonating() {
+        let mut app = TestApp::with_config(allowing_staff).await;
+        let staff_id = create_staff(&app, "imp_actor", false).await;
+        let other_staff_id = create_staff(&app, "imp_other_staff", false).await;
+        let bystander = app.create_user("imp_bystander", PASSWORD).await;
+        let bystander_id = bystander.id().unwrap();
+        app.login("imp_actor", PASSWORD).await;
+        assert_eq!(
+            impersonate(&mut app, other_staff_id).await,
+            StatusCode::SEE_OTHER
+        );
+
+        let anonymize = app
+            .post(&format!("/admin/users/{bystander_id}/anonymize"), &[])
+            .await;
+        let invite = app
+            .post("/admin/invites", &[("email", "imp_invited@example.com")])
+            .await;
+        let set_staff = app
+            .post(
+                &format!("/admin/users/{bystander_id}/staff"),
+                &[("is_staff", "true")],
+            )
+            .await;
+        let impersonate_again = impersonate(&mut app, bystander_id).await;
+
+        assert_eq!(anonymize.status, StatusCode::FORBIDDEN);
+        assert_eq!(invite.status, StatusCode::FORBIDDEN);
+        assert_eq!(set_staff.status, StatusCode::FORBIDDEN);
+        assert_eq!(impersonate_again, StatusCode::FORBIDDEN);
+        let bystander = User::get_by_id(app.db(), bystander_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bystander.username(), "imp_bystander");
+        assert!(!bystander.is_staff());
+        assert_eq!(
+            audit::entries(app.db()).await.unwrap(),
+            [(
+                staff_id,
+                "impersonate_start".to_owned(),
+                Some(other_staff_id)
+            )]
+        );
+    }
+
+    #[cot::test]
+    async fn the_audit_log_names_the_impersonator() {
+        let mut app = TestApp::new().await;
+        let staff_id = create_staff(&app, "imp_logged", false).await;
+        let target_id = app
+            .create_user("imp_logged_target", PASSWORD)
+            .await
+            .id()
+            .unwrap();
+        app.login("imp_logged", PASSWORD).await;
+
+        impersonate(&mut app, target_id).await;
+        assert!(app.get("/home").await.body.contains("imp_logged_target"));
+        app.post("/admin/stop-impersonating", &[]).await;
+
+        assert!(!app.get("/home").await.body.contains("imp_logged_target"));
+        assert_eq!(
+            audit::entries(app.db()).await.unwrap(),
+            [
+                (staff_id, "impersonate_start".to_owned(), Some(target_id)),
+                (staff_id, "impersonate_stop".to_owned(), Some(target_id)),
+            ]
+        );
+    }
+
+    #[cot::test]
+    async fn staff_changes_are_audited() {
+        let mut app = TestApp::new().await;
+        let superuser_id = create_staff(&app, "staff_granter", true).await;
+        let target_id = app
+            .create_user("staff_grantee", PASSWORD)
+            .await
+            .id()
+            .unwrap();
+        app.login("staff_granter", PASSWORD).await;
+
+        let response = app
+            .post(
+                &format!("/admin/users/{target_id}/staff"),
+                &[("is_staff", "true")],
+            )
+            .await;
+
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        let target = User::get_by_id(app.db(), target_id).await.unwrap().unwrap();
+        assert!(target.is_staff());
+        assert_eq!(
+            audit::entries(app.db()).await.unwrap(),
+            [(superuser_id, "staff_grant".to_owned(), Some(target_id))]
+        );
+    }
+
+    #[cot::test]
+    async fn staff_cannot_promote_themselves() {
+        let mut app = TestApp::new().await;
+        let staff_id = create_staff(&app, "staff_climber", false).await;
+        app.login("staff_climber", PASSWORD).await;
+
+        let response = app
+            .post(
+                &format!("/admin/users/{staff_id}/staff"),
+                &[("is_staff", "true")],
+            )
+            .await;
+
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        let staff = User::get_by_id(app.db(), staff_id).await.unwrap().unwrap();
+        assert!(!staff.is_superuser());
+        assert!(audit::entries(app.db()).await.unwrap().is_empty());
+    }
+}
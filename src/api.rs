@@ -0,0 +1,444 @@
+use crate::auth::User;
+use crate::client_ip::ClientIp;
+use crate::forms::fields::Redacted;
+use crate::forms::form_from_request;
+use crate::idle_timeout;
+use crate::password_strength;
+use crate::rate_limit::{self, RateLimiter};
+use crate::sessions;
+use cot::auth::{Auth, UserId};
+use cot::common_types::{Email, Password};
+use cot::db::{Database, Model};
+use cot::form::{Form, FormResult};
+use cot::json::Json;
+use cot::request::Request;
+use cot::request::extractors::UrlQuery;
+use cot::response::{IntoResponse, Response, ResponseExt};
+use cot::session::Session;
+use cot::{Body, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::info;
+
+/// The budgets of a lookup endpoint: one per client address, and one shared
+/// by every client whose address isn't known.
+///
+/// Without a trusted proxy no address is known, so the shared budget is all
+/// there is. It's sized for all the app's visitors together rather than one
+/// client, so a single client can't lock everyone else out with a handful of
+/// lookups; bulk checking is still capped, just not per client.
+struct LookupThrottle {
+    per_client: Box<dyn RateLimiter>,
+    unknown_clients: Box<dyn RateLimiter>,
+}
+
+impl LookupThrottle {
+    fn new(
+        scope: &'static str,
+        unknown_scope: &'static str,
+        per_client: u32,
+        unknown_clients: u32,
+    ) -> Self {
+        let window = Duration::from_secs(60);
+        Self {
+            per_client: rate_limit::from_config(scope, per_client, window),
+            unknown_clients: rate_limit::from_config(unknown_scope, unknown_clients, window),
+        }
+    }
+
+    /// Records a lookup from `client_ip`, returning the budget it ran out of
+    /// if it did.
+    async fn check(
+        &self,
+        db: &Database,
+        client_ip: ClientIp,
+    ) -> cot::Result<Result<(), (&dyn RateLimiter, Duration)>> {
+        let (limiter, key) = match client_ip.0 {
+            Some(ip) => (&*self.per_client, ip.to_string()),
+            None => (&*self.unknown_clients, String::new()),
+        };
+        Ok(limiter
+            .check(db, &key)
+            .await?
+            .map_err(|retry_after| (limiter, retry_after)))
+    }
+}
+
+// email lookups get tighter budgets since they disclose more
+static USERNAME_THROTTLE: LazyLock<LookupThrottle> =
+    LazyLock::new(|| LookupThrottle::new("username_lookup", "username_lookup_unknown", 30, 600));
+static EMAIL_THROTTLE: LazyLock<LookupThrottle> =
+    LazyLock::new(|| LookupThrottle::new("email_lookup", "email_lookup_unknown", 5, 60));
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct UsernameQuery {
+    username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct EmailQuery {
+    email: String,
+}
+
+#[derive(Form)]
+pub(crate) struct PasswordStrengthForm {
+    password: Password,
+}
+
+impl std::fmt::Debug for PasswordStrengthForm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PasswordStrengthForm")
+            .field("password", &Redacted)
+            .finish()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Availability {
+    available: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionStatus {
+    /// Seconds until the session times out without further requests, or
+    /// `None` if it never does.
+    remaining_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct PasswordStrength {
+    #[serde(flatten)]
+    strength: password_strength::Strength,
+    /// Whether the password clears the configured minimum.
+    acceptable: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ApiError {
+    error: &'static str,
+}
+
+fn method_not_allowed() -> cot::Result<Response> {
+    Json(ApiError {
+        error: "only POST is allowed",
+    })
+    .with_status(StatusCode::METHOD_NOT_ALLOWED)
+    .into_response()
+}
+
+fn not_logged_in() -> cot::Result<Response> {
+    Json(ApiError {
+        error: "not logged in",
+    })
+    .with_status(StatusCode::UNAUTHORIZED)
+    .into_response()
+}
+
+fn no_content() -> cot::Result<Response> {
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .expect("the status is valid"))
+}
+
+fn too_many_requests(limiter: &dyn RateLimiter, retry_after: Duration) -> cot::Result<Response> {
+    let mut response = Json(ApiError {
+        error: "too many requests",
+    })
+    .with_status(StatusCode::TOO_MANY_REQUESTS)
+    .into_response()?;
+    rate_limit::add_throttle_headers(response.headers_mut(), limiter.limit(), retry_after);
+    Ok(response)
+}
+
+pub(crate) async fn username_available(
+    UrlQuery(query): UrlQuery<UsernameQuery>,
+    db: Database,
+    client_ip: ClientIp,
+) -> cot::Result<Response> {
+    if let Err((limiter, retry_after)) = USERNAME_THROTTLE.check(&db, client_ip).await? {
+        return too_many_requests(limiter, retry_after);
+    }
+
+    let taken = User::exists_by_username(&db, &query.username).await?;
+    Json(Availability { available: !taken }).into_response()
+}
+
+/// Tells whether an address can still be signed up with.
+///
+/// This does say whether an account exists for the address, but signing up
+/// with it says so too: signup has to refuse an address that is taken. The
+/// answer stays exact so the form can say so before it's submitted; what
+/// keeps the endpoint from being used to check addresses in bulk is
+/// [`EMAIL_THROTTLE`]'s small budgets.
+pub(crate) async fn email_available(
+    UrlQuery(query): UrlQuery<EmailQuery>,
+    db: Database,
+    client_ip: ClientIp,
+) -> cot::Result<Response> {
+    if let Err((limiter, retry_after)) = EMAIL_THROTTLE.check(&db, client_ip).await? {
+        return too_many_requests(limiter, retry_after);
+    }
+
+    // a malformed address can't be registered, so report it as unavailable
+    // rather than telling the client why
+    let available = match Email::new(&query.email) {
+        Ok(email) => !User::exists_by_email(&db, &email).await?,
+        Err(_) => false,
+    };
+    Json(Availability { available }).into_response()
+}
+
+/// Keeps the session alive and reports how long it has left, so pages can warn
+/// users before they are logged out for inactivity.
+///
+/// The refresh itself is done by [`idle_timeout::IdleTimeoutMiddleware`], like
+/// for any other request.
+pub(crate) async fn session_ping(auth: Auth, session: Session) -> cot::Result<Response> {
+    if !auth.user().is_authenticated() {
+        return not_logged_in();
+    }
+
+    let remaining_secs = idle_timeout::remaining_secs(&session).await?;
+    Json(SessionStatus { remaining_secs }).into_response()
+}
+
+/// Scores a candidate password for a live strength meter. The password comes
+/// in a POSTed form so it stays out of URLs and access logs.
+pub(crate) async fn password_strength(mut request: Request) -> cot::Result<Response> {
+    if request.method() != Method::POST {
+        return method_not_allowed();
+    }
+    let FormResult::Ok(form) = form_from_request::<PasswordStrengthForm>(&mut request).await?
+    else {
+        return Json(ApiError {
+            error: "missing password",
+        })
+        .with_status(StatusCode::BAD_REQUEST)
+        .into_response();
+    };
+
+    Json(PasswordStrength {
+        strength: password_strength::estimate(form.password.as_str()),
+        acceptable: password_strength::validate_entropy(&form.password).is_ok(),
+    })
+    .into_response()
+}
+
+/// Logs the current session out. Answers `204 No Content` whether or not it
+/// was logged in.
+pub(crate) async fn logout(
+    auth: Auth,
+    db: Database,
+    session: Session,
+    request: Request,
+) -> cot::Result<Response> {
+    if request.method() != Method::POST {
+        return method_not_allowed();
+    }
+
+    sessions::forget(&session, &db).await?;
+    auth.logout().await?;
+    no_content()
+}
+
+/// Logs the user out of every session, this one included.
+pub(crate) async fn logout_all(
+    auth: Auth,
+    db: Database,
+    request: Request,
+) -> cot::Result<Response> {
+    if request.method() != Method::POST {
+        return method_not_allowed();
+    }
+    let Some(UserId::Int(user_id)) = auth.user().id() else {
+        return not_logged_in();
+    };
+
+    // rotating the salt also ends sessions that predate session tracking
+    if let Some(mut user) = User::get_by_id(&db, user_id).await? {
+        user.rotate_sessions().save(&db).await?;
+    }
+    sessions::forget_all(&db, user_id).await?;
+    auth.logout().await?;
+    info!(event = "logout_all", user_id, "logged out of every session");
+    no_content()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp, from_ip};
+    use std::collections::BTreeMap;
+
+    #[cot::test]
+    async fn reports_taken_and_free_usernames() {
+        let mut app = TestApp::new().await;
+        app.create_user("api_taken", PASSWORD).await;
+
+        let taken = app.get("/api/username-available?username=api_taken").await;
+        let free = app.get("/api/username-available?username=api_free").await;
+
+        assert_eq!(taken.body, r#"{"available":false}"#);
+        assert_eq!(free.body, r#"{"available":true}"#);
+    }
+
+    #[cot::test]
+    async fn answers_are_sent_as_json() {
+        let mut app = TestApp::new().await;
+
+        let response = app.get("/api/username-available?username=api_json").await;
+
+        assert_eq!(
+            response.headers[cot::http::header::CONTENT_TYPE],
+            "application/json"
+        );
+    }
+
+    #[cot::test]
+    async fn pinging_restarts_the_idle_countdown() {
+        let mut app = TestApp::with_config(|config| config.session_idle_timeout_secs = 2).await;
+        app.create_user("api_ping", PASSWORD).await;
+        app.login("api_ping", PASSWORD).await;
+        let first = app.get("/api/session/ping").await;
+        assert_eq!(first.body, r#"{"remaining_secs":2}"#);
+
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        let second = app.get("/api/session/ping").await;
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+
+        // three seconds after the first ping, but only half that since the last
+        assert_eq!(second.body, r#"{"remaining_secs":2}"#);
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn pinging_without_a_timeout_reports_none() {
+        let mut app = TestApp::new().await;
+        app.create_user("api_ping_forever", PASSWORD).await;
+        app.login("api_ping_forever", PASSWORD).await;
+
+        let response = app.get("/api/session/ping").await;
+
+        assert_eq!(response.body, r#"{"remaining_secs":null}"#);
+    }
+
+    #[cot::test]
+    async fn logging_out_ends_only_this_session() {
+        let mut app = TestApp::new().await;
+        app.create_user("api_logout", PASSWORD).await;
+        app.login("api_logout", PASSWORD).await;
+        let other_browser = app.swap_cookies(BTreeMap::new());
+        app.login("api_logout", PASSWORD).await;
+
+        let response = app.post("/api/logout", &[]).await;
+
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+        app.swap_cookies(other_browser);
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn logging_out_everywhere_ends_every_session() {
+        let mut app = TestApp::new().await;
+        app.create_user("api_logout_all", PASSWORD).await;
+        app.login("api_logout_all", PASSWORD).await;
+        let other_browser = app.swap_cookies(BTreeMap::new());
+        app.login("api_logout_all", PASSWORD).await;
+
+        let response = app.post("/api/logout-all", &[]).await;
+
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+        app.swap_cookies(other_browser);
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+        assert_eq!(
+            app.post("/api/logout-all", &[]).await.status,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[cot::test]
+    async fn reports_taken_and_free_emails() {
+        let mut app = TestApp::new().await;
+        app.create_user("api_email", PASSWORD).await;
+
+        let taken = app
+            .get("/api/email-available?email=api_email%40EXAMPLE.com")
+            .await;
+        let free = app
+            .get("/api/email-available?email=api_free%40example.com")
+            .await;
+
+        assert_eq!(taken.body, r#"{"available":false}"#);
+        assert_eq!(free.body, r#"{"available":true}"#);
+    }
+
+    #[cot::test]
+    async fn throttles_email_lookups_per_client() {
+        let mut app = TestApp::with_config(|config| config.behind_trusted_proxy = true).await;
+        let lookup = "/api/email-available?email=someone%40example.com";
+
+        for _ in 0..EMAIL_THROTTLE.per_client.limit() {
+            let response = app
+                .send(Method::GET, lookup, from_ip("192.0.2.10"), Body::empty())
+                .await;
+            assert_eq!(response.status, StatusCode::OK);
+        }
+        let throttled = app
+            .send(Method::GET, lookup, from_ip("192.0.2.10"), Body::empty())
+            .await;
+        let other_client = app
+            .send(Method::GET, lookup, from_ip("192.0.2.11"), Body::empty())
+            .await;
+
+        assert_eq!(throttled.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(throttled.headers.contains_key("retry-after"));
+        assert_eq!(other_client.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn unknown_clients_share_a_larger_budget() {
+        let mut app = TestApp::new().await;
+
+        // more than any one known client gets, from clients with no address
+        for _ in 0..=EMAIL_THROTTLE.per_client.limit() {
+            let response = app
+                .get("/api/email-available?email=someone%40example.com")
+                .await;
+            assert_eq!(response.status, StatusCode::OK);
+        }
+    }
+
+    #[cot::test]
+    async fn the_shared_budget_is_separate_from_known_clients() {
+        let test_db = cot::test::TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        let throttle = LookupThrottle {
+            per_client: Box::new(rate_limit::InMemoryRateLimiter::new(
+                1,
+                Duration::from_secs(60),
+            )),
+            unknown_clients: Box::new(rate_limit::InMemoryRateLimiter::new(
+                2,
+                Duration::from_secs(60),
+            )),
+        };
+        let known = ClientIp(Some("192.0.2.20".parse().unwrap()));
+
+        assert!(throttle.check(&db, ClientIp(None)).await.unwrap().is_ok());
+        assert!(throttle.check(&db, ClientIp(None)).await.unwrap().is_ok());
+        let (limiter, _) = throttle
+            .check(&db, ClientIp(None))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(limiter.limit(), 2);
+
+        assert!(throttle.check(&db, known).await.unwrap().is_ok());
+        let (limiter, _) = throttle.check(&db, known).await.unwrap().unwrap_err();
+        assert_eq!(limiter.limit(), 1);
+    }
+}
@@ -0,0 +1,50 @@
+//! A log of security-sensitive actions staff take on other accounts.
+
+use chrono::{DateTime, FixedOffset, Utc};
+use cot::db::{Auto, Database, LimitedString, Model, model};
+use tracing::info;
+
+#[derive(Debug, Clone)]
+#[model]
+pub(crate) struct AuditEntry {
+    #[model(primary_key)]
+    id: Auto<i64>,
+    /// The user who took the action.
+    actor_id: i64,
+    /// What was done, e.g. `impersonate_start`.
+    action: LimitedString<64>,
+    /// The user the action was taken on, if any.
+    target_id: Option<i64>,
+    created_at: DateTime<FixedOffset>,
+}
+
+/// Records that `actor_id` did `action`, optionally to `target_id`.
+pub(crate) async fn record(
+    db: &Database,
+    actor_id: i64,
+    action: &'static str,
+    target_id: Option<i64>,
+) -> cot::Result<()> {
+    AuditEntry {
+        id: Auto::auto(),
+        actor_id,
+        action: LimitedString::new(action).expect("action names are short"),
+        target_id,
+        created_at: Utc::now().fixed_offset(),
+    }
+    .save(db)
+    .await?;
+    info!(event = "audit", actor_id, action, target_id, "audit");
+    Ok(())
+}
+
+/// Every entry, oldest first, as its actor, action and target.
+#[cfg(test)]
+pub(crate) async fn entries(db: &Database) -> cot::Result<Vec<(i64, String, Option<i64>)>> {
+    let mut entries = AuditEntry::objects().all(db).await?;
+    entries.sort_by_key(|entry| entry.created_at);
+    Ok(entries
+        .into_iter()
+        .map(|entry| (entry.actor_id, entry.action.to_string(), entry.target_id))
+        .collect())
+}
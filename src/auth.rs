@@ -1,18 +1,34 @@
-use crate::forms::login::LoginForm;
+use crate::audit;
+use crate::client_ip::ClientIp;
+use crate::config::app_config;
+use crate::password_hashing;
+use crate::profile_attributes;
+use crate::rate_limit::{self, RateLimiter};
+use crate::request_timing;
+use crate::reset_codes;
+use crate::sessions;
+use crate::telemetry::{self, time_password_hash};
 use async_trait::async_trait;
-use cot::auth::db::CreateUserError;
+use chrono::{DateTime, FixedOffset, Utc};
 use cot::auth::{
     Auth, AuthBackend, AuthError, PasswordHash, PasswordVerificationResult, SessionAuthHash, UserId,
 };
 use cot::common_types::{Email, Password};
 use cot::config::SecretKey;
-use cot::db::{Auto, Database, LimitedString, Model, model, query};
-use cot::form::Form;
+use cot::db::{Auto, Database, DatabaseError, LimitedString, Model, model, query};
+use cot::form::{Form, FormFieldValidationError};
+use cot::session::Session;
 use hmac::{Hmac, Mac};
+use metrics::counter;
 use sha2::Sha512;
 use std::any::Any;
 use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use unicode_normalization::UnicodeNormalization;
+use zeroize::Zeroize;
 
 #[derive(Debug, Clone, Form)]
 #[model]
@@ -23,10 +39,152 @@ pub struct User {
     username: LimitedString<254>,
     name: LimitedString<254>,
     password: PasswordHash,
+    #[model(unique)]
     email: Email,
+    locale: Option<String>,
+    is_active: bool,
+    last_login: Option<DateTime<FixedOffset>>,
+    /// When the account was created; unknown for accounts that predate the
+    /// column.
+    created_at: Option<DateTime<FixedOffset>>,
+    /// When the password was last changed; part of the data reset tokens are
+    /// signed over, so changing the password invalidates every outstanding
+    /// token.
+    password_changed_at: Option<DateTime<FixedOffset>>,
+    /// Set by staff to make the user pick a new password before they can use
+    /// the site again.
+    must_change_password: bool,
+    /// `false` for accounts created through an external identity provider,
+    /// which get a random password nobody knows until they set one through
+    /// the reset flow.
+    has_usable_password: bool,
+    is_staff: bool,
+    /// When the account was last activated or deactivated.
+    updated_at: Option<DateTime<FixedOffset>>,
+    /// When the user followed the link sent to their email address, or
+    /// `None` if they haven't yet.
+    email_verified_at: Option<DateTime<FixedOffset>>,
+    /// Mixed into the session auth hash; changing it ends every session the
+    /// user is logged in with. `None` until it is first rotated.
+    session_salt: Option<String>,
+    /// Where SMS password reset codes are sent, in E.164 form.
+    phone: Option<String>,
+    /// Whether the user can grant and revoke other users' staff status.
+    /// Always staff as well.
+    is_superuser: bool,
+}
+
+/// Builds a new, unsaved [`User`] from named parts.
+///
+/// The username is normalized like [`normalize_username`] and the email like
+/// [`normalize_email`]. Policy checks such as [`validate_username`] are left
+/// to the caller; [`UserBuilder::build`] only checks that every part is there
+/// and fits its column.
+#[derive(Debug, Default)]
+pub struct UserBuilder {
+    username: Option<String>,
+    password: Option<Password>,
+    unusable_password: bool,
+    email: Option<Email>,
+    name: String,
+}
+
+impl UserBuilder {
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn password(mut self, password: &Password) -> Self {
+        self.password = Some(password.clone());
+        self
+    }
+
+    /// Makes the user unable to log in with a password, e.g. one signed up
+    /// through an external identity provider. Takes the place of
+    /// [`UserBuilder::password`].
+    pub fn unusable_password(mut self) -> Self {
+        self.unusable_password = true;
+        self
+    }
+
+    pub fn email(mut self, email: Email) -> Self {
+        self.email = Some(email);
+        self
+    }
+
+    /// The user's full name. Optional; defaults to empty.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn build(self) -> Result<User, UserBuildError> {
+        let username = normalize_username(&self.username.unwrap_or_default());
+        if username.is_empty() {
+            return Err(UserBuildError::Missing("username"));
+        }
+        let username =
+            LimitedString::new(username).map_err(|_| UserBuildError::TooLong("username"))?;
+        let name = LimitedString::new(self.name).map_err(|_| UserBuildError::TooLong("name"))?;
+        let email = self.email.ok_or(UserBuildError::Missing("email"))?;
+        let password = match (self.password, self.unusable_password) {
+            (_, true) => Password::new(hex::encode(rand::random::<[u8; 32]>())),
+            (Some(password), false) => password,
+            (None, false) => return Err(UserBuildError::Missing("password")),
+        };
+
+        Ok(User {
+            id: Auto::auto(),
+            username,
+            password: time_password_hash("hash", || password_hashing::hash(&password)),
+            email: normalize_email(&email),
+            name,
+            locale: None,
+            is_active: true,
+            last_login: None,
+            created_at: Some(Utc::now().fixed_offset()),
+            password_changed_at: None,
+            must_change_password: false,
+            has_usable_password: !self.unusable_password,
+            is_staff: false,
+            updated_at: None,
+            email_verified_at: None,
+            session_salt: None,
+            phone: None,
+            is_superuser: false,
+        })
+    }
 }
 
+/// Why [`UserBuilder::build`] couldn't make a user. Each variant names the
+/// part at fault.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UserBuildError {
+    Missing(&'static str),
+    /// The part doesn't fit its database column.
+    TooLong(&'static str),
+}
+
+impl Display for UserBuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UserBuildError::Missing(part) => write!(f, "a user needs a {part}"),
+            UserBuildError::TooLong(part) => write!(f, "the user's {part} is too long"),
+        }
+    }
+}
+
+impl std::error::Error for UserBuildError {}
+
 impl User {
+    /// Starts building a new user; see [`UserBuilder`].
+    pub fn builder() -> UserBuilder {
+        UserBuilder::default()
+    }
+
+    #[deprecated(note = "use `User::builder()`, which names each part")]
+    #[expect(unused)]
     pub fn new(
         id: Auto<i64>,
         username: LimitedString<254>,
@@ -34,32 +192,42 @@ impl User {
         email: Email,
         name: LimitedString<254>,
     ) -> Self {
-        Self {
-            id,
-            username,
-            password: PasswordHash::from_password(password),
-            email,
-            name,
-        }
+        let mut user = Self::builder()
+            .username(username.as_str())
+            .password(password)
+            .email(email)
+            .name(name.as_str())
+            .build()
+            .expect("every part is given and fits its column");
+        user.id = id;
+        user
     }
 
     pub async fn authenticate<DB: cot::db::DatabaseBackend>(
         db: &DB,
         credentials: &UserCredentials,
     ) -> cot::auth::Result<Option<Self>> {
-        let username = credentials.username();
-        let username_limited = LimitedString::<254>::new(username.to_string()).map_err(|_| {
-            AuthError::backend_error(CreateUserError::UsernameTooLong(username.len()))
-        })?;
+        // no stored username can be this long, so it's simply not a match
+        let Ok(username_limited) =
+            LimitedString::<254>::new(normalize_username(credentials.username()))
+        else {
+            verify_dummy_password(credentials.password());
+            return Ok(None);
+        };
 
         let user = query!(User, $username == username_limited)
             .get(db)
             .await
             .map_err(AuthError::backend_error)?;
 
+        // users without a usable password still go through verification so
+        // they take as long to reject as a wrong password does
         if let Some(mut user) = user {
             let password_hash = &user.password;
-            match password_hash.verify(credentials.password()) {
+            match time_password_hash("verify", || {
+                password_hashing::verify(password_hash, credentials.password())
+            }) {
+                _ if !user.has_usable_password => Ok(None),
                 PasswordVerificationResult::Ok => Ok(Some(user)),
                 PasswordVerificationResult::OkObsolete(new_hash) => {
                     user.password = new_hash;
@@ -69,6 +237,7 @@ impl User {
                 PasswordVerificationResult::Invalid => Ok(None),
             }
         } else {
+            verify_dummy_password(credentials.password());
             Ok(None)
         }
     }
@@ -78,10 +247,16 @@ impl User {
     }
 
     #[must_use]
-    pub fn id(&self) -> i64 {
+    pub fn email(&self) -> &Email {
+        &self.email
+    }
+
+    /// The user's database id, or `None` if the user hasn't been saved yet.
+    #[must_use]
+    pub fn id(&self) -> Option<i64> {
         match self.id {
-            Auto::Fixed(id) => id,
-            Auto::Auto => unreachable!("DatabaseUser constructed with an unknown ID"),
+            Auto::Fixed(id) => Some(id),
+            Auto::Auto => None,
         }
     }
     #[must_use]
@@ -90,9 +265,21 @@ impl User {
     }
 
     #[must_use]
+    #[expect(unused)]
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// The name to greet the user by: their full name, or their username for
+    /// accounts that have none.
+    #[must_use]
+    pub fn display_name(&self) -> &str {
+        match self.name.trim() {
+            "" => &self.username,
+            name => name,
+        }
+    }
+
     pub async fn get_by_id<DB: cot::db::DatabaseBackend>(
         db: &DB,
         id: i64,
@@ -104,17 +291,415 @@ impl User {
 
         Ok(db_user)
     }
-    pub async fn set_password(&mut self, password: &Password) -> &mut Self {
-        self.password = PasswordHash::from_password(password);
+    /// Finds a user by their email address, normalized the way addresses are
+    /// stored.
+    pub async fn get_by_email<DB: cot::db::DatabaseBackend>(
+        db: &DB,
+        email: &Email,
+    ) -> cot::auth::Result<Option<Self>> {
+        let email = normalize_email(email);
+        query!(User, $email == email)
+            .get(db)
+            .await
+            .map_err(AuthError::backend_error)
+    }
+
+    /// Finds a user by either their email address or their username.
+    pub async fn get_by_email_or_username<DB: cot::db::DatabaseBackend>(
+        db: &DB,
+        identifier: &str,
+    ) -> cot::auth::Result<Option<Self>> {
+        if let Ok(email) = Email::new(identifier)
+            && let Some(user) = Self::get_by_email(db, &email).await?
+        {
+            return Ok(Some(user));
+        }
+
+        Self::get_by_username(db, identifier).await
+    }
+
+    pub async fn get_by_username<DB: cot::db::DatabaseBackend>(
+        db: &DB,
+        username: &str,
+    ) -> cot::auth::Result<Option<Self>> {
+        let Ok(username) = LimitedString::<254>::new(normalize_username(username)) else {
+            return Ok(None);
+        };
+        query!(User, $username == username)
+            .get(db)
+            .await
+            .map_err(AuthError::backend_error)
+    }
+    pub async fn exists_by_username<DB: cot::db::DatabaseBackend>(
+        db: &DB,
+        username: &str,
+    ) -> cot::auth::Result<bool> {
+        let Ok(username) = LimitedString::<254>::new(normalize_username(username)) else {
+            return Ok(false);
+        };
+
+        query!(User, $username == username)
+            .exists(db)
+            .await
+            .map_err(AuthError::backend_error)
+    }
+
+    pub async fn exists_by_email<DB: cot::db::DatabaseBackend>(
+        db: &DB,
+        email: &Email,
+    ) -> cot::auth::Result<bool> {
+        let email = normalize_email(email);
+        query!(User, $email == email)
+            .exists(db)
+            .await
+            .map_err(AuthError::backend_error)
+    }
+
+    pub async fn all<DB: cot::db::DatabaseBackend>(db: &DB) -> cot::auth::Result<Vec<Self>> {
+        let users = User::objects()
+            .all(db)
+            .await
+            .map_err(AuthError::backend_error)?;
+
+        Ok(users)
+    }
+
+    /// Whether the stored hash isn't in the configured algorithm with its
+    /// current parameters.
+    ///
+    /// Such hashes can only be upgraded once the plaintext is known again, which
+    /// happens transparently on the user's next login.
+    #[must_use]
+    pub fn has_obsolete_password_hash(&self) -> bool {
+        password_hashing::is_obsolete(&self.password)
+    }
+
+    /// The locale the user picked on their profile, if any.
+    #[must_use]
+    pub fn locale(&self) -> Option<&str> {
+        self.locale.as_deref()
+    }
+
+    /// Normalizes the stored username with [`normalize_username`], for
+    /// accounts created before usernames were normalized. Returns whether it
+    /// changed, or an error if the normalized name no longer fits the column.
+    pub(crate) fn normalize_stored_username(&mut self) -> Result<bool, UserBuildError> {
+        let normalized = normalize_username(&self.username);
+        if normalized == self.username.as_str() {
+            return Ok(false);
+        }
+        self.username =
+            LimitedString::new(normalized).map_err(|_| UserBuildError::TooLong("username"))?;
+        Ok(true)
+    }
+
+    pub fn set_locale(&mut self, locale: Option<String>) -> &mut Self {
+        self.locale = locale;
+        self
+    }
+
+    pub fn phone(&self) -> Option<&str> {
+        self.phone.as_deref()
+    }
+
+    /// Sets the phone number, which should already be normalized with
+    /// [`crate::sms::normalize_phone`].
+    pub fn set_phone(&mut self, phone: Option<String>) -> &mut Self {
+        self.phone = phone;
+        self
+    }
+
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.is_active
+    }
+
+    /// Stops the user from logging in. Sessions they are already logged in
+    /// with are ended on their next request by
+    /// [`crate::sessions::SessionLimitMiddleware`].
+    pub fn deactivate(&mut self) -> &mut Self {
+        self.is_active = false;
+        self.updated_at = Some(Utc::now().fixed_offset());
+        self
+    }
+
+    /// Whether the user has confirmed they own their email address.
+    #[must_use]
+    pub fn is_email_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+
+    pub fn mark_email_verified(&mut self) -> &mut Self {
+        self.email_verified_at = Some(Utc::now().fixed_offset());
+        self
+    }
+
+    /// Ends every session the user is logged in with, wherever sessions are
+    /// stored: each one fails its auth hash check on its next request.
+    pub fn rotate_sessions(&mut self) -> &mut Self {
+        self.session_salt = Some(hex::encode(rand::random::<[u8; 16]>()));
+        self
+    }
+
+    /// Scrubs the personal data from the account and saves it, keeping the
+    /// row so records pointing at the user stay valid.
+    ///
+    /// The username and email are replaced with random placeholders that
+    /// can't be traced back to the originals, the name, phone and locale are
+    /// cleared along with the profile details, and the password is made
+    /// unusable. The account is also
+    /// deactivated and logged out everywhere, so it can't be used again.
+    pub async fn anonymize(&mut self, db: &Database) -> cot::Result<()> {
+        let id = self.id().expect("only saved users can be anonymized");
+        let old_username = self.username().to_owned();
+        let placeholder = format!("deleted-{}", hex::encode(rand::random::<[u8; 16]>()));
+
+        self.email = Email::new(format!("{placeholder}@anonymized.invalid"))
+            .expect("the placeholder is a valid address");
+        self.username = LimitedString::new(placeholder).expect("the placeholder is short");
+        self.name = LimitedString::new(String::new()).expect("an empty name fits");
+        self.phone = None;
+        self.locale = None;
+        self.email_verified_at = None;
+        let password = Password::new(hex::encode(rand::random::<[u8; 32]>()));
+        self.password = time_password_hash("hash", || password_hashing::hash(&password));
+        self.has_usable_password = false;
+        self.must_change_password = false;
+        self.rotate_sessions().deactivate().save(db).await?;
+
+        sessions::forget_all(db, id).await?;
+        reset_codes::consume(db, id).await?;
+        profile_attributes::clear_all(db, id).await?;
+        // failed logins are counted by username
+        Self::reset_failed_attempts(db, &old_username).await
+    }
+
+    #[cfg_attr(not(test), expect(unused))]
+    pub fn activate(&mut self) -> &mut Self {
+        self.is_active = true;
+        self.updated_at = Some(Utc::now().fixed_offset());
+        self
+    }
+
+    /// Whether the user hasn't logged in since `cutoff`.
+    ///
+    /// Users who never logged in count as stale once their account is older
+    /// than `cutoff`; accounts of unknown age never do.
+    #[must_use]
+    pub fn is_stale(&self, cutoff: DateTime<FixedOffset>) -> bool {
+        match (self.last_login, self.created_at) {
+            (Some(last_login), _) => last_login < cutoff,
+            (None, Some(created_at)) => created_at < cutoff,
+            (None, None) => false,
+        }
+    }
+
+    /// Clears the failed logins counted against `username`, unlocking it if
+    /// it was locked.
+    pub async fn reset_failed_attempts(db: &Database, username: &str) -> cot::Result<()> {
+        FAILED_LOGINS.reset(db, username).await
+    }
+
+    #[must_use]
+    pub fn last_login(&self) -> Option<DateTime<FixedOffset>> {
+        self.last_login
+    }
+
+    /// Records a successful login.
+    pub async fn touch_last_login<DB: cot::db::DatabaseBackend>(
+        db: &DB,
+        id: i64,
+    ) -> cot::Result<()> {
+        if let Some(mut user) = Self::get_by_id(db, id).await? {
+            user.last_login = Some(Utc::now().fixed_offset());
+            user.save(db).await?;
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn password_changed_at(&self) -> Option<DateTime<FixedOffset>> {
+        self.password_changed_at
+    }
+
+    #[must_use]
+    pub fn must_change_password(&self) -> bool {
+        self.must_change_password
+    }
+
+    pub fn set_must_change_password(&mut self, must_change_password: bool) -> &mut Self {
+        self.must_change_password = must_change_password;
+        self
+    }
+
+    #[must_use]
+    pub fn has_usable_password(&self) -> bool {
+        self.has_usable_password
+    }
+
+    #[must_use]
+    pub fn is_staff(&self) -> bool {
+        self.is_staff
+    }
+
+    /// Grants or revokes staff status without recording who did it, for the
+    /// command line, where no user is acting. Everywhere else, use
+    /// [`User::set_staff`]. Revoking staff status also revokes superuser
+    /// status.
+    pub fn set_staff_unaudited(&mut self, is_staff: bool) -> &mut Self {
+        self.is_staff = is_staff;
+        self.is_superuser &= is_staff;
+        self
+    }
+
+    /// Grants or revokes staff status on behalf of user `actor_id`, saves the
+    /// user and records who made the change in the audit log.
+    ///
+    /// Revoking staff status also revokes superuser status. Whether
+    /// `actor_id` may make the change is up to the caller.
+    pub async fn set_staff(
+        &mut self,
+        is_staff: bool,
+        db: &Database,
+        actor_id: i64,
+    ) -> cot::Result<()> {
+        let id = self.id().expect("only saved users can become staff");
+        self.set_staff_unaudited(is_staff).save(db).await?;
+        let action = if is_staff {
+            "staff_grant"
+        } else {
+            "staff_revoke"
+        };
+        audit::record(db, actor_id, action, Some(id)).await
+    }
+
+    #[must_use]
+    pub fn is_superuser(&self) -> bool {
+        self.is_superuser
+    }
+
+    /// Grants or revokes superuser status, which is only done from the
+    /// command line. Granting it also makes the user staff.
+    pub fn set_superuser(&mut self, is_superuser: bool) -> &mut Self {
+        self.is_superuser = is_superuser;
+        self.is_staff |= is_superuser;
+        self
+    }
+
+    /// The user's role, as used to pick their landing page.
+    #[must_use]
+    pub fn role(&self) -> &'static str {
+        if self.is_staff { "staff" } else { "user" }
+    }
+
+    /// Whether `password` is the user's current password.
+    #[must_use]
+    pub fn check_password(&self, password: &Password) -> bool {
+        self.has_usable_password
+            && !matches!(
+                time_password_hash("verify", || password_hashing::verify(
+                    &self.password,
+                    password
+                )),
+                PasswordVerificationResult::Invalid
+            )
+    }
+
+    /// Replaces the user's password, which also lifts a pending
+    /// forced password change and makes the password usable for logging in.
+    ///
+    /// Like the other setters this only changes the in-memory user; call
+    /// `save` on the result to persist it.
+    pub fn set_password(&mut self, password: &Password) -> &mut Self {
+        self.password = time_password_hash("hash", || password_hashing::hash(password));
+        self.password_changed_at = Some(Utc::now().fixed_offset());
+        self.must_change_password = false;
+        self.has_usable_password = true;
         self
     }
 }
 
+/// A hash of a random password, made with the current hashing parameters.
+static DUMMY_PASSWORD_HASH: LazyLock<PasswordHash> = LazyLock::new(|| {
+    password_hashing::hash(&Password::new(hex::encode(rand::random::<[u8; 32]>())))
+});
+
+/// Verifies `password` against [`DUMMY_PASSWORD_HASH`] and throws the result
+/// away, so a login for an unknown username takes as long as one with a
+/// wrong password and response times don't reveal which usernames exist.
+fn verify_dummy_password(password: &Password) {
+    let _ = time_password_hash("verify", || {
+        password_hashing::verify(&DUMMY_PASSWORD_HASH, password)
+    });
+}
+
+/// Normalizes `username` the way usernames are stored: NFKC, so names that
+/// look the same, like `ﬁona` with its ligature and `fiona`, can't be told
+/// apart at signup or login.
+pub(crate) fn normalize_username(username: &str) -> String {
+    username.nfkc().collect()
+}
+
+/// The longest username the `username` column can hold.
+const USERNAME_COLUMN_LEN: usize = 254;
+
+/// The shortest and longest usernames allowed, in characters.
+pub(crate) fn username_length_limits() -> (usize, usize) {
+    let config = &app_config().username;
+    (
+        config.min_length,
+        config.max_length.min(USERNAME_COLUMN_LEN),
+    )
+}
+
+/// Checks a new username, already normalized, against the length limits and
+/// reserved names in [`crate::config::UsernameConfig`].
+pub(crate) fn validate_username(username: &str) -> Result<(), FormFieldValidationError> {
+    let config = &app_config().username;
+    let (min_length, max_length) = username_length_limits();
+    let length = username.chars().count();
+    if length < min_length {
+        return Err(FormFieldValidationError::from_string(format!(
+            "usernames need at least {min_length} characters."
+        )));
+    }
+    if length > max_length {
+        return Err(FormFieldValidationError::from_string(format!(
+            "usernames can have at most {max_length} characters."
+        )));
+    }
+    let lowercase = username.to_lowercase();
+    if config
+        .reserved
+        .iter()
+        .any(|reserved| reserved.to_lowercase() == lowercase)
+    {
+        return Err(FormFieldValidationError::from_static(
+            "this username is reserved.",
+        ));
+    }
+    Ok(())
+}
+
+/// Normalizes `email` the way addresses are stored: the domain is always
+/// lowercased, and so is the local part unless
+/// `AppConfig::lowercase_email_local_part` is turned off.
+pub(crate) fn normalize_email(email: &Email) -> Email {
+    let address = email.as_str();
+    let normalized = match address.rsplit_once('@') {
+        Some((local, domain)) if !app_config().lowercase_email_local_part => {
+            format!("{local}@{}", domain.to_lowercase())
+        }
+        _ => address.to_lowercase(),
+    };
+    Email::new(&normalized).expect("changing the case keeps an address valid")
+}
+
 type SessionAuthHmac = Hmac<Sha512>;
 
 impl cot::auth::User for User {
     fn id(&self) -> Option<UserId> {
-        Some(UserId::Int(self.id()))
+        self.id().map(UserId::Int)
     }
 
     fn username(&self) -> Option<Cow<'_, str>> {
@@ -122,7 +707,7 @@ impl cot::auth::User for User {
     }
 
     fn is_active(&self) -> bool {
-        true
+        self.is_active
     }
 
     fn is_authenticated(&self) -> bool {
@@ -133,6 +718,11 @@ impl cot::auth::User for User {
         let mut mac = SessionAuthHmac::new_from_slice(secret_key.as_bytes())
             .expect("HMAC can take key of any size");
         mac.update(self.password.as_str().as_bytes());
+        // users whose salt was never rotated keep the hash they had before
+        // salts existed, so their sessions survive the upgrade
+        if let Some(salt) = &self.session_salt {
+            mac.update(salt.as_bytes());
+        }
         let hmac_data = mac.finalize().into_bytes();
 
         Some(SessionAuthHash::new(&hmac_data))
@@ -151,6 +741,16 @@ pub struct UserCredentials {
     password: Password,
 }
 
+/// Wipes the plaintext password from memory once the credentials are done
+/// with. `Password` itself doesn't.
+impl Drop for UserCredentials {
+    fn drop(&mut self) {
+        std::mem::replace(&mut self.password, Password::new(String::new()))
+            .into_string()
+            .zeroize();
+    }
+}
+
 impl UserCredentials {
     pub fn new(username: String, password: Password) -> Self {
         Self { username, password }
@@ -181,11 +781,12 @@ impl AuthBackend for UserBackend {
         credentials: &(dyn Any + Send + Sync),
     ) -> cot::auth::Result<Option<Box<dyn cot::auth::User + Send + Sync>>> {
         if let Some(credentials) = credentials.downcast_ref::<UserCredentials>() {
-            let user = User::authenticate(&self.database, credentials)
-                .await
-                .map(|user| {
-                    user.map(|user| Box::new(user) as Box<dyn cot::auth::User + Send + Sync>)
-                })?;
+            let start = Instant::now();
+            let user = with_db_retry(|| User::authenticate(&self.database, credentials)).await;
+            request_timing::warn_if_slow_query("authenticate", start);
+            let user = user.map(|user| {
+                user.map(|user| Box::new(user) as Box<dyn cot::auth::User + Send + Sync>)
+            })?;
             Ok(user)
         } else {
             Err(AuthError::CredentialsTypeNotSupported)
@@ -200,25 +801,625 @@ impl AuthBackend for UserBackend {
             return Err(AuthError::UserIdTypeNotSupported);
         };
 
+        let start = Instant::now();
+        let user = with_db_retry(|| User::get_by_id(&self.database, id)).await;
+        request_timing::warn_if_slow_query("get_user_by_id", start);
         #[expect(trivial_casts)]
-        let user = User::get_by_id(&self.database, id)
-            .await?
-            .map(|user| Box::new(user) as Box<dyn cot::auth::User + Send + Sync>);
+        let user = user?.map(|user| Box::new(user) as Box<dyn cot::auth::User + Send + Sync>);
         Ok(user)
     }
 }
 
-pub(crate) async fn authenticate(auth: &Auth, login_form: &LoginForm) -> cot::Result<bool> {
-    let user = auth
-        .authenticate(&UserCredentials::new(
-            login_form.username.clone(),
-            Password::new(login_form.password.clone().into_string()),
-        ))
-        .await?;
+/// Runs `op`, retrying it with an exponential backoff while it fails with a
+/// database error that is likely to clear up on its own.
+async fn with_db_retry<T, F, Fut>(mut op: F) -> cot::auth::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = cot::auth::Result<T>>,
+{
+    let config = &app_config().db_retry;
+    let mut backoff = Duration::from_millis(config.backoff_ms);
+    let mut retries = 0;
+    loop {
+        match op().await {
+            Err(err) if retries < config.max_retries && is_transient(&err) => {
+                warn!(error = %err, retry = retries + 1, "retrying a transient database error");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `err` is a connection-level or lock-contention failure rather than
+/// something retrying can't fix, like a bad query.
+fn is_transient(err: &AuthError) -> bool {
+    let AuthError::UserBackend(source) = err else {
+        return false;
+    };
+    let Some(DatabaseError::DatabaseEngineError(err)) = source.downcast_ref::<DatabaseError>()
+    else {
+        return false;
+    };
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        // SQLITE_BUSY and SQLITE_LOCKED
+        sqlx::Error::Database(err) => matches!(err.code().as_deref(), Some("5" | "6")),
+        _ => false,
+    }
+}
+
+/// Loads the currently logged-in user, if there is one.
+pub(crate) async fn current_user(auth: &Auth, db: &Database) -> cot::Result<Option<User>> {
+    match auth.user().id() {
+        Some(UserId::Int(id)) => Ok(User::get_by_id(db, id).await?),
+        _ => Ok(None),
+    }
+}
+
+/// How many failed logins for the same username lock the account.
+const MAX_FAILED_LOGINS: u32 = 5;
+/// How long an account stays locked after too many failed logins.
+const LOCKOUT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+static FAILED_LOGINS: LazyLock<Box<dyn RateLimiter>> =
+    LazyLock::new(|| rate_limit::from_config("failed_login", MAX_FAILED_LOGINS, LOCKOUT_WINDOW));
+
+#[derive(Debug)]
+pub(crate) enum LoginError {
+    InvalidCredentials,
+    AccountInactive,
+    /// The user hasn't verified their email address and
+    /// `AppConfig::require_verified_email_for_login` is on.
+    EmailUnverified,
+    AccountLocked {
+        /// How many failed logins lock an account.
+        limit: u32,
+        retry_after: Duration,
+    },
+    Other(cot::Error),
+}
+
+impl Display for LoginError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // an inactive account gets the generic message so it doesn't
+            // reveal that the username exists
+            LoginError::InvalidCredentials | LoginError::AccountInactive => {
+                write!(f, "Invalid username or password")
+            }
+            LoginError::EmailUnverified => {
+                write!(f, "Please verify your email address before logging in.")
+            }
+            LoginError::AccountLocked { retry_after, .. } => {
+                let minutes = retry_after.as_secs().div_ceil(60).max(1);
+                write!(
+                    f,
+                    "Too many failed login attempts. Try again in {minutes} minute(s)."
+                )
+            }
+            LoginError::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<cot::Error> for LoginError {
+    fn from(err: cot::Error) -> Self {
+        LoginError::Other(err)
+    }
+}
+
+impl From<AuthError> for LoginError {
+    fn from(err: AuthError) -> Self {
+        LoginError::Other(err.into())
+    }
+}
+
+/// Whether the user with `user_id` has verified their email address.
+async fn is_email_verified(db: &Database, user_id: Option<UserId>) -> cot::Result<bool> {
+    let Some(UserId::Int(id)) = user_id else {
+        return Ok(false);
+    };
+    Ok(User::get_by_id(db, id)
+        .await?
+        .is_some_and(|user| user.is_email_verified()))
+}
+
+pub(crate) async fn authenticate(
+    auth: &Auth,
+    db: &Database,
+    session: &Session,
+    username: &str,
+    password: Password,
+    client_ip: ClientIp,
+) -> Result<(), LoginError> {
+    // the password is moved in rather than copied, and wiped when the
+    // credentials are dropped
+    let credentials = UserCredentials::new(username.to_owned(), password);
+    let username = normalize_username(username);
+    let username = username.as_str();
+    if let Some(retry_after) = FAILED_LOGINS.retry_after(db, username).await? {
+        warn!(event = "login", outcome = "locked", username, %client_ip, "login attempt on a locked account");
+        counter!(telemetry::LOGINS, "outcome" => "locked").increment(1);
+        return Err(LoginError::AccountLocked {
+            limit: FAILED_LOGINS.limit(),
+            retry_after,
+        });
+    }
+
+    let user = auth.authenticate(&credentials).await?;
     if let Some(user) = user {
+        if !user.is_active() {
+            warn!(event = "login", outcome = "inactive", username, %client_ip, "login attempt on an inactive account");
+            counter!(telemetry::LOGINS, "outcome" => "inactive").increment(1);
+            return Err(LoginError::AccountInactive);
+        }
+        if app_config().require_verified_email_for_login
+            && !is_email_verified(db, user.id()).await?
+        {
+            warn!(event = "login", outcome = "unverified", username, %client_ip, "login attempt with an unverified email");
+            counter!(telemetry::LOGINS, "outcome" => "unverified").increment(1);
+            return Err(LoginError::EmailUnverified);
+        }
+        let user_id = user.id();
+        // `Auth::login` cycles the session id, so a session fixated before
+        // login can't be reused afterwards. There are no CSRF tokens to rotate:
+        // `CsrfMiddleware` checks where requests come from instead.
         auth.login(user).await?;
-        Ok(true)
+        // earlier failures shouldn't count towards a lockout once the user
+        // has proven they know the password
+        User::reset_failed_attempts(db, username).await?;
+        if let Some(UserId::Int(id)) = user_id {
+            User::touch_last_login(db, id).await?;
+            sessions::register(session, db, id).await?;
+        }
+        info!(event = "login", outcome = "success", username, %client_ip, "user logged in");
+        counter!(telemetry::LOGINS, "outcome" => "success").increment(1);
+        Ok(())
     } else {
-        Ok(false)
+        // the limiter refuses hits past the limit, which is fine: the account
+        // is locked at that point anyway
+        let _ = FAILED_LOGINS.check(db, username).await?;
+        warn!(event = "login", outcome = "failure", username, %client_ip, "failed login");
+        counter!(telemetry::LOGINS, "outcome" => "failure").increment(1);
+        Err(LoginError::InvalidCredentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, DbRetryConfig, set_test_app_config};
+    use crate::forms::forgot_password::ResetToken;
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::StatusCode;
+
+    fn unsaved_user(username: &str) -> User {
+        User::builder()
+            .username(username)
+            .password(&Password::new(PASSWORD))
+            .email(Email::new(format!("{username}@example.com")).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[cot::test]
+    async fn only_saved_users_have_an_id() {
+        let app = TestApp::new().await;
+        let mut user = unsaved_user("id_on_save");
+        assert_eq!(user.id(), None);
+        // nothing to bind a token to yet
+        assert_eq!(ResetToken::from_config().make_token(&user, b"secret"), None);
+
+        user.save(app.db()).await.unwrap();
+
+        let id = user.id().expect("a saved user has an id");
+        let loaded = User::get_by_id(app.db(), id).await.unwrap().unwrap();
+        assert_eq!(loaded.id(), Some(id));
+    }
+
+    fn retry_quickly(max_retries: u32) {
+        set_test_app_config(AppConfig {
+            db_retry: DbRetryConfig {
+                max_retries,
+                backoff_ms: 1,
+            },
+            ..AppConfig::default()
+        });
+    }
+
+    fn db_error(err: sqlx::Error) -> AuthError {
+        AuthError::backend_error(DatabaseError::DatabaseEngineError(err))
+    }
+
+    /// Runs [`with_db_retry`] on a lookup failing with `err` its first
+    /// `failures` times, returning the result and how many times it ran.
+    async fn flaky_lookup(
+        failures: usize,
+        err: fn() -> sqlx::Error,
+    ) -> (cot::auth::Result<&'static str>, usize) {
+        let calls = std::cell::Cell::new(0);
+        let result = with_db_retry(|| {
+            calls.set(calls.get() + 1);
+            let call = calls.get();
+            async move {
+                if call <= failures {
+                    Err(db_error(err()))
+                } else {
+                    Ok("found")
+                }
+            }
+        })
+        .await;
+        (result, calls.get())
+    }
+
+    #[cot::test]
+    async fn a_transient_error_is_retried() {
+        retry_quickly(2);
+
+        let (result, calls) = flaky_lookup(1, || sqlx::Error::PoolTimedOut).await;
+
+        assert_eq!(result.unwrap(), "found");
+        assert_eq!(calls, 2);
+    }
+
+    #[cot::test]
+    async fn retrying_gives_up_after_the_configured_retries() {
+        retry_quickly(2);
+
+        let (result, calls) = flaky_lookup(usize::MAX, || sqlx::Error::PoolTimedOut).await;
+
+        assert!(is_transient(&result.unwrap_err()));
+        assert_eq!(calls, 3);
+    }
+
+    #[cot::test]
+    async fn other_errors_are_not_retried() {
+        retry_quickly(2);
+
+        let (result, calls) = flaky_lookup(1, || sqlx::Error::RowNotFound).await;
+
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    /// The fastest of a few runs of `authenticate` with `username` and
+    /// `password`, beside what it returned.
+    async fn fastest_authenticate(
+        db: &Database,
+        username: &str,
+        password: &str,
+    ) -> (Option<User>, Duration) {
+        let mut fastest = Duration::MAX;
+        let mut user = None;
+        for _ in 0..3 {
+            let credentials = UserCredentials::new(username.to_owned(), Password::new(password));
+            let start = Instant::now();
+            user = User::authenticate(db, &credentials).await.unwrap();
+            fastest = fastest.min(start.elapsed());
+        }
+        (user, fastest)
+    }
+
+    #[cot::test]
+    async fn an_unknown_username_is_refused_like_a_wrong_password() {
+        let app = TestApp::new().await;
+        app.create_user("timing_known", PASSWORD).await;
+
+        let (wrong_password, known) =
+            fastest_authenticate(app.db(), "timing_known", "not-the-password").await;
+        let (unknown_user, unknown) =
+            fastest_authenticate(app.db(), "timing_unknown", "not-the-password").await;
+        let (too_long, overlong) = fastest_authenticate(app.db(), &"x".repeat(300), PASSWORD).await;
+
+        assert!(wrong_password.is_none() && unknown_user.is_none() && too_long.is_none());
+        // without the dummy verification these take microseconds, not the
+        // tens of milliseconds a hash takes
+        assert!(unknown > known / 4, "{unknown:?} against {known:?}");
+        assert!(overlong > known / 4, "{overlong:?} against {known:?}");
+    }
+
+    #[cot::test]
+    async fn the_right_password_authenticates() {
+        let mut app = TestApp::new().await;
+        app.create_user("moved_password", PASSWORD).await;
+        let credentials =
+            UserCredentials::new("moved_password".to_owned(), Password::new(PASSWORD));
+
+        let user = User::authenticate(app.db(), &credentials).await.unwrap();
+
+        assert_eq!(user.unwrap().username(), "moved_password");
+        assert_eq!(
+            app.login("  moved_password  ", PASSWORD).await.location(),
+            Some("/home")
+        );
+    }
+
+    #[test]
+    fn the_builder_puts_each_part_in_its_place() {
+        let user = User::builder()
+            .username("Ada")
+            .name("Ada Lovelace")
+            .email(Email::new("ADA@Example.com").unwrap())
+            .password(&Password::new(PASSWORD))
+            .build()
+            .unwrap();
+
+        assert_eq!(user.username(), "Ada");
+        assert_eq!(user.display_name(), "Ada Lovelace");
+        assert_eq!(user.email().as_str(), "ada@example.com");
+        assert!(user.check_password(&Password::new(PASSWORD)));
+        assert!(user.is_active());
+    }
+
+    #[test]
+    fn the_builder_says_which_part_is_missing_or_too_long() {
+        let email = || Email::new("builder@example.com").unwrap();
+        let password = Password::new(PASSWORD);
+        let cases = [
+            (
+                User::builder().email(email()).password(&password),
+                UserBuildError::Missing("username"),
+            ),
+            (
+                User::builder()
+                    .username("")
+                    .email(email())
+                    .password(&password),
+                UserBuildError::Missing("username"),
+            ),
+            (
+                User::builder().username("builder").password(&password),
+                UserBuildError::Missing("email"),
+            ),
+            (
+                User::builder().username("builder").email(email()),
+                UserBuildError::Missing("password"),
+            ),
+            (
+                User::builder()
+                    .username("b".repeat(255))
+                    .email(email())
+                    .password(&password),
+                UserBuildError::TooLong("username"),
+            ),
+            (
+                User::builder()
+                    .username("builder")
+                    .name("n".repeat(255))
+                    .email(email())
+                    .password(&password),
+                UserBuildError::TooLong("name"),
+            ),
+        ];
+
+        for (builder, expected) in cases {
+            assert_eq!(builder.build().unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn an_unusable_password_stands_in_for_a_password() {
+        let user = User::builder()
+            .username("builder_no_password")
+            .email(Email::new("builder_no_password@example.com").unwrap())
+            .unusable_password()
+            .build()
+            .unwrap();
+
+        assert!(!user.has_usable_password());
+    }
+
+    #[cot::test]
+    async fn anonymizing_removes_personal_data_and_refuses_logins() {
+        let mut app = TestApp::new().await;
+        let mut user = User::builder()
+            .username("anonymize_me")
+            .name("Ada Lovelace")
+            .email(Email::new("anonymize_me@example.com").unwrap())
+            .password(&Password::new(PASSWORD))
+            .build()
+            .unwrap();
+        user.set_phone(Some("+15550000009".to_owned()))
+            .set_locale(Some("fr".to_owned()));
+        user.save(app.db()).await.unwrap();
+        app.login("anonymize_me", PASSWORD).await;
+
+        user.anonymize(app.db()).await.unwrap();
+
+        let stored = User::get_by_id(app.db(), user.id().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.username().starts_with("deleted-"));
+        assert!(stored.email().as_str().ends_with("@anonymized.invalid"));
+        assert!(!stored.email().as_str().contains("anonymize_me"));
+        // with the name gone, the placeholder is all that's left to show
+        assert_eq!(stored.display_name(), stored.username());
+        assert_eq!(stored.phone(), None);
+        assert_eq!(stored.locale(), None);
+        assert!(!stored.is_active());
+        assert!(!stored.has_usable_password());
+        assert!(
+            User::get_by_username(app.db(), "anonymize_me")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+        let credentials = UserCredentials::new("anonymize_me".to_owned(), Password::new(PASSWORD));
+        assert!(
+            User::authenticate(app.db(), &credentials)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_ne!(
+            app.login("anonymize_me", PASSWORD).await.location(),
+            Some("/home")
+        );
+    }
+
+    #[cot::test]
+    async fn a_new_password_is_only_stored_once_saved() {
+        let app = TestApp::new().await;
+        let mut user = app.create_user("unsaved_password", PASSWORD).await;
+        let new_password = Password::new("An0ther!Passphrase-xyz");
+        let stored = async || {
+            User::get_by_username(app.db(), "unsaved_password")
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        user.set_password(&new_password);
+        assert!(user.check_password(&new_password));
+        assert!(stored().await.check_password(&Password::new(PASSWORD)));
+
+        user.save(app.db()).await.unwrap();
+        assert!(stored().await.check_password(&new_password));
+    }
+
+    #[cot::test]
+    async fn replacing_a_password_stores_it() {
+        let app = TestApp::new().await;
+        let mut user = app.create_user("replaced_password", PASSWORD).await;
+        let new_password = Password::new("An0ther!Passphrase-xyz");
+
+        crate::password_history::replace_password(app.db(), &mut user, &new_password)
+            .await
+            .unwrap();
+
+        let stored = User::get_by_username(app.db(), "replaced_password")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.check_password(&new_password));
+    }
+
+    #[cot::test]
+    async fn deactivating_and_activating_update_the_account() {
+        let mut app = TestApp::new().await;
+        let mut user = app.create_user("toggled", PASSWORD).await;
+        app.login("toggled", PASSWORD).await;
+        let created = user.updated_at;
+
+        user.deactivate().save(app.db()).await.unwrap();
+
+        assert!(!user.is_active());
+        let deactivated = user.updated_at.expect("deactivating sets the timestamp");
+        assert!(created.is_none_or(|created| created <= deactivated));
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+        assert_ne!(
+            app.login("toggled", PASSWORD).await.location(),
+            Some("/home")
+        );
+
+        user.activate().save(app.db()).await.unwrap();
+
+        assert!(user.is_active());
+        assert!(user.updated_at.unwrap() >= deactivated);
+        assert_eq!(
+            app.login("toggled", PASSWORD).await.location(),
+            Some("/home")
+        );
+    }
+
+    #[test]
+    fn the_display_name_falls_back_to_the_username() {
+        let named = |name: &str| {
+            User::builder()
+                .username("display_name")
+                .password(&Password::new(PASSWORD))
+                .email(Email::new("display_name@example.com").unwrap())
+                .name(name)
+                .build()
+                .unwrap()
+        };
+
+        assert_eq!(unsaved_user("no_name").display_name(), "no_name");
+        assert_eq!(named("").display_name(), "display_name");
+        assert_eq!(named("   ").display_name(), "display_name");
+        assert_eq!(named("Ada Lovelace").display_name(), "Ada Lovelace");
+    }
+
+    #[cot::test]
+    async fn home_greets_a_nameless_user_by_username() {
+        let mut app = TestApp::new().await;
+        app.create_user("greeted_by_username", PASSWORD).await;
+        app.login("greeted_by_username", PASSWORD).await;
+
+        let body = app.get("/home").await.body;
+
+        assert!(body.contains("Signed in as greeted_by_username"));
+    }
+
+    #[cot::test]
+    async fn users_are_found_by_email_whatever_its_case() {
+        let app = TestApp::new().await;
+        app.create_user("by_email", PASSWORD).await;
+        let find = async |email: &str| {
+            User::get_by_email(app.db(), &Email::new(email).unwrap())
+                .await
+                .unwrap()
+                .map(|user| user.username().to_owned())
+        };
+
+        assert_eq!(
+            find("by_email@example.com").await.as_deref(),
+            Some("by_email")
+        );
+        assert_eq!(
+            find("By_Email@EXAMPLE.com").await.as_deref(),
+            Some("by_email")
+        );
+        assert_eq!(find("nobody@example.com").await, None);
+    }
+
+    #[cot::test]
+    async fn a_case_sensitive_local_part_only_ignores_the_domain_case() {
+        let app = TestApp::with_config(|config| config.lowercase_email_local_part = false).await;
+        app.create_user("by_exact_email", PASSWORD).await;
+        let exists = async |email: &str| {
+            User::exists_by_email(app.db(), &Email::new(email).unwrap())
+                .await
+                .unwrap()
+        };
+
+        assert!(exists("by_exact_email@EXAMPLE.COM").await);
+        assert!(!exists("BY_EXACT_EMAIL@example.com").await);
+    }
+
+    #[test]
+    fn rotating_the_salt_changes_the_session_auth_hash() {
+        let mut user = unsaved_user("rotated_hash");
+        let secret = SecretKey::new(b"test-secret-key-that-is-at-least-32-bytes");
+        let before = cot::auth::User::session_auth_hash(&user, &secret);
+
+        user.rotate_sessions();
+
+        let after = cot::auth::User::session_auth_hash(&user, &secret);
+        assert_ne!(before, after);
+        assert_eq!(after, cot::auth::User::session_auth_hash(&user, &secret));
+    }
+
+    #[cot::test]
+    async fn rotating_the_salt_logs_out_existing_sessions() {
+        let mut app = TestApp::new().await;
+        let mut user = app.create_user("rotated_sessions", PASSWORD).await;
+        app.login("rotated_sessions", PASSWORD).await;
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+
+        user.rotate_sessions().save(app.db()).await.unwrap();
+
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+        assert_eq!(
+            app.login("rotated_sessions", PASSWORD).await.location(),
+            Some("/home")
+        );
     }
 }
@@ -0,0 +1,197 @@
+//! Checking passwords against the Have I Been Pwned range API.
+//!
+//! Only the first five hex characters of the password's SHA-1 hash ever leave
+//! the server (k-anonymity); the API answers with every known suffix sharing
+//! that prefix and the match is done locally.
+
+use crate::config::app_config;
+use crate::telemetry;
+use async_trait::async_trait;
+use cot::common_types::Password;
+use cot::form::FormFieldValidationError;
+use metrics::counter;
+use sha1::{Digest, Sha1};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::warn;
+
+/// Number of hash characters sent to the API.
+const PREFIX_LEN: usize = 5;
+
+static BREACH_CHECKER: LazyLock<BreachChecker> = LazyLock::new(|| {
+    let config = &app_config().breach_check;
+    BreachChecker::new(
+        config.enabled,
+        Box::new(HibpClient::new(
+            config.api_url.clone(),
+            Duration::from_secs(config.timeout_secs),
+        )),
+    )
+});
+
+/// Rejects `password` if the configured checker knows it to be breached.
+pub(crate) async fn validate_not_breached(
+    password: &Password,
+) -> Result<(), FormFieldValidationError> {
+    if BREACH_CHECKER.is_breached(password).await {
+        return Err(FormFieldValidationError::from_static(
+            "this password has appeared in a data breach, please choose another.",
+        ));
+    }
+    Ok(())
+}
+
+/// Fetches the list of hash suffixes for a SHA-1 prefix.
+#[async_trait]
+pub(crate) trait RangeClient: Send + Sync {
+    /// Returns the raw `SUFFIX:COUNT` lines for `prefix`.
+    async fn fetch_range(&self, prefix: &str) -> Result<String, RangeError>;
+}
+
+pub(crate) type RangeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A [`RangeClient`] talking to the real HIBP API over HTTP.
+pub(crate) struct HibpClient {
+    http: reqwest::Client,
+    api_url: String,
+}
+
+impl HibpClient {
+    pub(crate) fn new(api_url: String, timeout: Duration) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("failed to build the HTTP client");
+        Self { http, api_url }
+    }
+}
+
+#[async_trait]
+impl RangeClient for HibpClient {
+    async fn fetch_range(&self, prefix: &str) -> Result<String, RangeError> {
+        let url = format!("{}/{prefix}", self.api_url.trim_end_matches('/'));
+        let body = self
+            .http
+            .get(url)
+            .header("Add-Padding", "true")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(body)
+    }
+}
+
+pub(crate) struct BreachChecker {
+    enabled: bool,
+    client: Box<dyn RangeClient>,
+}
+
+impl BreachChecker {
+    pub(crate) fn new(enabled: bool, client: Box<dyn RangeClient>) -> Self {
+        Self { enabled, client }
+    }
+
+    /// Returns whether `password` appears in the breach corpus.
+    ///
+    /// This fails open: when the check is disabled or the API can't be reached,
+    /// the password is treated as not breached so that signups and resets
+    /// aren't blocked by an outage.
+    pub(crate) async fn is_breached(&self, password: &Password) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        let hash = hex::encode_upper(Sha1::digest(password.as_str().as_bytes()));
+        let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+
+        match self.client.fetch_range(prefix).await {
+            Ok(body) => contains_suffix(&body, suffix),
+            Err(err) => {
+                warn!(
+                    event = "breach_check_failed",
+                    error = %err,
+                    "could not check a password against the breach API, letting it through"
+                );
+                counter!(telemetry::BREACH_CHECK_FAILURES).increment(1);
+                false
+            }
+        }
+    }
+}
+
+/// Looks for `suffix` in a range response, ignoring the zero-count padding
+/// entries the API adds.
+fn contains_suffix(body: &str, suffix: &str) -> bool {
+    body.lines().any(|line| {
+        line.trim()
+            .split_once(':')
+            .is_some_and(|(candidate, count)| {
+                candidate.eq_ignore_ascii_case(suffix)
+                    && count.trim().parse::<u64>().unwrap_or(0) > 0
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every prefix with `body`, or fails like an unreachable API.
+    struct FakeClient {
+        body: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl RangeClient for FakeClient {
+        async fn fetch_range(&self, _prefix: &str) -> Result<String, RangeError> {
+            self.body
+                .map(String::from)
+                .ok_or_else(|| "unreachable".into())
+        }
+    }
+
+    fn checker(body: Option<&'static str>) -> BreachChecker {
+        BreachChecker::new(true, Box::new(FakeClient { body }))
+    }
+
+    // the SHA-1 of "password" is 5BAA6 1E4C9B93F3F0682250B6CF8331B7EE68FD8
+    const PASSWORD_RANGE: &str = "\
+        1E4C9B93F3F0682250B6CF8331B7EE68FD8:9545824\r\n\
+        1E4C9B93F3F0682250B6CF8331B7EE68FD9:0\r\n";
+
+    #[cot::test]
+    async fn rejects_a_breached_password() {
+        assert!(
+            checker(Some(PASSWORD_RANGE))
+                .is_breached(&Password::new("password"))
+                .await
+        );
+    }
+
+    #[cot::test]
+    async fn accepts_a_password_missing_from_the_range() {
+        assert!(
+            !checker(Some(PASSWORD_RANGE))
+                .is_breached(&Password::new("Str0ng!Passphrase-xyz"))
+                .await
+        );
+    }
+
+    #[cot::test]
+    async fn ignores_padding_entries() {
+        let padded = "1E4C9B93F3F0682250B6CF8331B7EE68FD8:0\r\n";
+
+        assert!(
+            !checker(Some(padded))
+                .is_breached(&Password::new("password"))
+                .await
+        );
+    }
+
+    #[cot::test]
+    async fn lets_passwords_through_when_the_api_is_unreachable() {
+        assert!(!checker(None).is_breached(&Password::new("password")).await);
+    }
+}
@@ -0,0 +1,148 @@
+//! The IP address a request came from.
+
+use crate::config::{ClientIpHeader, app_config};
+use cot::http::HeaderMap;
+use cot::request::RequestHead;
+use cot::request::extractors::FromRequestHead;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+
+const X_REAL_IP: &str = "x-real-ip";
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The client's IP address, if it can be determined.
+///
+/// Cot doesn't expose the peer address of the connection to handlers, so the
+/// address is only known when the app runs behind a trusted reverse proxy
+/// (`app.behind_trusted_proxy`) that reports it in `app.client_ip_header`.
+/// Proxy headers are ignored otherwise, as any client could set them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct ClientIp(pub(crate) Option<IpAddr>);
+
+impl ClientIp {
+    /// The address `headers` say the request came from, going by the
+    /// `app.behind_trusted_proxy` and `app.client_ip_header` settings.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let config = app_config();
+        Self::parse(
+            headers,
+            config
+                .behind_trusted_proxy
+                .then_some(config.client_ip_header),
+        )
+    }
+
+    /// Reads the address from `trusted_header`, or nothing if there's no proxy
+    /// to trust.
+    fn parse(headers: &HeaderMap, trusted_header: Option<ClientIpHeader>) -> Self {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let value = match trusted_header {
+            None => None,
+            Some(ClientIpHeader::RealIp) => header(X_REAL_IP),
+            // the proxy appends the address it saw to the end of the list;
+            // anything before it was supplied by the client and can't be
+            // trusted
+            Some(ClientIpHeader::ForwardedFor) => {
+                header(X_FORWARDED_FOR).and_then(|value| value.rsplit(',').next())
+            }
+        };
+        Self(value.and_then(|value| value.trim().parse().ok()))
+    }
+}
+
+impl FromRequestHead for ClientIp {
+    async fn from_request_head(head: &RequestHead) -> cot::Result<Self> {
+        Ok(Self::from_headers(&head.headers))
+    }
+}
+
+impl Display for ClientIp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(ip) => write!(f, "{ip}"),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(address: &str) -> ClientIp {
+        ClientIp(Some(address.parse().unwrap()))
+    }
+
+    #[test]
+    fn ignores_proxy_headers_without_a_proxy() {
+        let headers = headers(&[(X_FORWARDED_FOR, "203.0.113.1"), (X_REAL_IP, "203.0.113.2")]);
+
+        assert_eq!(ClientIp::parse(&headers, None), ClientIp(None));
+    }
+
+    #[test]
+    fn takes_the_hop_the_proxy_appended() {
+        let headers = headers(&[(X_FORWARDED_FOR, "10.0.0.1, 198.51.100.7 , 203.0.113.9")]);
+
+        assert_eq!(
+            ClientIp::parse(&headers, Some(ClientIpHeader::ForwardedFor)),
+            ip("203.0.113.9")
+        );
+    }
+
+    #[test]
+    fn only_reads_the_configured_header() {
+        let headers = headers(&[
+            (X_FORWARDED_FOR, "1.2.3.4, 203.0.113.9"),
+            (X_REAL_IP, "5.6.7.8"),
+        ]);
+
+        assert_eq!(
+            ClientIp::parse(&headers, Some(ClientIpHeader::ForwardedFor)),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            ClientIp::parse(&headers, Some(ClientIpHeader::RealIp)),
+            ip("5.6.7.8")
+        );
+        let real_ip_only = self::headers(&[(X_REAL_IP, "5.6.7.8")]);
+        assert_eq!(
+            ClientIp::parse(&real_ip_only, Some(ClientIpHeader::ForwardedFor)),
+            ClientIp(None)
+        );
+    }
+
+    #[test]
+    fn ignores_values_that_are_not_addresses() {
+        for value in [
+            "",
+            "unknown",
+            "203.0.113.9, not-an-ip",
+            "203.0.113.300",
+            "::g",
+        ] {
+            let headers = headers(&[(X_FORWARDED_FOR, value), (X_REAL_IP, value)]);
+
+            for header in [ClientIpHeader::ForwardedFor, ClientIpHeader::RealIp] {
+                assert_eq!(
+                    ClientIp::parse(&headers, Some(header)),
+                    ClientIp(None),
+                    "{value}"
+                );
+            }
+        }
+        let ipv6 = headers(&[(X_FORWARDED_FOR, "10.0.0.1,2001:db8::1")]);
+        assert_eq!(
+            ClientIp::parse(&ipv6, Some(ClientIpHeader::ForwardedFor)),
+            ip("2001:db8::1")
+        );
+    }
+}
@@ -0,0 +1,382 @@
+//! Compressing HTML and JSON responses with gzip or brotli, for clients that
+//! ask for it in `Accept-Encoding`.
+
+use cot::bytes::Bytes;
+use cot::http::{HeaderMap, HeaderValue, header};
+use cot::request::Request;
+use cot::response::Response;
+use cot::{Body, StatusCode};
+use flate2::write::GzEncoder;
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use http_body_util::BodyExt;
+use std::io::Write;
+use std::task::{Context, Poll};
+use tower::Service;
+
+/// Responses smaller than this many bytes are sent as they are: compression
+/// would barely shrink them, and could even make them bigger.
+const MIN_SIZE: u64 = 1024;
+/// The types worth compressing. Images and fonts are compressed already.
+const COMPRESSIBLE_TYPES: [&str; 2] = ["text/html", "application/json"];
+/// Brotli quality, from 0 to 11. Higher levels cost far more CPU per request
+/// than they save in bytes.
+const BROTLI_QUALITY: u32 = 5;
+/// Brotli window size, as a power of two.
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// The encoding the client prefers out of the ones supported, going by the
+/// q-values in `Accept-Encoding`. Brotli wins ties, as it compresses better.
+fn preferred_encoding(headers: &HeaderMap) -> Option<Encoding> {
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut wildcard = None;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        for item in value.split(',') {
+            let mut parts = item.split(';');
+            let coding = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+            let Some(quality) = quality else {
+                continue;
+            };
+            if coding.eq_ignore_ascii_case("br") {
+                brotli = Some(quality);
+            } else if coding.eq_ignore_ascii_case("gzip") {
+                gzip = Some(quality);
+            } else if coding == "*" {
+                wildcard = Some(quality);
+            }
+        }
+    }
+
+    // codings not listed by name get the wildcard's quality, if there is one
+    let brotli = brotli.or(wildcard).unwrap_or(0.0);
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    if brotli > 0.0 && brotli >= gzip {
+        Some(Encoding::Brotli)
+    } else if gzip > 0.0 {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+fn is_compressible(response: &Response) -> bool {
+    let status = response.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return false;
+    }
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| {
+            COMPRESSIBLE_TYPES
+                .iter()
+                .any(|compressible| mime.trim().eq_ignore_ascii_case(compressible))
+        })
+}
+
+/// Adds `Accept-Encoding` to `Vary`, so caches keep the compressed and the
+/// plain version of a page apart.
+fn vary_on_accept_encoding(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !listed {
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+}
+
+/// A gzip or brotli encoder writing into a buffer that's emptied after every
+/// chunk.
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            Encoding::Gzip => {
+                Encoder::Gzip(GzEncoder::new(Vec::new(), flate2::Compression::default()))
+            }
+        }
+    }
+
+    /// Compresses `data` and returns whatever output the encoder has ready.
+    fn write(&mut self, data: &[u8]) -> cot::Result<Bytes> {
+        let output = match self {
+            Encoder::Brotli(encoder) => {
+                encoder.write_all(data).map_err(cot::Error::wrap)?;
+                encoder.get_mut()
+            }
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(data).map_err(cot::Error::wrap)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    /// Ends the compressed stream and returns the rest of the output.
+    fn finish(self) -> cot::Result<Bytes> {
+        let output = match self {
+            Encoder::Brotli(encoder) => encoder.into_inner(),
+            Encoder::Gzip(encoder) => encoder.finish().map_err(cot::Error::wrap)?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Compresses `body` as it's streamed, a chunk at a time.
+fn compress_stream(body: Body, encoding: Encoding) -> Body {
+    let chunks = body.into_data_stream();
+    let stream =
+        futures::stream::unfold(Some((chunks, Encoder::new(encoding))), |state| async move {
+            let (mut chunks, mut encoder) = state?;
+            loop {
+                match chunks.next().await {
+                    Some(Ok(chunk)) => match encoder.write(&chunk) {
+                        // brotli and gzip hold on to input until they have a
+                        // block's worth, so there may be nothing to send yet
+                        Ok(output) if output.is_empty() => {}
+                        Ok(output) => return Some((Ok(output), Some((chunks, encoder)))),
+                        Err(error) => return Some((Err(error), None)),
+                    },
+                    Some(Err(error)) => return Some((Err(error), None)),
+                    None => return Some((encoder.finish(), None)),
+                }
+            }
+        });
+    Body::streaming(stream)
+}
+
+/// Compresses HTML and JSON responses with the encoding the client prefers,
+/// and gives fixed-size responses an explicit `Content-Length`.
+///
+/// Responses already carrying a `Content-Encoding`, and ones smaller than
+/// [`MIN_SIZE`], are left alone. It has to be outside `LiveReloadMiddleware`,
+/// which edits the HTML it sends.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct CompressionMiddleware;
+
+impl<S> tower::Layer<S> for CompressionMiddleware {
+    type Service = CompressionService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CompressionService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CompressionService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for CompressionService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let encoding = preferred_encoding(req.headers());
+            let response = inner.call(req).await?;
+            let compressible = is_compressible(&response);
+            let (mut parts, body) = response.into_parts();
+            let size = http_body::Body::size_hint(&body).exact();
+            if compressible {
+                vary_on_accept_encoding(&mut parts.headers);
+            }
+
+            let encoding = match encoding {
+                Some(encoding) if compressible && size.is_none_or(|size| size >= MIN_SIZE) => {
+                    encoding
+                }
+                _ => {
+                    if let Some(size) = size {
+                        parts.headers.insert(header::CONTENT_LENGTH, size.into());
+                    }
+                    return Ok(Response::from_parts(parts, body));
+                }
+            };
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.name()),
+            );
+
+            let body = if size.is_some() {
+                let mut encoder = Encoder::new(encoding);
+                let mut compressed = encoder.write(&body.into_bytes().await?)?.to_vec();
+                compressed.extend_from_slice(&encoder.finish()?);
+                parts
+                    .headers
+                    .insert(header::CONTENT_LENGTH, compressed.len().into());
+                Body::fixed(compressed)
+            } else {
+                // the length of a streamed page isn't known until it's all
+                // been sent
+                parts.headers.remove(header::CONTENT_LENGTH);
+                compress_stream(body, encoding)
+            };
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{TestApp, TestResponse};
+    use cot::html::Html;
+    use cot::http::Method;
+    use cot::router::{Route, Router};
+    use std::io::Read;
+
+    async fn login_page(app: &mut TestApp, accept_encoding: Option<&'static str>) -> TestResponse {
+        let mut headers = HeaderMap::new();
+        if let Some(accept_encoding) = accept_encoding {
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static(accept_encoding),
+            );
+        }
+        app.send(Method::GET, "/login", headers, Body::empty())
+            .await
+    }
+
+    fn content_length(response: &TestResponse) -> usize {
+        response.headers[header::CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn picks_the_encoding_the_client_ranks_highest() {
+        let prefers = |accept_encoding: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static(accept_encoding),
+            );
+            preferred_encoding(&headers)
+        };
+
+        assert_eq!(prefers("gzip, br"), Some(Encoding::Brotli));
+        assert_eq!(prefers("gzip;q=1, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(prefers("*"), Some(Encoding::Brotli));
+        assert_eq!(prefers("br;q=0, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(prefers("identity"), None);
+        assert_eq!(prefers("*;q=0"), None);
+        assert_eq!(preferred_encoding(&HeaderMap::new()), None);
+    }
+
+    #[cot::test]
+    async fn large_pages_are_compressed_when_the_client_asks() {
+        let mut app = TestApp::new().await;
+        let plain = login_page(&mut app, None).await;
+
+        let gzipped = login_page(&mut app, Some("gzip")).await;
+        let brotli = login_page(&mut app, Some("br")).await;
+
+        assert!(plain.raw_body.len() as u64 >= MIN_SIZE);
+        assert!(!plain.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(content_length(&plain), plain.raw_body.len());
+        assert_eq!(plain.headers[header::VARY], "accept-encoding");
+
+        assert_eq!(gzipped.headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(content_length(&gzipped), gzipped.raw_body.len());
+        assert!(gzipped.raw_body.len() < plain.raw_body.len());
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&gzipped.raw_body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert!(decoded.contains("name=\"username\""), "{decoded}");
+
+        assert_eq!(brotli.headers[header::CONTENT_ENCODING], "br");
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&brotli.raw_body[..], BROTLI_BUFFER_SIZE)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert!(decoded.contains("name=\"username\""), "{decoded}");
+    }
+
+    async fn tiny() -> cot::Result<Html> {
+        Ok(Html::new("<p>tiny</p>"))
+    }
+
+    #[cot::test]
+    async fn tiny_responses_are_sent_uncompressed() {
+        let mut app = TestApp::with_routes(
+            Router::with_urls([Route::with_handler_and_name("/tiny", tiny, "tiny")]),
+            |_| {},
+        )
+        .await;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, br"),
+        );
+
+        let response = app
+            .send(Method::GET, "/test/tiny", headers, Body::empty())
+            .await;
+
+        assert!(!response.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(response.body, "<p>tiny</p>");
+        assert_eq!(content_length(&response), response.body.len());
+    }
+}
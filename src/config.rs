@@ -0,0 +1,645 @@
+//! App-specific configuration.
+//!
+//! These settings live in the `[app]` table of the project's TOML config file,
+//! next to the settings understood by Cot itself.
+
+use cot::config::ProjectConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+static APP_CONFIG: OnceLock<AppConfig> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    /// The settings of the test running on this thread, which take the place
+    /// of [`APP_CONFIG`] so tests can each use their own.
+    static TEST_APP_CONFIG: std::cell::Cell<Option<&'static AppConfig>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Returns the loaded app config, or the defaults if none has been loaded.
+pub(crate) fn app_config() -> &'static AppConfig {
+    #[cfg(test)]
+    if let Some(config) = TEST_APP_CONFIG.get() {
+        return config;
+    }
+    APP_CONFIG.get_or_init(AppConfig::default)
+}
+
+/// Makes [`app_config`] return `config` on the current thread. The config is
+/// leaked, which is fine for the lifetime of a test.
+#[cfg(test)]
+pub(crate) fn set_test_app_config(config: AppConfig) {
+    TEST_APP_CONFIG.set(Some(Box::leak(Box::new(config))));
+}
+
+/// Parses the `[app]` table of `config_content`.
+#[cfg(test)]
+pub(crate) fn parse_app_config(config_content: &str) -> cot::Result<AppConfig> {
+    let config: ConfigFile = toml::from_str(config_content).map_err(cot::Error::wrap)?;
+    Ok(config.app)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct AppConfig {
+    /// The name shown in page titles and emails.
+    pub(crate) site_name: String,
+    /// The scheme and host the site is reachable at, used for links in emails.
+    pub(crate) base_url: String,
+    /// Name of the route users are sent to after logging in, or when they open
+    /// the login or signup page while already logged in.
+    pub(crate) landing_route: String,
+    /// Landing routes for specific roles (`staff` or `user`), overriding
+    /// `landing_route` for them.
+    pub(crate) role_landing_routes: HashMap<String, String>,
+    /// Name of the route users are sent to after logging out when the request
+    /// doesn't carry a safe `next` path.
+    pub(crate) logout_route: String,
+    /// Whether failed logins say if the username or the password was wrong.
+    ///
+    /// Only honoured when the project runs in debug mode, so a config copied to
+    /// production keeps the generic message.
+    pub(crate) verbose_auth_errors: bool,
+    /// Whether requests arrive through a reverse proxy that reports the
+    /// client's address in `client_ip_header`.
+    pub(crate) behind_trusted_proxy: bool,
+    /// The header the proxy puts the client's address in. Only that one is
+    /// read, so a client can't pick its address with the other.
+    pub(crate) client_ip_header: ClientIpHeader,
+    /// Whether the part of email addresses before the `@` is lowercased along
+    /// with the domain. RFC 5321 lets servers treat it as case-sensitive, but
+    /// next to none do.
+    pub(crate) lowercase_email_local_part: bool,
+    /// How many sessions a user can be logged in with at once; logging in
+    /// past it ends the oldest one. 0 means no limit.
+    pub(crate) max_sessions_per_user: u32,
+    /// How many seconds a session can go without a request before it's
+    /// logged out. 0 means sessions never time out.
+    pub(crate) session_idle_timeout_secs: u64,
+    /// How many of a user's most recent passwords, the current one included,
+    /// can't be picked again on a reset or change. 0 turns the check off.
+    pub(crate) password_history_depth: u32,
+    /// The lowest estimated entropy, in bits, a new password can have. The
+    /// default of 36 takes eight random lowercase letters but not `aaaaaaaa`
+    /// or `12345678`. 0 turns the check off.
+    pub(crate) min_password_entropy_bits: f64,
+    /// The algorithm new password hashes are made with. Hashes made with one
+    /// of the others still verify, and are rehashed with this one on the
+    /// user's next login.
+    pub(crate) password_hash_algorithm: PasswordHashAlgorithm,
+    /// What happens to requests for paths ending in a slash.
+    pub(crate) trailing_slash: TrailingSlashPolicy,
+    /// How log events are written to standard output.
+    pub(crate) log_format: LogFormat,
+    /// Where throttling counters are kept.
+    pub(crate) rate_limit_backend: RateLimitBackend,
+    /// Whether staff can impersonate other staff accounts, not just regular
+    /// users. Superusers can't be impersonated either way.
+    pub(crate) allow_impersonating_staff: bool,
+    /// Whether users can reset a forgotten password themselves. When off,
+    /// the forgot password and reset pages answer 404, e.g. for deployments
+    /// that only log in through SSO.
+    pub(crate) password_reset_enabled: bool,
+    /// Whether signing up needs an invite from staff. Each invite works once,
+    /// and only for the address it was sent to.
+    pub(crate) invite_only: bool,
+    /// Whether users have to follow the link sent to their email address
+    /// before they can log in with a password. When off, verification is
+    /// only informational.
+    pub(crate) require_verified_email_for_login: bool,
+    /// Whether error pages list the error and its causes instead of only the
+    /// status. Leave it off in production: the details can include queries,
+    /// paths and other internals. In debug mode, browsers get Cot's own
+    /// diagnostics page regardless.
+    pub(crate) error_details: bool,
+    /// How many milliseconds a request or an auth query can take before it's
+    /// logged as slow. 0 turns the warnings off.
+    pub(crate) slow_request_threshold_ms: u64,
+    pub(crate) reset_token: ResetTokenConfig,
+    pub(crate) breach_check: BreachCheckConfig,
+    pub(crate) db_retry: DbRetryConfig,
+    pub(crate) https: HttpsConfig,
+    pub(crate) email_queue: EmailQueueConfig,
+    pub(crate) magic_link: MagicLinkConfig,
+    pub(crate) sms_reset: SmsResetConfig,
+    pub(crate) username: UsernameConfig,
+    pub(crate) ip_rate_limit: IpRateLimitConfig,
+    pub(crate) static_cache: StaticCacheConfig,
+    pub(crate) csrf: CsrfConfig,
+    pub(crate) metrics: MetricsConfig,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            site_name: "Cot Auth".to_string(),
+            base_url: "http://127.0.0.1:8000".to_string(),
+            landing_route: "home".to_string(),
+            role_landing_routes: HashMap::new(),
+            // `/` serves the login page, so there's no separate index route
+            logout_route: "login".to_string(),
+            verbose_auth_errors: false,
+            behind_trusted_proxy: false,
+            client_ip_header: ClientIpHeader::default(),
+            lowercase_email_local_part: true,
+            max_sessions_per_user: 0,
+            session_idle_timeout_secs: 0,
+            password_history_depth: 5,
+            min_password_entropy_bits: 36.0,
+            password_hash_algorithm: PasswordHashAlgorithm::default(),
+            rate_limit_backend: RateLimitBackend::default(),
+            trailing_slash: TrailingSlashPolicy::default(),
+            log_format: LogFormat::default(),
+            allow_impersonating_staff: false,
+            password_reset_enabled: true,
+            invite_only: false,
+            require_verified_email_for_login: false,
+            error_details: false,
+            slow_request_threshold_ms: 1000,
+            reset_token: ResetTokenConfig::default(),
+            breach_check: BreachCheckConfig::default(),
+            db_retry: DbRetryConfig::default(),
+            https: HttpsConfig::default(),
+            email_queue: EmailQueueConfig::default(),
+            magic_link: MagicLinkConfig::default(),
+            sms_reset: SmsResetConfig::default(),
+            username: UsernameConfig::default(),
+            ip_rate_limit: IpRateLimitConfig::default(),
+            static_cache: StaticCacheConfig::default(),
+            csrf: CsrfConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct ResetTokenConfig {
+    pub(crate) algorithm: TokenAlgorithm,
+    /// How many seconds a reset link works for after it's sent.
+    pub(crate) timeout_secs: i64,
+    /// How many seconds ahead of this server's clock a token's timestamp can
+    /// be, for tokens made by another server whose clock runs fast. Tokens
+    /// further in the future are rejected.
+    pub(crate) allowed_clock_skew_secs: i64,
+}
+
+impl Default for ResetTokenConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: TokenAlgorithm::default(),
+            timeout_secs: 60 * 60,
+            allowed_clock_skew_secs: 60,
+        }
+    }
+}
+
+/// The HMAC digest used to sign password reset tokens.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TokenAlgorithm {
+    #[default]
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ClientIpHeader {
+    /// The last address in `X-Forwarded-For`, the one the proxy appended.
+    #[default]
+    ForwardedFor,
+    /// `X-Real-IP`, for proxies that set it and drop any the client sent.
+    RealIp,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PasswordHashAlgorithm {
+    /// What Cot itself hashes with.
+    #[default]
+    Argon2id,
+    Scrypt,
+    /// PBKDF2 with HMAC-SHA256.
+    Pbkdf2,
+    /// Only the first 72 bytes of a password count towards its bcrypt hash.
+    Bcrypt,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event, with the event's fields as keys, for log
+    /// aggregators.
+    Json,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TrailingSlashPolicy {
+    /// Permanently redirect `/login/` to `/login`.
+    #[default]
+    Strip,
+    /// Leave such paths to the router, which answers 404.
+    Off,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RateLimitBackend {
+    /// Per process, lost on restart.
+    #[default]
+    Memory,
+    /// Shared by every process using the same database.
+    Database,
+}
+
+/// Rejecting passwords that show up in the Have I Been Pwned corpus.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct BreachCheckConfig {
+    pub(crate) enabled: bool,
+    /// Base URL of the range API; the hash prefix is appended to it.
+    pub(crate) api_url: String,
+    pub(crate) timeout_secs: u64,
+}
+
+impl Default for BreachCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            api_url: "https://api.pwnedpasswords.com/range".to_string(),
+            timeout_secs: 3,
+        }
+    }
+}
+
+/// Retrying user lookups that fail with a transient database error.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct DbRetryConfig {
+    /// How many times a failed lookup is retried; 0 disables retrying.
+    pub(crate) max_retries: u32,
+    /// Delay before the first retry, doubled for every retry after it.
+    pub(crate) backoff_ms: u64,
+}
+
+impl Default for DbRetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            backoff_ms: 50,
+        }
+    }
+}
+
+/// What new usernames can look like.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct UsernameConfig {
+    /// The fewest characters a username can have.
+    pub(crate) min_length: usize,
+    /// The most characters a username can have. Usernames are stored in 254
+    /// characters, so anything above that acts as 254.
+    pub(crate) max_length: usize,
+    /// Names nobody can sign up with, e.g. ones that would pass for the
+    /// site's own accounts. Matched ignoring case.
+    pub(crate) reserved: Vec<String>,
+}
+
+impl Default for UsernameConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 3,
+            max_length: 150,
+            reserved: [
+                "admin",
+                "administrator",
+                "api",
+                "help",
+                "root",
+                "security",
+                "staff",
+                "support",
+                "system",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+/// Passwordless login through single-use links sent by email.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct MagicLinkConfig {
+    /// When off, the magic link pages answer 404.
+    pub(crate) enabled: bool,
+    /// How long a link stays valid after it was sent.
+    pub(crate) timeout_secs: i64,
+}
+
+impl Default for MagicLinkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: 15 * 60,
+        }
+    }
+}
+
+/// Resetting a forgotten password with a code sent by text message to the
+/// phone number on the account.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct SmsResetConfig {
+    /// When off, or when `password_reset_enabled` is, the SMS reset pages
+    /// answer 404.
+    pub(crate) enabled: bool,
+    /// How many digits a code has, between 4 and 12.
+    pub(crate) code_length: u32,
+    /// How long a code stays valid after it was sent.
+    pub(crate) code_ttl_secs: i64,
+    /// How many wrong codes can be entered before the account is locked out.
+    /// Asking for a new code doesn't reset the count.
+    pub(crate) max_attempts: u32,
+    /// How long an account locked out by `max_attempts` is neither sent codes
+    /// nor has them accepted.
+    pub(crate) lockout_secs: i64,
+}
+
+impl Default for SmsResetConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            code_length: 6,
+            code_ttl_secs: 10 * 60,
+            max_attempts: 5,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+/// Sending emails from a background worker rather than inside the handler.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct EmailQueueConfig {
+    /// When off, handlers send their emails themselves and wait for the result.
+    pub(crate) enabled: bool,
+    /// How many emails can wait to be sent; past it handlers send inline.
+    pub(crate) capacity: usize,
+    /// How many times a failed send is retried; 0 disables retrying.
+    pub(crate) max_retries: u32,
+    /// Delay before the first retry, doubled for every retry after it.
+    pub(crate) backoff_ms: u64,
+}
+
+impl Default for EmailQueueConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            capacity: 100,
+            max_retries: 3,
+            backoff_ms: 1000,
+        }
+    }
+}
+
+/// Throttling requests to the auth endpoints, such as login, signup and
+/// password reset, by client IP address, on top of the per-username login
+/// lockout.
+///
+/// Addresses are only known behind a trusted proxy (`behind_trusted_proxy`);
+/// without one, nothing is throttled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct IpRateLimitConfig {
+    /// How many requests to the auth endpoints an address can make per
+    /// window. 0 turns the limit off.
+    pub(crate) max_requests: u32,
+    pub(crate) window_secs: u64,
+    /// Addresses that are never throttled, such as monitoring or an office
+    /// gateway.
+    pub(crate) trusted_ips: Vec<IpAddr>,
+}
+
+impl Default for IpRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: 300,
+            window_secs: 60,
+            trusted_ips: Vec::new(),
+        }
+    }
+}
+
+/// Refusing cross-site requests that could change something; see
+/// `crate::csrf`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct CsrfConfig {
+    pub(crate) enabled: bool,
+    /// Paths that accept cross-site requests, such as a webhook, matched
+    /// exactly: `/hooks/mail` doesn't exempt `/hooks/mail/bounce`. There are
+    /// no wildcards, so a new route is never exempted by accident.
+    pub(crate) exempt_paths: Vec<String>,
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            exempt_paths: Vec::new(),
+        }
+    }
+}
+
+/// The Prometheus scrape endpoint at `/metrics`, which answers 404 unless
+/// it's enabled.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct MetricsConfig {
+    pub(crate) enabled: bool,
+    /// A token scrapers have to send as `Authorization: Bearer <token>`.
+    /// Empty lets anyone who can reach the endpoint read it, for deployments
+    /// where only the scraper can.
+    pub(crate) bearer_token: String,
+}
+
+/// Browser caching of static files, on top of the `max-age` Cot sets from
+/// `static_files.cache_timeout`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct StaticCacheConfig {
+    /// Send an `ETag` with static files and answer `304 Not Modified` when the
+    /// browser already has the current version.
+    pub(crate) etag: bool,
+    /// Mark files requested through a content-hashed URL, as made by
+    /// `static_files.rewrite = "query_param"`, `immutable`, so browsers don't
+    /// revalidate them before `max-age` runs out.
+    pub(crate) immutable: bool,
+}
+
+impl Default for StaticCacheConfig {
+    fn default() -> Self {
+        Self {
+            etag: true,
+            immutable: true,
+        }
+    }
+}
+
+/// Serving the site over HTTPS, typically with TLS terminated by a proxy.
+///
+/// Cookies sent over HTTPS are always marked `Secure`; Cot's own
+/// `middlewares.session.secure` additionally marks them on plain HTTP.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct HttpsConfig {
+    /// Treat every request as HTTPS, whatever `X-Forwarded-Proto` says.
+    pub(crate) assume_https: bool,
+    /// Send `Strict-Transport-Security` on HTTPS responses.
+    pub(crate) hsts: bool,
+    pub(crate) hsts_max_age_secs: u64,
+    pub(crate) hsts_include_subdomains: bool,
+}
+
+impl Default for HttpsConfig {
+    fn default() -> Self {
+        Self {
+            assume_https: false,
+            hsts: false,
+            hsts_max_age_secs: 365 * 24 * 60 * 60,
+            hsts_include_subdomains: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    app: AppConfig,
+}
+
+/// Reads the config file the same way Cot does: `config_name` is tried as a
+/// path first, then as `config/{config_name}.toml`.
+pub(crate) fn read_config_file(config_name: &str) -> cot::Result<String> {
+    std::fs::read_to_string(config_name)
+        .or_else(|_| {
+            std::fs::read_to_string(
+                PathBuf::from("config")
+                    .join(config_name)
+                    .with_extension("toml"),
+            )
+        })
+        .map_err(cot::Error::wrap)
+}
+
+/// The shortest secret key, in bytes, the project starts with. Reset tokens,
+/// session hashes and reset codes are all HMACs keyed with it.
+const MIN_SECRET_KEY_LEN: usize = 32;
+
+/// Fails if the secret key or any fallback key is shorter than
+/// [`MIN_SECRET_KEY_LEN`], rather than signing with a key that's easy to guess.
+/// An empty key, which is what a config without `secret_key` gets, counts as
+/// too short.
+pub(crate) fn validate_secret_keys(config: &ProjectConfig) -> cot::Result<()> {
+    if config.secret_key.as_bytes().len() < MIN_SECRET_KEY_LEN {
+        return Err(cot::Error::internal(format!(
+            "`secret_key` must be at least {MIN_SECRET_KEY_LEN} bytes long"
+        )));
+    }
+    if let Some(index) = config
+        .fallback_secret_keys
+        .iter()
+        .position(|key| key.as_bytes().len() < MIN_SECRET_KEY_LEN)
+    {
+        return Err(cot::Error::internal(format!(
+            "`fallback_secret_keys[{index}]` must be at least {MIN_SECRET_KEY_LEN} bytes long"
+        )));
+    }
+    Ok(())
+}
+
+/// Parses the `[app]` table out of the config file and makes it available
+/// through [`app_config`].
+pub(crate) fn load_app_config(config_content: &str) -> cot::Result<()> {
+    let config: ConfigFile = toml::from_str(config_content).map_err(cot::Error::wrap)?;
+    // the project config is only read once at startup, so this can't be set yet
+    let _ = APP_CONFIG.set(config.app);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `config/test.toml` with its `secret_key` line replaced by `keys`.
+    fn project_config_with(keys: &str) -> ProjectConfig {
+        let config_content = read_config_file("test").expect("config/test.toml exists");
+        let config_content = config_content
+            .lines()
+            .map(|line| {
+                if line.starts_with("secret_key") {
+                    keys
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        ProjectConfig::from_toml(&config_content).expect("the config is valid TOML")
+    }
+
+    #[test]
+    fn refuses_an_empty_or_short_secret_key() {
+        for keys in [
+            "",
+            r#"secret_key = """#,
+            r#"secret_key = "random-key-here""#,
+        ] {
+            let error = validate_secret_keys(&project_config_with(keys)).unwrap_err();
+
+            assert!(
+                error
+                    .to_string()
+                    .contains("`secret_key` must be at least 32"),
+                "{keys:?}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_a_short_fallback_key() {
+        let config = project_config_with(
+            r#"secret_key = "a-secret-key-that-is-at-least-32-bytes"
+fallback_secret_keys = ["another-key-that-is-at-least-32-bytes", "short"]"#,
+        );
+
+        let error = validate_secret_keys(&config).unwrap_err();
+
+        assert!(
+            error.to_string().contains("`fallback_secret_keys[1]`"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn accepts_long_enough_keys() {
+        assert!(
+            validate_secret_keys(&project_config_with(
+                r#"secret_key = "a-secret-key-that-is-at-least-32-bytes""#
+            ))
+            .is_ok()
+        );
+    }
+}
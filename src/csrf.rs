@@ -0,0 +1,256 @@
+//! Refusing cross-site requests that could change something, so another site
+//! can't make a logged-in user's browser post to this one.
+//!
+//! Requests are checked by where the browser says they come from rather than
+//! with tokens: `Sec-Fetch-Site` when the browser sends it, and `Origin`
+//! otherwise. Requests with neither don't come from a browser, and carry no
+//! cookies a third party could be riding on.
+
+use crate::config::app_config;
+use crate::https;
+use cot::http::{HeaderMap, HeaderValue, Method, header};
+use cot::request::Request;
+use cot::response::{Response, ResponseExt};
+use cot::{Body, StatusCode};
+use futures::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower::Service;
+use tracing::warn;
+use url::Url;
+
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+
+fn forbidden() -> Response {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        )
+        .body(Body::fixed("cross-site request refused"))
+        .expect("the status and header are valid")
+}
+
+/// Whether a request with `method` and `headers` may have been made by a page
+/// on another site.
+fn is_cross_site(method: &Method, headers: &HeaderMap) -> bool {
+    if matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) {
+        return false;
+    }
+
+    if let Some(site) = headers.get(SEC_FETCH_SITE) {
+        // "none" is the user typing the address or following a bookmark
+        return !matches!(site.as_bytes(), b"same-origin" | b"none");
+    }
+    match headers.get(header::ORIGIN) {
+        Some(origin) => origin.to_str().ok() != Some(&site_origin(headers)),
+        None => false,
+    }
+}
+
+/// The origin of `app.base_url`, as browsers put it in `Origin`.
+fn site_origin(headers: &HeaderMap) -> String {
+    Url::parse(&https::base_url(headers))
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_default()
+}
+
+/// Whether requests to `path` are checked, i.e. it's not one of
+/// `app.csrf.exempt_paths`.
+fn is_checked(path: &str) -> bool {
+    let config = &app_config().csrf;
+    config.enabled && !config.exempt_paths.iter().any(|exempt| exempt == path)
+}
+
+/// Answers `403 Forbidden` to cross-site requests with methods other than
+/// `GET`, `HEAD`, `OPTIONS` and `TRACE`, when `app.csrf.enabled` is on and the
+/// path isn't exempt.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct CsrfMiddleware;
+
+impl<S> tower::Layer<S> for CsrfMiddleware {
+    type Service = CsrfService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CsrfService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CsrfService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for CsrfService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            if is_checked(req.uri().path()) && is_cross_site(req.method(), req.headers()) {
+                warn!(
+                    event = "csrf_refused",
+                    method = %req.method(),
+                    path = req.uri().path(),
+                    "refused a cross-site request"
+                );
+                return Ok(forbidden());
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[cot::test]
+    async fn refuses_posts_from_other_sites() {
+        let mut app = TestApp::new().await;
+        app.create_user("csrf_target", PASSWORD).await;
+
+        let response = app
+            .post_with_headers(
+                "/login",
+                &[("username", "csrf_target"), ("password", PASSWORD)],
+                headers(&[("origin", "https://evil.example")]),
+            )
+            .await;
+
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn accepts_posts_from_the_site_itself() {
+        let mut app = TestApp::new().await;
+        app.create_user("csrf_same_site", PASSWORD).await;
+
+        let response = app
+            .post_with_headers(
+                "/login",
+                &[("username", "csrf_same_site"), ("password", PASSWORD)],
+                headers(&[("origin", "http://127.0.0.1:8000")]),
+            )
+            .await;
+
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn can_be_turned_off() {
+        let mut app = TestApp::with_config(|config| config.csrf.enabled = false).await;
+        app.create_user("csrf_off", PASSWORD).await;
+
+        let response = app
+            .post_with_headers(
+                "/login",
+                &[("username", "csrf_off"), ("password", PASSWORD)],
+                headers(&[("sec-fetch-site", "cross-site")]),
+            )
+            .await;
+
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn lets_exempt_paths_through() {
+        let mut app = TestApp::with_config(|config| {
+            config.csrf.exempt_paths = vec!["/login".to_owned()];
+        })
+        .await;
+        app.create_user("csrf_exempt", PASSWORD).await;
+        let cross_site = || headers(&[("origin", "https://evil.example")]);
+
+        let response = app
+            .post_with_headers(
+                "/login",
+                &[("username", "csrf_exempt"), ("password", PASSWORD)],
+                cross_site(),
+            )
+            .await;
+        assert_eq!(response.location(), Some("/home"));
+
+        let response = app
+            .post_with_headers(
+                "/forgot-password",
+                &[("email", "csrf_exempt@example.com")],
+                cross_site(),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+    }
+
+    #[cot::test]
+    async fn matches_exempt_paths_exactly() {
+        let _app = TestApp::with_config(|config| {
+            config.csrf.exempt_paths = vec!["/hooks/mail".to_owned()];
+        })
+        .await;
+
+        assert!(!is_checked("/hooks/mail"));
+        assert!(is_checked("/hooks/mail/bounce"));
+        assert!(is_checked("/hooks/mail/"));
+        assert!(is_checked("/hooks"));
+    }
+
+    #[cot::test]
+    async fn judges_by_fetch_metadata_first_then_origin() {
+        let _app = TestApp::new().await;
+        let post = |pairs| is_cross_site(&Method::POST, &headers(pairs));
+
+        assert!(post(&[("sec-fetch-site", "cross-site")]));
+        assert!(post(&[("sec-fetch-site", "same-site")]));
+        assert!(!post(&[("sec-fetch-site", "same-origin")]));
+        assert!(!post(&[("sec-fetch-site", "none")]));
+        // fetch metadata wins over a matching origin
+        assert!(post(&[
+            ("sec-fetch-site", "cross-site"),
+            ("origin", "http://127.0.0.1:8000")
+        ]));
+        assert!(post(&[("origin", "null")]));
+        assert!(post(&[("origin", "http://127.0.0.1:9000")]));
+        assert!(!post(&[("origin", "http://127.0.0.1:8000")]));
+        // not a browser, so no cookies to ride on
+        assert!(!post(&[]));
+    }
+
+    #[cot::test]
+    async fn leaves_safe_methods_alone() {
+        let _app = TestApp::new().await;
+        let cross_site = headers(&[("sec-fetch-site", "cross-site")]);
+
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert!(!is_cross_site(&method, &cross_site));
+        }
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            assert!(is_cross_site(&method, &cross_site));
+        }
+    }
+}
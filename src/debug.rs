@@ -0,0 +1,67 @@
+//! Development-only endpoints.
+
+use crate::AuthApp;
+use cot::App;
+use cot::error::NotFound;
+use cot::json::Json;
+use cot::request::{Request, RequestExt};
+use cot::response::{IntoResponse, Response};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct RouteInfo {
+    name: Option<String>,
+    path: String,
+}
+
+/// Lists the app's routes with their names, so they can be checked against
+/// `reverse!` calls. Answers 404 unless the project runs in debug mode.
+pub(crate) async fn routes(request: Request) -> cot::Result<Response> {
+    if !request.context().config().debug {
+        return Err(NotFound::new().into());
+    }
+
+    let routes: Vec<RouteInfo> = AuthApp
+        .router()
+        .routes()
+        .iter()
+        .map(|route| RouteInfo {
+            name: route.name().map(str::to_owned),
+            path: route.url(),
+        })
+        .collect();
+    Json(routes).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::TestApp;
+    use cot::StatusCode;
+
+    #[cot::test]
+    async fn routes_are_not_listed_outside_debug_mode() {
+        let mut app = TestApp::with_project_config(|config| config.debug = false).await;
+
+        assert_eq!(app.get("/debug/routes").await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[cot::test]
+    async fn routes_are_listed_with_their_names_in_debug_mode() {
+        let mut app = TestApp::with_project_config(|config| config.debug = true).await;
+
+        let response = app.get("/debug/routes").await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(response.body.starts_with('['));
+        assert!(
+            response
+                .body
+                .contains(r#"{"name":"login","path":"/login"}"#)
+        );
+        assert!(
+            response
+                .body
+                .contains(r#"{"name":"reset_password_confirm","path":"/reset/{token}/{uid}"}"#)
+        );
+    }
+}
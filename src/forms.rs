@@ -1,4 +1,104 @@
+pub(crate) mod change_password;
+pub(crate) mod fields;
 pub(crate) mod forgot_password;
 pub(crate) mod home;
 pub(crate) mod login;
+pub(crate) mod logout;
+pub(crate) mod magic_link;
+pub(crate) mod profile;
 pub(crate) mod signup;
+pub(crate) mod sms_reset;
+pub(crate) mod verify_email;
+
+use cot::form::{Form, FormResult};
+use cot::request::Request;
+use cot::{Body, StatusCode, http};
+
+/// Parses `F` from the request, answering `400 Bad Request` when a URL-encoded
+/// body doesn't decode to valid UTF-8.
+///
+/// Cot decodes form values lossily, so without this check malformed bytes
+/// would silently turn into replacement characters.
+pub(crate) async fn form_from_request<F: Form>(
+    request: &mut Request,
+) -> cot::Result<FormResult<F>> {
+    let is_urlencoded = request
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+
+    if is_urlencoded {
+        let body = std::mem::replace(request.body_mut(), Body::empty());
+        let bytes = body.into_bytes().await?;
+        let valid = bytes
+            .split(|&byte| byte == b'&' || byte == b'=')
+            .all(|part| {
+                let decoded: Vec<u8> = percent_encoding::percent_decode(part).collect();
+                std::str::from_utf8(&decoded).is_ok()
+            });
+        if !valid {
+            return Err(cot::Error::with_status(
+                "form data is not valid UTF-8",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        *request.body_mut() = Body::fixed(bytes);
+    }
+
+    Ok(F::from_request(request).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::TestApp;
+    use cot::http::{HeaderMap, HeaderValue, Method, header};
+    use cot::{Body, StatusCode};
+
+    async fn post_login(
+        app: &mut TestApp,
+        content_type: &'static str,
+        body: &'static [u8],
+    ) -> StatusCode {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        app.send(Method::POST, "/login", headers, Body::fixed(body))
+            .await
+            .status
+    }
+
+    #[cot::test]
+    async fn a_form_that_is_not_utf8_is_a_bad_request() {
+        let mut app = TestApp::new().await;
+        let urlencoded = "application/x-www-form-urlencoded";
+
+        for body in [
+            &b"username=%FF&password=x"[..],
+            b"username=\xff&password=x",
+            b"%C3=x",
+        ] {
+            assert_eq!(
+                post_login(&mut app, urlencoded, body).await,
+                StatusCode::BAD_REQUEST,
+                "{body:?}"
+            );
+        }
+        assert_eq!(
+            post_login(&mut app, urlencoded, b"username=%C3%A9&password=x").await,
+            StatusCode::OK
+        );
+    }
+
+    #[cot::test]
+    async fn a_body_that_is_not_a_form_is_a_bad_request() {
+        let mut app = TestApp::new().await;
+
+        for content_type in ["text/plain", "application/json"] {
+            assert_eq!(
+                post_login(&mut app, content_type, b"username=a&password=x").await,
+                StatusCode::BAD_REQUEST,
+                "{content_type}"
+            );
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::auth::current_user;
+use crate::breach::validate_not_breached;
+use crate::config::app_config;
+use crate::forms::fields::Redacted;
+use crate::forms::form_from_request;
+use crate::forms::login::landing_redirect;
+use crate::page::form_page;
+use crate::password_history;
+use crate::password_strength::validate_entropy;
+use crate::render::render_form;
+use crate::sessions;
+use cot::auth::Auth;
+use cot::common_types::Password;
+use cot::db::Database;
+use cot::form::{Form, FormContext, FormErrorTarget, FormFieldValidationError, FormResult};
+use cot::request::Request;
+use cot::request::extractors::StaticFiles;
+use cot::response::Response;
+use cot::router::Urls;
+use cot::session::Session;
+use cot::{Method, StatusCode};
+use std::fmt::{Debug, Formatter};
+
+#[derive(Form)]
+pub(crate) struct ChangePasswordForm {
+    current_password: Password,
+    password1: Password,
+    password2: Password,
+}
+
+impl ChangePasswordForm {
+    async fn validate_password(
+        &self,
+    ) -> Result<&Password, (FormErrorTarget<'static>, FormFieldValidationError)> {
+        if self.password1.as_str() != self.password2.as_str() {
+            return Err((
+                FormErrorTarget::Field("password2"),
+                FormFieldValidationError::from_static("passwords do not match."),
+            ));
+        }
+        if self.password1.as_str() == self.current_password.as_str() {
+            return Err((
+                FormErrorTarget::Field("password1"),
+                FormFieldValidationError::from_static(
+                    "the new password must differ from the current one.",
+                ),
+            ));
+        }
+        validate_entropy(&self.password1)
+            .map_err(|err| (FormErrorTarget::Field("password1"), err))?;
+        validate_not_breached(&self.password1)
+            .await
+            .map_err(|err| (FormErrorTarget::Form, err))?;
+        Ok(&self.password1)
+    }
+}
+
+form_page! {
+    #[template(path = "change_password.html")]
+    pub(crate) struct ChangePasswordTemplate for ChangePasswordForm {
+        /// Whether staff require the change before the user can go on.
+        forced: bool,
+    }
+}
+
+pub(crate) async fn change_password(
+    urls: Urls,
+    auth: Auth,
+    session: Session,
+    mut request: Request,
+    db: Database,
+    static_files: StaticFiles,
+) -> cot::Result<Response> {
+    let Some(mut user) = current_user(&auth, &db).await? else {
+        return Ok(cot::reverse_redirect!(urls, "login")?);
+    };
+
+    let change_password_context = if request.method() == Method::GET {
+        ChangePasswordForm::build_context(&mut request).await?
+    } else if request.method() == Method::POST {
+        match form_from_request::<ChangePasswordForm>(&mut request).await? {
+            FormResult::Ok(form) => {
+                let mut ctx = form.to_context().await;
+                if user.check_password(&form.current_password) {
+                    match form.validate_password().await {
+                        Ok(password)
+                            if password_history::is_recent(&db, &user, password).await? =>
+                        {
+                            ctx.add_error(
+                                FormErrorTarget::Field("password1"),
+                                FormFieldValidationError::from_static(
+                                    password_history::REUSED_PASSWORD,
+                                ),
+                            );
+                        }
+                        Ok(password) => {
+                            password_history::replace_password(&db, &mut user, password).await?;
+                            let redirect = landing_redirect(&urls, Some(&user));
+                            // sessions are bound to the old password hash, which
+                            // logs out every other one; log this one back in to
+                            // keep the user signed in here
+                            let user_id =
+                                user.id().expect("user loaded from the database has an id");
+                            auth.login(Box::new(user)).await?;
+                            sessions::forget_others(&session, &db, user_id).await?;
+                            return redirect;
+                        }
+                        Err((target, err)) => ctx.add_error(target, err),
+                    }
+                } else if user.has_usable_password() {
+                    ctx.add_error(
+                        FormErrorTarget::Field("current_password"),
+                        FormFieldValidationError::from_static("incorrect password."),
+                    );
+                } else {
+                    let message = if app_config().password_reset_enabled {
+                        "your account has no password yet; set one with the forgot password page."
+                    } else {
+                        "your account has no password yet; ask an administrator to set one."
+                    };
+                    ctx.add_error(
+                        FormErrorTarget::Form,
+                        FormFieldValidationError::from_static(message),
+                    );
+                }
+                ctx
+            }
+            FormResult::ValidationError(context) => context,
+        }
+    } else {
+        panic!("unexpected request method")
+    };
+
+    let template = ChangePasswordTemplate {
+        urls: &urls,
+        static_files,
+        form: change_password_context,
+        forced: user.must_change_password(),
+    };
+    render_form(
+        request.headers(),
+        &template,
+        &template.form,
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+impl Debug for ChangePasswordForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChangePasswordForm")
+            .field("current_password", &Redacted)
+            .field("password1", &Redacted)
+            .field("password2", &Redacted)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::User;
+    use crate::sessions;
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::StatusCode;
+    use cot::db::Model;
+    use std::collections::BTreeMap;
+
+    const NEW_PASSWORD: &str = "An0ther!Passphrase-xyz";
+
+    #[cot::test]
+    async fn a_flagged_user_changes_their_password_before_going_home() {
+        let mut app = TestApp::new().await;
+        let mut user = app.create_user("forced_change", PASSWORD).await;
+        user.set_must_change_password(true)
+            .save(app.db())
+            .await
+            .unwrap();
+
+        let response = app.login("forced_change", PASSWORD).await;
+        assert_eq!(response.location(), Some("/change-password"));
+        assert_eq!(app.get("/home").await.location(), Some("/change-password"));
+
+        let response = app
+            .post(
+                "/change-password",
+                &[
+                    ("current_password", PASSWORD),
+                    ("password1", NEW_PASSWORD),
+                    ("password2", NEW_PASSWORD),
+                ],
+            )
+            .await;
+
+        assert_eq!(response.location(), Some("/home"));
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+        let user = User::get_by_username(app.db(), "forced_change")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!user.must_change_password());
+    }
+
+    #[cot::test]
+    async fn changing_the_password_logs_out_the_other_sessions() {
+        let mut app = TestApp::new().await;
+        let user = app.create_user("changed_elsewhere", PASSWORD).await;
+        app.login("changed_elsewhere", PASSWORD).await;
+        let session_a = app.swap_cookies(BTreeMap::new());
+        app.login("changed_elsewhere", PASSWORD).await;
+        let user_id = user.id().unwrap();
+        assert_eq!(sessions::count(app.db(), user_id).await.unwrap(), 2);
+
+        let response = app
+            .post(
+                "/change-password",
+                &[
+                    ("current_password", PASSWORD),
+                    ("password1", NEW_PASSWORD),
+                    ("password2", NEW_PASSWORD),
+                ],
+            )
+            .await;
+
+        assert_eq!(response.location(), Some("/home"));
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+        assert_eq!(sessions::count(app.db(), user_id).await.unwrap(), 1);
+        app.swap_cookies(session_a);
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::auth::username_length_limits;
+use crate::{password_strength, profile_attributes};
+use cot::common_types::Email;
+use cot::form::fields::{EmailField, StringField};
+use cot::form::{AsFormField, FormField, FormFieldValidationError};
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Deref;
+
+/// A stand-in for secret form values in hand-written `Debug` impls.
+pub(crate) struct Redacted;
+
+impl Debug for Redacted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// The `minlength` and `maxlength` attributes of a text input, taken from the
+/// rules the server checks the value against so the two can't drift apart.
+///
+/// Renders as the attributes themselves, each with a leading space, and only
+/// ever contains numbers, so templates can output it with `|safe`:
+/// `<input name="username"{{ InputLimits::username()|safe }}>`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct InputLimits {
+    /// In characters; 0 renders no attribute.
+    min_length: usize,
+    max_length: Option<usize>,
+}
+
+impl InputLimits {
+    pub(crate) fn username() -> Self {
+        let (min_length, max_length) = username_length_limits();
+        Self {
+            min_length,
+            max_length: Some(max_length),
+        }
+    }
+
+    /// For fields setting a new password, which the entropy check only
+    /// bounds from below.
+    pub(crate) fn new_password() -> Self {
+        Self {
+            min_length: password_strength::min_length(),
+            max_length: None,
+        }
+    }
+
+    pub(crate) fn bio() -> Self {
+        Self {
+            min_length: 0,
+            max_length: Some(profile_attributes::MAX_BIO_LENGTH),
+        }
+    }
+}
+
+impl Display for InputLimits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.min_length > 0 {
+            write!(f, r#" minlength="{}""#, self.min_length)?;
+        }
+        if let Some(max_length) = self.max_length {
+            write!(f, r#" maxlength="{max_length}""#)?;
+        }
+        Ok(())
+    }
+}
+
+fn trimmed_value<T: FormField>(field: &T) -> Result<&str, FormFieldValidationError> {
+    match field.value().map(str::trim) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(FormFieldValidationError::Required),
+    }
+}
+
+/// A string form value with leading and trailing whitespace removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TrimmedString(String);
+
+impl Deref for TrimmedString {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for TrimmedString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsFormField for TrimmedString {
+    type Type = StringField;
+
+    fn clean_value(field: &Self::Type) -> Result<Self, FormFieldValidationError> {
+        Ok(Self(trimmed_value(field)?.to_owned()))
+    }
+
+    fn to_field_value(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// Replaces control characters and runs of whitespace in `name` with single
+/// spaces, or returns `None` if nothing is left.
+pub(crate) fn clean_person_name(name: &str) -> Option<String> {
+    let name = name.replace(char::is_control, " ");
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!name.is_empty()).then_some(name)
+}
+
+/// A person's name cleaned with [`clean_person_name`]. A name that ends up
+/// empty is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PersonName(String);
+
+impl Deref for PersonName {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for PersonName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsFormField for PersonName {
+    type Type = StringField;
+
+    fn clean_value(field: &Self::Type) -> Result<Self, FormFieldValidationError> {
+        clean_person_name(field.value().unwrap_or_default())
+            .map(Self)
+            .ok_or(FormFieldValidationError::Required)
+    }
+
+    fn to_field_value(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// An email form value with leading and trailing whitespace removed before
+/// it is parsed.
+#[derive(Debug, Clone)]
+pub(crate) struct TrimmedEmail(Email);
+
+impl TrimmedEmail {
+    pub(crate) fn into_email(self) -> Email {
+        self.0
+    }
+}
+
+impl Deref for TrimmedEmail {
+    type Target = Email;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsFormField for TrimmedEmail {
+    type Type = EmailField;
+
+    fn clean_value(field: &Self::Type) -> Result<Self, FormFieldValidationError> {
+        Ok(Self(Email::new(trimmed_value(field)?)?))
+    }
+
+    fn to_field_value(&self) -> String {
+        self.0.as_str().to_owned()
+    }
+}
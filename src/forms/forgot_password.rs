@@ -1,134 +1,510 @@
 use crate::auth::User;
-use crate::utils::{BASE36_RADIX, Base36};
+use crate::breach::validate_not_breached;
+use crate::client_ip::ClientIp;
+use crate::config::{TokenAlgorithm, app_config};
+use crate::forms::fields::{Redacted, TrimmedString};
+use crate::forms::form_from_request;
+use crate::https;
+use crate::mail_queue;
+use crate::page::{PageContext, form_page};
+use crate::password_history;
+use crate::password_strength::validate_entropy;
+use crate::rate_limit::{self, EmailThrottle};
+use crate::render::{render, render_form};
+use crate::sessions;
+use crate::telemetry;
+use crate::utils::{Base36, constant_time_eq};
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use chrono::Utc;
 use cot::common_types::{Email, Password};
-use cot::db::{Database, Model, query};
+use cot::config::{ProjectConfig, SecretKey};
+use cot::db::Database;
 use cot::email::{Email as EmailService, EmailMessage};
+use cot::error::NotFound;
 use cot::form::{Form, FormContext, FormErrorTarget, FormFieldValidationError, FormResult};
-use cot::html::Html;
 use cot::request::extractors::StaticFiles;
-use cot::request::{Request, RequestExt};
-use cot::response::{Response, ResponseExt};
+use cot::request::{PathParams, Request, RequestExt};
+use cot::response::Response;
 use cot::router::Urls;
-use cot::{Body, Method, StatusCode, Template};
+use cot::{Method, StatusCode, Template};
+use hmac::digest::KeyInit;
 use hmac::{Hmac, Mac};
-use sha2::Sha256;
+use metrics::counter;
+use sha2::{Sha256, Sha384, Sha512};
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::warn;
 
-type HmacSHa256 = Hmac<Sha256>;
+/// Signs and checks the tokens in password reset, magic login and email
+/// verification links.
+///
+/// A token is `<base36 timestamp>-<signature>`, where the signature is the
+/// first [`SIGNATURE_LEN`] hex characters of an HMAC over:
+///
+/// - the user's id, so a token made for one user never verifies for another;
+/// - the password hash prefix and the time the password last changed, so
+///   changing the password invalidates every token issued before;
+/// - the timestamp, so a token can't be made to look newer than it is, and
+///   expires `timeout_secs` after it was issued;
+/// - for magic logins the last login time, and for email verification the
+///   address and whether it is verified, so those links work only once.
+///
+/// Without the secret key a valid signature can only be guessed, one in
+/// 2^80 tries. The HMAC isn't keyed with the secret key itself but with a
+/// key derived from it and a fixed salt per [`TokenPurpose`] (see
+/// [`TokenPurpose::key_salt`]). That stops a token for one purpose from
+/// verifying for another. It also stops the tokens from sharing a key with the
+/// other HMACs made from the secret key, such as session auth hashes and SMS
+/// reset codes.
+///
+/// Nothing here reads the clock or the config on its own: [`Self::new`] takes
+/// the settings, every method takes the secrets, and
+/// [`Self::make_token_with_timestamp`] and [`Self::check_token_at`] take the
+/// time.
+pub(crate) struct ResetToken {
+    algorithm: TokenAlgorithm,
+    purpose: TokenPurpose,
+    /// How many seconds in the future a token's timestamp can be and still
+    /// count as fresh.
+    allowed_clock_skew_secs: i64,
+}
 
-pub(crate) struct ResetToken;
+/// What a token lets its bearer do. Tokens made for one purpose never verify
+/// for another.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TokenPurpose {
+    PasswordReset,
+    /// Logging in without a password; see [`crate::forms::magic_link`].
+    MagicLogin,
+    /// Confirming an email address; see [`crate::forms::verify_email`].
+    EmailVerification,
+}
+
+impl TokenPurpose {
+    /// Mixed with the secret key into the key tokens for this purpose are
+    /// signed with. Changing one invalidates every outstanding token of its
+    /// purpose.
+    fn key_salt(self) -> &'static str {
+        match self {
+            TokenPurpose::PasswordReset => "cot-basic-auth.token.password-reset",
+            TokenPurpose::MagicLogin => "cot-basic-auth.token.magic-login",
+            TokenPurpose::EmailVerification => "cot-basic-auth.token.email-verification",
+        }
+    }
+}
 
 impl ResetToken {
-    pub fn make_token(&self, user: &User, secret: &[u8]) -> String {
+    pub fn new(algorithm: TokenAlgorithm) -> Self {
+        Self {
+            algorithm,
+            purpose: TokenPurpose::PasswordReset,
+            allowed_clock_skew_secs: 0,
+        }
+    }
+
+    pub fn from_config() -> Self {
+        let config = &app_config().reset_token;
+        Self {
+            allowed_clock_skew_secs: config.allowed_clock_skew_secs,
+            ..Self::new(config.algorithm)
+        }
+    }
+
+    /// A token signer for magic login links, using the reset token algorithm.
+    pub fn magic_login_from_config() -> Self {
+        Self {
+            purpose: TokenPurpose::MagicLogin,
+            ..Self::from_config()
+        }
+    }
+
+    /// A token signer for email verification links, using the reset token
+    /// algorithm.
+    pub fn email_verification_from_config() -> Self {
+        Self {
+            purpose: TokenPurpose::EmailVerification,
+            ..Self::from_config()
+        }
+    }
+
+    /// Makes a reset token for `user`, or returns `None` if the user hasn't
+    /// been saved yet and so has no id to bind the token to.
+    pub fn make_token(&self, user: &User, secret: &[u8]) -> Option<String> {
         let ts = Utc::now().timestamp();
         self.make_token_with_timestamp(user, secret, ts)
     }
 
-    pub fn make_token_with_timestamp(&self, user: &User, secret: &[u8], ts: i64) -> String {
+    pub fn make_token_with_timestamp(&self, user: &User, secret: &[u8], ts: i64) -> Option<String> {
+        let id = user.id()?;
         // the current timestamp is always going to be positive, so this cast is safe.
         let ts_b36 = Base36::encode(ts as u64);
-        let data = format!("{}{:?}{}", user.id(), &user.password_hash(), ts);
+        // `PasswordHash`'s `Debug` output only shows the algorithm prefix, so
+        // the change timestamp is what ties the token to the current password:
+        // completing one reset invalidates every token issued before it.
+        let changed_at = user
+            .password_changed_at()
+            .map_or(0, |changed_at| changed_at.timestamp_micros());
+        let mut data = format!("{}{:?}{}{}", id, &user.password_hash(), changed_at, ts);
+        match self.purpose {
+            TokenPurpose::PasswordReset => {}
+            TokenPurpose::MagicLogin => {
+                // logging in updates the last login time, so a magic link
+                // works only once
+                let last_login = user
+                    .last_login()
+                    .map_or(0, |last_login| last_login.timestamp_micros());
+                data = format!("magic-login{data}{last_login}");
+            }
+            TokenPurpose::EmailVerification => {
+                // a link confirms only the address it was sent to, and only
+                // until it has been used
+                data = format!(
+                    "verify-email{data}{}{}",
+                    user.email().as_str(),
+                    user.is_email_verified()
+                );
+            }
+        }
 
-        let mut mac = HmacSHa256::new_from_slice(secret).unwrap();
-        mac.update(data.as_bytes());
-        let full = mac.finalize().into_bytes();
-        let short = hex::encode(&full)[..20].to_string();
-        format!("{ts_b36}-{short}")
+        let full = self.sign(secret, data.as_bytes());
+        let short = hex::encode(full)[..SIGNATURE_LEN].to_string();
+        Some(format!("{ts_b36}-{short}"))
     }
 
-    pub fn check_token(&self, user: &User, token: &str, secret: &[u8], timeout_secs: i64) -> bool {
-        let (ts_b36, sig) = match token.split_once("-") {
-            Some((x, y)) => (x, y),
-            _ => return false,
-        };
+    /// HMACs `data` with a key derived from `secret` and the purpose's salt.
+    fn sign(&self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        let salt = self.purpose.key_salt().as_bytes();
+        match self.algorithm {
+            TokenAlgorithm::Sha256 => salted_hmac::<Hmac<Sha256>>(secret, salt, data),
+            TokenAlgorithm::Sha384 => salted_hmac::<Hmac<Sha384>>(secret, salt, data),
+            TokenAlgorithm::Sha512 => salted_hmac::<Hmac<Sha512>>(secret, salt, data),
+        }
+    }
+
+    /// Checks `token` against every secret in `secrets`.
+    ///
+    /// Tokens are always signed with the current secret key, but old keys stay
+    /// valid for verification so rotating the key doesn't invalidate links
+    /// that are already in users' inboxes.
+    pub fn check_token<'a>(
+        &self,
+        user: &User,
+        token: &str,
+        secrets: impl IntoIterator<Item = &'a [u8]>,
+        timeout_secs: i64,
+    ) -> Result<(), TokenError> {
+        let now = Utc::now().timestamp();
+        self.check_token_at(user, token, secrets, timeout_secs, now)
+    }
 
-        // decode the b36 timestamp
-        let ts = i64::from_str_radix(ts_b36, BASE36_RADIX).unwrap();
+    /// Like [`Self::check_token`], but judges the token's age as of the Unix
+    /// timestamp `now` instead of the current time.
+    pub fn check_token_at<'a>(
+        &self,
+        user: &User,
+        token: &str,
+        secrets: impl IntoIterator<Item = &'a [u8]>,
+        timeout_secs: i64,
+        now: i64,
+    ) -> Result<(), TokenError> {
+        let (ts, sig) = parse_token(token)?;
 
-        let age = Utc::now().timestamp() - ts;
-        if age < 0 || age > timeout_secs {
-            return false;
+        let age = now - ts;
+        // a token from a server whose clock runs slightly ahead looks like it
+        // was made in the future
+        if age < -self.allowed_clock_skew_secs || age > timeout_secs {
+            return Err(TokenError::Expired);
         }
 
-        let expected = self.make_token_with_timestamp(user, secret, ts);
-        expected == format!("{ts_b36}-{sig}")
+        let matches = |secret: &[u8]| {
+            // no token can have been issued for a user without an id
+            self.make_token_with_timestamp(user, secret, ts)
+                .is_some_and(|expected| {
+                    let (_, expected_sig) = expected
+                        .split_once('-')
+                        .expect("generated tokens always contain a separator");
+                    constant_time_eq(expected_sig.as_bytes(), sig.as_bytes())
+                })
+        };
+        if secrets.into_iter().any(matches) {
+            Ok(())
+        } else {
+            Err(TokenError::InvalidSignature)
+        }
     }
 }
 
-fn decode_b64url_to_i64_from_decimal(
-    b64: &str,
-) -> Result<i64, Box<dyn std::error::Error + Sync + Send>> {
-    let bytes = URL_SAFE_NO_PAD.decode(b64)?;
-    let s = String::from_utf8(bytes)?;
-    let id = s.parse::<i64>()?;
+/// The secret keys reset tokens may have been signed with: the current one
+/// first, then any fallbacks kept around during a key rotation.
+pub(crate) fn verification_secrets(config: &ProjectConfig) -> impl Iterator<Item = &[u8]> {
+    std::iter::once(&config.secret_key)
+        .chain(&config.fallback_secret_keys)
+        .map(SecretKey::as_bytes)
+}
+
+/// What a client that asked for too many reset emails is told.
+const TOO_MANY_REQUESTS: &str = "Too many reset requests. Please try again later.";
+
+/// Reset emails an account can be sent per [`RESET_ACCOUNT_WINDOW`].
+const RESET_EMAILS_PER_ACCOUNT: u32 = 3;
+const RESET_ACCOUNT_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Reset requests a client address can make per [`RESET_CLIENT_WINDOW`].
+const RESET_REQUESTS_PER_CLIENT: u32 = 10;
+const RESET_CLIENT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+static RESET_THROTTLE: LazyLock<EmailThrottle> = LazyLock::new(|| {
+    EmailThrottle::new(
+        rate_limit::from_config(
+            "reset_email",
+            RESET_EMAILS_PER_ACCOUNT,
+            RESET_ACCOUNT_WINDOW,
+        ),
+        rate_limit::from_config(
+            "reset_request",
+            RESET_REQUESTS_PER_CLIENT,
+            RESET_CLIENT_WINDOW,
+        ),
+    )
+});
+
+/// The number of hex characters kept from the token's HMAC.
+const SIGNATURE_LEN: usize = 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TokenError {
+    /// The token isn't of the `<base36 timestamp>-<hex signature>` shape.
+    Malformed,
+    Expired,
+    InvalidSignature,
+}
+
+/// Splits a token into its timestamp and signature, rejecting anything that
+/// isn't exactly `<base36 timestamp>-<lowercase hex signature>`.
+fn parse_token(token: &str) -> Result<(i64, &str), TokenError> {
+    let mut segments = token.split('-');
+    let (Some(ts_b36), Some(sig), None) = (segments.next(), segments.next(), segments.next())
+    else {
+        return Err(TokenError::Malformed);
+    };
+
+    let is_base36 = |b: u8| b.is_ascii_digit() || b.is_ascii_lowercase();
+    if ts_b36.is_empty() || !ts_b36.bytes().all(is_base36) {
+        return Err(TokenError::Malformed);
+    }
+    let is_lower_hex = |b: u8| matches!(b, b'0'..=b'9' | b'a'..=b'f');
+    if sig.len() != SIGNATURE_LEN || !sig.bytes().all(is_lower_hex) {
+        return Err(TokenError::Malformed);
+    }
+
+    let ts = Base36::decode(ts_b36)
+        .ok()
+        .flatten()
+        .and_then(|ts| i64::try_from(ts).ok())
+        .ok_or(TokenError::Malformed)?;
+
+    Ok((ts, sig))
+}
+
+fn hmac_bytes<M: Mac + KeyInit>(secret: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <M as Mac>::new_from_slice(secret).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// HMACs `data` with the key `HMAC(secret, salt)`, so each salt gets its own
+/// key and the secret is never used directly.
+fn salted_hmac<M: Mac + KeyInit>(secret: &[u8], salt: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac_bytes::<M>(&hmac_bytes::<M>(secret, salt), data)
+}
+
+/// The `/reset/{token}/{uid}` link emailed to users.
+///
+/// The uid is the user's id as a decimal string, base64url-encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResetLink {
+    token: String,
+    uid: i64,
+}
+
+impl ResetLink {
+    pub fn new(token: String, uid: i64) -> Self {
+        Self { token, uid }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn uid(&self) -> i64 {
+        self.uid
+    }
+
+    /// Builds the absolute URL of the reset page for this link, with `base`
+    /// being the scheme and host the site is reachable at.
+    pub fn to_url(&self, urls: &Urls, base: &str) -> cot::Result<String> {
+        let uid = URL_SAFE_NO_PAD.encode(self.uid.to_string());
+        let path = cot::reverse!(
+            urls,
+            "reset_password_confirm",
+            token = self.token.as_str(),
+            uid = uid.as_str()
+        )?;
+        Ok(format!("{}{path}", base.trim_end_matches('/')))
+    }
+
+    /// Builds the absolute URL of the magic login page for this link.
+    pub fn to_magic_login_url(&self, urls: &Urls, base: &str) -> cot::Result<String> {
+        let uid = URL_SAFE_NO_PAD.encode(self.uid.to_string());
+        let path = cot::reverse!(
+            urls,
+            "magic_login",
+            token = self.token.as_str(),
+            uid = uid.as_str()
+        )?;
+        Ok(format!("{}{path}", base.trim_end_matches('/')))
+    }
+
+    /// Builds the absolute URL of the email verification page for this link.
+    pub fn to_verify_email_url(&self, urls: &Urls, base: &str) -> cot::Result<String> {
+        let uid = URL_SAFE_NO_PAD.encode(self.uid.to_string());
+        let path = cot::reverse!(
+            urls,
+            "verify_email",
+            token = self.token.as_str(),
+            uid = uid.as_str()
+        )?;
+        Ok(format!("{}{path}", base.trim_end_matches('/')))
+    }
+
+    /// Reads the link back out of the reset, magic login or email
+    /// verification page's path parameters.
+    pub fn from_path_params(params: &PathParams) -> Result<Self, ResetLinkError> {
+        let (Some(token), Some(uid)) = (params.get("token"), params.get("uid")) else {
+            return Err(ResetLinkError::Missing);
+        };
+
+        let uid = URL_SAFE_NO_PAD
+            .decode(uid)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .and_then(|uid| uid.parse::<i64>().ok())
+            .ok_or(ResetLinkError::InvalidUid)?;
+
+        Ok(Self::new(token.to_owned(), uid))
+    }
+}
 
-    Ok(id)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ResetLinkError {
+    Missing,
+    InvalidUid,
+}
+
+impl Display for ResetLinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetLinkError::Missing => write!(f, "token or uid cannot be empty"),
+            ResetLinkError::InvalidUid => write!(f, "Invalid token or uid"),
+        }
+    }
 }
 
 #[derive(Debug, Form)]
 pub(crate) struct ForgotPasswordForm {
-    email: Email,
+    /// The account's email address or username.
+    identifier: TrimmedString,
 }
 
-#[derive(Debug, Template)]
-#[template(path = "forgot_password.html")]
-pub(crate) struct ForgotPasswordTemplate<'a> {
-    urls: &'a Urls,
-    static_files: StaticFiles,
-    form: <ForgotPasswordForm as Form>::Context,
-    email_sent: bool,
+form_page! {
+    #[template(path = "forgot_password.html")]
+    pub(crate) struct ForgotPasswordTemplate for ForgotPasswordForm {
+        email_sent: bool,
+    }
+}
+
+/// Answers 404 when self-service password reset is turned off.
+pub(crate) fn ensure_enabled() -> cot::Result<()> {
+    if app_config().password_reset_enabled {
+        Ok(())
+    } else {
+        Err(NotFound::new().into())
+    }
 }
 
 async fn send_reset_email(
     email_sender: EmailService,
-    reset_token: String,
-    uid_encoded: String,
-) -> cot::Result<Html> {
+    to: &Email,
+    reset_url: String,
+) -> cot::Result<()> {
     let message = EmailMessage::builder()
         .from(Email::try_from("no-reply@example.com").unwrap())
-        .to(vec![Email::try_from("user@example.com").unwrap()])
-        .subject("Password Reset")
+        .to(vec![to.clone()])
+        .subject(format!("{} password reset", app_config().site_name))
         .body(format!(
             r#"
                     click link to reset password:
 
-                    http://127.0.0.1:8000/reset/{reset_token}/{uid_encoded}
+                    {reset_url}
 
                   "#
         ))
         .build()?;
 
-    email_sender.send(message).await?;
-
-    Ok(Html::new("Email sent!"))
+    mail_queue::send(email_sender, message).await
 }
 
 pub(crate) async fn forgot_password(
-    urls: Urls,
-    mut request: Request,
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
     db: Database,
-    static_files: StaticFiles,
     email: EmailService,
+    client_ip: ClientIp,
+    mut request: Request,
 ) -> cot::Result<Response> {
+    ensure_enabled()?;
     let mut email_sent: bool = false;
+    // the client's limit and time left, if it's out of requests
+    let mut throttled = None;
 
-    let forgot_pass_context = if request.method() == Method::GET {
+    let mut forgot_pass_context = if request.method() == Method::GET {
         ForgotPasswordForm::build_context(&mut request).await?
     } else if request.method() == Method::POST {
-        let fg_form = ForgotPasswordForm::from_request(&mut request).await?;
-        let forgot_pass_context = match fg_form {
+        let fg_form = form_from_request::<ForgotPasswordForm>(&mut request).await?;
+        match fg_form {
             FormResult::Ok(fg_form) => {
-                let user = query!(User, $email == fg_form.email.clone())
-                    .get(&db)
-                    .await?;
+                if let Err(retry_after) = RESET_THROTTLE.check_client(&db, client_ip).await? {
+                    warn!(event = "reset_request", outcome = "throttled", %client_ip, "too many password reset requests");
+                    throttled = Some((RESET_THROTTLE.client_limit(), retry_after));
+                } else {
+                    counter!(telemetry::RESET_REQUESTS).increment(1);
+                    let user = User::get_by_email_or_username(&db, &fg_form.identifier).await?;
 
-                if let Some(user) = user {
-                    let uid_encoded = URL_SAFE_NO_PAD.encode(user.id().to_string());
-                    let reset_token = ResetToken
-                        .make_token(&user, request.context().config().secret_key.as_bytes());
+                    // an account out of emails is skipped silently, so the
+                    // answer doesn't tell that it exists
+                    if let Some(user) = user
+                        && RESET_THROTTLE
+                            .check_account(&db, user.email())
+                            .await?
+                            .is_ok()
+                    {
+                        let secret = request.context().config().secret_key.as_bytes();
+                        let (Some(uid), Some(reset_token)) = (
+                            user.id(),
+                            ResetToken::from_config().make_token(&user, secret),
+                        ) else {
+                            return Err(cot::Error::internal(
+                                "user loaded from the database has no id",
+                            ));
+                        };
+                        let reset_url = ResetLink::new(reset_token, uid)
+                            .to_url(&urls, &https::base_url(request.headers()))?;
 
-                    send_reset_email(email, reset_token, uid_encoded).await?;
+                        send_reset_email(email, user.email(), reset_url).await?;
+                    }
+                    // answer the same way whether or not an account matched, so
+                    // the form can't be used to probe for accounts
                     email_sent = true;
                 }
 
@@ -136,11 +512,16 @@ pub(crate) async fn forgot_password(
             }
 
             FormResult::ValidationError(context) => context,
-        };
-        forgot_pass_context
+        }
     } else {
         panic!("unexpected request method")
     };
+    if throttled.is_some() {
+        forgot_pass_context.add_error(
+            FormErrorTarget::Form,
+            FormFieldValidationError::from_static(TOO_MANY_REQUESTS),
+        );
+    }
 
     let forgot_password_template = ForgotPasswordTemplate {
         urls: &urls,
@@ -148,144 +529,868 @@ pub(crate) async fn forgot_password(
         form: forgot_pass_context,
         email_sent,
     };
-    let rendered = forgot_password_template.render()?;
-
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::fixed(rendered))
-        .unwrap();
+    let error_status = if throttled.is_some() {
+        StatusCode::TOO_MANY_REQUESTS
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    let mut response = render_form(
+        request.headers(),
+        &forgot_password_template,
+        &forgot_password_template.form,
+        error_status,
+    )?;
+    if let Some((limit, retry_after)) = throttled {
+        rate_limit::add_throttle_headers(response.headers_mut(), limit, retry_after);
+    }
     Ok(response)
 }
 
-#[derive(Debug, Form)]
+#[derive(Form)]
 pub(crate) struct ResetPasswordConfirmForm {
     password1: Password,
     password2: Password,
 }
 
+impl Debug for ResetPasswordConfirmForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResetPasswordConfirmForm")
+            .field("password1", &Redacted)
+            .field("password2", &Redacted)
+            .finish()
+    }
+}
+
+/// Checks a new password and its confirmation the way every reset does.
+pub(crate) async fn validate_new_password(
+    password1: &Password,
+    password2: &Password,
+) -> Result<(), (FormErrorTarget<'static>, FormFieldValidationError)> {
+    if password1.as_str() != password2.as_str() {
+        return Err((
+            FormErrorTarget::Field("password2"),
+            FormFieldValidationError::from_static("passwords do not match."),
+        ));
+    }
+    validate_entropy(password1).map_err(|err| (FormErrorTarget::Field("password1"), err))?;
+    validate_not_breached(password1)
+        .await
+        .map_err(|err| (FormErrorTarget::Form, err))?;
+    Ok(())
+}
+
 impl ResetPasswordConfirmForm {
-    fn validate_password(self) -> Result<ValidatedResetForm, FormFieldValidationError> {
-        if self.password1.as_str() != self.password2.as_str() {
-            return Err(FormFieldValidationError::from_static(
-                "passwords do not match.",
-            ));
-        }
+    async fn validate_password(
+        self,
+    ) -> Result<ValidatedResetForm, (FormErrorTarget<'static>, FormFieldValidationError)> {
+        validate_new_password(&self.password1, &self.password2).await?;
         Ok(ValidatedResetForm::new(self.password1))
     }
 }
 
-#[derive(Debug)]
 struct ValidatedResetForm {
     password: Password,
 }
 
+impl Debug for ValidatedResetForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ValidatedResetForm")
+            .field("password", &Redacted)
+            .finish()
+    }
+}
+
 impl ValidatedResetForm {
     fn new(password: Password) -> Self {
         Self { password }
     }
 }
 
+form_page! {
+    #[template(path = "forgot_password_confirm.html")]
+    pub(crate) struct ResetPasswordConfirmTemplate for ResetPasswordConfirmForm {
+    }
+}
+
 #[derive(Debug, Template)]
-#[template(path = "forgot_password_confirm.html")]
-pub(crate) struct ResetPasswordConfirmTemplate<'a> {
+#[template(path = "password_reset_done.html")]
+pub(crate) struct PasswordResetDoneTemplate<'a> {
     urls: &'a Urls,
     static_files: StaticFiles,
-    form: <ResetPasswordConfirmForm as Form>::Context,
-    reset_success: bool,
+}
+
+/// Why a submitted reset was refused: the error to show and where to show it.
+#[derive(Debug)]
+pub(crate) struct ResetError {
+    pub(crate) target: FormErrorTarget<'static>,
+    pub(crate) error: FormFieldValidationError,
+}
+
+impl ResetError {
+    pub(crate) fn form(error: FormFieldValidationError) -> Self {
+        Self {
+            target: FormErrorTarget::Form,
+            error,
+        }
+    }
+}
+
+impl From<(FormErrorTarget<'static>, FormFieldValidationError)> for ResetError {
+    fn from((target, error): (FormErrorTarget<'static>, FormFieldValidationError)) -> Self {
+        Self { target, error }
+    }
+}
+
+/// What a submission with a link that doesn't check out is told, whatever is
+/// wrong with it.
+const INVALID_LINK: &str = "this reset link is invalid or has expired.";
+
+/// Sets the new password of the user the reset link in `params` was issued
+/// for.
+///
+/// The link is checked before anything else, and every way it can be wrong
+/// gets the same answer: without a valid link, the form can't be used to find
+/// out which user ids exist or how strong a password has to be, nor to have
+/// the server query the breach check.
+///
+/// The outer error is for failures the user can't fix, like the database being
+/// unreachable; the inner one is for a submission that has to be corrected.
+async fn reset_password(
+    db: &Database,
+    config: &ProjectConfig,
+    params: &PathParams,
+    form: ResetPasswordConfirmForm,
+) -> cot::Result<Result<(), ResetError>> {
+    let user = match ResetLink::from_path_params(params) {
+        Ok(link) => User::get_by_id(db, link.uid()).await?.filter(|user| {
+            ResetToken::from_config()
+                .check_token(
+                    user,
+                    link.token(),
+                    verification_secrets(config),
+                    app_config().reset_token.timeout_secs,
+                )
+                .is_ok()
+        }),
+        Err(_) => None,
+    };
+    let Some(mut user) = user else {
+        return Ok(Err(ResetError::form(
+            FormFieldValidationError::from_static(INVALID_LINK),
+        )));
+    };
+    let validated_form = match form.validate_password().await {
+        Ok(validated_form) => validated_form,
+        Err(err) => return Ok(Err(err.into())),
+    };
+
+    set_new_password(db, &mut user, &validated_form.password).await
+}
+
+/// Gives `user` the already validated `password` at the end of a reset,
+/// unless it's one of their recent ones.
+pub(crate) async fn set_new_password(
+    db: &Database,
+    user: &mut User,
+    password: &Password,
+) -> cot::Result<Result<(), ResetError>> {
+    if password_history::is_recent(db, user, password).await? {
+        return Ok(Err(ResetError {
+            target: FormErrorTarget::Field("password1"),
+            error: FormFieldValidationError::from_static(password_history::REUSED_PASSWORD),
+        }));
+    }
+    password_history::replace_password(db, user, password).await?;
+    // the new password hash has logged out every session of the user
+    if let Some(user_id) = user.id() {
+        sessions::forget_all(db, user_id).await?;
+    }
+    counter!(telemetry::RESETS).increment(1);
+    Ok(Ok(()))
 }
 
 pub(crate) async fn reset_password_confirm(
-    urls: Urls,
-    mut request: Request,
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
     db: Database,
-    static_files: StaticFiles,
+    mut request: Request,
 ) -> cot::Result<Response> {
-    let mut reset_success = false;
+    ensure_enabled()?;
     let reset_pass_context = if request.method() == Method::GET {
         ResetPasswordConfirmForm::build_context(&mut request).await?
     } else if request.method() == Method::POST {
         let params = request.path_params().clone();
 
-        let form = ResetPasswordConfirmForm::from_request(&mut request).await?;
-        let form_context = match form {
+        let form = form_from_request::<ResetPasswordConfirmForm>(&mut request).await?;
+        match form {
             FormResult::Ok(form) => {
                 let mut ctx = form.to_context().await;
-
-                if let (Some(token), Some(uid)) = (params.get("token"), params.get("uid")) {
-                    let user_id = decode_b64url_to_i64_from_decimal(uid);
-
-                    match user_id {
-                        Ok(user_id) => {
-                            let user = User::get_by_id(&db, user_id).await?;
-                            if let Some(mut user) = user {
-                                let validated_form = form.validate_password();
-                                match validated_form {
-                                    Ok(validated_form) => {
-                                        if ResetToken.check_token(
-                                            &user,
-                                            token,
-                                            request.context().config().secret_key.as_bytes(),
-                                            3600,
-                                        ) {
-                                            user.set_password(&validated_form.password).await;
-                                            user.save(&db).await?;
-                                            reset_success = true;
-                                        } else {
-                                            ctx.add_error(
-                                                FormErrorTarget::Form,
-                                                FormFieldValidationError::from_static(
-                                                    "Invalid token or uid",
-                                                ),
-                                            );
-                                        }
-                                    }
-                                    Err(err) => {
-                                        ctx.add_error(FormErrorTarget::Form, err);
-                                    }
-                                }
-                            } else {
-                                ctx.add_error(
-                                    FormErrorTarget::Form,
-                                    FormFieldValidationError::from_static("could not find user"),
-                                );
-                            }
-                        }
-                        Err(err) => {
-                            ctx.add_error(
-                                FormErrorTarget::Form,
-                                FormFieldValidationError::from_string(err.to_string()),
-                            );
-                        }
+                match reset_password(&db, request.context().config(), &params, form).await? {
+                    Ok(()) => {
+                        return Ok(cot::reverse_redirect!(urls, "password_reset_done")?);
                     }
-
-                    ctx
-                } else {
-                    let mut ctx = form.to_context().await;
-                    ctx.add_error(
-                        FormErrorTarget::Form,
-                        FormFieldValidationError::from_static("token or uid cannot be empty"),
-                    );
-                    ctx
+                    Err(ResetError { target, error }) => ctx.add_error(target, error),
                 }
+                ctx
             }
             FormResult::ValidationError(context) => context,
-        };
-        form_context
+        }
     } else {
         panic!("unexpected request method")
     };
 
     let reset_template = ResetPasswordConfirmTemplate {
         urls: &urls,
-        static_files: static_files,
+        static_files,
         form: reset_pass_context,
-        reset_success,
     };
-    let rendered = reset_template.render()?;
+    render_form(
+        request.headers(),
+        &reset_template,
+        &reset_template.form,
+        StatusCode::BAD_REQUEST,
+    )
+}
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::fixed(rendered))
-        .unwrap();
-    Ok(response)
+/// Where a successful reset redirects to, so reloading the page doesn't post
+/// the new password again.
+pub(crate) async fn password_reset_done(
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
+) -> cot::Result<Response> {
+    ensure_enabled()?;
+    render(&PasswordResetDoneTemplate {
+        urls: &urls,
+        static_files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use cot::db::Model;
+    use cot::router::{Route, Router};
+
+    use super::*;
+    use crate::config::{AppConfig, set_test_app_config};
+    use crate::test_utils::{PASSWORD, TestApp, find_link, from_ip, sent_emails};
+
+    const NEW_PASSWORD: &str = "An0ther!Passphrase-abc";
+    const SECRET: &[u8] = b"test-secret-key-that-is-at-least-32-bytes";
+    /// When the tokens below are issued, as a Unix timestamp.
+    const ISSUED_AT: i64 = 1_700_000_000;
+    const TIMEOUT_SECS: i64 = 3600;
+
+    fn signer() -> ResetToken {
+        ResetToken {
+            allowed_clock_skew_secs: 60,
+            ..ResetToken::new(TokenAlgorithm::Sha256)
+        }
+    }
+
+    fn check_at(user: &User, token: &str, now: i64) -> Result<(), TokenError> {
+        signer().check_token_at(user, token, [SECRET], TIMEOUT_SECS, now)
+    }
+
+    #[cot::test]
+    async fn token_expires_right_after_its_timeout() {
+        let app = TestApp::new().await;
+        let user = app.create_user("token_expiry", PASSWORD).await;
+        let token = signer()
+            .make_token_with_timestamp(&user, SECRET, ISSUED_AT)
+            .unwrap();
+
+        assert_eq!(check_at(&user, &token, ISSUED_AT), Ok(()));
+        assert_eq!(check_at(&user, &token, ISSUED_AT + TIMEOUT_SECS), Ok(()));
+        assert_eq!(
+            check_at(&user, &token, ISSUED_AT + TIMEOUT_SECS + 1),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[cot::test]
+    async fn token_from_the_future_is_accepted_within_the_clock_skew() {
+        let app = TestApp::new().await;
+        let user = app.create_user("token_skew", PASSWORD).await;
+        let token = signer()
+            .make_token_with_timestamp(&user, SECRET, ISSUED_AT)
+            .unwrap();
+
+        assert_eq!(check_at(&user, &token, ISSUED_AT - 60), Ok(()));
+        assert_eq!(
+            check_at(&user, &token, ISSUED_AT - 61),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[cot::test]
+    async fn token_is_bound_to_the_user_it_was_made_for() {
+        let app = TestApp::new().await;
+        let alice = app.create_user("token_alice", PASSWORD).await;
+        let bob = app.create_user("token_bob", PASSWORD).await;
+        let token = signer()
+            .make_token_with_timestamp(&alice, SECRET, ISSUED_AT)
+            .unwrap();
+
+        assert_eq!(
+            check_at(&bob, &token, ISSUED_AT),
+            Err(TokenError::InvalidSignature)
+        );
+    }
+
+    #[cot::test]
+    async fn token_stops_working_once_the_password_changes() {
+        let app = TestApp::new().await;
+        let mut user = app.create_user("token_password_change", PASSWORD).await;
+        let token = signer()
+            .make_token_with_timestamp(&user, SECRET, ISSUED_AT)
+            .unwrap();
+
+        user.set_password(&Password::new(NEW_PASSWORD));
+
+        assert_eq!(
+            check_at(&user, &token, ISSUED_AT),
+            Err(TokenError::InvalidSignature)
+        );
+    }
+
+    #[cot::test]
+    async fn token_cannot_be_made_without_the_secret() {
+        let app = TestApp::new().await;
+        let user = app.create_user("token_forgery", PASSWORD).await;
+        let forged = signer()
+            .make_token_with_timestamp(&user, b"some-other-secret-key-of-32-bytes!", ISSUED_AT)
+            .unwrap();
+        let token = signer()
+            .make_token_with_timestamp(&user, SECRET, ISSUED_AT)
+            .unwrap();
+        let (timestamp, signature) = token.split_once('-').unwrap();
+        let flipped = if signature.starts_with('0') { '1' } else { '0' };
+        let tampered = format!("{timestamp}-{flipped}{}", &signature[1..]);
+        // a later timestamp with the old signature, to make the token look newer
+        let extended = format!(
+            "{}-{signature}",
+            Base36::encode(u64::try_from(ISSUED_AT + TIMEOUT_SECS).unwrap())
+        );
+
+        for token in [forged, tampered] {
+            assert_eq!(
+                check_at(&user, &token, ISSUED_AT),
+                Err(TokenError::InvalidSignature)
+            );
+        }
+        // checked once the real token has expired and the extended one hasn't
+        assert_eq!(
+            check_at(&user, &extended, ISSUED_AT + TIMEOUT_SECS + 1),
+            Err(TokenError::InvalidSignature)
+        );
+    }
+
+    #[cot::test]
+    async fn token_signed_with_a_fallback_secret_still_works() {
+        let app = TestApp::new().await;
+        let user = app.create_user("token_rotation", PASSWORD).await;
+        let old_secret = b"the-secret-key-before-the-rotation";
+        let token = signer()
+            .make_token_with_timestamp(&user, old_secret, ISSUED_AT)
+            .unwrap();
+
+        let result = signer().check_token_at(
+            &user,
+            &token,
+            [SECRET, old_secret.as_slice()],
+            TIMEOUT_SECS,
+            ISSUED_AT,
+        );
+
+        assert_eq!(result, Ok(()));
+    }
+
+    #[cot::test]
+    async fn token_for_one_purpose_does_not_work_for_another() {
+        let app = TestApp::new().await;
+        let user = app.create_user("token_purpose", PASSWORD).await;
+        let magic_login = ResetToken {
+            purpose: TokenPurpose::MagicLogin,
+            ..signer()
+        };
+        let token = magic_login
+            .make_token_with_timestamp(&user, SECRET, ISSUED_AT)
+            .unwrap();
+
+        assert_eq!(
+            check_at(&user, &token, ISSUED_AT),
+            Err(TokenError::InvalidSignature)
+        );
+    }
+
+    #[cot::test]
+    async fn email_verification_tokens_do_not_reset_passwords_or_log_in() {
+        let app = TestApp::new().await;
+        let user = app.create_user("token_purpose_email", PASSWORD).await;
+        let with_purpose = |purpose| ResetToken {
+            purpose,
+            ..signer()
+        };
+        let token = with_purpose(TokenPurpose::EmailVerification)
+            .make_token_with_timestamp(&user, SECRET, ISSUED_AT)
+            .unwrap();
+
+        for purpose in [TokenPurpose::PasswordReset, TokenPurpose::MagicLogin] {
+            assert_eq!(
+                with_purpose(purpose).check_token_at(
+                    &user,
+                    &token,
+                    [SECRET],
+                    TIMEOUT_SECS,
+                    ISSUED_AT
+                ),
+                Err(TokenError::InvalidSignature),
+                "{purpose:?}"
+            );
+        }
+    }
+
+    #[cot::test]
+    async fn token_signed_with_one_algorithm_does_not_verify_with_another() {
+        let app = TestApp::new().await;
+        let user = app.create_user("token_algorithm", PASSWORD).await;
+        let algorithms = [
+            TokenAlgorithm::Sha256,
+            TokenAlgorithm::Sha384,
+            TokenAlgorithm::Sha512,
+        ];
+        let with_algorithm = |algorithm| ResetToken {
+            allowed_clock_skew_secs: 60,
+            ..ResetToken::new(algorithm)
+        };
+
+        for signed_with in algorithms {
+            let token = with_algorithm(signed_with)
+                .make_token_with_timestamp(&user, SECRET, ISSUED_AT)
+                .unwrap();
+            for checked_with in algorithms {
+                let expected = if checked_with == signed_with {
+                    Ok(())
+                } else {
+                    Err(TokenError::InvalidSignature)
+                };
+                assert_eq!(
+                    with_algorithm(checked_with).check_token_at(
+                        &user,
+                        &token,
+                        [SECRET],
+                        TIMEOUT_SECS,
+                        ISSUED_AT
+                    ),
+                    expected,
+                    "signed with {signed_with:?}, checked with {checked_with:?}"
+                );
+            }
+        }
+    }
+
+    #[cot::test]
+    async fn reset_link_survives_the_trip_through_its_url() {
+        let mut router_request = cot::test::TestRequestBuilder::get("/");
+        let request = router_request
+            .router(Router::with_urls([Route::with_handler_and_name(
+                "/reset/{token}/{uid}",
+                reset_password_confirm,
+                "reset_password_confirm",
+            )]))
+            .build();
+        let urls = Urls::from_request(&request);
+
+        for uid in [1, 42, i64::MAX] {
+            let link = ResetLink::new("tmwj5y-d235a554b10bc433ef22".to_owned(), uid);
+            let url = link.to_url(&urls, "https://example.com/").unwrap();
+
+            let path = url.strip_prefix("https://example.com/reset/").unwrap();
+            let (token, encoded_uid) = path.split_once('/').unwrap();
+            let mut params = PathParams::new();
+            params.insert("token".to_owned(), token.to_owned());
+            params.insert("uid".to_owned(), encoded_uid.to_owned());
+
+            assert_eq!(ResetLink::from_path_params(&params), Ok(link), "{url}");
+        }
+    }
+
+    #[test]
+    fn reset_link_with_a_bad_uid_is_rejected() {
+        let mut params = PathParams::new();
+        params.insert("token".to_owned(), "tmwj5y-d235a554b10bc433ef22".to_owned());
+        assert_eq!(
+            ResetLink::from_path_params(&params),
+            Err(ResetLinkError::Missing)
+        );
+
+        for uid in ["", "not-base64!", "YWJj", "MS41"] {
+            params.insert("uid".to_owned(), uid.to_owned());
+            assert_eq!(
+                ResetLink::from_path_params(&params),
+                Err(ResetLinkError::InvalidUid),
+                "{uid}"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected_before_checking_the_signature() {
+        for token in [
+            "",
+            "abc",
+            "abc-",
+            "-0123456789abcdef0123",
+            "ABC-0123456789abcdef0123",
+            "abc-0123456789abcdef0123-4",
+            "abc-0123-456789abcdef0123",
+            "abc-0123456789abcdefghij",
+            "abc-0123456789ABCDEF0123",
+        ] {
+            assert_eq!(parse_token(token), Err(TokenError::Malformed), "{token}");
+        }
+    }
+
+    #[cot::test]
+    async fn forgot_password_then_reset_changes_the_password() {
+        let mut app = TestApp::new().await;
+        app.create_user("reset_happy_path", PASSWORD).await;
+
+        let response = app
+            .post(
+                "/forgot-password",
+                &[("identifier", "reset_happy_path@example.com")],
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::OK);
+        let emails = sent_emails();
+        assert_eq!(emails.len(), 1);
+        assert!(emails[0].contains("reset_happy_path@example.com"));
+        let reset_path = find_link(&emails[0], "/reset/").expect("the email has a reset link");
+
+        let response = app.get(&reset_path).await;
+        assert_eq!(response.status, StatusCode::OK);
+        let response = app
+            .post(
+                &reset_path,
+                &[("password1", NEW_PASSWORD), ("password2", NEW_PASSWORD)],
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        assert_eq!(response.location(), Some("/password-reset-done"));
+
+        let response = app.login("reset_happy_path", PASSWORD).await;
+        assert!(response.body.contains("Invalid username or password"));
+        let response = app.login("reset_happy_path", NEW_PASSWORD).await;
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn a_user_without_a_password_sets_one_through_a_reset() {
+        let mut app = TestApp::new().await;
+        let mut user = User::builder()
+            .username("reset_no_password")
+            .email(Email::new("reset_no_password@example.com").unwrap())
+            .unusable_password()
+            .build()
+            .unwrap();
+        user.save(app.db()).await.unwrap();
+        let response = app.login("reset_no_password", PASSWORD).await;
+        assert!(response.body.contains("Invalid username or password"));
+
+        let reset_path = reset_link(&mut app, "reset_no_password").await;
+        app.post(
+            &reset_path,
+            &[("password1", NEW_PASSWORD), ("password2", NEW_PASSWORD)],
+        )
+        .await;
+
+        let response = app.login("reset_no_password", NEW_PASSWORD).await;
+        assert_eq!(response.location(), Some("/home"));
+        let user = User::get_by_username(app.db(), "reset_no_password")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.has_usable_password());
+    }
+
+    #[cot::test]
+    async fn debug_output_leaves_the_new_password_out() {
+        let mut request = cot::test::TestRequestBuilder::post("/reset")
+            .form_data(&[("password1", NEW_PASSWORD), ("password2", NEW_PASSWORD)])
+            .build();
+        let FormResult::Ok(form) = ResetPasswordConfirmForm::from_request(&mut request)
+            .await
+            .unwrap()
+        else {
+            panic!("the form is valid");
+        };
+
+        let debug = format!("{form:?}");
+
+        assert_eq!(
+            debug,
+            "ResetPasswordConfirmForm { password1: [redacted], password2: [redacted] }"
+        );
+    }
+
+    #[cot::test]
+    async fn every_reset_page_is_gone_when_reset_is_disabled() {
+        let mut app = TestApp::new().await;
+        app.create_user("reset_disabled", PASSWORD).await;
+        let reset_path = reset_link(&mut app, "reset_disabled").await;
+        assert!(app.get("/login").await.body.contains("Forgot password?"));
+        set_test_app_config(AppConfig {
+            password_reset_enabled: false,
+            ..app_config().clone()
+        });
+
+        let new_password = [("password1", NEW_PASSWORD), ("password2", NEW_PASSWORD)];
+        for response in [
+            app.get("/forgot-password").await,
+            app.post("/forgot-password", &[("identifier", "reset_disabled")])
+                .await,
+            app.get(&reset_path).await,
+            app.post(&reset_path, &new_password).await,
+            app.get("/password-reset-done").await,
+        ] {
+            assert_eq!(response.status, StatusCode::NOT_FOUND);
+        }
+        assert!(sent_emails().is_empty());
+        assert!(!app.get("/login").await.body.contains("Forgot password?"));
+        assert_eq!(
+            app.login("reset_disabled", PASSWORD).await.location(),
+            Some("/home")
+        );
+    }
+
+    #[cot::test]
+    async fn a_reset_ends_on_the_done_page() {
+        let mut app = TestApp::new().await;
+        app.create_user("reset_done", PASSWORD).await;
+        let reset_path = reset_link(&mut app, "reset_done").await;
+
+        let response = app
+            .post(
+                &reset_path,
+                &[("password1", NEW_PASSWORD), ("password2", NEW_PASSWORD)],
+            )
+            .await;
+        let done = app.get(response.location().unwrap()).await;
+
+        assert_eq!(done.status, StatusCode::OK);
+        assert!(
+            done.body
+                .contains("Your password has been successfully reset.")
+        );
+        assert!(done.body.contains("href=\"/login\""));
+    }
+
+    /// Asks for a reset link for `username` and returns its path.
+    async fn reset_link(app: &mut TestApp, username: &str) -> String {
+        app.post("/forgot-password", &[("identifier", username)])
+            .await;
+        let emails = sent_emails();
+        find_link(&emails[0], "/reset/").expect("the email has a reset link")
+    }
+
+    #[cot::test]
+    async fn a_bad_link_is_refused_before_the_password_is_checked() {
+        let mut app = TestApp::new().await;
+        let user = app.create_user("reset_bad_link", PASSWORD).await;
+        let reset_path = reset_link(&mut app, "reset_bad_link").await;
+        let (_, uid) = reset_path.trim_end_matches('/').rsplit_once('/').unwrap();
+        let unknown_uid = URL_SAFE_NO_PAD.encode((user.id().unwrap() + 1000).to_string());
+        let mismatched = [("password1", "aaaaaaaa"), ("password2", "bbbbbbbb")];
+
+        for path in [
+            format!("/reset/0-0123456789abcdef0123/{uid}"),
+            reset_path.replace(uid, &unknown_uid),
+            reset_path.replace(uid, "not-base64!"),
+        ] {
+            let response = app.post(&path, &mismatched).await;
+
+            assert!(response.body.contains(INVALID_LINK), "{path}");
+            // nothing about the password, which wasn't looked at
+            assert!(!response.body.contains("passwords do not match"), "{path}");
+        }
+
+        let response = app.post(&reset_path, &mismatched).await;
+        assert!(!response.body.contains(INVALID_LINK));
+        assert!(response.body.contains("passwords do not match"));
+    }
+
+    #[cot::test]
+    async fn a_mismatched_confirmation_is_reported_on_its_field() {
+        let mut app = TestApp::new().await;
+        app.create_user("reset_mismatch", PASSWORD).await;
+        let reset_path = reset_link(&mut app, "reset_mismatch").await;
+        let mut headers = cot::http::HeaderMap::new();
+        headers.insert(
+            cot::http::header::ACCEPT,
+            "application/json".parse().unwrap(),
+        );
+
+        let response = app
+            .post_with_headers(
+                &reset_path,
+                &[("password1", NEW_PASSWORD), ("password2", PASSWORD)],
+                headers,
+            )
+            .await;
+
+        assert_eq!(
+            response.body,
+            r#"{"errors":[{"field":"password2","message":"passwords do not match."}]}"#
+        );
+    }
+
+    #[cot::test]
+    async fn links_expire_after_the_configured_timeout() {
+        let mut app = TestApp::with_config(|config| config.reset_token.timeout_secs = -1).await;
+        app.create_user("reset_expired", PASSWORD).await;
+        let reset_path = reset_link(&mut app, "reset_expired").await;
+
+        let response = app
+            .post(
+                &reset_path,
+                &[("password1", NEW_PASSWORD), ("password2", NEW_PASSWORD)],
+            )
+            .await;
+
+        assert!(response.body.contains(INVALID_LINK));
+    }
+
+    #[cot::test]
+    async fn forgot_password_by_username_emails_the_account() {
+        let mut app = TestApp::new().await;
+        app.create_user("reset_by_username", PASSWORD).await;
+
+        app.post("/forgot-password", &[("identifier", "reset_by_username")])
+            .await;
+
+        let emails = sent_emails();
+        assert_eq!(emails.len(), 1);
+        assert!(emails[0].contains("reset_by_username@example.com"));
+        assert!(find_link(&emails[0], "/reset/").is_some());
+    }
+
+    #[cot::test]
+    async fn reset_emails_carry_the_site_name() {
+        let mut app =
+            TestApp::with_config(|config| config.site_name = "Rebranded Portal".to_string()).await;
+        app.create_user("reset_rebranded", PASSWORD).await;
+
+        app.post("/forgot-password", &[("identifier", "reset_rebranded")])
+            .await;
+
+        let emails = sent_emails();
+        assert!(emails[0].contains("Rebranded Portal password reset"));
+    }
+
+    #[cot::test]
+    async fn forgot_password_for_an_unknown_account_sends_nothing() {
+        let mut app = TestApp::new().await;
+
+        let response = app
+            .post("/forgot-password", &[("identifier", "nobody@example.com")])
+            .await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(sent_emails().is_empty());
+    }
+
+    #[cot::test]
+    async fn reset_emails_are_throttled_per_account() {
+        let mut app = TestApp::new().await;
+        app.create_user("reset_throttled", PASSWORD).await;
+        for _ in 0..RESET_EMAILS_PER_ACCOUNT {
+            app.post("/forgot-password", &[("identifier", "reset_throttled")])
+                .await;
+        }
+        assert_eq!(sent_emails().len(), RESET_EMAILS_PER_ACCOUNT as usize);
+
+        let response = app
+            .post("/forgot-password", &[("identifier", "reset_throttled")])
+            .await;
+
+        // nothing is sent, but the answer is the one for an unknown account
+        assert!(sent_emails().is_empty());
+        assert!(response.body.contains("has been sent to its email"));
+    }
+
+    #[cot::test]
+    async fn reset_requests_are_throttled_per_client() {
+        let mut app = TestApp::with_config(|config| config.behind_trusted_proxy = true).await;
+        app.create_user("reset_flooded", PASSWORD).await;
+        for _ in 0..RESET_REQUESTS_PER_CLIENT {
+            app.post_with_headers(
+                "/forgot-password",
+                &[("identifier", "nobody@example.com")],
+                from_ip("203.0.113.30"),
+            )
+            .await;
+        }
+
+        let response = app
+            .post_with_headers(
+                "/forgot-password",
+                &[("identifier", "reset_flooded")],
+                from_ip("203.0.113.30"),
+            )
+            .await;
+        assert!(response.body.contains(TOO_MANY_REQUESTS));
+        assert!(sent_emails().is_empty());
+
+        let response = app
+            .post_with_headers(
+                "/forgot-password",
+                &[("identifier", "reset_flooded")],
+                from_ip("203.0.113.31"),
+            )
+            .await;
+        assert!(!response.body.contains(TOO_MANY_REQUESTS));
+        assert_eq!(sent_emails().len(), 1);
+    }
+
+    #[cot::test]
+    async fn throttled_reset_requests_get_rate_limit_headers() {
+        let mut app = TestApp::with_config(|config| config.behind_trusted_proxy = true).await;
+        for _ in 0..RESET_REQUESTS_PER_CLIENT {
+            app.post_with_headers(
+                "/forgot-password",
+                &[("identifier", "nobody@example.com")],
+                from_ip("203.0.113.32"),
+            )
+            .await;
+        }
+        let mut headers = from_ip("203.0.113.32");
+        headers.insert(
+            cot::http::header::ACCEPT,
+            "application/json".parse().unwrap(),
+        );
+
+        let response = app
+            .post_with_headers(
+                "/forgot-password",
+                &[("identifier", "nobody@example.com")],
+                headers,
+            )
+            .await;
+
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=RESET_CLIENT_WINDOW.as_secs()).contains(&retry_after));
+        assert_eq!(
+            response.headers["x-ratelimit-limit"],
+            RESET_REQUESTS_PER_CLIENT.to_string()
+        );
+        assert_eq!(response.headers["x-ratelimit-remaining"], "0");
+    }
 }
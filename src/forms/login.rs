@@ -1,51 +1,146 @@
-use crate::auth::authenticate;
+use crate::auth::{LoginError, User, authenticate, current_user};
+use crate::client_ip::ClientIp;
+use crate::config::app_config;
+use crate::forms::fields::{Redacted, TrimmedString};
+use crate::forms::form_from_request;
+use crate::page::{PageContext, form_page};
+use crate::rate_limit;
+use crate::render::render_form;
 use cot::auth::Auth;
 use cot::common_types::Password;
+use cot::db::Database;
 use cot::form::{
     Form, FormContext, FormErrorTarget, FormField, FormFieldValidationError, FormResult,
 };
-use cot::request::Request;
-use cot::request::extractors::StaticFiles;
-use cot::response::{Response, ResponseExt};
+use cot::request::{Request, RequestExt};
+use cot::response::{IntoResponse, Redirect, Response};
 use cot::router::Urls;
-use cot::{Body, Method, StatusCode, Template, reverse_redirect};
+use cot::router::path::ReverseParamMap;
+use cot::session::Session;
+use cot::{Method, StatusCode};
+use std::fmt::{Debug, Formatter};
 
-#[derive(Debug, Form, Clone)]
+#[derive(Form, Clone)]
 pub(crate) struct LoginForm {
-    pub(crate) username: String,
+    pub(crate) username: TrimmedString,
     pub(crate) password: Password,
 }
 
-#[derive(Debug, Template)]
-#[template(path = "login.html")]
-pub(crate) struct LoginTemplate<'a> {
-    urls: &'a Urls,
-    form: <LoginForm as Form>::Context,
-    static_files: StaticFiles,
+form_page! {
+    #[template(path = "login.html")]
+    pub(crate) struct LoginTemplate for LoginForm {
+        /// Whether the login was refused for an unverified email, so the page
+        /// should offer a new verification link.
+        email_unverified: bool,
+    }
+}
+
+/// Redirects to the landing route configured for `user`'s role, falling back
+/// to the default landing route.
+pub(crate) fn landing_redirect(urls: &Urls, user: Option<&User>) -> cot::Result<Response> {
+    let config = app_config();
+    let route = user
+        .and_then(|user| config.role_landing_routes.get(user.role()))
+        .unwrap_or(&config.landing_route);
+    let url = urls
+        .router()
+        .reverse(urls.app_name(), route, &ReverseParamMap::new())?;
+    Redirect::new(url).into_response()
+}
+
+/// Where to send a user who just logged in: the change-password page if staff
+/// flagged their account, the landing route otherwise.
+pub(crate) async fn post_login_redirect(
+    urls: &Urls,
+    auth: &Auth,
+    db: &Database,
+) -> cot::Result<Response> {
+    let user = current_user(auth, db).await?;
+    if user.as_ref().is_some_and(User::must_change_password) {
+        return Ok(cot::reverse_redirect!(urls, "change_password")?);
+    }
+    landing_redirect(urls, user.as_ref())
+}
+
+/// Whether login failures should tell users which part of their credentials
+/// was wrong. See `AppConfig::verbose_auth_errors`.
+fn verbose_auth_errors(request: &Request) -> bool {
+    app_config().verbose_auth_errors && request.context().config().debug
 }
 
 pub(crate) async fn login(
-    urls: Urls,
+    PageContext {
+        urls,
+        static_files,
+        user,
+    }: PageContext,
     auth: Auth,
+    db: Database,
+    client_ip: ClientIp,
     mut request: Request,
-    static_files: StaticFiles,
 ) -> cot::Result<Response> {
+    if let Some(user) = user {
+        return landing_redirect(&urls, Some(&user));
+    }
+
+    // what JSON clients get when the form comes back with errors
+    let mut error_status = StatusCode::BAD_REQUEST;
+    // the lockout's limit and time left, if the account is locked
+    let mut throttled = None;
+    let mut email_unverified = false;
     let login_form_context = if request.method() == Method::GET {
         LoginForm::build_context(&mut request).await?
     } else if request.method() == Method::POST {
-        let login_form = LoginForm::from_request(&mut request).await?;
+        let login_form = form_from_request::<LoginForm>(&mut request).await?;
 
         match login_form {
             FormResult::Ok(login_form) => {
-                if authenticate(&auth, &login_form).await? {
-                    return Ok(reverse_redirect!(urls, "home")?);
+                let session = Session::from_request(&request);
+                match authenticate(
+                    &auth,
+                    &db,
+                    session,
+                    &login_form.username,
+                    login_form.password,
+                    client_ip,
+                )
+                .await
+                {
+                    Ok(()) => return post_login_redirect(&urls, &auth, &db).await,
+                    Err(LoginError::Other(err)) => return Err(err),
+                    Err(err) => {
+                        error_status = match err {
+                            LoginError::AccountLocked { limit, retry_after } => {
+                                throttled = Some((limit, retry_after));
+                                StatusCode::TOO_MANY_REQUESTS
+                            }
+                            LoginError::EmailUnverified => {
+                                email_unverified = true;
+                                StatusCode::FORBIDDEN
+                            }
+                            _ => StatusCode::UNAUTHORIZED,
+                        };
+                        let message = match err {
+                            LoginError::InvalidCredentials if verbose_auth_errors(&request) => {
+                                if User::exists_by_username(&db, &login_form.username).await? {
+                                    "Wrong password".to_string()
+                                } else {
+                                    "No such user".to_string()
+                                }
+                            }
+                            LoginError::AccountInactive if verbose_auth_errors(&request) => {
+                                "Account is inactive".to_string()
+                            }
+                            err => err.to_string(),
+                        };
+                        let mut ctx = LoginForm::build_context(&mut request).await?;
+                        ctx.add_error(
+                            FormErrorTarget::Form,
+                            FormFieldValidationError::from_string(message),
+                        );
+                        ctx
+                    }
                 }
-                let mut ctx = LoginForm::build_context(&mut request).await?;
-                ctx.add_error(
-                    FormErrorTarget::Form,
-                    FormFieldValidationError::from_static("Invalid username or password"),
-                );
-                ctx
             }
             FormResult::ValidationError(context) => context,
         }
@@ -57,11 +152,365 @@ pub(crate) async fn login(
         urls: &urls,
         form: login_form_context,
         static_files,
+        email_unverified,
     };
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::fixed(template.render()?))
-        .unwrap();
+    let mut response = render_form(request.headers(), &template, &template.form, error_status)?;
+    if let Some((limit, retry_after)) = throttled {
+        rate_limit::add_throttle_headers(response.headers_mut(), limit, retry_after);
+    }
     Ok(response)
 }
+
+impl Debug for LoginForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoginForm")
+            .field("username", &self.username)
+            .field("password", &Redacted)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{AppConfig, app_config, set_test_app_config};
+    use crate::test_utils::{PASSWORD, TestApp, TestResponse};
+    use cot::StatusCode;
+    use cot::db::Model;
+    use cot::http::{HeaderMap, header};
+
+    async fn fail_logins(app: &mut TestApp, username: &str, times: usize) {
+        for _ in 0..times {
+            let response = app.login(username, "wrong password").await;
+            assert!(response.body.contains("Invalid username or password"));
+        }
+    }
+
+    /// The session cookie `response` sets, as `name=value`.
+    fn session_cookie(response: &TestResponse) -> Option<&str> {
+        response
+            .headers
+            .get(header::SET_COOKIE)
+            .and_then(|cookie| cookie.to_str().ok())
+            .and_then(|cookie| cookie.split(';').next())
+    }
+
+    #[cot::test]
+    async fn a_successful_login_redirects_with_see_other() {
+        let mut app = TestApp::new().await;
+        app.create_user("see_other", PASSWORD).await;
+
+        let response = app.login("see_other", PASSWORD).await;
+
+        // 303 rather than 302, so clients follow it with a GET instead of
+        // posting the credentials again
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn logging_out_redirects_with_see_other() {
+        let mut app = TestApp::new().await;
+        app.create_user("see_other_out", PASSWORD).await;
+        app.login("see_other_out", PASSWORD).await;
+
+        let response = app.post("/logout", &[]).await;
+
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+    }
+
+    #[cot::test]
+    async fn logging_in_replaces_the_session_cookie() {
+        let mut app = TestApp::with_config(|config| config.sms_reset.enabled = true).await;
+        let mut user = app.create_user("fixated_session", PASSWORD).await;
+        user.set_phone(Some("+15550000006".to_owned()));
+        user.save(app.db()).await.unwrap();
+        // starting an SMS reset is one way to get a session before logging in
+        let before = app
+            .post("/forgot-password/sms", &[("identifier", "fixated_session")])
+            .await;
+        let before = session_cookie(&before).expect("the reset request starts a session");
+
+        let after = app.login("fixated_session", PASSWORD).await;
+
+        let after = session_cookie(&after).expect("logging in sets the session cookie");
+        assert_ne!(before, after);
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn a_logged_in_user_is_sent_past_the_login_form() {
+        let mut app = TestApp::new().await;
+        app.create_user("already_in", PASSWORD).await;
+        app.login("already_in", PASSWORD).await;
+
+        let response = app.get("/login").await;
+
+        assert!(response.status.is_redirection());
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn a_logged_in_user_is_sent_to_the_configured_landing_route() {
+        let mut app =
+            TestApp::with_config(|config| config.landing_route = "profile".to_string()).await;
+        app.create_user("already_in_elsewhere", PASSWORD).await;
+        app.login("already_in_elsewhere", PASSWORD).await;
+
+        let response = app.get("/login").await;
+
+        assert_eq!(response.location(), Some("/profile"));
+    }
+
+    #[cot::test]
+    async fn the_login_page_carries_the_site_name() {
+        let mut app =
+            TestApp::with_config(|config| config.site_name = "Rebranded Portal".to_string()).await;
+
+        let response = app.get("/login").await;
+
+        assert!(
+            response
+                .body
+                .contains("<title>Login Page | Rebranded Portal</title>")
+        );
+    }
+
+    /// The error each kind of failed login shows: an unknown user, a wrong
+    /// password and an inactive account, in that order.
+    async fn login_errors(app: &mut TestApp, prefix: &str) -> [String; 3] {
+        let known = format!("{prefix}_known");
+        let inactive = format!("{prefix}_inactive");
+        app.create_user(&known, PASSWORD).await;
+        let mut user = app.create_user(&inactive, PASSWORD).await;
+        user.deactivate().save(app.db()).await.unwrap();
+
+        [
+            app.login(&format!("{prefix}_unknown"), PASSWORD).await.body,
+            app.login(&known, "wrong password").await.body,
+            app.login(&inactive, PASSWORD).await.body,
+        ]
+    }
+
+    #[cot::test]
+    async fn login_errors_are_generic_by_default() {
+        let mut app = TestApp::new().await;
+
+        for body in login_errors(&mut app, "generic_errors").await {
+            assert!(body.contains("Invalid username or password"));
+            assert!(!body.contains("No such user"));
+            assert!(!body.contains("Wrong password"));
+            assert!(!body.contains("Account is inactive"));
+        }
+    }
+
+    #[cot::test]
+    async fn verbose_login_errors_say_what_was_wrong() {
+        let mut app = TestApp::with_config(|config| config.verbose_auth_errors = true).await;
+
+        let [unknown, wrong_password, inactive] = login_errors(&mut app, "verbose_errors").await;
+
+        assert!(unknown.contains("No such user"));
+        assert!(wrong_password.contains("Wrong password"));
+        assert!(inactive.contains("Account is inactive"));
+        assert!(!unknown.contains("Invalid username or password"));
+    }
+
+    #[cot::test]
+    async fn verbose_login_errors_stay_generic_outside_debug_mode() {
+        let mut app = TestApp::with_project_config(|config| config.debug = false).await;
+        set_test_app_config(AppConfig {
+            verbose_auth_errors: true,
+            ..app_config().clone()
+        });
+
+        for body in login_errors(&mut app, "release_errors").await {
+            assert!(body.contains("Invalid username or password"));
+        }
+    }
+
+    #[cot::test]
+    async fn an_overlong_username_is_just_invalid_credentials() {
+        let mut app = TestApp::new().await;
+
+        let response = app.login(&"a".repeat(300), PASSWORD).await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(response.body.contains("Invalid username or password"));
+    }
+
+    #[cot::test]
+    async fn each_role_lands_on_its_configured_route() {
+        let mut app = TestApp::with_config(|config| {
+            config
+                .role_landing_routes
+                .insert("staff".to_string(), "create_invite".to_string());
+        })
+        .await;
+        let mut staff = app.create_user("lands_as_staff", PASSWORD).await;
+        staff
+            .set_staff_unaudited(true)
+            .save(app.db())
+            .await
+            .unwrap();
+        app.create_user("lands_as_user", PASSWORD).await;
+
+        let response = app.login("lands_as_staff", PASSWORD).await;
+        assert_eq!(response.location(), Some("/admin/invites"));
+        app.post("/logout", &[]).await;
+
+        let response = app.login("lands_as_user", PASSWORD).await;
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    async fn fail_login_accepting(app: &mut TestApp, accept: &'static str) -> TestResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, accept.parse().unwrap());
+        app.post_with_headers(
+            "/login",
+            &[("username", "negotiated"), ("password", "wrong password")],
+            headers,
+        )
+        .await
+    }
+
+    #[cot::test]
+    async fn json_clients_get_login_errors_as_json() {
+        let mut app = TestApp::new().await;
+
+        let response = fail_login_accepting(&mut app, "application/json").await;
+
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            response.body,
+            r#"{"errors":[{"field":null,"message":"Invalid username or password"}]}"#
+        );
+    }
+
+    #[cot::test]
+    async fn browsers_get_the_login_page_with_its_errors() {
+        let mut app = TestApp::new().await;
+
+        let response = fail_login_accepting(&mut app, "text/html,application/json;q=0.9").await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            response.headers[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert!(response.body.contains("Invalid username or password"));
+    }
+
+    /// Saves `username`, with a verified email address if `verified` is set.
+    async fn create_user_verified(app: &TestApp, username: &str, verified: bool) {
+        let mut user = app.create_user(username, PASSWORD).await;
+        if verified {
+            user.mark_email_verified().save(app.db()).await.unwrap();
+        }
+    }
+
+    #[cot::test]
+    async fn unverified_users_are_asked_to_verify_when_required() {
+        let mut app =
+            TestApp::with_config(|config| config.require_verified_email_for_login = true).await;
+        create_user_verified(&app, "required_verified", true).await;
+        create_user_verified(&app, "required_unverified", false).await;
+
+        let refused = app.login("required_unverified", PASSWORD).await;
+        let verified = app.login("required_verified", PASSWORD).await;
+
+        assert!(
+            refused
+                .body
+                .contains("Please verify your email address before logging in.")
+        );
+        assert!(refused.body.contains("href=\"/verify-email\""));
+        assert_eq!(verified.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn unverified_users_log_in_when_verification_is_optional() {
+        let mut app = TestApp::new().await;
+        create_user_verified(&app, "optional_unverified", false).await;
+
+        let response = app.login("optional_unverified", PASSWORD).await;
+
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn a_locked_account_gets_rate_limit_headers() {
+        let mut app = TestApp::new().await;
+        app.create_user("lockout_headers", PASSWORD).await;
+        fail_logins(&mut app, "lockout_headers", 5).await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "application/json".parse().unwrap());
+
+        let response = app
+            .post_with_headers(
+                "/login",
+                &[("username", "lockout_headers"), ("password", PASSWORD)],
+                headers,
+            )
+            .await;
+
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=15 * 60).contains(&retry_after));
+        assert_eq!(response.headers["x-ratelimit-limit"], "5");
+        assert_eq!(response.headers["x-ratelimit-remaining"], "0");
+        assert_eq!(
+            response.headers["x-ratelimit-reset"],
+            response.headers["retry-after"]
+        );
+    }
+
+    #[cot::test]
+    async fn a_locked_account_is_told_when_to_try_again() {
+        let mut app = TestApp::new().await;
+        app.create_user("locked_out", PASSWORD).await;
+        fail_logins(&mut app, "locked_out", 5).await;
+
+        // the right password doesn't get in while the account is locked
+        let response = app.login("locked_out", PASSWORD).await;
+
+        assert!(
+            response
+                .body
+                .contains("Too many failed login attempts. Try again in 15 minute(s).")
+        );
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn locking_one_account_leaves_others_alone() {
+        let mut app = TestApp::new().await;
+        app.create_user("locked_neighbour", PASSWORD).await;
+        app.create_user("free_neighbour", PASSWORD).await;
+        fail_logins(&mut app, "locked_neighbour", 5).await;
+
+        let response = app.login("free_neighbour", PASSWORD).await;
+
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn a_successful_login_clears_earlier_failures() {
+        let mut app = TestApp::new().await;
+        app.create_user("cleared_failures", PASSWORD).await;
+        fail_logins(&mut app, "cleared_failures", 4).await;
+        app.login("cleared_failures", PASSWORD).await;
+        app.post("/logout", &[]).await;
+
+        // four more failures would have locked the account without the reset
+        fail_logins(&mut app, "cleared_failures", 4).await;
+        let response = app.login("cleared_failures", PASSWORD).await;
+
+        assert_eq!(response.location(), Some("/home"));
+    }
+}
@@ -0,0 +1,96 @@
+use crate::config::app_config;
+use crate::sessions;
+use crate::utils::is_safe_redirect;
+use cot::Method;
+use cot::auth::Auth;
+use cot::db::Database;
+use cot::error::MethodNotAllowed;
+use cot::request::extractors::UrlQuery;
+use cot::response::{IntoResponse, Redirect, Response};
+use cot::router::Urls;
+use cot::router::path::ReverseParamMap;
+use cot::session::Session;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LogoutQuery {
+    next: Option<String>,
+}
+
+/// Logs the user out and sends them to `?next=` if it's a path on this site,
+/// or to the configured logout route otherwise.
+///
+/// Only a POST logs out. A GET can come from any page that links to or
+/// embeds `/logout`, and would log users out without them asking.
+pub(crate) async fn logout(
+    method: Method,
+    urls: Urls,
+    auth: Auth,
+    db: Database,
+    session: Session,
+    UrlQuery(query): UrlQuery<LogoutQuery>,
+) -> cot::Result<Response> {
+    if method != Method::POST {
+        return Err(MethodNotAllowed::new(method).into());
+    }
+
+    sessions::forget(&session, &db).await?;
+    auth.logout().await?;
+
+    let url = match query.next {
+        Some(next) if is_safe_redirect(&next) => next,
+        _ => urls.router().reverse(
+            urls.app_name(),
+            &app_config().logout_route,
+            &ReverseParamMap::new(),
+        )?,
+    };
+    Redirect::new(url).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::StatusCode;
+
+    #[cot::test]
+    async fn a_get_does_not_log_out() {
+        let mut app = TestApp::new().await;
+        app.create_user("logout_get", PASSWORD).await;
+        app.login("logout_get", PASSWORD).await;
+
+        let response = app.get("/logout").await;
+
+        assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn logging_out_goes_to_a_next_path_on_this_site() {
+        let mut app = TestApp::new().await;
+        app.create_user("logout_next", PASSWORD).await;
+        app.login("logout_next", PASSWORD).await;
+
+        let response = app.post("/logout?next=/profile", &[]).await;
+
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        assert_eq!(response.location(), Some("/profile"));
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn logging_out_ignores_a_next_url_off_the_site() {
+        let mut app = TestApp::new().await;
+
+        for next in [
+            "https://evil.example/",
+            "//evil.example/",
+            "/%5Cevil.example/",
+            "evil",
+        ] {
+            let response = app.post(&format!("/logout?next={next}"), &[]).await;
+
+            assert_eq!(response.location(), Some("/login"), "{next}");
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Passwordless login: users ask for a link by email and following it logs
+//! them in.
+//!
+//! Links are signed like password reset links, but with their own purpose and
+//! bound to the user's last login time so each one works only once. Opening a
+//! link only asks the user to confirm; the login happens on the form's POST,
+//! so mail scanners and link previews that fetch it don't use it up.
+
+use crate::auth::User;
+use crate::client_ip::ClientIp;
+use crate::config::app_config;
+use crate::forms::fields::TrimmedString;
+use crate::forms::forgot_password::{ResetLink, ResetToken, verification_secrets};
+use crate::forms::form_from_request;
+use crate::forms::login::{landing_redirect, post_login_redirect};
+use crate::page::{PageContext, form_page};
+use crate::render::{render, render_form};
+use crate::{https, mail_queue, sessions, telemetry};
+use cot::auth::Auth;
+use cot::common_types::Email;
+use cot::db::Database;
+use cot::email::{Email as EmailService, EmailMessage};
+use cot::error::NotFound;
+use cot::form::{Form, FormContext, FormErrorTarget, FormFieldValidationError, FormResult};
+use cot::request::extractors::StaticFiles;
+use cot::request::{Request, RequestExt};
+use cot::response::Response;
+use cot::router::Urls;
+use cot::session::Session;
+use cot::{Method, StatusCode, Template};
+use metrics::counter;
+use tracing::{info, warn};
+
+const INVALID_LINK: &str = "This login link is invalid or has expired.";
+
+#[derive(Debug, Form)]
+pub(crate) struct MagicLinkForm {
+    /// The account's email address or username.
+    identifier: TrimmedString,
+}
+
+form_page! {
+    #[template(path = "magic_link.html")]
+    pub(crate) struct MagicLinkTemplate for MagicLinkForm {
+        email_sent: bool,
+    }
+}
+
+#[derive(Debug, Template)]
+#[template(path = "magic_login_confirm.html")]
+pub(crate) struct MagicLoginConfirmTemplate<'a> {
+    urls: &'a Urls,
+    static_files: StaticFiles,
+    username: &'a str,
+}
+
+fn ensure_enabled() -> cot::Result<()> {
+    if app_config().magic_link.enabled {
+        Ok(())
+    } else {
+        Err(NotFound::new().into())
+    }
+}
+
+async fn send_magic_link_email(
+    email_sender: EmailService,
+    to: &Email,
+    login_url: String,
+) -> cot::Result<()> {
+    let message = EmailMessage::builder()
+        .from(Email::try_from("no-reply@example.com").unwrap())
+        .to(vec![to.clone()])
+        .subject(format!("{} login link", app_config().site_name))
+        .body(format!(
+            r#"
+                    click link to log in:
+
+                    {login_url}
+
+                  "#
+        ))
+        .build()?;
+
+    mail_queue::send(email_sender, message).await
+}
+
+/// Emails a login link to the account matching the submitted email address
+/// or username.
+pub(crate) async fn magic_link(
+    PageContext {
+        urls,
+        static_files,
+        user,
+    }: PageContext,
+    db: Database,
+    email: EmailService,
+    mut request: Request,
+) -> cot::Result<Response> {
+    ensure_enabled()?;
+    if let Some(user) = user {
+        return landing_redirect(&urls, Some(&user));
+    }
+
+    let mut email_sent = false;
+    let form_context = if request.method() == Method::GET {
+        MagicLinkForm::build_context(&mut request).await?
+    } else if request.method() == Method::POST {
+        match form_from_request::<MagicLinkForm>(&mut request).await? {
+            FormResult::Ok(form) => {
+                let user = User::get_by_email_or_username(&db, &form.identifier).await?;
+                if let Some(user) = user.filter(User::is_active) {
+                    let secret = request.context().config().secret_key.as_bytes();
+                    let (Some(uid), Some(token)) = (
+                        user.id(),
+                        ResetToken::magic_login_from_config().make_token(&user, secret),
+                    ) else {
+                        return Err(cot::Error::internal(
+                            "user loaded from the database has no id",
+                        ));
+                    };
+                    let login_url = ResetLink::new(token, uid)
+                        .to_magic_login_url(&urls, &https::base_url(request.headers()))?;
+                    send_magic_link_email(email, user.email(), login_url).await?;
+                }
+                // answer the same way whether or not an account matched, so
+                // the form can't be used to probe for accounts
+                email_sent = true;
+
+                form.to_context().await
+            }
+            FormResult::ValidationError(context) => context,
+        }
+    } else {
+        panic!("unexpected request method")
+    };
+
+    let template = MagicLinkTemplate {
+        urls: &urls,
+        static_files,
+        form: form_context,
+        email_sent,
+    };
+    render_form(
+        request.headers(),
+        &template,
+        &template.form,
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+/// Asks the user a magic link was sent to to confirm, and logs them in when
+/// they do. Shows the request form again with an error if the link can't be
+/// used.
+pub(crate) async fn magic_login(
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
+    auth: Auth,
+    db: Database,
+    session: Session,
+    client_ip: ClientIp,
+    mut request: Request,
+) -> cot::Result<Response> {
+    ensure_enabled()?;
+
+    let user = match ResetLink::from_path_params(request.path_params()) {
+        Ok(link) => User::get_by_id(&db, link.uid())
+            .await?
+            .filter(User::is_active)
+            .filter(|user| {
+                ResetToken::magic_login_from_config()
+                    .check_token(
+                        user,
+                        link.token(),
+                        verification_secrets(request.context().config()),
+                        app_config().magic_link.timeout_secs,
+                    )
+                    .is_ok()
+            }),
+        Err(_) => None,
+    };
+
+    let Some(user) = user else {
+        warn!(event = "magic_login", outcome = "failure", %client_ip, "rejected a magic login link");
+        let mut form_context = MagicLinkForm::build_context(&mut request).await?;
+        form_context.add_error(
+            FormErrorTarget::Form,
+            FormFieldValidationError::from_static(INVALID_LINK),
+        );
+        let template = MagicLinkTemplate {
+            urls: &urls,
+            static_files,
+            form: form_context,
+            email_sent: false,
+        };
+        return render_form(
+            request.headers(),
+            &template,
+            &template.form,
+            StatusCode::BAD_REQUEST,
+        );
+    };
+
+    if request.method() != Method::POST {
+        return render(&MagicLoginConfirmTemplate {
+            urls: &urls,
+            static_files,
+            username: user.username(),
+        });
+    }
+
+    let id = user.id().expect("user loaded from the database has an id");
+    let username = user.username().to_owned();
+    auth.login(Box::new(user)).await?;
+    // also invalidates the link, which is bound to the last login time
+    User::touch_last_login(&db, id).await?;
+    User::reset_failed_attempts(&db, &username).await?;
+    sessions::register(&session, &db, id).await?;
+    info!(event = "magic_login", outcome = "success", username, %client_ip, "user logged in with a magic link");
+    counter!(telemetry::LOGINS, "outcome" => "magic_link").increment(1);
+
+    post_login_redirect(&urls, &auth, &db).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp, find_link, sent_emails};
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+    use chrono::Utc;
+
+    const SECRET: &[u8] = b"test-secret-key-that-is-at-least-32-bytes";
+
+    async fn magic_link_app() -> TestApp {
+        TestApp::with_config(|config| config.magic_link.enabled = true).await
+    }
+
+    async fn request_link(app: &mut TestApp, identifier: &str) -> String {
+        app.post("/magic-link", &[("identifier", identifier)]).await;
+        let emails = sent_emails();
+        assert_eq!(emails.len(), 1);
+        find_link(&emails[0], "/magic/").expect("the email has a login link")
+    }
+
+    #[cot::test]
+    async fn opening_the_link_asks_before_logging_in() {
+        let mut app = magic_link_app().await;
+        app.create_user("magic_user", PASSWORD).await;
+        let link = request_link(&mut app, "magic_user").await;
+
+        let response = app.get(&link).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(response.body.contains("Continue as magic_user?"));
+        // fetching the link again, as a mail scanner might, still works
+        let response = app.get(&link).await;
+        assert!(response.body.contains("Continue as magic_user?"));
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+
+        let response = app.post(&link, &[]).await;
+        assert_eq!(response.location(), Some("/home"));
+        let response = app.get("/home").await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(response.body.contains("magic_user"));
+    }
+
+    #[cot::test]
+    async fn a_used_link_is_rejected() {
+        let mut app = magic_link_app().await;
+        app.create_user("magic_reuse", PASSWORD).await;
+        let link = request_link(&mut app, "magic_reuse").await;
+        app.post(&link, &[]).await;
+        app.post("/logout", &[]).await;
+
+        let response = app.post(&link, &[]).await;
+
+        assert!(response.body.contains(INVALID_LINK));
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn an_expired_link_is_rejected() {
+        let mut app = magic_link_app().await;
+        let user = app.create_user("magic_expired", PASSWORD).await;
+        let issued_at = Utc::now().timestamp() - app_config().magic_link.timeout_secs - 120;
+        let token = ResetToken::magic_login_from_config()
+            .make_token_with_timestamp(&user, SECRET, issued_at)
+            .expect("the user has an id");
+        let uid = URL_SAFE_NO_PAD.encode(user.id().expect("saved").to_string());
+        let link = format!("/magic/{token}/{uid}");
+
+        let response = app.get(&link).await;
+        assert!(response.body.contains(INVALID_LINK));
+
+        let response = app.post(&link, &[]).await;
+        assert!(response.body.contains(INVALID_LINK));
+        assert_ne!(app.get("/home").await.status, StatusCode::OK);
+    }
+}
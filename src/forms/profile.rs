@@ -0,0 +1,257 @@
+use crate::auth::current_user;
+use crate::forms::fields::TrimmedString;
+use crate::forms::form_from_request;
+use crate::page::form_page;
+use crate::profile_attributes::{self, Attribute, Profile};
+use crate::render::render_form;
+use crate::{i18n, sms};
+use cot::auth::Auth;
+use cot::db::{Database, Model};
+use cot::form::{
+    Form, FormContext, FormErrorTarget, FormField, FormFieldValidationError, FormResult,
+};
+use cot::request::Request;
+use cot::request::extractors::StaticFiles;
+use cot::response::Response;
+use cot::router::Urls;
+use cot::{Method, StatusCode};
+
+/// The value to store for each profile detail, `None` for the ones to clear.
+type AttributeValues = Vec<(Attribute, Option<String>)>;
+
+#[derive(Debug, Form)]
+pub(crate) struct ProfileForm {
+    locale: TrimmedString,
+    /// Where SMS password reset codes go; left empty to remove it.
+    phone: Option<TrimmedString>,
+    // the profile details; each is cleared if left empty
+    timezone: Option<TrimmedString>,
+    bio: Option<TrimmedString>,
+    avatar_url: Option<TrimmedString>,
+}
+
+impl ProfileForm {
+    fn validate_locale(&self) -> Result<&str, FormFieldValidationError> {
+        if !i18n::is_supported(&self.locale) {
+            return Err(FormFieldValidationError::from_static(
+                "unsupported language.",
+            ));
+        }
+        Ok(&self.locale)
+    }
+
+    /// The phone number in E.164 form, or `None` if the field was left empty.
+    fn validate_phone(&self) -> Result<Option<String>, FormFieldValidationError> {
+        let Some(phone) = &self.phone else {
+            return Ok(None);
+        };
+        sms::normalize_phone(phone).map(Some).ok_or_else(|| {
+            FormFieldValidationError::from_static(
+                "enter the number in international form, starting with + and the country code.",
+            )
+        })
+    }
+
+    fn attribute(&self, attribute: Attribute) -> Option<&TrimmedString> {
+        match attribute {
+            Attribute::Timezone => self.timezone.as_ref(),
+            Attribute::Bio => self.bio.as_ref(),
+            Attribute::AvatarUrl => self.avatar_url.as_ref(),
+        }
+    }
+
+    fn validate_attributes(
+        &self,
+    ) -> Result<AttributeValues, (Attribute, FormFieldValidationError)> {
+        Attribute::ALL
+            .into_iter()
+            .map(|attribute| {
+                let value = self
+                    .attribute(attribute)
+                    .map(|value| attribute.validate(value))
+                    .transpose()
+                    .map_err(|err| (attribute, err))?;
+                Ok((attribute, value))
+            })
+            .collect()
+    }
+}
+
+form_page! {
+    #[template(path = "profile.html")]
+    pub(crate) struct ProfileTemplate for ProfileForm {
+        /// The phone number on the account, to prefill the field with.
+        phone: Option<String>,
+        /// The profile details on the account, to prefill their fields with.
+        profile: Profile,
+        /// The locale the page is rendered in.
+        locale: &'static str,
+        locales: &'static [&'static str],
+    }
+}
+
+pub(crate) async fn profile(
+    urls: Urls,
+    auth: Auth,
+    mut request: Request,
+    db: Database,
+    static_files: StaticFiles,
+) -> cot::Result<Response> {
+    let Some(mut user) = current_user(&auth, &db).await? else {
+        return Ok(cot::reverse_redirect!(urls, "login")?);
+    };
+    let user_id = user.id().expect("user loaded from the database has an id");
+
+    let profile_context = if request.method() == Method::GET {
+        ProfileForm::build_context(&mut request).await?
+    } else if request.method() == Method::POST {
+        match form_from_request::<ProfileForm>(&mut request).await? {
+            FormResult::Ok(profile_form) => {
+                let validated =
+                    profile_form
+                        .validate_locale()
+                        .map_err(|err| (FormErrorTarget::Form, err))
+                        .and_then(|locale| {
+                            let phone = profile_form
+                                .validate_phone()
+                                .map_err(|err| (FormErrorTarget::Field("phone"), err))?;
+                            let attributes = profile_form.validate_attributes().map_err(
+                                |(attribute, err)| (FormErrorTarget::Field(attribute.key()), err),
+                            )?;
+                            Ok((locale, phone, attributes))
+                        });
+                match validated {
+                    Ok((locale, phone, attributes)) => {
+                        user.set_locale(Some(locale.to_owned()))
+                            .set_phone(phone)
+                            .save(&db)
+                            .await?;
+                        for (attribute, value) in attributes {
+                            profile_attributes::set(&db, user_id, attribute, value).await?;
+                        }
+                        return Ok(cot::reverse_redirect!(urls, "profile")?);
+                    }
+                    Err((target, err)) => {
+                        let mut ctx = profile_form.to_context().await;
+                        ctx.add_error(target, err);
+                        ctx
+                    }
+                }
+            }
+            FormResult::ValidationError(context) => context,
+        }
+    } else {
+        panic!("unexpected request method")
+    };
+
+    let profile_template = ProfileTemplate {
+        urls: &urls,
+        static_files,
+        form: profile_context,
+        phone: user.phone().map(str::to_owned),
+        profile: Profile::load(&db, user_id).await?,
+        locale: i18n::resolve(user.locale(), request.headers()),
+        locales: i18n::SUPPORTED_LOCALES,
+    };
+    render_form(
+        request.headers(),
+        &profile_template,
+        &profile_template.form,
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::User;
+    use crate::profile_attributes::Profile;
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::http::{HeaderMap, header};
+    use cot::{Body, Method};
+
+    async fn home_in(app: &mut TestApp, accept_language: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_LANGUAGE, accept_language.parse().unwrap());
+        app.send(Method::GET, "/home", headers, Body::empty())
+            .await
+            .body
+    }
+
+    #[cot::test]
+    async fn a_stored_locale_wins_over_accept_language() {
+        let mut app = TestApp::new().await;
+        app.create_user("prefers_french", PASSWORD).await;
+        app.login("prefers_french", PASSWORD).await;
+        assert!(home_in(&mut app, "fr").await.contains("Accueil"));
+
+        app.post("/profile", &[("locale", "fr")]).await;
+
+        let body = home_in(&mut app, "en-GB, en;q=0.9").await;
+        assert!(body.contains("<html lang=\"fr\">"));
+        assert!(body.contains("Bienvenue sur la page"));
+        assert!(!body.contains("This is home!"));
+    }
+
+    #[cot::test]
+    async fn an_unsupported_locale_is_not_stored() {
+        let mut app = TestApp::new().await;
+        app.create_user("prefers_klingon", PASSWORD).await;
+        app.login("prefers_klingon", PASSWORD).await;
+
+        let response = app.post("/profile", &[("locale", "tlh")]).await;
+
+        assert!(response.body.contains("unsupported language."));
+        let user = User::get_by_username(app.db(), "prefers_klingon")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.locale(), None);
+    }
+
+    #[cot::test]
+    async fn profile_details_are_edited_from_the_profile_page() {
+        let mut app = TestApp::new().await;
+        let user_id = app.create_user("has_details", PASSWORD).await.id().unwrap();
+        app.login("has_details", PASSWORD).await;
+
+        app.post(
+            "/profile",
+            &[
+                ("locale", "en"),
+                ("timezone", "europe/paris"),
+                ("bio", "Likes <b>tea</b>"),
+            ],
+        )
+        .await;
+
+        let profile = Profile::load(app.db(), user_id).await.unwrap();
+        assert_eq!(profile.timezone(), Some(chrono_tz::Europe::Paris));
+        assert_eq!(profile.bio(), Some("Likes <b>tea</b>"));
+        let home = app.get("/home").await.body;
+        assert!(home.contains("Likes &#60;b&#62;tea&#60;/b&#62;"), "{home}");
+        assert!(
+            home.contains("CET</dd>") || home.contains("CEST</dd>"),
+            "{home}"
+        );
+
+        let response = app
+            .post(
+                "/profile",
+                &[("locale", "en"), ("timezone", "Mars/Olympus_Mons")],
+            )
+            .await;
+
+        assert!(response.body.contains("enter a time zone name"));
+        assert!(
+            Profile::load(app.db(), user_id)
+                .await
+                .unwrap()
+                .timezone()
+                .is_some()
+        );
+        app.post("/profile", &[("locale", "en")]).await;
+        let profile = Profile::load(app.db(), user_id).await.unwrap();
+        assert_eq!(profile.timezone(), None);
+        assert_eq!(profile.bio(), None);
+    }
+}
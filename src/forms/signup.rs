@@ -1,84 +1,184 @@
-use crate::auth::User;
-use cot::common_types::{Email, Password};
-use cot::db::{Auto, Database, LimitedString, Model};
+use crate::auth::{User, normalize_username, validate_username};
+use crate::breach::validate_not_breached;
+use crate::config::app_config;
+use crate::forms::fields::{PersonName, Redacted, TrimmedEmail, TrimmedString};
+use crate::forms::form_from_request;
+use crate::forms::login::landing_redirect;
+use crate::forms::verify_email::{remember_pending, send_verification_email};
+use crate::invites;
+use crate::page::{PageContext, form_page};
+use crate::password_strength::validate_entropy;
+use crate::render::render_form;
+use crate::telemetry;
+use cot::common_types::Password;
+use cot::db::{Database, Model};
+use cot::email::Email as EmailService;
 use cot::form::{Form, FormContext, FormErrorTarget, FormFieldValidationError, FormResult};
-use cot::request::Request;
-use cot::request::extractors::StaticFiles;
-use cot::response::{Response, ResponseExt};
-use cot::router::Urls;
-use cot::{Body, Method, StatusCode, Template};
+use cot::request::extractors::UrlQuery;
+use cot::request::{Request, RequestExt};
+use cot::response::Response;
+use cot::session::Session;
+use cot::{Method, StatusCode};
+use metrics::counter;
+use serde::Deserialize;
+use std::fmt::{Debug, Formatter};
+use tracing::info;
 
-#[derive(Debug, Form)]
+#[derive(Form)]
 pub(crate) struct SignupForm {
-    fullname: String,
-    email: Email,
-    username: String,
+    fullname: PersonName,
+    email: TrimmedEmail,
+    username: TrimmedString,
     password1: Password,
     password2: Password,
+    /// A honeypot hidden from people by the template; only bots fill it in.
+    website: Option<String>,
 }
 
-#[derive(Debug, Template)]
-#[template(path = "signup.html")]
-pub(crate) struct SignupTemplate<'a> {
-    urls: &'a Urls,
-    static_files: StaticFiles,
-    form: <SignupForm as Form>::Context,
+/// The signup page's query string. Invite links carry the invite token in it.
+#[derive(Debug, Deserialize)]
+pub(crate) struct SignupQuery {
+    invite: Option<String>,
+}
+
+form_page! {
+    #[template(path = "signup.html")]
+    pub(crate) struct SignupTemplate for SignupForm {
+    }
 }
 
 impl SignupForm {
-    fn validate_password(&self) -> Result<&Self, FormFieldValidationError> {
+    fn is_bot(&self) -> bool {
+        self.website
+            .as_deref()
+            .is_some_and(|website| !website.is_empty())
+    }
+
+    /// Checks what the field types can't: the username rules and that the
+    /// password is confirmed and strong enough.
+    async fn validate(
+        &self,
+    ) -> Result<&Self, (FormErrorTarget<'static>, FormFieldValidationError)> {
+        validate_username(&normalize_username(&self.username))
+            .map_err(|err| (FormErrorTarget::Field("username"), err))?;
         if self.password1.as_str() != self.password2.as_str() {
-            return Err(FormFieldValidationError::from_static(
-                "passwords do not match.",
+            return Err((
+                FormErrorTarget::Field("password2"),
+                FormFieldValidationError::from_static("passwords do not match."),
             ));
         }
+        validate_entropy(&self.password1)
+            .map_err(|err| (FormErrorTarget::Field("password1"), err))?;
+        validate_not_breached(&self.password1)
+            .await
+            .map_err(|err| (FormErrorTarget::Form, err))?;
         Ok(self)
     }
 }
 
 pub(crate) async fn signup(
-    urls: Urls,
-    mut request: Request,
+    PageContext {
+        urls,
+        static_files,
+        user,
+    }: PageContext,
     db: Database,
-    static_files: StaticFiles,
+    email_sender: EmailService,
+    session: Session,
+    UrlQuery(query): UrlQuery<SignupQuery>,
+    mut request: Request,
 ) -> cot::Result<Response> {
+    if let Some(user) = user {
+        return landing_redirect(&urls, Some(&user));
+    }
+
     let signup_context = if request.method() == Method::GET {
         SignupForm::build_context(&mut request).await?
     } else if request.method() == Method::POST {
-        let signup_form = SignupForm::from_request(&mut request).await?;
-        let val = match signup_form {
-            FormResult::Ok(signup_form) => {
-                let val = match signup_form.validate_password() {
-                    Err(err) => {
-                        let mut ctx = signup_form.to_context().await;
-                        ctx.add_error(FormErrorTarget::Form, err);
-                        ctx
-                    }
+        let signup_form = form_from_request::<SignupForm>(&mut request).await?;
+        match signup_form {
+            FormResult::Ok(signup_form) if signup_form.is_bot() => {
+                // respond as if the signup went through so bots get no signal
+                info!("dropped a signup with the honeypot field filled in");
+                signup_form.to_context().await
+            }
+            FormResult::Ok(signup_form) => match signup_form.validate().await {
+                Err((target, err)) => {
+                    let mut ctx = signup_form.to_context().await;
+                    ctx.add_error(target, err);
+                    ctx
+                }
 
-                    Ok(form) => {
-                        let username = LimitedString::new(form.username.clone())
-                            .expect("username is too long");
-                        let name =
-                            LimitedString::new(form.fullname.clone()).expect("name is too long");
-
-                        User::new(
-                            Auto::auto(),
-                            username,
-                            &form.password1,
-                            form.email.clone(),
-                            name,
-                        )
-                        .save(&db)
-                        .await?;
-                        form.to_context().await
-                    }
-                };
+                Ok(form) if User::exists_by_username(&db, &form.username).await? => {
+                    let mut ctx = form.to_context().await;
+                    ctx.add_error(
+                        FormErrorTarget::Field("username"),
+                        FormFieldValidationError::from_static("this username is already taken."),
+                    );
+                    ctx
+                }
+                Ok(form)
+                    if User::exists_by_email(&db, &form.email.clone().into_email()).await? =>
+                {
+                    let mut ctx = form.to_context().await;
+                    ctx.add_error(
+                        FormErrorTarget::Field("email"),
+                        FormFieldValidationError::from_static(
+                            "an account with this email address already exists.",
+                        ),
+                    );
+                    ctx
+                }
+                Ok(form) => {
+                    let email = form.email.clone().into_email();
+                    let create_user = async || {
+                        let mut user = User::builder()
+                            .username(form.username.to_string())
+                            .password(&form.password1)
+                            .email(email.clone())
+                            .name(form.fullname.to_string())
+                            .build()
+                            .map_err(cot::Error::wrap)?;
+                        user.save(&db).await?;
+                        Ok(user)
+                    };
+                    let created = if app_config().invite_only {
+                        invites::redeem(&db, query.invite.as_deref(), &email, create_user).await?
+                    } else {
+                        Ok(create_user().await?)
+                    };
 
-                val
-            }
+                    match created {
+                        Err(err) => {
+                            let mut ctx = form.to_context().await;
+                            ctx.add_error(
+                                FormErrorTarget::Form,
+                                FormFieldValidationError::from_string(err.to_string()),
+                            );
+                            ctx
+                        }
+                        Ok(user) => {
+                            counter!(telemetry::SIGNUPS).increment(1);
+                            let secret = request.context().config().secret_key.as_bytes();
+                            send_verification_email(
+                                &urls,
+                                request.headers(),
+                                secret,
+                                email_sender,
+                                &user,
+                            )
+                            .await?;
+                            if app_config().require_verified_email_for_login {
+                                remember_pending(&session, &user).await?;
+                                return Ok(cot::reverse_redirect!(urls, "verify_pending")?);
+                            }
+                            form.to_context().await
+                        }
+                    }
+                }
+            },
             FormResult::ValidationError(context) => context,
-        };
-        val
+        }
     } else {
         panic!("unexpected request method")
     };
@@ -87,11 +187,421 @@ pub(crate) async fn signup(
         form: signup_context,
         static_files,
     };
-    let rendered = signup_template.render()?;
+    render_form(
+        request.headers(),
+        &signup_template,
+        &signup_template.form,
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+impl Debug for SignupForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignupForm")
+            .field("fullname", &self.fullname)
+            .field("email", &self.email)
+            .field("username", &self.username)
+            .field("password1", &Redacted)
+            .field("password2", &Redacted)
+            .field("website", &self.website)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::common_types::Email;
+
+    async fn sign_up(app: &mut TestApp, username: &str, email: &str) -> String {
+        app.post(
+            "/signup",
+            &[
+                ("fullname", "Test User"),
+                ("email", email),
+                ("username", username),
+                ("password1", PASSWORD),
+                ("password2", PASSWORD),
+            ],
+        )
+        .await
+        .body
+    }
+
+    #[cot::test]
+    async fn debug_output_leaves_the_passwords_out() {
+        let mut request = cot::test::TestRequestBuilder::post("/signup")
+            .form_data(&[
+                ("fullname", "Test User"),
+                ("email", "debug@example.com"),
+                ("username", "debug_user"),
+                ("password1", PASSWORD),
+                ("password2", PASSWORD),
+            ])
+            .build();
+        let FormResult::Ok(form) = SignupForm::from_request(&mut request).await.unwrap() else {
+            panic!("the form is valid");
+        };
+
+        let debug = format!("{form:?}");
+
+        assert!(debug.contains("debug_user"));
+        assert!(debug.contains("password1: [redacted]"));
+        assert!(debug.contains("password2: [redacted]"));
+        assert!(!debug.contains(PASSWORD));
+    }
+
+    #[cot::test]
+    async fn a_logged_in_user_is_sent_past_the_signup_form() {
+        let mut app = TestApp::new().await;
+        app.create_user("signed_up_already", PASSWORD).await;
+        app.login("signed_up_already", PASSWORD).await;
+
+        let response = app.get("/signup").await;
+
+        assert!(response.status.is_redirection());
+        assert_eq!(response.location(), Some("/home"));
+    }
+
+    #[cot::test]
+    async fn trims_everything_but_the_password() {
+        let mut app = TestApp::new().await;
+        let padded_password = format!("  {PASSWORD}  ");
+
+        app.post(
+            "/signup",
+            &[
+                ("fullname", "  Padded \t User  "),
+                ("email", "  padded@example.com  "),
+                ("username", "  padded_user  "),
+                ("password1", &padded_password),
+                ("password2", &padded_password),
+            ],
+        )
+        .await;
+
+        let user = User::get_by_username(app.db(), "padded_user")
+            .await
+            .unwrap()
+            .expect("the user was created under the trimmed name");
+        assert_eq!(user.display_name(), "Padded User");
+        assert_eq!(user.email().as_str(), "padded@example.com");
+        assert!(user.check_password(&Password::new(&padded_password)));
+        assert!(!user.check_password(&Password::new(PASSWORD)));
+    }
+
+    #[cot::test]
+    async fn a_mismatched_confirmation_is_shown_on_its_field() {
+        let mut app = TestApp::new().await;
+
+        let response = app
+            .post(
+                "/signup",
+                &[
+                    ("fullname", "Test User"),
+                    ("email", "mismatch@example.com"),
+                    ("username", "mismatch"),
+                    ("password1", PASSWORD),
+                    ("password2", "Str0ng!Passphrase-abc"),
+                ],
+            )
+            .await;
+
+        let (before, after) = response
+            .body
+            .split_once("id=\"password2\"")
+            .expect("the form has a confirmation field");
+        assert!(!before.contains("passwords do not match."));
+        assert!(after.contains("passwords do not match."));
+    }
+
+    async fn sign_up_with_website(app: &mut TestApp, username: &str, website: &str) {
+        app.post(
+            "/signup",
+            &[
+                ("fullname", "Test User"),
+                ("email", &format!("{username}@example.com")),
+                ("username", username),
+                ("password1", PASSWORD),
+                ("password2", PASSWORD),
+                ("website", website),
+            ],
+        )
+        .await;
+    }
+
+    #[cot::test]
+    async fn a_filled_in_honeypot_creates_no_user() {
+        let mut app = TestApp::new().await;
+
+        sign_up_with_website(&mut app, "honeypot_bot", "https://spam.example").await;
+
+        let user = User::get_by_username(app.db(), "honeypot_bot")
+            .await
+            .unwrap();
+        assert!(user.is_none());
+    }
+
+    #[cot::test]
+    async fn a_blank_honeypot_signs_up() {
+        let mut app = TestApp::new().await;
+
+        sign_up_with_website(&mut app, "honeypot_person", "").await;
+
+        let user = User::get_by_username(app.db(), "honeypot_person")
+            .await
+            .unwrap();
+        assert!(user.is_some());
+    }
+
+    async fn sign_up_named(app: &mut TestApp, username: &str, fullname: &str) -> String {
+        app.post(
+            "/signup",
+            &[
+                ("fullname", fullname),
+                ("email", &format!("{username}@example.com")),
+                ("username", username),
+                ("password1", PASSWORD),
+                ("password2", PASSWORD),
+            ],
+        )
+        .await
+        .body
+    }
+
+    #[cot::test]
+    async fn refuses_a_name_with_nothing_visible() {
+        let mut app = TestApp::new().await;
+
+        for (username, fullname) in [("blank_name", ""), ("spaces_name", " \t\n ")] {
+            let body = sign_up_named(&mut app, username, fullname).await;
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::fixed(rendered))
-        .unwrap();
-    Ok(response)
+            let (_, after_name) = body.split_once("id=\"fullname\"").unwrap();
+            let (name_group, _) = after_name.split_once("id=\"email\"").unwrap();
+            assert!(name_group.contains("class=\"error\""), "{fullname:?}");
+            let user = User::get_by_username(app.db(), username).await.unwrap();
+            assert!(user.is_none(), "{fullname:?}");
+        }
+    }
+
+    #[cot::test]
+    async fn strips_control_characters_from_the_name() {
+        let mut app = TestApp::new().await;
+
+        sign_up_named(&mut app, "control_name", "Ada\nLove\u{7}lace\r").await;
+
+        let user = User::get_by_username(app.db(), "control_name")
+            .await
+            .unwrap()
+            .expect("the user was created");
+        assert_eq!(user.display_name(), "Ada Love lace");
+    }
+
+    #[cot::test]
+    async fn refuses_a_reserved_username_in_any_case() {
+        let mut app = TestApp::new().await;
+
+        let body = sign_up(&mut app, "Support", "reserved@example.com").await;
+
+        assert!(body.contains("this username is reserved."));
+        let user = User::get_by_username(app.db(), "Support").await.unwrap();
+        assert!(user.is_none());
+    }
+
+    #[cot::test]
+    async fn allows_a_name_that_only_contains_a_reserved_one() {
+        let mut app = TestApp::new().await;
+
+        sign_up(&mut app, "supportive", "supportive@example.com").await;
+
+        let user = User::get_by_username(app.db(), "supportive").await.unwrap();
+        assert!(user.is_some());
+    }
+
+    #[cot::test]
+    async fn enforces_the_configured_username_lengths() {
+        let mut app = TestApp::with_config(|config| {
+            config.username.min_length = 5;
+            config.username.max_length = 8;
+        })
+        .await;
+
+        let short = sign_up(&mut app, "abcd", "short_name@example.com").await;
+        let long = sign_up(&mut app, "abcdefghi", "long_name@example.com").await;
+        sign_up(&mut app, "abcde", "fitting_name@example.com").await;
+
+        assert!(short.contains("usernames need at least 5 characters."));
+        assert!(long.contains("usernames can have at most 8 characters."));
+        let user = User::get_by_username(app.db(), "abcde").await.unwrap();
+        assert!(user.is_some());
+    }
+
+    #[cot::test]
+    async fn stores_the_email_address_normalized() {
+        let mut app = TestApp::new().await;
+
+        sign_up(&mut app, "mixed_case", "Mixed.Case@Example.COM").await;
+
+        let user = User::get_by_username(app.db(), "mixed_case")
+            .await
+            .unwrap()
+            .expect("the user was created");
+        assert_eq!(user.email().as_str(), "mixed.case@example.com");
+    }
+
+    #[cot::test]
+    async fn keeps_the_local_part_case_when_configured() {
+        let mut app =
+            TestApp::with_config(|config| config.lowercase_email_local_part = false).await;
+
+        sign_up(&mut app, "local_case", "Local.Case@Example.COM").await;
+
+        let user = User::get_by_username(app.db(), "local_case")
+            .await
+            .unwrap()
+            .expect("the user was created");
+        assert_eq!(user.email().as_str(), "Local.Case@example.com");
+    }
+
+    #[cot::test]
+    async fn rejects_an_email_address_that_is_taken() {
+        let mut app = TestApp::new().await;
+        sign_up(&mut app, "first_owner", "shared@example.com").await;
+
+        let body = sign_up(&mut app, "second_owner", "SHARED@example.com").await;
+
+        assert!(body.contains("an account with this email address already exists."));
+        assert!(
+            User::get_by_username(app.db(), "second_owner")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[cot::test]
+    async fn rejects_a_username_that_only_looks_different() {
+        let mut app = TestApp::new().await;
+        sign_up(&mut app, "fiona", "fiona@example.com").await;
+
+        // with the "fi" ligature, which NFKC turns into "fi"
+        let body = sign_up(&mut app, "\u{fb01}ona", "other-fiona@example.com").await;
+
+        assert!(body.contains("this username is already taken."));
+        assert!(
+            User::get_by_email(app.db(), &Email::new("other-fiona@example.com").unwrap())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    async fn invite_only_app() -> TestApp {
+        TestApp::with_config(|config| config.invite_only = true).await
+    }
+
+    async fn sign_up_with_invite(app: &mut TestApp, invite: &str, username: &str) -> String {
+        app.post(
+            &format!("/signup?invite={invite}"),
+            &[
+                ("fullname", "Test User"),
+                ("email", &format!("{username}@example.com")),
+                ("username", username),
+                ("password1", PASSWORD),
+                ("password2", PASSWORD),
+            ],
+        )
+        .await
+        .body
+    }
+
+    #[cot::test]
+    async fn signs_up_with_a_valid_invite() {
+        let mut app = invite_only_app().await;
+        let address = Email::new("invitee@example.com").unwrap();
+        let token = invites::create(app.db(), &address, 1).await.unwrap();
+
+        sign_up_with_invite(&mut app, &token, "invitee").await;
+
+        assert!(
+            User::get_by_email(app.db(), &address)
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[cot::test]
+    async fn refuses_a_signup_without_an_invite() {
+        let mut app = invite_only_app().await;
+
+        let body = sign_up(&mut app, "uninvited", "uninvited@example.com").await;
+
+        assert!(body.contains("signing up requires an invite."));
+        assert!(
+            !User::exists_by_username(app.db(), "uninvited")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[cot::test]
+    async fn refuses_a_used_invite() {
+        let mut app = invite_only_app().await;
+        let token = invites::create(app.db(), &Email::new("first@example.com").unwrap(), 1)
+            .await
+            .unwrap();
+        sign_up_with_invite(&mut app, &token, "first").await;
+        // with the first account gone, the address is free again but the
+        // invite stays used
+        app.db().raw(r#"DELETE FROM "auth__user""#).await.unwrap();
+
+        let body = app
+            .post(
+                &format!("/signup?invite={token}"),
+                &[
+                    ("fullname", "Test User"),
+                    ("email", "first@example.com"),
+                    ("username", "first_again"),
+                    ("password1", PASSWORD),
+                    ("password2", PASSWORD),
+                ],
+            )
+            .await
+            .body;
+
+        assert!(body.contains("this invite has already been used."));
+        assert!(
+            !User::exists_by_username(app.db(), "first_again")
+                .await
+                .unwrap()
+        );
+    }
+
+    #[cot::test]
+    async fn the_form_renders_the_configured_length_limits() {
+        let mut app = TestApp::with_config(|config| {
+            config.min_password_entropy_bits = 60.0;
+            config.username.min_length = 4;
+            config.username.max_length = 40;
+        })
+        .await;
+
+        let body = app.get("/signup").await.body;
+
+        let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(
+            body.contains(r#"name="username" minlength="4" maxlength="40""#),
+            "{body}"
+        );
+        let min_length = crate::password_strength::min_length();
+        assert!(min_length > 0);
+        assert!(
+            body.contains(&format!(
+                r#"name="password1" minlength="{min_length}" placeholder"#
+            )),
+            "{body}"
+        );
+    }
 }
@@ -0,0 +1,508 @@
+//! Resetting a forgotten password with a code texted to the phone number on
+//! the account, as an alternative to the emailed link.
+//!
+//! The request page remembers the matching account in the session, so the
+//! code page only asks for the code and the new password. Codes themselves
+//! are handled by [`crate::reset_codes`].
+
+use crate::auth::User;
+use crate::client_ip::ClientIp;
+use crate::config::app_config;
+use crate::forms::fields::{Redacted, TrimmedString};
+use crate::forms::forgot_password::{
+    ResetError, ensure_enabled as ensure_reset_enabled, set_new_password, validate_new_password,
+};
+use crate::forms::form_from_request;
+use crate::page::{PageContext, form_page};
+use crate::rate_limit::{self, RateLimiter};
+use crate::render::render_form;
+use crate::{reset_codes, sms, telemetry};
+use cot::common_types::Password;
+use cot::db::Database;
+use cot::error::NotFound;
+use cot::form::{Form, FormContext, FormErrorTarget, FormFieldValidationError, FormResult};
+use cot::request::{Request, RequestExt};
+use cot::response::Response;
+use cot::session::Session;
+use cot::{Method, StatusCode};
+use metrics::counter;
+use std::fmt::{Debug, Formatter};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The session entry holding the id of the account a code was requested for.
+const SESSION_KEY: &str = "sms_reset_user_id";
+
+const INVALID_CODE: &str = "this code is invalid or has expired.";
+const TOO_MANY_REQUESTS: &str = "Too many reset requests. Please try again later.";
+
+/// Codes a phone number can be sent per [`SMS_ACCOUNT_WINDOW`].
+const SMS_PER_ACCOUNT: u32 = 3;
+const SMS_ACCOUNT_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Code requests a client address can make per [`SMS_CLIENT_WINDOW`].
+const SMS_REQUESTS_PER_CLIENT: u32 = 10;
+const SMS_CLIENT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Keyed by the phone number, which is what a flood of codes would hit. As
+/// with reset emails, running out doesn't show in the response.
+static ACCOUNT_THROTTLE: LazyLock<Box<dyn RateLimiter>> =
+    LazyLock::new(|| rate_limit::from_config("reset_sms", SMS_PER_ACCOUNT, SMS_ACCOUNT_WINDOW));
+static CLIENT_THROTTLE: LazyLock<Box<dyn RateLimiter>> = LazyLock::new(|| {
+    rate_limit::from_config(
+        "reset_sms_request",
+        SMS_REQUESTS_PER_CLIENT,
+        SMS_CLIENT_WINDOW,
+    )
+});
+
+#[derive(Debug, Form)]
+pub(crate) struct SmsResetRequestForm {
+    /// The account's email address or username.
+    identifier: TrimmedString,
+}
+
+#[derive(Form)]
+pub(crate) struct SmsResetConfirmForm {
+    code: TrimmedString,
+    password1: Password,
+    password2: Password,
+}
+
+impl Debug for SmsResetConfirmForm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmsResetConfirmForm")
+            .field("code", &Redacted)
+            .field("password1", &Redacted)
+            .field("password2", &Redacted)
+            .finish()
+    }
+}
+
+form_page! {
+    #[template(path = "sms_reset_request.html")]
+    pub(crate) struct SmsResetRequestTemplate for SmsResetRequestForm {
+    }
+}
+
+form_page! {
+    #[template(path = "sms_reset_confirm.html")]
+    pub(crate) struct SmsResetConfirmTemplate for SmsResetConfirmForm {
+    }
+}
+
+/// Answers 404 unless both password reset and its SMS channel are turned on.
+fn ensure_enabled() -> cot::Result<()> {
+    ensure_reset_enabled()?;
+    if app_config().sms_reset.enabled {
+        Ok(())
+    } else {
+        Err(NotFound::new().into())
+    }
+}
+
+/// Texts a new code to `phone` unless it's out of codes or the account is
+/// locked out.
+async fn send_code(
+    db: &Database,
+    secret: &[u8],
+    user: &User,
+    user_id: i64,
+    phone: &str,
+) -> cot::Result<()> {
+    if ACCOUNT_THROTTLE.check(db, phone).await?.is_err() {
+        warn!(
+            event = "sms_reset_requested",
+            outcome = "throttled",
+            username = user.username(),
+            "too many reset codes sent to the account's phone"
+        );
+        return Ok(());
+    }
+    let Some(code) = reset_codes::issue(db, secret, user_id).await? else {
+        warn!(
+            event = "sms_reset_requested",
+            outcome = "locked_out",
+            username = user.username(),
+            "no reset code sent, as the account is locked out"
+        );
+        return Ok(());
+    };
+    let ttl_minutes = (app_config().sms_reset.code_ttl_secs + 59) / 60;
+    sms::send(
+        phone,
+        &format!(
+            "Your {} password reset code is {code}. It expires in {ttl_minutes} min.",
+            app_config().site_name
+        ),
+    )
+    .await?;
+    info!(
+        event = "sms_reset_requested",
+        username = user.username(),
+        "sent a password reset code by SMS"
+    );
+    Ok(())
+}
+
+/// Texts a reset code to the phone number of the account matching the
+/// submitted email address or username, then moves on to the code page.
+pub(crate) async fn sms_reset_request(
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
+    db: Database,
+    session: Session,
+    client_ip: ClientIp,
+    mut request: Request,
+) -> cot::Result<Response> {
+    ensure_enabled()?;
+    // the time left until the client can ask again, if it's out of requests
+    let mut throttled = None;
+
+    let mut form_context = if request.method() == Method::GET {
+        SmsResetRequestForm::build_context(&mut request).await?
+    } else if request.method() == Method::POST {
+        match form_from_request::<SmsResetRequestForm>(&mut request).await? {
+            // unknown addresses aren't counted, as they would all share one
+            // budget
+            FormResult::Ok(form)
+                if let Some(ip) = client_ip.0
+                    && let Err(retry_after) =
+                        CLIENT_THROTTLE.check(&db, &ip.to_string()).await? =>
+            {
+                warn!(event = "sms_reset_requested", outcome = "throttled", %client_ip, "too many SMS reset requests");
+                throttled = Some(retry_after);
+                form.to_context().await
+            }
+            FormResult::Ok(form) => {
+                counter!(telemetry::RESET_REQUESTS).increment(1);
+                let user = User::get_by_email_or_username(&db, &form.identifier).await?;
+                let user_id = match &user {
+                    Some(user) if let (Some(user_id), Some(phone)) = (user.id(), user.phone()) => {
+                        let secret = request.context().config().secret_key.as_bytes();
+                        send_code(&db, secret, user, user_id, phone).await?;
+                        Some(user_id)
+                    }
+                    _ => None,
+                };
+                // go on to the code page whether or not an account matched or
+                // was sent a code, so the form can't be used to probe for
+                // accounts or phones
+                match user_id {
+                    Some(user_id) => session.insert(SESSION_KEY, user_id).await,
+                    None => session.remove::<i64>(SESSION_KEY).await.map(drop),
+                }
+                .map_err(cot::Error::wrap)?;
+                return Ok(cot::reverse_redirect!(urls, "sms_reset_confirm")?);
+            }
+            FormResult::ValidationError(context) => context,
+        }
+    } else {
+        panic!("unexpected request method")
+    };
+    if throttled.is_some() {
+        form_context.add_error(
+            FormErrorTarget::Form,
+            FormFieldValidationError::from_static(TOO_MANY_REQUESTS),
+        );
+    }
+
+    let template = SmsResetRequestTemplate {
+        urls: &urls,
+        static_files,
+        form: form_context,
+    };
+    let error_status = if throttled.is_some() {
+        StatusCode::TOO_MANY_REQUESTS
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    let mut response = render_form(request.headers(), &template, &template.form, error_status)?;
+    if let Some(retry_after) = throttled {
+        rate_limit::add_throttle_headers(
+            response.headers_mut(),
+            CLIENT_THROTTLE.limit(),
+            retry_after,
+        );
+    }
+    Ok(response)
+}
+
+/// Sets the new password of the account remembered in `session` if `form`
+/// carries its code.
+///
+/// The outer error is for failures the user can't fix; the inner one is for a
+/// submission that has to be corrected.
+async fn reset_password(
+    db: &Database,
+    session: &Session,
+    secret: &[u8],
+    form: &SmsResetConfirmForm,
+) -> cot::Result<Result<(), ResetError>> {
+    if let Err(err) = validate_new_password(&form.password1, &form.password2).await {
+        return Ok(Err(err.into()));
+    }
+    let user_id: Option<i64> = session.get(SESSION_KEY).await.map_err(cot::Error::wrap)?;
+    let invalid_code = || {
+        ResetError::from((
+            FormErrorTarget::Field("code"),
+            FormFieldValidationError::from_static(INVALID_CODE),
+        ))
+    };
+    let Some(user_id) = user_id else {
+        return Ok(Err(invalid_code()));
+    };
+    // the password history is only consulted after the code checks out, so
+    // it can't be probed without one
+    if !reset_codes::check(db, secret, user_id, &form.code).await? {
+        return Ok(Err(invalid_code()));
+    }
+    let Some(mut user) = User::get_by_id(db, user_id).await? else {
+        return Ok(Err(invalid_code()));
+    };
+
+    if let Err(err) = set_new_password(db, &mut user, &form.password1).await? {
+        return Ok(Err(err));
+    }
+    reset_codes::consume(db, user_id).await?;
+    session
+        .remove::<i64>(SESSION_KEY)
+        .await
+        .map_err(cot::Error::wrap)?;
+    info!(
+        event = "password_reset",
+        outcome = "success",
+        username = user.username(),
+        "password reset with an SMS code"
+    );
+    Ok(Ok(()))
+}
+
+pub(crate) async fn sms_reset_confirm(
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
+    db: Database,
+    session: Session,
+    mut request: Request,
+) -> cot::Result<Response> {
+    ensure_enabled()?;
+    let form_context = if request.method() == Method::GET {
+        SmsResetConfirmForm::build_context(&mut request).await?
+    } else if request.method() == Method::POST {
+        match form_from_request::<SmsResetConfirmForm>(&mut request).await? {
+            FormResult::Ok(form) => {
+                let secret = request.context().config().secret_key.as_bytes();
+                let result = reset_password(&db, &session, secret, &form).await?;
+                let mut ctx = form.to_context().await;
+                match result {
+                    Ok(()) => return Ok(cot::reverse_redirect!(urls, "password_reset_done")?),
+                    Err(ResetError { target, error }) => ctx.add_error(target, error),
+                }
+                ctx
+            }
+            FormResult::ValidationError(context) => context,
+        }
+    } else {
+        panic!("unexpected request method")
+    };
+
+    let template = SmsResetConfirmTemplate {
+        urls: &urls,
+        static_files,
+        form: form_context,
+    };
+    render_form(
+        request.headers(),
+        &template,
+        &template.form,
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp, TestResponse, from_ip, sent_sms};
+    use cot::db::Model;
+
+    const NEW_PASSWORD: &str = "An0ther!Passphrase-xyz";
+
+    async fn app() -> TestApp {
+        TestApp::with_config(|config| {
+            config.sms_reset.enabled = true;
+            config.behind_trusted_proxy = true;
+        })
+        .await
+    }
+
+    /// Saves `username` with the phone number `phone`.
+    async fn create_user_with_phone(app: &TestApp, username: &str, phone: &str) {
+        let mut user = app.create_user(username, PASSWORD).await;
+        user.set_phone(Some(phone.to_owned()));
+        user.save(app.db()).await.unwrap();
+    }
+
+    /// The code in each text message sent since the last call.
+    fn sent_codes() -> Vec<String> {
+        sent_sms()
+            .into_iter()
+            .map(|(_, body)| {
+                let (_, rest) = body.split_once("code is ").expect("the message has a code");
+                rest.chars().take_while(char::is_ascii_digit).collect()
+            })
+            .collect()
+    }
+
+    async fn request_code(app: &mut TestApp, identifier: &str) -> TestResponse {
+        app.post("/forgot-password/sms", &[("identifier", identifier)])
+            .await
+    }
+
+    async fn confirm(app: &mut TestApp, code: &str) -> TestResponse {
+        app.post(
+            "/forgot-password/sms/confirm",
+            &[
+                ("code", code),
+                ("password1", NEW_PASSWORD),
+                ("password2", NEW_PASSWORD),
+            ],
+        )
+        .await
+    }
+
+    fn wrong(code: &str) -> String {
+        let first = if code.starts_with('0') { '1' } else { '0' };
+        format!("{first}{}", &code[1..])
+    }
+
+    #[cot::test]
+    async fn resets_the_password_with_the_texted_code() {
+        let mut app = app().await;
+        create_user_with_phone(&app, "sms_reset", "+15550000001").await;
+
+        let response = request_code(&mut app, "sms_reset").await;
+        assert_eq!(response.location(), Some("/forgot-password/sms/confirm"));
+        let sent = sent_sms();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "+15550000001");
+        let (_, code) = sent[0].1.split_once("code is ").unwrap();
+        let code: String = code.chars().take_while(char::is_ascii_digit).collect();
+
+        let response = confirm(&mut app, &code).await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+
+        assert_ne!(
+            app.login("sms_reset", PASSWORD).await.location(),
+            Some("/home")
+        );
+        assert_eq!(
+            app.login("sms_reset", NEW_PASSWORD).await.location(),
+            Some("/home")
+        );
+    }
+
+    #[cot::test]
+    async fn refuses_the_right_code_after_too_many_wrong_ones() {
+        let mut app = app().await;
+        create_user_with_phone(&app, "sms_guessed", "+15550000002").await;
+        request_code(&mut app, "sms_guessed").await;
+        let code = sent_codes().remove(0);
+
+        for _ in 0..app_config().sms_reset.max_attempts {
+            let response = confirm(&mut app, &wrong(&code)).await;
+            assert!(response.body.contains(INVALID_CODE));
+        }
+        let response = confirm(&mut app, &code).await;
+
+        assert!(response.body.contains(INVALID_CODE));
+        assert_ne!(
+            app.login("sms_guessed", NEW_PASSWORD).await.location(),
+            Some("/home")
+        );
+    }
+
+    #[cot::test]
+    async fn a_new_code_does_not_reset_the_wrong_guesses() {
+        let mut app = app().await;
+        create_user_with_phone(&app, "sms_reissued", "+15550000003").await;
+        let max_attempts = app_config().sms_reset.max_attempts;
+        request_code(&mut app, "sms_reissued").await;
+        let code = sent_codes().remove(0);
+        for _ in 1..max_attempts {
+            confirm(&mut app, &wrong(&code)).await;
+        }
+
+        request_code(&mut app, "sms_reissued").await;
+        let code = sent_codes().remove(0);
+        confirm(&mut app, &wrong(&code)).await;
+
+        // locked out: the code is refused and no new one is sent, though the
+        // answer looks the same
+        assert!(confirm(&mut app, &code).await.body.contains(INVALID_CODE));
+        let response = request_code(&mut app, "sms_reissued").await;
+        assert_eq!(response.location(), Some("/forgot-password/sms/confirm"));
+        assert!(sent_sms().is_empty());
+    }
+
+    #[cot::test]
+    async fn codes_are_throttled_per_phone() {
+        let mut app = app().await;
+        create_user_with_phone(&app, "sms_flooded", "+15550000004").await;
+        for _ in 0..SMS_PER_ACCOUNT {
+            request_code(&mut app, "sms_flooded").await;
+        }
+        assert_eq!(sent_sms().len(), SMS_PER_ACCOUNT as usize);
+
+        let response = request_code(&mut app, "sms_flooded").await;
+
+        // nothing is sent, but the answer is the one for an unknown account
+        assert!(sent_sms().is_empty());
+        assert_eq!(response.location(), Some("/forgot-password/sms/confirm"));
+    }
+
+    #[cot::test]
+    async fn code_requests_are_throttled_per_client() {
+        let mut app = app().await;
+        create_user_with_phone(&app, "sms_client", "+15550000005").await;
+        for _ in 0..SMS_REQUESTS_PER_CLIENT {
+            app.post_with_headers(
+                "/forgot-password/sms",
+                &[("identifier", "nobody@example.com")],
+                from_ip("203.0.113.50"),
+            )
+            .await;
+        }
+        let mut headers = from_ip("203.0.113.50");
+        headers.insert(
+            cot::http::header::ACCEPT,
+            "application/json".parse().unwrap(),
+        );
+
+        let response = app
+            .post_with_headers(
+                "/forgot-password/sms",
+                &[("identifier", "sms_client")],
+                headers,
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers.contains_key("retry-after"));
+        assert_eq!(
+            response.headers["x-ratelimit-limit"],
+            SMS_REQUESTS_PER_CLIENT.to_string()
+        );
+        assert!(sent_sms().is_empty());
+
+        let response = app
+            .post_with_headers(
+                "/forgot-password/sms",
+                &[("identifier", "sms_client")],
+                from_ip("203.0.113.51"),
+            )
+            .await;
+        assert_eq!(response.location(), Some("/forgot-password/sms/confirm"));
+        assert_eq!(sent_sms().len(), 1);
+    }
+}
@@ -0,0 +1,455 @@
+//! Confirming that users own the email address they signed up with.
+//!
+//! A link is emailed at signup and can be sent again from the resend page, or
+//! from the pending page signups land on when verification is required.
+//! Links are signed like password reset links, but with their own purpose and
+//! bound to the address and its verification state, so each one works only
+//! once and only for the address it was sent to.
+
+use crate::auth::User;
+use crate::client_ip::ClientIp;
+use crate::config::app_config;
+use crate::forms::fields::TrimmedString;
+use crate::forms::forgot_password::{ResetLink, ResetToken, verification_secrets};
+use crate::forms::form_from_request;
+use crate::page::{PageContext, form_page};
+use crate::rate_limit::{self, EmailThrottle};
+use crate::render::{render, render_form};
+use crate::{https, mail_queue};
+use cot::common_types::Email;
+use cot::db::{Database, Model};
+use cot::email::{Email as EmailService, EmailMessage};
+use cot::form::{Form, FormContext, FormErrorTarget, FormFieldValidationError, FormResult};
+use cot::http::HeaderMap;
+use cot::request::extractors::StaticFiles;
+use cot::request::{Request, RequestExt};
+use cot::response::Response;
+use cot::router::Urls;
+use cot::session::Session;
+use cot::{Method, StatusCode, Template};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tracing::info;
+
+/// How long a verification link stays valid after it was sent.
+const VERIFICATION_TIMEOUT_SECS: i64 = 3 * 24 * 60 * 60;
+
+const INVALID_LINK: &str = "This verification link is invalid or has expired.";
+
+/// What a client that asked for too many verification emails is told.
+const TOO_MANY_REQUESTS: &str = "Too many verification emails requested. Please try again later.";
+
+/// Verification emails an account can be sent per
+/// [`VERIFICATION_ACCOUNT_WINDOW`], on top of the one sent at signup.
+const VERIFICATION_EMAILS_PER_ACCOUNT: u32 = 3;
+const VERIFICATION_ACCOUNT_WINDOW: Duration = Duration::from_secs(15 * 60);
+/// Resend requests a client address can make per
+/// [`VERIFICATION_CLIENT_WINDOW`].
+const VERIFICATION_REQUESTS_PER_CLIENT: u32 = 10;
+const VERIFICATION_CLIENT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Shared by the resend and pending pages, which both send links again.
+static VERIFICATION_THROTTLE: LazyLock<EmailThrottle> = LazyLock::new(|| {
+    EmailThrottle::new(
+        rate_limit::from_config(
+            "verification_email",
+            VERIFICATION_EMAILS_PER_ACCOUNT,
+            VERIFICATION_ACCOUNT_WINDOW,
+        ),
+        rate_limit::from_config(
+            "verification_request",
+            VERIFICATION_REQUESTS_PER_CLIENT,
+            VERIFICATION_CLIENT_WINDOW,
+        ),
+    )
+});
+
+/// The session entry holding the id of the account that just signed up and
+/// still has to verify its address.
+const PENDING_SESSION_KEY: &str = "verification_pending_user_id";
+
+#[derive(Debug, Form)]
+pub(crate) struct ResendVerificationForm {
+    /// The account's email address or username.
+    identifier: TrimmedString,
+}
+
+form_page! {
+    #[template(path = "verify_email.html")]
+    pub(crate) struct VerifyEmailTemplate for ResendVerificationForm {
+        email_sent: bool,
+        verified: bool,
+    }
+}
+
+#[derive(Debug, Template)]
+#[template(path = "verify_pending.html")]
+pub(crate) struct VerifyPendingTemplate<'a> {
+    urls: &'a Urls,
+    static_files: StaticFiles,
+    /// The address the link was sent to.
+    email: &'a str,
+    /// Whether a new link was sent from this page.
+    resent: bool,
+    /// Whether no link was sent because too many were asked for.
+    throttled: bool,
+}
+
+/// Emails `user` a link confirming their address.
+pub(crate) async fn send_verification_email(
+    urls: &Urls,
+    headers: &HeaderMap,
+    secret: &[u8],
+    email_sender: EmailService,
+    user: &User,
+) -> cot::Result<()> {
+    let (Some(uid), Some(token)) = (
+        user.id(),
+        ResetToken::email_verification_from_config().make_token(user, secret),
+    ) else {
+        return Err(cot::Error::internal("only saved users can verify an email"));
+    };
+    let verify_url =
+        ResetLink::new(token, uid).to_verify_email_url(urls, &https::base_url(headers))?;
+
+    let message = EmailMessage::builder()
+        .from(Email::try_from("no-reply@example.com").unwrap())
+        .to(vec![user.email().clone()])
+        .subject(format!(
+            "Confirm your {} email address",
+            app_config().site_name
+        ))
+        .body(format!(
+            r#"
+                    click link to confirm your email address:
+
+                    {verify_url}
+
+                  "#
+        ))
+        .build()?;
+
+    mail_queue::send(email_sender, message).await
+}
+
+/// Remembers that `user` has just signed up and has to verify their address,
+/// so [`verify_pending`] knows whose address to show and resend to.
+pub(crate) async fn remember_pending(session: &Session, user: &User) -> cot::Result<()> {
+    let user_id = user.id().expect("only saved users can verify an email");
+    session
+        .insert(PENDING_SESSION_KEY, user_id)
+        .await
+        .map_err(cot::Error::wrap)
+}
+
+/// Tells a user who just signed up to check their email, with a button that
+/// sends the link again.
+///
+/// Visitors who didn't sign up in this session are sent to the resend page,
+/// and ones whose address has been verified since to the login page.
+pub(crate) async fn verify_pending(
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
+    db: Database,
+    email_sender: EmailService,
+    session: Session,
+    client_ip: ClientIp,
+    request: Request,
+) -> cot::Result<Response> {
+    let user_id: Option<i64> = session
+        .get(PENDING_SESSION_KEY)
+        .await
+        .map_err(cot::Error::wrap)?;
+    let user = match user_id {
+        Some(user_id) => User::get_by_id(&db, user_id).await?,
+        None => None,
+    };
+    let Some(user) = user.filter(|user| user.is_active() && !user.is_email_verified()) else {
+        if user_id.is_none() {
+            return Ok(cot::reverse_redirect!(urls, "verify_email_resend")?);
+        }
+        session
+            .remove::<i64>(PENDING_SESSION_KEY)
+            .await
+            .map_err(cot::Error::wrap)?;
+        return Ok(cot::reverse_redirect!(urls, "login")?);
+    };
+
+    let mut resent = false;
+    // the limit and time left, if no more links can be sent for now
+    let mut throttled = None;
+    if request.method() == Method::POST {
+        // the page is only shown to the account's own session, so it can say
+        // that the account is out of emails
+        let allowed = match VERIFICATION_THROTTLE.check_client(&db, client_ip).await? {
+            Ok(()) => VERIFICATION_THROTTLE
+                .check_account(&db, user.email())
+                .await?
+                .map_err(|retry_after| (VERIFICATION_THROTTLE.account_limit(), retry_after)),
+            Err(retry_after) => Err((VERIFICATION_THROTTLE.client_limit(), retry_after)),
+        };
+        match allowed {
+            Ok(()) => {
+                let secret = request.context().config().secret_key.as_bytes();
+                send_verification_email(&urls, request.headers(), secret, email_sender, &user)
+                    .await?;
+                resent = true;
+            }
+            Err(throttle) => throttled = Some(throttle),
+        }
+    }
+    let mut response = render(&VerifyPendingTemplate {
+        urls: &urls,
+        static_files,
+        email: user.email().as_str(),
+        resent,
+        throttled: throttled.is_some(),
+    })?;
+    if let Some((limit, retry_after)) = throttled {
+        rate_limit::add_throttle_headers(response.headers_mut(), limit, retry_after);
+    }
+    Ok(response)
+}
+
+/// Sends a new verification link to the unverified account matching the
+/// submitted email address or username.
+pub(crate) async fn verify_email_resend(
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
+    db: Database,
+    email: EmailService,
+    client_ip: ClientIp,
+    mut request: Request,
+) -> cot::Result<Response> {
+    let mut email_sent = false;
+    // the client's limit and time left, if it's out of requests
+    let mut throttled = None;
+    let mut form_context = if request.method() == Method::GET {
+        ResendVerificationForm::build_context(&mut request).await?
+    } else if request.method() == Method::POST {
+        match form_from_request::<ResendVerificationForm>(&mut request).await? {
+            FormResult::Ok(form) => {
+                if let Err(retry_after) = VERIFICATION_THROTTLE.check_client(&db, client_ip).await?
+                {
+                    throttled = Some((VERIFICATION_THROTTLE.client_limit(), retry_after));
+                } else {
+                    let user = User::get_by_email_or_username(&db, &form.identifier).await?;
+                    // an account out of emails is skipped silently, so the
+                    // answer doesn't tell that it exists
+                    if let Some(user) =
+                        user.filter(|user| user.is_active() && !user.is_email_verified())
+                        && VERIFICATION_THROTTLE
+                            .check_account(&db, user.email())
+                            .await?
+                            .is_ok()
+                    {
+                        let secret = request.context().config().secret_key.as_bytes();
+                        send_verification_email(&urls, request.headers(), secret, email, &user)
+                            .await?;
+                    }
+                    // answer the same way whether or not an account matched, so
+                    // the form can't be used to probe for accounts
+                    email_sent = true;
+                }
+
+                form.to_context().await
+            }
+            FormResult::ValidationError(context) => context,
+        }
+    } else {
+        panic!("unexpected request method")
+    };
+    if throttled.is_some() {
+        form_context.add_error(
+            FormErrorTarget::Form,
+            FormFieldValidationError::from_static(TOO_MANY_REQUESTS),
+        );
+    }
+
+    let template = VerifyEmailTemplate {
+        urls: &urls,
+        static_files,
+        form: form_context,
+        email_sent,
+        verified: false,
+    };
+    let error_status = if throttled.is_some() {
+        StatusCode::TOO_MANY_REQUESTS
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    let mut response = render_form(request.headers(), &template, &template.form, error_status)?;
+    if let Some((limit, retry_after)) = throttled {
+        rate_limit::add_throttle_headers(response.headers_mut(), limit, retry_after);
+    }
+    Ok(response)
+}
+
+/// Marks the email address a verification link was sent to as confirmed, or
+/// shows the resend form with an error if the link can't be used.
+pub(crate) async fn verify_email(
+    PageContext {
+        urls, static_files, ..
+    }: PageContext,
+    db: Database,
+    mut request: Request,
+) -> cot::Result<Response> {
+    let user = match ResetLink::from_path_params(request.path_params()) {
+        Ok(link) => User::get_by_id(&db, link.uid()).await?.filter(|user| {
+            ResetToken::email_verification_from_config()
+                .check_token(
+                    user,
+                    link.token(),
+                    verification_secrets(request.context().config()),
+                    VERIFICATION_TIMEOUT_SECS,
+                )
+                .is_ok()
+        }),
+        Err(_) => None,
+    };
+
+    let mut form_context = ResendVerificationForm::build_context(&mut request).await?;
+    let verified = if let Some(mut user) = user {
+        user.mark_email_verified().save(&db).await?;
+        info!(
+            event = "email_verified",
+            username = user.username(),
+            "user verified their email address"
+        );
+        true
+    } else {
+        form_context.add_error(
+            FormErrorTarget::Form,
+            FormFieldValidationError::from_static(INVALID_LINK),
+        );
+        false
+    };
+
+    let template = VerifyEmailTemplate {
+        urls: &urls,
+        static_files,
+        form: form_context,
+        email_sent: false,
+        verified,
+    };
+    render_form(
+        request.headers(),
+        &template,
+        &template.form,
+        StatusCode::BAD_REQUEST,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp, from_ip, sent_emails};
+
+    const SENT: &str = "a verification link has been sent to its email";
+
+    #[cot::test]
+    async fn resends_are_throttled_per_account() {
+        let mut app = TestApp::new().await;
+        app.create_user("resend_throttled", PASSWORD).await;
+        for _ in 0..VERIFICATION_EMAILS_PER_ACCOUNT {
+            app.post("/verify-email", &[("identifier", "resend_throttled")])
+                .await;
+        }
+        assert_eq!(
+            sent_emails().len(),
+            VERIFICATION_EMAILS_PER_ACCOUNT as usize
+        );
+
+        let response = app
+            .post("/verify-email", &[("identifier", "resend_throttled")])
+            .await;
+
+        // nothing is sent, but the answer is the one for an unknown account
+        assert!(sent_emails().is_empty());
+        assert!(response.body.contains(SENT));
+    }
+
+    #[cot::test]
+    async fn resends_are_throttled_per_client() {
+        let mut app = TestApp::with_config(|config| config.behind_trusted_proxy = true).await;
+        app.create_user("resend_flooded", PASSWORD).await;
+        for _ in 0..VERIFICATION_REQUESTS_PER_CLIENT {
+            app.post_with_headers(
+                "/verify-email",
+                &[("identifier", "nobody@example.com")],
+                from_ip("203.0.113.40"),
+            )
+            .await;
+        }
+
+        let response = app
+            .post_with_headers(
+                "/verify-email",
+                &[("identifier", "resend_flooded")],
+                from_ip("203.0.113.40"),
+            )
+            .await;
+        assert!(response.body.contains(TOO_MANY_REQUESTS));
+        assert!(sent_emails().is_empty());
+        assert_eq!(
+            response.headers["x-ratelimit-limit"],
+            VERIFICATION_REQUESTS_PER_CLIENT.to_string()
+        );
+        assert!(response.headers.contains_key("retry-after"));
+
+        let response = app
+            .post_with_headers(
+                "/verify-email",
+                &[("identifier", "resend_flooded")],
+                from_ip("203.0.113.41"),
+            )
+            .await;
+        assert!(response.body.contains(SENT));
+        assert_eq!(sent_emails().len(), 1);
+    }
+
+    #[cot::test]
+    async fn the_pending_page_says_when_no_more_links_can_be_sent() {
+        let mut app =
+            TestApp::with_config(|config| config.require_verified_email_for_login = true).await;
+        app.post(
+            "/signup",
+            &[
+                ("fullname", "Test User"),
+                ("email", "pending_throttled@example.com"),
+                ("username", "pending_throttled"),
+                ("password1", PASSWORD),
+                ("password2", PASSWORD),
+            ],
+        )
+        .await;
+        sent_emails();
+        for _ in 0..VERIFICATION_EMAILS_PER_ACCOUNT {
+            let response = app.post("/verify-pending", &[]).await;
+            assert!(response.body.contains("A new link is on its way."));
+        }
+        assert_eq!(
+            sent_emails().len(),
+            VERIFICATION_EMAILS_PER_ACCOUNT as usize
+        );
+
+        let response = app.post("/verify-pending", &[]).await;
+
+        assert!(sent_emails().is_empty());
+        assert!(response.body.contains(TOO_MANY_REQUESTS));
+        assert!(!response.body.contains("A new link is on its way."));
+        let retry_after: u64 = response.headers["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=VERIFICATION_ACCOUNT_WINDOW.as_secs()).contains(&retry_after));
+        assert_eq!(
+            response.headers["x-ratelimit-limit"],
+            VERIFICATION_EMAILS_PER_ACCOUNT.to_string()
+        );
+        assert_eq!(response.headers["x-ratelimit-remaining"], "0");
+    }
+}
@@ -0,0 +1,243 @@
+//! Knowing whether a request reached the site over HTTPS, for deployments
+//! where TLS is terminated by a reverse proxy in front of the app.
+
+use crate::config::app_config;
+use cot::http::{HeaderMap, HeaderValue, header};
+use cot::request::Request;
+use cot::response::Response;
+use futures::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower::Service;
+
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Whether the client is talking to the site over HTTPS.
+///
+/// That is the case when `app.https.assume_https` is set, or when a trusted
+/// reverse proxy (`app.behind_trusted_proxy`) says so in `X-Forwarded-Proto`.
+pub(crate) fn is_https(headers: &HeaderMap) -> bool {
+    let config = app_config();
+    config.https.assume_https
+        || config.behind_trusted_proxy
+            && headers
+                .get(X_FORWARDED_PROTO)
+                .and_then(|value| value.to_str().ok())
+                // each proxy appends what it saw, so only the last value comes
+                // from the trusted one; earlier ones are up to the client, as
+                // with `X-Forwarded-For` in `client_ip`
+                .and_then(|value| value.rsplit(',').next())
+                .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// The configured `app.base_url`, upgraded to `https://` when the request came
+/// in over HTTPS so emailed links don't point at the plain-HTTP site.
+pub(crate) fn base_url(headers: &HeaderMap) -> String {
+    let base_url = &app_config().base_url;
+    match base_url.strip_prefix("http://") {
+        Some(rest) if is_https(headers) => format!("https://{rest}"),
+        _ => base_url.clone(),
+    }
+}
+
+/// Marks the cookies of responses sent over HTTPS `Secure`, and adds
+/// `Strict-Transport-Security` to them when `app.https.hsts` is on.
+///
+/// Cot's `middlewares.session.secure` is fixed for the whole site; this makes
+/// the session cookie `Secure` whenever the client is on HTTPS, even when the
+/// app only sees plain HTTP from the proxy. It has to sit outside
+/// `SessionMiddleware` to see the cookie it sets.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct HttpsMiddleware;
+
+impl<S> tower::Layer<S> for HttpsMiddleware {
+    type Service = HttpsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpsService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct HttpsService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for HttpsService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let config = &app_config().https;
+            let https = is_https(req.headers());
+            let mut response = inner.call(req).await?;
+
+            if https {
+                mark_cookies_secure(response.headers_mut());
+            }
+            if https && config.hsts {
+                let mut value = format!("max-age={}", config.hsts_max_age_secs);
+                if config.hsts_include_subdomains {
+                    value.push_str("; includeSubDomains");
+                }
+                response.headers_mut().insert(
+                    header::STRICT_TRANSPORT_SECURITY,
+                    HeaderValue::from_str(&value).expect("the value is plain ASCII"),
+                );
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Whether the `Set-Cookie` value `cookie` has the `Secure` attribute.
+fn is_secure(cookie: &str) -> bool {
+    cookie
+        .split(';')
+        .skip(1)
+        .any(|attribute| attribute.trim().eq_ignore_ascii_case("secure"))
+}
+
+/// Adds the `Secure` attribute to every `Set-Cookie` in `headers` that lacks
+/// it.
+fn mark_cookies_secure(headers: &mut HeaderMap) {
+    let cookies: Vec<HeaderValue> = headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .cloned()
+        .collect();
+    headers.remove(header::SET_COOKIE);
+    for cookie in cookies {
+        let cookie = if cookie.to_str().is_ok_and(is_secure) {
+            cookie
+        } else {
+            let mut value = cookie.as_bytes().to_vec();
+            value.extend_from_slice(b"; Secure");
+            HeaderValue::from_bytes(&value).expect("appending ASCII keeps the value valid")
+        };
+        headers.append(header::SET_COOKIE, cookie);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::{Body, Method};
+
+    async fn https_app() -> TestApp {
+        TestApp::with_config(|config| {
+            config.behind_trusted_proxy = true;
+            config.https.hsts = true;
+        })
+        .await
+    }
+
+    fn login_form(username: &str, forwarded_proto: &str) -> (HeaderMap, Body) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        headers.insert(
+            X_FORWARDED_PROTO,
+            HeaderValue::from_str(forwarded_proto).expect("valid"),
+        );
+        let body = format!("username={username}&password={PASSWORD}");
+        (headers, Body::fixed(body))
+    }
+
+    fn set_cookies(headers: &HeaderMap) -> Vec<&str> {
+        headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().expect("cookies are ASCII"))
+            .collect()
+    }
+
+    #[cot::test]
+    async fn forwarded_https_gets_hsts_and_secure_cookies() {
+        let mut app = https_app().await;
+        app.create_user("https_user", PASSWORD).await;
+
+        let (headers, body) = login_form("https_user", "https");
+        let response = app.send(Method::POST, "/login", headers, body).await;
+
+        assert_eq!(
+            response.headers[header::STRICT_TRANSPORT_SECURITY],
+            "max-age=31536000"
+        );
+        let cookies = set_cookies(&response.headers);
+        assert!(!cookies.is_empty());
+        assert!(cookies.iter().all(|cookie| is_secure(cookie)));
+    }
+
+    #[cot::test]
+    async fn plain_http_gets_neither() {
+        let mut app = https_app().await;
+        app.create_user("http_user", PASSWORD).await;
+
+        let (headers, body) = login_form("http_user", "http");
+        let response = app.send(Method::POST, "/login", headers, body).await;
+
+        assert!(
+            !response
+                .headers
+                .contains_key(header::STRICT_TRANSPORT_SECURITY)
+        );
+        let cookies = set_cookies(&response.headers);
+        assert!(!cookies.is_empty());
+        assert!(!cookies.iter().any(|cookie| is_secure(cookie)));
+    }
+
+    #[cot::test]
+    async fn only_the_last_forwarded_proto_counts() {
+        let _app = https_app().await;
+        let mut headers = HeaderMap::new();
+
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https, http"));
+        assert!(!is_https(&headers));
+
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http, https"));
+        assert!(is_https(&headers));
+    }
+
+    #[cot::test]
+    async fn forwarded_proto_is_ignored_without_a_trusted_proxy() {
+        let _app = TestApp::new().await;
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+
+        assert!(!is_https(&headers));
+    }
+
+    #[test]
+    fn already_secure_cookies_are_left_alone() {
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("a=1; Path=/; secure; HttpOnly"),
+        );
+        headers.append(header::SET_COOKIE, HeaderValue::from_static("b=2; Path=/"));
+
+        mark_cookies_secure(&mut headers);
+
+        assert_eq!(
+            set_cookies(&headers),
+            ["a=1; Path=/; secure; HttpOnly", "b=2; Path=/; Secure"]
+        );
+    }
+}
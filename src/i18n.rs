@@ -0,0 +1,129 @@
+//! Locale negotiation and the small message catalog used by the templates.
+
+use cot::http::HeaderMap;
+use cot::http::header::ACCEPT_LANGUAGE;
+
+/// Locales the app has translations for, in the order they are offered to
+/// users.
+pub(crate) const SUPPORTED_LOCALES: &[&str] = &["en", "fr"];
+/// The locale used when neither the user nor the browser asks for a
+/// supported one.
+pub(crate) const DEFAULT_LOCALE: &str = "en";
+
+pub(crate) fn is_supported(locale: &str) -> bool {
+    SUPPORTED_LOCALES.contains(&locale)
+}
+
+/// Picks the locale to render a page in.
+///
+/// A locale stored on the user's profile always wins; otherwise the best
+/// supported match from the `Accept-Language` header is used.
+pub(crate) fn resolve(user_locale: Option<&str>, headers: &HeaderMap) -> &'static str {
+    user_locale
+        .and_then(supported)
+        .or_else(|| {
+            headers
+                .get(ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(from_accept_language)
+        })
+        .unwrap_or(DEFAULT_LOCALE)
+}
+
+fn supported(locale: &str) -> Option<&'static str> {
+    SUPPORTED_LOCALES
+        .iter()
+        .copied()
+        .find(|supported| supported.eq_ignore_ascii_case(locale))
+}
+
+/// Returns the supported locale with the highest quality value in an
+/// `Accept-Language` header, matching on the primary language subtag.
+fn from_accept_language(header: &str) -> Option<&'static str> {
+    let mut best: Option<(&'static str, f32)> = None;
+
+    for entry in header.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let Some(tag) = parts.next() else {
+            continue;
+        };
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok());
+        let (Some(quality), Some(locale)) = (quality, tag.split('-').next().and_then(supported))
+        else {
+            continue;
+        };
+
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((locale, quality));
+        }
+    }
+
+    best.map(|(locale, _)| locale)
+}
+
+/// A translatable string shown to users.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Message {
+    HomeTitle,
+    HomeGreeting,
+    ProfileTitle,
+    LanguageLabel,
+    PhoneLabel,
+    TimezoneLabel,
+    BioLabel,
+    AvatarUrlLabel,
+    Save,
+    SignedInAs,
+    UsernameLabel,
+    EmailLabel,
+    LastLoginLabel,
+    NeverLoggedIn,
+    ActiveSessionsLabel,
+    ImpersonatingNotice,
+    StopImpersonating,
+}
+
+impl Message {
+    pub(crate) fn translate(self, locale: &str) -> &'static str {
+        match (locale, self) {
+            ("fr", Message::HomeTitle) => "Accueil",
+            ("fr", Message::HomeGreeting) => "Bienvenue sur la page d'accueil !",
+            ("fr", Message::ProfileTitle) => "Profil",
+            ("fr", Message::LanguageLabel) => "Langue",
+            ("fr", Message::PhoneLabel) => "Téléphone (pour les codes de réinitialisation)",
+            ("fr", Message::TimezoneLabel) => "Fuseau horaire",
+            ("fr", Message::BioLabel) => "Présentation",
+            ("fr", Message::AvatarUrlLabel) => "Lien vers votre photo",
+            ("fr", Message::Save) => "Enregistrer",
+            ("fr", Message::SignedInAs) => "Connecté en tant que",
+            ("fr", Message::UsernameLabel) => "Nom d'utilisateur",
+            ("fr", Message::EmailLabel) => "Adresse e-mail",
+            ("fr", Message::LastLoginLabel) => "Dernière connexion",
+            ("fr", Message::NeverLoggedIn) => "jamais",
+            ("fr", Message::ActiveSessionsLabel) => "Sessions actives",
+            ("fr", Message::ImpersonatingNotice) => {
+                "Vous consultez le site en tant que cet utilisateur."
+            }
+            ("fr", Message::StopImpersonating) => "Revenir à mon compte",
+            (_, Message::HomeTitle) => "Home",
+            (_, Message::HomeGreeting) => "This is home!",
+            (_, Message::ProfileTitle) => "Profile",
+            (_, Message::LanguageLabel) => "Language",
+            (_, Message::PhoneLabel) => "Phone (for password reset codes)",
+            (_, Message::TimezoneLabel) => "Time zone",
+            (_, Message::BioLabel) => "Bio",
+            (_, Message::AvatarUrlLabel) => "Picture link",
+            (_, Message::Save) => "Save",
+            (_, Message::SignedInAs) => "Signed in as",
+            (_, Message::UsernameLabel) => "Username",
+            (_, Message::EmailLabel) => "Email",
+            (_, Message::LastLoginLabel) => "Last login",
+            (_, Message::NeverLoggedIn) => "never",
+            (_, Message::ActiveSessionsLabel) => "Active sessions",
+            (_, Message::ImpersonatingNotice) => "You are viewing the site as this user.",
+            (_, Message::StopImpersonating) => "Back to my account",
+        }
+    }
+}
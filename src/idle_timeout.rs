@@ -0,0 +1,134 @@
+//! Logging out sessions that have gone unused for too long.
+//!
+//! Every request from a logged-in user stamps the session with the time it was
+//! made. A request arriving more than `AppConfig::session_idle_timeout_secs`
+//! after the previous one is served logged out instead. Nothing is stamped
+//! while the timeout is off.
+
+use crate::config::app_config;
+use crate::sessions;
+use chrono::Utc;
+use cot::auth::Auth;
+use cot::request::{Request, RequestExt};
+use cot::response::Response;
+use cot::session::Session;
+use futures::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower::Service;
+use tracing::info;
+
+/// The session entry holding the Unix time of the session's last request.
+const LAST_SEEN_KEY: &str = "last_seen";
+
+fn idle_timeout_secs() -> Option<i64> {
+    match app_config().session_idle_timeout_secs {
+        0 => None,
+        secs => Some(i64::try_from(secs).unwrap_or(i64::MAX)),
+    }
+}
+
+async fn last_seen(session: &Session) -> cot::Result<Option<i64>> {
+    session.get(LAST_SEEN_KEY).await.map_err(cot::Error::wrap)
+}
+
+/// How many seconds the session can stay unused before it's logged out, or
+/// `None` if the timeout is off or the session was never stamped.
+pub(crate) async fn remaining_secs(session: &Session) -> cot::Result<Option<u64>> {
+    let Some(timeout) = idle_timeout_secs() else {
+        return Ok(None);
+    };
+    let Some(last_seen) = last_seen(session).await? else {
+        return Ok(None);
+    };
+    let idle = Utc::now().timestamp().saturating_sub(last_seen);
+    Ok(Some(
+        u64::try_from(timeout.saturating_sub(idle)).unwrap_or(0),
+    ))
+}
+
+/// Logs out idle sessions and stamps active ones.
+///
+/// Must be placed inside [`cot::middleware::AuthMiddleware`] so the request
+/// already carries the session and the logged-in user.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct IdleTimeoutMiddleware;
+
+impl<S> tower::Layer<S> for IdleTimeoutMiddleware {
+    type Service = IdleTimeoutService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IdleTimeoutService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IdleTimeoutService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for IdleTimeoutService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let auth = req
+                .extensions()
+                .get::<Auth>()
+                .expect("IdleTimeoutMiddleware must run inside AuthMiddleware");
+            if let Some(timeout) = idle_timeout_secs()
+                && auth.user().is_authenticated()
+            {
+                let session = Session::from_request(&req);
+                let now = Utc::now().timestamp();
+                match last_seen(session).await? {
+                    Some(last_seen) if now.saturating_sub(last_seen) > timeout => {
+                        sessions::forget(session, req.context().database()).await?;
+                        auth.logout().await?;
+                        info!("logged out an idle session");
+                    }
+                    _ => {
+                        session
+                            .insert(LAST_SEEN_KEY, now)
+                            .await
+                            .map_err(cot::Error::wrap)?;
+                    }
+                }
+            }
+
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::StatusCode;
+    use std::time::Duration;
+
+    #[cot::test]
+    async fn an_unused_session_times_out() {
+        let mut app = TestApp::with_config(|config| config.session_idle_timeout_secs = 1).await;
+        app.create_user("idle_timeout", PASSWORD).await;
+        app.login("idle_timeout", PASSWORD).await;
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Single-use invites staff send out when signup is invite-only.
+//!
+//! Only a hash of each invite's token is stored, so the signup links can't be
+//! rebuilt from the database.
+
+use crate::auth::normalize_email;
+use chrono::{DateTime, FixedOffset, Utc};
+use cot::common_types::Email;
+use cot::db::{Auto, Database, LimitedString, Model, model, query};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone)]
+#[model]
+pub(crate) struct Invite {
+    #[model(primary_key)]
+    id: Auto<i64>,
+    /// The address the invite was sent to; the account has to use it.
+    email: Email,
+    /// SHA-256 of the token in the signup link, hex-encoded.
+    #[model(unique)]
+    token_hash: LimitedString<64>,
+    /// The staff user who sent the invite.
+    created_by: i64,
+    created_at: DateTime<FixedOffset>,
+    /// When the invite was used to sign up, if it has been.
+    consumed_at: Option<DateTime<FixedOffset>>,
+}
+
+/// Makes the check, the signup and the marking of [`redeem`] happen as one
+/// step, so two signups racing with the same invite can't both use it. Other
+/// server processes aren't covered.
+static REDEEM_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+fn hash_token(token: &str) -> LimitedString<64> {
+    LimitedString::new(hex::encode(Sha256::digest(token)))
+        .expect("a SHA-256 hex digest is 64 characters")
+}
+
+/// Saves a new invite for `email` from staff user `created_by` and returns
+/// the token to put in the signup link.
+pub(crate) async fn create(db: &Database, email: &Email, created_by: i64) -> cot::Result<String> {
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    Invite {
+        id: Auto::auto(),
+        email: normalize_email(email),
+        token_hash: hash_token(&token),
+        created_by,
+        created_at: Utc::now().fixed_offset(),
+        consumed_at: None,
+    }
+    .save(db)
+    .await?;
+    Ok(token)
+}
+
+/// Runs `sign_up` if the invite with `token` can be used for a signup with
+/// `email`, and marks the invite used once it succeeds. Says why the invite
+/// can't be used otherwise, without running `sign_up`.
+///
+/// An invite whose signup fails, say because the account can't be saved, is
+/// left as it was so it can be tried again.
+pub(crate) async fn redeem<T>(
+    db: &Database,
+    token: Option<&str>,
+    email: &Email,
+    sign_up: impl AsyncFnOnce() -> cot::Result<T>,
+) -> cot::Result<Result<T, InviteError>> {
+    let Some(token) = token else {
+        return Ok(Err(InviteError::Missing));
+    };
+    let _guard = REDEEM_LOCK.lock().await;
+    let token_hash = hash_token(token);
+    let Some(mut invite) = query!(Invite, $token_hash == token_hash).get(db).await? else {
+        return Ok(Err(InviteError::Missing));
+    };
+    if invite.consumed_at.is_some() {
+        return Ok(Err(InviteError::Used));
+    }
+    if invite.email != normalize_email(email) {
+        return Ok(Err(InviteError::WrongEmail));
+    }
+
+    let signed_up = sign_up().await?;
+    invite.consumed_at = Some(Utc::now().fixed_offset());
+    invite.save(db).await?;
+    Ok(Ok(signed_up))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum InviteError {
+    /// There's no invite token, or no invite has it.
+    Missing,
+    Used,
+    /// The signup is for a different address than the invite was sent to.
+    WrongEmail,
+}
+
+impl Display for InviteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InviteError::Missing => write!(f, "signing up requires an invite."),
+            InviteError::Used => write!(f, "this invite has already been used."),
+            InviteError::WrongEmail => {
+                write!(f, "use the email address the invite was sent to.")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::migrate;
+    use cot::test::TestDatabase;
+
+    async fn database() -> TestDatabase {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        migrate(&test_db.database()).await.unwrap();
+        test_db
+    }
+
+    fn email(address: &str) -> Email {
+        Email::new(address).unwrap()
+    }
+
+    #[cot::test]
+    async fn an_invite_works_once() {
+        let test_db = database().await;
+        let db = test_db.database();
+        let token = create(&db, &email("invited@example.com"), 1).await.unwrap();
+
+        let first = redeem(
+            &db,
+            Some(&token),
+            &email("invited@example.com"),
+            async || Ok(1),
+        )
+        .await;
+        let second = redeem(
+            &db,
+            Some(&token),
+            &email("invited@example.com"),
+            async || Ok(2),
+        )
+        .await;
+
+        assert_eq!(first.unwrap(), Ok(1));
+        assert_eq!(second.unwrap(), Err(InviteError::Used));
+    }
+
+    #[cot::test]
+    async fn a_failed_signup_leaves_the_invite_usable() {
+        let test_db = database().await;
+        let db = test_db.database();
+        let token = create(&db, &email("retry@example.com"), 1).await.unwrap();
+
+        let failed = redeem(&db, Some(&token), &email("retry@example.com"), async || {
+            Err::<(), _>(cot::Error::internal("the account could not be saved"))
+        })
+        .await;
+        let retried = redeem(&db, Some(&token), &email("retry@example.com"), async || {
+            Ok(())
+        })
+        .await;
+
+        assert!(failed.is_err());
+        assert_eq!(retried.unwrap(), Ok(()));
+    }
+
+    #[cot::test]
+    async fn signup_does_not_run_without_a_usable_invite() {
+        let test_db = database().await;
+        let db = test_db.database();
+        let token = create(&db, &email("Invited@Example.com"), 1).await.unwrap();
+        let refused = async |token: Option<&str>, address: &str| {
+            redeem(&db, token, &email(address), async || -> cot::Result<()> {
+                panic!("the signup ran")
+            })
+            .await
+            .unwrap()
+        };
+
+        assert_eq!(
+            refused(None, "invited@example.com").await,
+            Err(InviteError::Missing)
+        );
+        assert_eq!(
+            refused(Some("not-a-token"), "invited@example.com").await,
+            Err(InviteError::Missing)
+        );
+        assert_eq!(
+            refused(Some(&token), "someone-else@example.com").await,
+            Err(InviteError::WrongEmail)
+        );
+    }
+}
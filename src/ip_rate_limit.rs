@@ -0,0 +1,211 @@
+//! Throttling requests to the auth endpoints by the IP address they came
+//! from, so no single client can flood them.
+//!
+//! Only pages that check credentials or send messages are counted: loading a
+//! page's stylesheet or browsing while logged in doesn't use up the budget.
+
+use crate::client_ip::ClientIp;
+use crate::config::app_config;
+use crate::rate_limit::{self, RateLimiter};
+use cot::http::{HeaderValue, header};
+use cot::request::{Request, RequestExt};
+use cot::response::{Response, ResponseExt};
+use cot::{Body, StatusCode};
+use futures::future::BoxFuture;
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::Service;
+use tracing::warn;
+
+static IP_LIMITER: LazyLock<Box<dyn RateLimiter>> = LazyLock::new(|| {
+    let config = &app_config().ip_rate_limit;
+    rate_limit::from_config(
+        "ip_request",
+        config.max_requests,
+        Duration::from_secs(config.window_secs),
+    )
+});
+
+/// The paths counted by [`IpRateLimitMiddleware`]. Each also covers the
+/// paths below it, such as `/reset/{token}/{uid}` for `/reset`.
+const AUTH_PATHS: &[&str] = &[
+    "/login",
+    "/signup",
+    "/change-password",
+    "/forgot-password",
+    "/reset",
+    "/verify-email",
+    "/magic-link",
+    "/magic",
+    "/api/username-available",
+    "/api/email-available",
+    "/api/password-strength",
+];
+
+/// Whether requests to `path` are counted. `/` is too, as it serves the login
+/// page.
+fn is_auth_path(path: &str) -> bool {
+    // trailing slashes aren't dealt with yet at this point
+    let path = path.trim_end_matches('/');
+    path.is_empty()
+        || AUTH_PATHS.iter().any(|auth_path| {
+            path.strip_prefix(auth_path)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    let mut response = Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        )
+        .body(Body::fixed("too many requests"))
+        .expect("the status and header are valid");
+    rate_limit::add_throttle_headers(response.headers_mut(), IP_LIMITER.limit(), retry_after);
+    response
+}
+
+/// Answers `429 Too Many Requests` to addresses that made more than
+/// `app.ip_rate_limit.max_requests` requests to the auth endpoints in the
+/// current window.
+///
+/// Requests whose address isn't known, and ones from
+/// `app.ip_rate_limit.trusted_ips`, are let through without being counted.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct IpRateLimitMiddleware;
+
+impl<S> tower::Layer<S> for IpRateLimitMiddleware {
+    type Service = IpRateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IpRateLimitService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IpRateLimitService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for IpRateLimitService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let config = &app_config().ip_rate_limit;
+            let client_ip = ClientIp::from_headers(req.headers());
+            if let ClientIp(Some(ip)) = client_ip
+                && config.max_requests > 0
+                && !config.trusted_ips.contains(&ip)
+                && is_auth_path(req.uri().path())
+                && let Err(retry_after) = IP_LIMITER
+                    .check(req.context().database(), &ip.to_string())
+                    .await?
+            {
+                warn!(event = "ip_rate_limited", %client_ip, "request rate limit exceeded");
+                return Ok(too_many_requests(retry_after));
+            }
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{TestApp, from_ip};
+    use cot::http::HeaderMap;
+
+    async fn app() -> TestApp {
+        TestApp::with_config(|config| config.behind_trusted_proxy = true).await
+    }
+
+    async fn get(app: &mut TestApp, path: &str, headers: HeaderMap) -> StatusCode {
+        app.send(cot::Method::GET, path, headers, Body::empty())
+            .await
+            .status
+    }
+
+    /// Uses up the budget of `ip`, which no other test may use.
+    async fn flood(app: &mut TestApp, ip: &str) {
+        for _ in 0..app_config().ip_rate_limit.max_requests {
+            assert_eq!(get(app, "/login", from_ip(ip)).await, StatusCode::OK);
+        }
+    }
+
+    #[cot::test]
+    async fn throttles_an_address_past_the_limit() {
+        let mut app = app().await;
+        flood(&mut app, "198.51.100.10").await;
+
+        let response = app
+            .send(
+                cot::Method::GET,
+                "/signup",
+                from_ip("198.51.100.10"),
+                Body::empty(),
+            )
+            .await;
+
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers.contains_key(header::RETRY_AFTER));
+        assert_eq!(
+            get(&mut app, "/login", from_ip("198.51.100.11")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[cot::test]
+    async fn leaves_other_pages_alone() {
+        let mut app = app().await;
+        flood(&mut app, "198.51.100.12").await;
+
+        assert_ne!(
+            get(&mut app, "/home", from_ip("198.51.100.12")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            get(&mut app, "/login", from_ip("198.51.100.12")).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn counts_the_auth_endpoints_only() {
+        for path in [
+            "/",
+            "/login",
+            "/login/",
+            "/reset/abc/1",
+            "/api/email-available",
+        ] {
+            assert!(is_auth_path(path), "{path}");
+        }
+        for path in [
+            "/home",
+            "/static/css/login.css",
+            "/loginx",
+            "/magical",
+            "/metrics",
+        ] {
+            assert!(!is_auth_path(path), "{path}");
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Sending emails in the background so handlers don't wait on the mail server.
+//!
+//! Messages are put on a bounded channel drained by a single worker task,
+//! which retries transport failures with exponential backoff. A message that
+//! still can't be sent after the last retry is logged and dropped.
+
+use crate::config::app_config;
+use cot::email::transport::TransportError;
+use cot::email::{Email as EmailService, EmailError, EmailMessage};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+type Job = (EmailService, EmailMessage);
+
+/// Spawned on first use, which is always from inside a request handler and
+/// therefore inside the runtime.
+static QUEUE: LazyLock<mpsc::Sender<Job>> = LazyLock::new(|| {
+    let (sender, receiver) = mpsc::channel(app_config().email_queue.capacity.max(1));
+    tokio::spawn(work(receiver));
+    sender
+});
+
+/// Hands `message` to the worker, or sends it right away when the queue is
+/// off or full.
+pub(crate) async fn send(email: EmailService, message: EmailMessage) -> cot::Result<()> {
+    #[cfg(test)]
+    crate::test_utils::record_email(&message);
+    if !app_config().email_queue.enabled {
+        email.send(message).await?;
+        return Ok(());
+    }
+
+    match QUEUE.try_send((email, message)) {
+        Ok(()) => Ok(()),
+        Err(mpsc::error::TrySendError::Full((email, message))) => {
+            warn!("email queue is full, sending inline");
+            deliver(&email, message).await?;
+            Ok(())
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => {
+            unreachable!("the worker holds the receiver for the lifetime of the process")
+        }
+    }
+}
+
+async fn work(mut receiver: mpsc::Receiver<Job>) {
+    while let Some((email, message)) = receiver.recv().await {
+        if let Err(err) = deliver(&email, message).await {
+            error!(error = %err, "giving up on sending an email");
+        }
+    }
+}
+
+async fn deliver(email: &EmailService, message: EmailMessage) -> Result<(), EmailError> {
+    let config = &app_config().email_queue;
+    let mut backoff = Duration::from_millis(config.backoff_ms);
+    let mut retries = 0;
+    loop {
+        match email.send(message.clone()).await {
+            Err(err) if retries < config.max_retries && is_transient(&err) => {
+                warn!(error = %err, retry = retries + 1, "retrying a failed email send");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `err` came from talking to the mail server rather than from a
+/// message that could never be sent.
+fn is_transient(err: &EmailError) -> bool {
+    matches!(err, EmailError::Transport(TransportError::Backend(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{self, AppConfig};
+    use cot::common_types::Email;
+    use cot::email::transport::{Transport, TransportResult};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::Semaphore;
+
+    /// Fails the first `failures` sends, then records the subjects it's sent.
+    /// Each send waits for a permit from `gate` first.
+    struct FakeTransport {
+        failures: u32,
+        attempts: Arc<AtomicU32>,
+        sent: Arc<Mutex<Vec<String>>>,
+        gate: Arc<Semaphore>,
+    }
+
+    impl FakeTransport {
+        fn new(failures: u32) -> Self {
+            Self {
+                failures,
+                attempts: Arc::default(),
+                sent: Arc::default(),
+                gate: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            }
+        }
+    }
+
+    impl Transport for FakeTransport {
+        async fn send(&self, messages: &[EmailMessage]) -> TransportResult<()> {
+            let _permit = self.gate.acquire().await.expect("the gate stays open");
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(TransportError::Backend("connection refused".into()));
+            }
+            let mut sent = self.sent.lock().unwrap();
+            sent.extend(messages.iter().map(|message| format!("{message:?}")));
+            Ok(())
+        }
+    }
+
+    fn set_retries(max_retries: u32) {
+        let mut app_config = AppConfig::default();
+        app_config.email_queue.max_retries = max_retries;
+        app_config.email_queue.backoff_ms = 1;
+        config::set_test_app_config(app_config);
+    }
+
+    fn message(subject: &str) -> EmailMessage {
+        EmailMessage::builder()
+            .from(Email::try_from("no-reply@example.com").unwrap())
+            .to(vec![Email::try_from("alice@example.com").unwrap()])
+            .subject(subject)
+            .body("hello")
+            .build()
+            .unwrap()
+    }
+
+    #[cot::test]
+    async fn queued_emails_are_sent_after_the_handler_moves_on() {
+        set_retries(0);
+        // held back until the test lets it through, as a slow mail server
+        let gate = Arc::new(Semaphore::new(0));
+        let transport = FakeTransport {
+            gate: gate.clone(),
+            ..FakeTransport::new(0)
+        };
+        let sent = transport.sent.clone();
+        // a worker of its own, as the global queue's would outlive this test's
+        // runtime
+        let (sender, receiver) = mpsc::channel(1);
+        let worker = tokio::spawn(work(receiver));
+
+        sender
+            .try_send((EmailService::new(transport), message("queued")))
+            .expect("the queue has room");
+        tokio::task::yield_now().await;
+        assert!(sent.lock().unwrap().is_empty());
+
+        gate.add_permits(1);
+        drop(sender);
+        worker.await.unwrap();
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].contains("queued"));
+    }
+
+    #[cot::test]
+    async fn transient_failures_are_retried() {
+        set_retries(3);
+        let transport = FakeTransport::new(2);
+        let (attempts, sent) = (transport.attempts.clone(), transport.sent.clone());
+
+        deliver(&EmailService::new(transport), message("retried"))
+            .await
+            .unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[cot::test]
+    async fn gives_up_after_the_last_retry() {
+        set_retries(2);
+        let transport = FakeTransport::new(u32::MAX);
+        let attempts = transport.attempts.clone();
+
+        let result = deliver(&EmailService::new(transport), message("dropped")).await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
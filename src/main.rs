@@ -1,27 +1,73 @@
+mod admin;
+mod api;
+mod audit;
 mod auth;
+mod breach;
+mod client_ip;
+mod compression;
+mod config;
+mod csrf;
+mod debug;
 mod forms;
+mod https;
+mod i18n;
+mod idle_timeout;
+mod invites;
+mod ip_rate_limit;
+mod mail_queue;
 mod migrations;
+mod page;
+mod password_hashing;
+mod password_history;
+mod password_strength;
+mod profile_attributes;
+mod rate_limit;
+mod render;
+mod request_timing;
+mod reset_codes;
+mod sessions;
+mod sms;
+mod static_cache;
+mod tasks;
+mod telemetry;
+#[cfg(test)]
+mod test_utils;
+mod trailing_slash;
 mod utils;
 
 use std::sync::Arc;
 
-use crate::forms::forgot_password::{forgot_password, reset_password_confirm};
+use crate::forms::forgot_password::{forgot_password, password_reset_done, reset_password_confirm};
+use crate::profile_attributes::Profile;
+use crate::render::render;
 use auth::UserBackend;
-use cot::auth::AuthBackend;
 use cot::auth::db::DatabaseUserApp;
-use cot::cli::CliMetadata;
+use cot::auth::{Auth, AuthBackend};
+use cot::cli::{Cli, CliMetadata};
+use cot::config::ProjectConfig;
+use cot::db::Database;
 use cot::db::migrations::SyncDynMigration;
+use cot::error::handler::{DynErrorPageHandler, RequestError, RequestOuterError};
 use cot::middleware::{AuthMiddleware, LiveReloadMiddleware, SessionMiddleware};
 use cot::project::{
     AuthBackendContext, MiddlewareContext, RootHandler, RootHandlerBuilder, WithConfig,
 };
 use cot::request::Request;
-use cot::response::{Response, ResponseExt};
-use cot::router::{Route, Router};
+use cot::request::extractors::StaticFiles;
+use cot::response::{IntoResponse, Response};
+use cot::router::{Route, Router, Urls};
+use cot::session::Session;
+use cot::session::db::SessionApp;
 use cot::static_files::{StaticFile, StaticFilesMiddleware};
-use cot::{App, AppBuilder, Body, Project, ProjectContext, StatusCode, Template, static_files};
+use cot::{App, AppBuilder, Project, ProjectContext, StatusCode, Template, static_files};
+use forms::change_password::change_password;
 use forms::login::login;
+use forms::logout::logout;
+use forms::magic_link::{magic_link, magic_login};
+use forms::profile::profile;
 use forms::signup::signup;
+use forms::sms_reset::{sms_reset_confirm, sms_reset_request};
+use forms::verify_email::{verify_email, verify_email_resend, verify_pending};
 
 #[derive(Debug, Template)]
 #[template(path = "index.html")]
@@ -29,29 +75,119 @@ struct IndexTemplate {}
 
 #[derive(Debug, Template)]
 #[template(path = "home.html")]
-struct HomeTemplate {}
+struct HomeTemplate<'a> {
+    urls: &'a Urls,
+    locale: &'static str,
+    /// Whether staff are looking at the page as this user.
+    impersonating: bool,
+    display_name: &'a str,
+    username: &'a str,
+    email: &'a str,
+    /// When the user last logged in, formatted for display in their time
+    /// zone, or UTC if they haven't set one.
+    last_login: Option<String>,
+    bio: Option<&'a str>,
+    avatar_url: Option<String>,
+    /// How many sessions the user is logged in with, this one included.
+    session_count: u64,
+}
 
 #[expect(unused)]
 async fn index(_request: Request) -> cot::Result<Response> {
     let index_template = IndexTemplate {};
-    let rendered = index_template.render()?;
+    render(&index_template)
+}
+
+/// The logged-in user's dashboard. Anonymous visitors are sent to the login
+/// page.
+async fn home(
+    urls: Urls,
+    auth: Auth,
+    db: Database,
+    session: Session,
+    request: Request,
+) -> cot::Result<Response> {
+    let Some(user) = auth::current_user(&auth, &db).await? else {
+        return Ok(cot::reverse_redirect!(urls, "login")?);
+    };
+    if user.must_change_password() {
+        return Ok(cot::reverse_redirect!(urls, "change_password")?);
+    }
+    let user_id = user.id().expect("user loaded from the database has an id");
+    let profile = Profile::load(&db, user_id).await?;
+    let home_template = HomeTemplate {
+        urls: &urls,
+        locale: i18n::resolve(user.locale(), request.headers()),
+        impersonating: admin::impersonator_id(&session).await?.is_some(),
+        display_name: user.display_name(),
+        username: user.username(),
+        email: user.email().as_str(),
+        last_login: user
+            .last_login()
+            .map(|last_login| match profile.timezone() {
+                Some(tz) => last_login
+                    .with_timezone(&tz)
+                    .format("%Y-%m-%d %H:%M %Z")
+                    .to_string(),
+                None => last_login.to_utc().format("%Y-%m-%d %H:%M UTC").to_string(),
+            }),
+        bio: profile.bio(),
+        avatar_url: profile.avatar_url().map(String::from),
+        session_count: sessions::count(&db, user_id).await?,
+    };
+    render(&home_template)
+}
+
+#[derive(Debug, Template)]
+#[template(path = "404.html")]
+struct NotFoundTemplate {
+    static_files: StaticFiles,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate {
+    static_files: StaticFiles,
+    status: StatusCode,
+    /// The error and its causes, outermost first; empty unless
+    /// `AppConfig::error_details` is on.
+    details: Vec<String>,
+}
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::fixed(rendered))
-        .unwrap();
-    Ok(response)
+/// Lists `error` and everything in its source chain.
+fn error_chain(error: &cot::Error) -> Vec<String> {
+    let mut details = vec![error.to_string()];
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        details.push(cause.to_string());
+        source = cause.source();
+    }
+    details.dedup();
+    details
 }
 
-async fn home(_request: Request) -> cot::Result<Response> {
-    let home_template = HomeTemplate {};
-    let rendered = home_template.render()?;
+async fn error_page(
+    error: RequestError,
+    outer_error: RequestOuterError,
+    static_files: StaticFiles,
+) -> cot::Result<impl IntoResponse> {
+    let status = error.status_code();
+    let response = if status == StatusCode::NOT_FOUND {
+        render(&NotFoundTemplate { static_files })?
+    } else {
+        let details = if config::app_config().error_details {
+            error_chain(&outer_error)
+        } else {
+            Vec::new()
+        };
+        render(&ErrorTemplate {
+            static_files,
+            status,
+            details,
+        })?
+    };
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .body(Body::fixed(rendered))
-        .unwrap();
-    Ok(response)
+    Ok(response.with_status(status))
 }
 
 struct AuthApp;
@@ -65,14 +201,82 @@ impl App for AuthApp {
         Router::with_urls([
             Route::with_handler_and_name("/", login, "login"),
             Route::with_handler_and_name("/login", login, "login"),
+            Route::with_handler_and_name("/logout", logout, "logout"),
             Route::with_handler_and_name("/home", home, "home"),
             Route::with_handler_and_name("/signup", signup, "signup"),
+            Route::with_handler_and_name("/profile", profile, "profile"),
+            Route::with_handler_and_name("/change-password", change_password, "change_password"),
             Route::with_handler_and_name("/forgot-password", forgot_password, "forgot_password"),
             Route::with_handler_and_name(
                 "/reset/{token}/{uid}",
                 reset_password_confirm,
                 "reset_password_confirm",
             ),
+            Route::with_handler_and_name(
+                "/verify-email",
+                verify_email_resend,
+                "verify_email_resend",
+            ),
+            Route::with_handler_and_name("/verify-pending", verify_pending, "verify_pending"),
+            Route::with_handler_and_name(
+                "/verify-email/{token}/{uid}",
+                verify_email,
+                "verify_email",
+            ),
+            Route::with_handler_and_name(
+                "/forgot-password/sms",
+                sms_reset_request,
+                "sms_reset_request",
+            ),
+            Route::with_handler_and_name(
+                "/forgot-password/sms/confirm",
+                sms_reset_confirm,
+                "sms_reset_confirm",
+            ),
+            Route::with_handler_and_name(
+                "/password-reset-done",
+                password_reset_done,
+                "password_reset_done",
+            ),
+            Route::with_handler_and_name("/magic-link", magic_link, "magic_link"),
+            Route::with_handler_and_name("/magic/{token}/{uid}", magic_login, "magic_login"),
+            Route::with_handler_and_name(
+                "/api/username-available",
+                api::username_available,
+                "username_available",
+            ),
+            Route::with_handler_and_name(
+                "/api/email-available",
+                api::email_available,
+                "email_available",
+            ),
+            Route::with_handler_and_name(
+                "/admin/users/{id}/impersonate",
+                admin::impersonate,
+                "impersonate",
+            ),
+            Route::with_handler_and_name(
+                "/admin/users/{id}/anonymize",
+                admin::anonymize,
+                "anonymize_user",
+            ),
+            Route::with_handler_and_name("/admin/users/{id}/staff", admin::set_staff, "set_staff"),
+            Route::with_handler_and_name("/admin/invites", admin::create_invite, "create_invite"),
+            Route::with_handler_and_name(
+                "/admin/stop-impersonating",
+                admin::stop_impersonating,
+                "stop_impersonating",
+            ),
+            Route::with_handler_and_name(
+                "/api/password-strength",
+                api::password_strength,
+                "password_strength",
+            ),
+            Route::with_handler_and_name("/api/logout", api::logout, "api_logout"),
+            Route::with_handler_and_name("/api/logout-all", api::logout_all, "api_logout_all"),
+            Route::with_handler_and_name("/api/session/ping", api::session_ping, "session_ping"),
+            Route::with_handler_and_name("/metrics", telemetry::metrics_endpoint, "metrics"),
+            Route::with_handler_and_name("/debug/routes", debug::routes, "debug_routes"),
         ])
     }
 
@@ -92,28 +296,322 @@ impl Project for AuthProject {
         cot::cli::metadata!()
     }
 
+    fn config(&self, config_name: &str) -> cot::Result<ProjectConfig> {
+        let config_content = config::read_config_file(config_name)?;
+        config::load_app_config(&config_content)?;
+        // logging is set up here rather than in `main` as its format comes
+        // from the config
+        telemetry::init_logging(config::app_config().log_format);
+        let project_config = ProjectConfig::from_toml(&config_content)?;
+        config::validate_secret_keys(&project_config)?;
+        Ok(project_config)
+    }
+
+    fn register_tasks(&self, cli: &mut Cli) {
+        cli.add_task(tasks::RehashPasswords);
+        cli.add_task(tasks::DisableStaleAccounts);
+        cli.add_task(tasks::ForcePasswordReset);
+        cli.add_task(tasks::SetStaff);
+        cli.add_task(tasks::ForceLogout);
+        cli.add_task(tasks::ImportUsers);
+        cli.add_task(tasks::DuplicateEmails);
+        cli.add_task(tasks::NormalizeUsernames);
+    }
+
     fn register_apps(&self, apps: &mut AppBuilder, _context: &ProjectContext<WithConfig>) {
         apps.register(DatabaseUserApp::new());
+        // provides the table for `middlewares.session.store.type = "database"`
+        apps.register(SessionApp::new());
         apps.register_with_views(AuthApp, "");
     }
 
     fn auth_backend(&self, context: &AuthBackendContext) -> Arc<dyn AuthBackend> {
         let db = context.database().clone();
-        let backend = Arc::new(UserBackend::new(db)) as Arc<dyn AuthBackend>;
-        backend
+        Arc::new(UserBackend::new(db)) as Arc<dyn AuthBackend>
+    }
+
+    fn error_handler(&self) -> DynErrorPageHandler {
+        DynErrorPageHandler::new(error_page)
     }
 
     fn middlewares(&self, handler: RootHandlerBuilder, context: &MiddlewareContext) -> RootHandler {
+        // each middleware wraps the ones added before it, so requests pass
+        // through this list bottom to top. The session middleware has to come
+        // after (outside) `AuthMiddleware`, which reads the session, and the
+        // idle timeout and session limit checks need both.
         handler
             .middleware(StaticFilesMiddleware::from_context(context))
+            .middleware(static_cache::StaticCacheMiddleware)
+            .middleware(idle_timeout::IdleTimeoutMiddleware)
+            .middleware(sessions::SessionLimitMiddleware)
             .middleware(AuthMiddleware::new())
             .middleware(SessionMiddleware::from_context(context))
-            .middleware(LiveReloadMiddleware::new())
+            .middleware(https::HttpsMiddleware)
+            .middleware(LiveReloadMiddleware::from_context(context))
+            .middleware(trailing_slash::TrailingSlashMiddleware)
+            .middleware(csrf::CsrfMiddleware)
+            .middleware(ip_rate_limit::IpRateLimitMiddleware)
+            .middleware(compression::CompressionMiddleware)
+            .middleware(request_timing::RequestTimingMiddleware)
             .build()
     }
 }
 
 #[cot::main]
 fn main() -> impl Project {
+    telemetry::install();
+
     AuthProject
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{AppConfig, app_config, set_test_app_config};
+    use crate::test_utils::{PASSWORD, TestApp, migrate};
+    use cot::App;
+    use cot::StatusCode;
+    use cot::config::{SessionStoreConfig, SessionStoreTypeConfig};
+    use cot::db::migrations::MigrationEngine;
+    use cot::db::{Column, Database, Identifier, Model};
+    use cot::html::Html;
+    use cot::router::{Route, Router};
+    use cot::session::db::SessionApp;
+    use sqlx::Connection;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// Goes through every middleware in `AuthProject::middlewares`, so it
+    /// fails if they're put in an order where the session isn't there for
+    /// `AuthMiddleware`, or static files stop being served.
+    #[cot::test]
+    async fn logs_in_through_the_whole_middleware_chain() {
+        let mut app = TestApp::new().await;
+        app.create_user("middleware_chain", PASSWORD).await;
+
+        let login_page = app.get("/login").await;
+        let stylesheet = login_page
+            .body
+            .split("href=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("the login page links its stylesheet");
+        assert_eq!(app.get(stylesheet).await.status, StatusCode::OK);
+
+        let response = app.login("middleware_chain", PASSWORD).await;
+        assert_eq!(response.status, StatusCode::SEE_OTHER);
+        assert!(response.headers.contains_key("set-cookie"));
+
+        let home = app.get("/home").await;
+        assert_eq!(home.status, StatusCode::OK);
+        assert!(home.body.contains("middleware_chain"));
+
+        app.post("/logout", &[]).await;
+        let home = app.get("/home").await;
+        assert_ne!(home.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn pages_are_sent_as_utf8_html() {
+        let mut app = TestApp::new().await;
+
+        let response = app.get("/login").await;
+
+        assert_eq!(
+            response.headers[cot::http::header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[cot::test]
+    async fn unknown_paths_get_the_not_found_page() {
+        let mut app = TestApp::with_project_config(|config| config.debug = false).await;
+
+        let response = app.get("/no/such/page").await;
+
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert!(
+            response
+                .body
+                .contains("<title>Page Not Found | Cot Auth</title>")
+        );
+    }
+
+    async fn fail() -> cot::Result<Html> {
+        Err(cot::Error::wrap(std::io::Error::other(
+            "the database is on fire",
+        )))
+    }
+
+    #[cot::test]
+    async fn internal_errors_only_show_details_when_configured() {
+        let mut app = TestApp::with_routes(
+            Router::with_urls([Route::with_handler_and_name("/fail", fail, "fail")]),
+            |config| config.debug = false,
+        )
+        .await;
+
+        let generic = app.get("/test/fail").await;
+        set_test_app_config(AppConfig {
+            error_details: true,
+            ..app_config().clone()
+        });
+        let detailed = app.get("/test/fail").await;
+
+        assert_eq!(generic.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(generic.body.contains("Something went wrong"));
+        assert!(!generic.body.contains("error-details"));
+        assert!(!generic.body.contains("the database is on fire"));
+        assert_eq!(detailed.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(detailed.body.contains("<pre>the database is on fire</pre>"));
+    }
+
+    #[cot::test]
+    async fn home_shows_the_logged_in_user_their_dashboard() {
+        let mut app = TestApp::new().await;
+        app.create_user("dashboard", PASSWORD).await;
+        app.login("dashboard", PASSWORD).await;
+        let first_browser = app.swap_cookies(BTreeMap::new());
+        app.login("dashboard", PASSWORD).await;
+
+        let body = app.get("/home").await.body;
+
+        assert!(body.contains("<dd>dashboard</dd>"), "{body}");
+        assert!(body.contains("<dd>dashboard@example.com</dd>"), "{body}");
+        assert!(!body.contains("<dd>never</dd>"), "{body}");
+        assert!(body.contains("UTC</dd>"), "{body}");
+        assert!(body.contains("<dd>2</dd>"), "{body}");
+        app.swap_cookies(first_browser);
+        assert!(app.get("/home").await.body.contains("<dd>dashboard</dd>"));
+    }
+
+    #[cot::test]
+    async fn home_sends_anonymous_visitors_to_log_in() {
+        let mut app = TestApp::new().await;
+
+        let response = app.get("/home").await;
+
+        assert!(response.status.is_redirection());
+        assert_eq!(response.location(), Some("/login"));
+    }
+
+    #[cot::test]
+    async fn live_reload_is_only_injected_when_enabled() {
+        let mut off = TestApp::new().await;
+        let mut on = TestApp::with_project_config(|config| {
+            config.middlewares.live_reload.enabled = true;
+        })
+        .await;
+
+        let without = off.get("/login").await.body;
+        let with = on.get("/login").await.body;
+
+        // off unless the config turns it on, as only `config/dev.toml` does
+        assert!(!without.contains("tower-livereload"));
+        assert!(with.contains("tower-livereload"));
+    }
+
+    #[cot::test]
+    async fn sessions_can_be_kept_in_the_database() {
+        let mut app = TestApp::with_project_config(|config| {
+            config.middlewares.session.store = SessionStoreConfig {
+                store_type: SessionStoreTypeConfig::Database,
+            };
+        })
+        .await;
+        MigrationEngine::new(SessionApp::new().migrations())
+            .unwrap()
+            .run(app.db())
+            .await
+            .unwrap();
+        app.create_user("db_session", PASSWORD).await;
+        app.login("db_session", PASSWORD).await;
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+
+        // nothing but the table keeps the session once its row is gone
+        let deleted = app.db().raw(r#"DELETE FROM "cot__session""#).await.unwrap();
+
+        assert!(deleted.rows_affected().0 > 0);
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+    }
+
+    /// The columns of every table once all the migrations are applied, as
+    /// SQLite reports them.
+    struct MigratedSchema {
+        connection: sqlx::SqliteConnection,
+        path: std::path::PathBuf,
+    }
+
+    impl MigratedSchema {
+        async fn new() -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("cot-auth-schema-{}.sqlite3", std::process::id()));
+            let url = format!("sqlite://{}?mode=rwc", path.display());
+            let db = Database::new(url.as_str())
+                .await
+                .expect("the database can be created");
+            migrate(&db).await.expect("the migrations apply");
+            db.close().await.expect("the database closes");
+
+            let connection = sqlx::SqliteConnection::connect(&url)
+                .await
+                .expect("the database can be opened");
+            Self { connection, path }
+        }
+
+        async fn columns(&mut self, table: Identifier) -> BTreeSet<Column> {
+            sqlx::query_scalar::<_, String>("SELECT name FROM pragma_table_info(?)")
+                .bind(table.as_str())
+                .fetch_all(&mut self.connection)
+                .await
+                .expect("the table info can be read")
+                .into_iter()
+                // `Column` only holds static names; this is a test process
+                .map(|name| Column::new(Identifier::new(name.leak())))
+                .collect()
+        }
+
+        async fn assert_matches<T: Model>(&mut self) {
+            let migrated = self.columns(T::TABLE_NAME).await;
+            let model: BTreeSet<Column> = T::COLUMNS.iter().copied().collect();
+            assert_eq!(
+                model,
+                migrated,
+                "`{}` doesn't have the columns its model does; write a migration with \
+                 `cot migration make` and add it to `MIGRATIONS`",
+                T::TABLE_NAME
+            );
+        }
+    }
+
+    impl Drop for MigratedSchema {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    /// Fails when a `#[model]` struct gains or loses a field without a
+    /// migration to match. Only column names are compared: Cot doesn't
+    /// expose the types the models expect.
+    #[cot::test]
+    async fn the_migrations_match_the_models() {
+        let mut schema = MigratedSchema::new().await;
+
+        schema.assert_matches::<crate::auth::User>().await;
+        schema.assert_matches::<crate::audit::AuditEntry>().await;
+        schema.assert_matches::<crate::invites::Invite>().await;
+        schema
+            .assert_matches::<crate::password_history::PasswordHistory>()
+            .await;
+        schema
+            .assert_matches::<crate::profile_attributes::UserAttribute>()
+            .await;
+        schema
+            .assert_matches::<crate::rate_limit::RateLimitBucket>()
+            .await;
+        schema
+            .assert_matches::<crate::reset_codes::ResetCode>()
+            .await;
+        schema
+            .assert_matches::<crate::sessions::UserSession>()
+            .await;
+    }
+}
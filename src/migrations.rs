@@ -3,5 +3,91 @@
 //! Generated by cot CLI 0.3.1 on 2025-07-28 18:12:43+00:00
 
 pub mod m_0001_initial;
+pub mod m_0002_user_locale;
+pub mod m_0003_user_activity;
+pub mod m_0004_password_changed_at;
+pub mod m_0005_must_change_password;
+pub mod m_0006_has_usable_password;
+pub mod m_0007_user_session;
+pub mod m_0008_user_is_staff;
+pub mod m_0009_password_history;
+pub mod m_0010_user_updated_at;
+pub mod m_0011_audit_entry;
+pub mod m_0012_rate_limit_bucket;
+pub mod m_0013_invite;
+pub mod m_0014_user_email_verified_at;
+pub mod m_0015_user_session_salt;
+pub mod m_0016_user_phone;
+pub mod m_0017_reset_code;
+pub mod m_0018_user_attribute;
+pub mod m_0019_user_is_superuser;
+pub mod m_0020_user_email_unique;
+pub mod m_0021_reset_code_locked_until;
 /// The list of migrations for current app.
-pub const MIGRATIONS: &[&::cot::db::migrations::SyncDynMigration] = &[&m_0001_initial::Migration];
+pub const MIGRATIONS: &[&::cot::db::migrations::SyncDynMigration] = &[
+    &m_0001_initial::Migration,
+    &m_0002_user_locale::Migration,
+    &m_0003_user_activity::Migration,
+    &m_0004_password_changed_at::Migration,
+    &m_0005_must_change_password::Migration,
+    &m_0006_has_usable_password::Migration,
+    &m_0007_user_session::Migration,
+    &m_0008_user_is_staff::Migration,
+    &m_0009_password_history::Migration,
+    &m_0010_user_updated_at::Migration,
+    &m_0011_audit_entry::Migration,
+    &m_0012_rate_limit_bucket::Migration,
+    &m_0013_invite::Migration,
+    &m_0014_user_email_verified_at::Migration,
+    &m_0015_user_session_salt::Migration,
+    &m_0016_user_phone::Migration,
+    &m_0017_reset_code::Migration,
+    &m_0018_user_attribute::Migration,
+    &m_0019_user_is_superuser::Migration,
+    &m_0020_user_email_unique::Migration,
+    &m_0021_reset_code_locked_until::Migration,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::User;
+    use cot::common_types::{Email, Password};
+    use cot::db::Model;
+    use cot::db::migrations::{MigrationEngine, wrap_migrations};
+    use cot::test::TestDatabase;
+
+    #[cot::test]
+    async fn email_index_refuses_shared_addresses() {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        let before_index = MIGRATIONS
+            .iter()
+            .position(|migration| migration.name() == "m_0020_user_email_unique")
+            .unwrap();
+        MigrationEngine::new(wrap_migrations(&MIGRATIONS[..before_index]))
+            .unwrap()
+            .run(&db)
+            .await
+            .unwrap();
+        for username in ["first", "second"] {
+            User::builder()
+                .username(username)
+                .password(&Password::new("unused"))
+                .email(Email::new("shared@example.com").unwrap())
+                .build()
+                .unwrap()
+                .save(&db)
+                .await
+                .unwrap();
+        }
+
+        let err = MigrationEngine::new(wrap_migrations(MIGRATIONS))
+            .unwrap()
+            .run(&db)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("auth duplicate-emails"));
+    }
+}
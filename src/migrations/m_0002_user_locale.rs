@@ -0,0 +1,37 @@
+//! Generated by cot CLI 0.3.1 on 2025-08-04 09:21:17+00:00
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0002_user_locale";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0001_initial",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::add_field()
+            .table_name(::cot::db::Identifier::new("auth__user"))
+            .field(
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("locale"),
+                    <Option<String> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<Option<String> as ::cot::db::DatabaseField>::NULLABLE),
+            )
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _User {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    #[model(unique)]
+    username: cot::db::LimitedString<254>,
+    name: cot::db::LimitedString<254>,
+    password: cot::auth::PasswordHash,
+    email: cot::common_types::Email,
+    locale: Option<String>,
+}
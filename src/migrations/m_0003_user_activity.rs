@@ -0,0 +1,79 @@
+//! Adds the columns used to track account activity.
+//!
+//! `is_active` is added with raw SQL because SQLite can't add a `NOT NULL`
+//! column without a default, and existing accounts should stay active.
+
+use cot::db::migrations::{MigrationContext, migration_op};
+
+#[migration_op]
+async fn add_is_active(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" ADD COLUMN "is_active" boolean NOT NULL DEFAULT TRUE"#)
+        .await?;
+    Ok(())
+}
+
+#[migration_op]
+async fn remove_is_active(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" DROP COLUMN "is_active""#)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0003_user_activity";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0002_user_locale",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] = &[
+        ::cot::db::migrations::Operation::custom(add_is_active)
+            .backwards(remove_is_active)
+            .build(),
+        ::cot::db::migrations::Operation::add_field()
+            .table_name(::cot::db::Identifier::new("auth__user"))
+            .field(
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("last_login"),
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+            )
+            .build(),
+        ::cot::db::migrations::Operation::add_field()
+            .table_name(::cot::db::Identifier::new("auth__user"))
+            .field(
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("created_at"),
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+            )
+            .build(),
+    ];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _User {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    #[model(unique)]
+    username: cot::db::LimitedString<254>,
+    name: cot::db::LimitedString<254>,
+    password: cot::auth::PasswordHash,
+    email: cot::common_types::Email,
+    locale: Option<String>,
+    is_active: bool,
+    last_login: Option<chrono::DateTime<chrono::FixedOffset>>,
+    created_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
@@ -0,0 +1,57 @@
+//! Adds the flag staff use to force a password change on next login.
+//!
+//! Like `is_active`, the column is added with raw SQL so it can be `NOT NULL`
+//! with a default for the existing rows.
+
+use cot::db::migrations::{MigrationContext, migration_op};
+
+#[migration_op]
+async fn add_must_change_password(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" ADD COLUMN "must_change_password" boolean NOT NULL DEFAULT FALSE"#)
+        .await?;
+    Ok(())
+}
+
+#[migration_op]
+async fn remove_must_change_password(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" DROP COLUMN "must_change_password""#)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0005_must_change_password";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0004_password_changed_at",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] = &[
+        ::cot::db::migrations::Operation::custom(add_must_change_password)
+            .backwards(remove_must_change_password)
+            .build(),
+    ];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _User {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    #[model(unique)]
+    username: cot::db::LimitedString<254>,
+    name: cot::db::LimitedString<254>,
+    password: cot::auth::PasswordHash,
+    email: cot::common_types::Email,
+    locale: Option<String>,
+    is_active: bool,
+    last_login: Option<chrono::DateTime<chrono::FixedOffset>>,
+    created_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    password_changed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    must_change_password: bool,
+}
@@ -0,0 +1,58 @@
+//! Adds the flag marking accounts whose password can't be used to log in.
+//!
+//! Every existing account was created with a real password, so the column
+//! defaults to `TRUE`.
+
+use cot::db::migrations::{MigrationContext, migration_op};
+
+#[migration_op]
+async fn add_has_usable_password(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" ADD COLUMN "has_usable_password" boolean NOT NULL DEFAULT TRUE"#)
+        .await?;
+    Ok(())
+}
+
+#[migration_op]
+async fn remove_has_usable_password(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" DROP COLUMN "has_usable_password""#)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0006_has_usable_password";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0005_must_change_password",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] = &[
+        ::cot::db::migrations::Operation::custom(add_has_usable_password)
+            .backwards(remove_has_usable_password)
+            .build(),
+    ];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _User {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    #[model(unique)]
+    username: cot::db::LimitedString<254>,
+    name: cot::db::LimitedString<254>,
+    password: cot::auth::PasswordHash,
+    email: cot::common_types::Email,
+    locale: Option<String>,
+    is_active: bool,
+    last_login: Option<chrono::DateTime<chrono::FixedOffset>>,
+    created_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    password_changed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    must_change_password: bool,
+    has_usable_password: bool,
+}
@@ -0,0 +1,55 @@
+//! Adds the table tracking which sessions each user is logged in with.
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0007_user_session";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0006_has_usable_password",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::create_model()
+            .table_name(::cot::db::Identifier::new("auth__user_session"))
+            .fields(&[
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("id"),
+                    <cot::db::Auto<i64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .auto()
+                .primary_key()
+                .set_null(<cot::db::Auto<i64> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("user_id"),
+                    <i64 as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<i64 as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("key"),
+                    <cot::db::LimitedString<64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<cot::db::LimitedString<64> as ::cot::db::DatabaseField>::NULLABLE)
+                .unique(),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("created_at"),
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+            ])
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _UserSession {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    user_id: i64,
+    #[model(unique)]
+    key: cot::db::LimitedString<64>,
+    created_at: chrono::DateTime<chrono::FixedOffset>,
+}
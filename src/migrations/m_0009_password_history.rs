@@ -0,0 +1,53 @@
+//! Adds the table of users' previous password hashes.
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0009_password_history";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0008_user_is_staff",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::create_model()
+            .table_name(::cot::db::Identifier::new("auth__password_history"))
+            .fields(&[
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("id"),
+                    <cot::db::Auto<i64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .auto()
+                .primary_key()
+                .set_null(<cot::db::Auto<i64> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("user_id"),
+                    <i64 as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<i64 as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("password"),
+                    <cot::auth::PasswordHash as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<cot::auth::PasswordHash as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("created_at"),
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+            ])
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _PasswordHistory {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    user_id: i64,
+    password: cot::auth::PasswordHash,
+    created_at: chrono::DateTime<chrono::FixedOffset>,
+}
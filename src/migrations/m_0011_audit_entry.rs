@@ -0,0 +1,59 @@
+//! Adds the audit log of staff actions.
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0011_audit_entry";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0010_user_updated_at",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::create_model()
+            .table_name(::cot::db::Identifier::new("auth__audit_entry"))
+            .fields(&[
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("id"),
+                    <cot::db::Auto<i64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .auto()
+                .primary_key()
+                .set_null(<cot::db::Auto<i64> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("actor_id"),
+                    <i64 as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<i64 as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("action"),
+                    <cot::db::LimitedString<64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<cot::db::LimitedString<64> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("target_id"),
+                    <Option<i64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<Option<i64> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("created_at"),
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+            ])
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _AuditEntry {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    actor_id: i64,
+    action: cot::db::LimitedString<64>,
+    target_id: Option<i64>,
+    created_at: chrono::DateTime<chrono::FixedOffset>,
+}
@@ -0,0 +1,61 @@
+//! Adds the table of database-backed rate limit counters.
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0012_rate_limit_bucket";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0011_audit_entry",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::create_model()
+            .table_name(::cot::db::Identifier::new("auth__rate_limit_bucket"))
+            .fields(&[
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("id"),
+                    <cot::db::Auto<i64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .auto()
+                .primary_key()
+                .set_null(<cot::db::Auto<i64> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("scope"),
+                    <cot::db::LimitedString<32> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<cot::db::LimitedString<32> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("key"),
+                    <cot::db::LimitedString<64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<cot::db::LimitedString<64> as ::cot::db::DatabaseField>::NULLABLE)
+                .unique(),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("window_started_at"),
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("hits"),
+                    <i64 as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<i64 as ::cot::db::DatabaseField>::NULLABLE),
+            ])
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _RateLimitBucket {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    scope: cot::db::LimitedString<32>,
+    #[model(unique)]
+    key: cot::db::LimitedString<64>,
+    window_started_at: chrono::DateTime<chrono::FixedOffset>,
+    hits: i64,
+}
@@ -0,0 +1,69 @@
+//! Adds the table of signup invites.
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0013_invite";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0012_rate_limit_bucket",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::create_model()
+            .table_name(::cot::db::Identifier::new("auth__invite"))
+            .fields(&[
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("id"),
+                    <cot::db::Auto<i64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .auto()
+                .primary_key()
+                .set_null(<cot::db::Auto<i64> as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("email"),
+                    <cot::common_types::Email as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<cot::common_types::Email as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("token_hash"),
+                    <cot::db::LimitedString<64> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<cot::db::LimitedString<64> as ::cot::db::DatabaseField>::NULLABLE)
+                .unique(),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("created_by"),
+                    <i64 as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(<i64 as ::cot::db::DatabaseField>::NULLABLE),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("created_at"),
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <chrono::DateTime<chrono::FixedOffset> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("consumed_at"),
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+            ])
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _Invite {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    email: cot::common_types::Email,
+    #[model(unique)]
+    token_hash: cot::db::LimitedString<64>,
+    created_by: i64,
+    created_at: chrono::DateTime<chrono::FixedOffset>,
+    consumed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
@@ -0,0 +1,48 @@
+//! Adds the time a user confirmed their email address.
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0014_user_email_verified_at";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0013_invite",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::add_field()
+            .table_name(::cot::db::Identifier::new("auth__user"))
+            .field(
+                ::cot::db::migrations::Field::new(
+                    ::cot::db::Identifier::new("email_verified_at"),
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::TYPE,
+                )
+                .set_null(
+                    <Option<chrono::DateTime<chrono::FixedOffset>> as ::cot::db::DatabaseField>::NULLABLE,
+                ),
+            )
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _User {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    #[model(unique)]
+    username: cot::db::LimitedString<254>,
+    name: cot::db::LimitedString<254>,
+    password: cot::auth::PasswordHash,
+    email: cot::common_types::Email,
+    locale: Option<String>,
+    is_active: bool,
+    last_login: Option<chrono::DateTime<chrono::FixedOffset>>,
+    created_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    password_changed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    must_change_password: bool,
+    has_usable_password: bool,
+    is_staff: bool,
+    updated_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    email_verified_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+}
@@ -0,0 +1,60 @@
+//! Adds the flag marking the accounts that can grant and revoke staff status.
+
+use cot::db::migrations::{MigrationContext, migration_op};
+
+#[migration_op]
+async fn add_is_superuser(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" ADD COLUMN "is_superuser" boolean NOT NULL DEFAULT FALSE"#)
+        .await?;
+    Ok(())
+}
+
+#[migration_op]
+async fn remove_is_superuser(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"ALTER TABLE "auth__user" DROP COLUMN "is_superuser""#)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0019_user_is_superuser";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0018_user_attribute",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::custom(add_is_superuser)
+            .backwards(remove_is_superuser)
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _User {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    #[model(unique)]
+    username: cot::db::LimitedString<254>,
+    name: cot::db::LimitedString<254>,
+    password: cot::auth::PasswordHash,
+    email: cot::common_types::Email,
+    locale: Option<String>,
+    is_active: bool,
+    last_login: Option<chrono::DateTime<chrono::FixedOffset>>,
+    created_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    password_changed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    must_change_password: bool,
+    has_usable_password: bool,
+    is_staff: bool,
+    updated_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    email_verified_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    session_salt: Option<String>,
+    phone: Option<String>,
+    is_superuser: bool,
+}
@@ -0,0 +1,74 @@
+//! Lets each email address belong to one account only.
+//!
+//! Creating the index fails if addresses are already shared. Those have to be
+//! sorted out by hand, as only the people behind the accounts know which one
+//! should keep the address: `auth duplicate-emails` lists them, and once each
+//! account has its own address the migration can run again.
+
+use cot::db::DatabaseError;
+use cot::db::migrations::{MigrationContext, MigrationEngineError, migration_op};
+
+#[migration_op]
+async fn add_email_index(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"CREATE UNIQUE INDEX "auth__user_email_unique" ON "auth__user" ("email")"#)
+        .await
+        .map_err(|err| {
+            DatabaseError::MigrationError(MigrationEngineError::Custom(format!(
+                "could not make email addresses unique ({err}); if some are shared by \
+                 several accounts, run `auth duplicate-emails` to list them and give each \
+                 account its own address"
+            )))
+        })?;
+    Ok(())
+}
+
+#[migration_op]
+async fn remove_email_index(ctx: MigrationContext<'_>) -> cot::db::Result<()> {
+    ctx.db
+        .raw(r#"DROP INDEX "auth__user_email_unique""#)
+        .await?;
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(super) struct Migration;
+impl ::cot::db::migrations::Migration for Migration {
+    const APP_NAME: &'static str = "auth";
+    const MIGRATION_NAME: &'static str = "m_0020_user_email_unique";
+    const DEPENDENCIES: &'static [::cot::db::migrations::MigrationDependency] =
+        &[::cot::db::migrations::MigrationDependency::migration(
+            "auth",
+            "m_0019_user_is_superuser",
+        )];
+    const OPERATIONS: &'static [::cot::db::migrations::Operation] =
+        &[::cot::db::migrations::Operation::custom(add_email_index)
+            .backwards(remove_email_index)
+            .build()];
+}
+
+#[derive(::core::fmt::Debug)]
+#[::cot::db::model(model_type = "migration")]
+struct _User {
+    #[model(primary_key)]
+    id: cot::db::Auto<i64>,
+    #[model(unique)]
+    username: cot::db::LimitedString<254>,
+    name: cot::db::LimitedString<254>,
+    password: cot::auth::PasswordHash,
+    #[model(unique)]
+    email: cot::common_types::Email,
+    locale: Option<String>,
+    is_active: bool,
+    last_login: Option<chrono::DateTime<chrono::FixedOffset>>,
+    created_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    password_changed_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    must_change_password: bool,
+    has_usable_password: bool,
+    is_staff: bool,
+    updated_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    email_verified_at: Option<chrono::DateTime<chrono::FixedOffset>>,
+    session_salt: Option<String>,
+    phone: Option<String>,
+    is_superuser: bool,
+}
@@ -0,0 +1,144 @@
+//! What every page handler needs to render its template.
+
+use crate::auth::{User, current_user};
+use cot::auth::Auth;
+use cot::db::Database;
+use cot::request::RequestHead;
+use cot::request::extractors::{FromRequestHead, StaticFiles};
+use cot::router::Urls;
+
+/// Extracts the URL reverser, the static files and the logged-in user in one
+/// go, so handlers don't have to list them one by one.
+#[derive(Debug)]
+pub(crate) struct PageContext {
+    pub(crate) urls: Urls,
+    pub(crate) static_files: StaticFiles,
+    /// The logged-in user, loaded fresh from the database.
+    pub(crate) user: Option<User>,
+}
+
+impl FromRequestHead for PageContext {
+    async fn from_request_head(head: &RequestHead) -> cot::Result<Self> {
+        let auth = Auth::from_request_head(head).await?;
+        let db = Database::from_request_head(head).await?;
+        Ok(Self {
+            urls: Urls::from_request_head(head).await?,
+            static_files: StaticFiles::from_request_head(head).await?,
+            user: current_user(&auth, &db).await?,
+        })
+    }
+}
+
+/// Declares the template struct of a page built around one form, with the
+/// fields every such page has: `urls`, `static_files` and the form's context
+/// as `form`. Any other fields the page needs are listed in the braces, after
+/// the form type: `struct LoginTemplate for LoginForm { ... }`.
+macro_rules! form_page {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident for $form:ty {
+            $($(#[$field_attr:meta])* $field:ident: $field_ty:ty),* $(,)?
+        }
+    ) => {
+        #[derive(Debug, ::cot::Template)]
+        $(#[$attr])*
+        $vis struct $name<'a> {
+            urls: &'a ::cot::router::Urls,
+            static_files: ::cot::request::extractors::StaticFiles,
+            form: <$form as ::cot::form::Form>::Context,
+            $($(#[$field_attr])* $field: $field_ty,)*
+        }
+    };
+}
+pub(crate) use form_page;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::Template;
+    use cot::form::{Form, FormContext, FormField};
+    use cot::html::Html;
+    use cot::router::{Route, Router, Urls};
+
+    /// Answers with what the extractor found.
+    async fn describe(context: PageContext) -> cot::Result<Html> {
+        Ok(Html::new(format!(
+            "url={} css={} user={}",
+            cot::reverse!(context.urls, "describe")?,
+            context.static_files.url_for("css/login.css")?,
+            context.user.as_ref().map_or("none", User::username),
+        )))
+    }
+
+    #[cot::test]
+    async fn fills_in_everything_for_a_logged_in_user() {
+        let mut app = TestApp::with_routes(
+            Router::with_urls([Route::with_handler_and_name(
+                "/describe",
+                describe,
+                "describe",
+            )]),
+            |_| {},
+        )
+        .await;
+        app.create_user("page_context", PASSWORD).await;
+        assert!(app.get("/test/describe").await.body.ends_with("user=none"));
+        app.login("page_context", PASSWORD).await;
+
+        let body = app.get("/test/describe").await.body;
+
+        let (url, rest) = body.split_once(" css=").unwrap();
+        let (css, user) = rest.split_once(" user=").unwrap();
+        assert_eq!(url, "url=/test/describe");
+        assert!(css.starts_with("/static/css/login."), "{css}");
+        assert_eq!(user, "page_context");
+    }
+
+    #[derive(Form)]
+    struct NoteForm {
+        message: String,
+    }
+
+    form_page! {
+        #[template(
+            source = "{%- let urls = urls -%}{{ note }} {{ form.message.id() }} {{ cot::reverse!(urls, \"note\")? }} \
+                      {{ static_files.url_for(\"css/login.css\")? }}",
+            ext = "html"
+        )]
+        struct NotePage for NoteForm {
+            note: &'a str,
+        }
+    }
+
+    /// Renders a page declared with `form_page!`, with a fresh form.
+    async fn note_page(urls: Urls, static_files: StaticFiles) -> cot::Result<Html> {
+        let page = NotePage {
+            urls: &urls,
+            static_files,
+            form: <NoteForm as Form>::Context::new(),
+            note: "hello",
+        };
+        Ok(Html::new(page.render()?))
+    }
+
+    #[cot::test]
+    async fn form_pages_render_their_form_and_extra_fields() {
+        let mut app = TestApp::with_routes(
+            Router::with_urls([Route::with_handler_and_name("/note", note_page, "note")]),
+            |_| {},
+        )
+        .await;
+
+        let body = app.get("/test/note").await.body;
+
+        let mut parts = body.split(' ');
+        assert_eq!(parts.next(), Some("hello"));
+        assert_eq!(parts.next(), Some("message"));
+        assert_eq!(parts.next(), Some("/test/note"));
+        assert!(
+            parts.next().unwrap().starts_with("/static/css/login."),
+            "{body}"
+        );
+    }
+}
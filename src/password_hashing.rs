@@ -0,0 +1,270 @@
+//! Hashing passwords with the algorithm picked in the config.
+//!
+//! Cot's [`PasswordHash::from_password`] always hashes with Argon2id. New
+//! hashes here use [`AppConfig::password_hash_algorithm`] instead, and
+//! [`verify`] accepts a hash made with any of the supported algorithms,
+//! answering [`PasswordVerificationResult::OkObsolete`] for one that isn't
+//! in the configured algorithm with its current parameters.
+//!
+//! Every hash is a PHC string, the only kind [`PasswordHash`] holds. bcrypt
+//! has no PHC form of its own, so its hashes are kept as
+//! `$bcrypt$cost=<cost>$<salt>$<hash>`, the salt and the 23 hash bytes
+//! base64-encoded like the other formats.
+//!
+//! [`AppConfig::password_hash_algorithm`]: crate::config::AppConfig::password_hash_algorithm
+
+use crate::config::{PasswordHashAlgorithm, app_config};
+use argon2::Argon2;
+use cot::auth::{PasswordHash, PasswordVerificationResult};
+use cot::common_types::Password;
+use password_hash::{
+    Ident, Output, ParamsString, PasswordHash as PhcHash, PasswordHasher, PasswordVerifier,
+    SaltString,
+};
+use pbkdf2::Pbkdf2;
+use scrypt::Scrypt;
+
+const BCRYPT_IDENT: Ident<'static> = Ident::new_unwrap("bcrypt");
+const BCRYPT_COST: u32 = bcrypt::DEFAULT_COST;
+/// bcrypt reads at most this many bytes of the NUL-terminated password.
+const BCRYPT_MAX_INPUT_LEN: usize = 72;
+/// How many of the 24 bytes bcrypt outputs go into the hash, as in `$2b$`.
+const BCRYPT_OUTPUT_LEN: usize = 23;
+
+/// Hashes `password` with the configured algorithm.
+pub(crate) fn hash(password: &Password) -> PasswordHash {
+    hash_with(app_config().password_hash_algorithm, password)
+}
+
+fn hash_with(algorithm: PasswordHashAlgorithm, password: &Password) -> PasswordHash {
+    let salt = rand::random::<[u8; 16]>();
+    let salt_string = SaltString::encode_b64(&salt).expect("16 bytes make a valid salt");
+    let password_bytes = password.as_str().as_bytes();
+    let hash = match algorithm {
+        PasswordHashAlgorithm::Argon2id => Argon2::default()
+            .hash_password(password_bytes, &salt_string)
+            .map(|hash| hash.to_string()),
+        PasswordHashAlgorithm::Scrypt => Scrypt
+            .hash_password(password_bytes, &salt_string)
+            .map(|hash| hash.to_string()),
+        PasswordHashAlgorithm::Pbkdf2 => Pbkdf2
+            .hash_password(password_bytes, &salt_string)
+            .map(|hash| hash.to_string()),
+        PasswordHashAlgorithm::Bcrypt => Ok(PhcHash {
+            algorithm: BCRYPT_IDENT,
+            version: None,
+            params: bcrypt_params(BCRYPT_COST),
+            salt: Some(salt_string.as_salt()),
+            hash: Some(bcrypt_output(BCRYPT_COST, salt, password)),
+        }
+        .to_string()),
+    }
+    .expect("password hashing error");
+
+    PasswordHash::new(hash).expect("the hash is a PHC string of a known algorithm")
+}
+
+/// Checks `password` against `hash`, whichever supported algorithm made it.
+///
+/// A match on a hash that isn't in the configured algorithm with its current
+/// parameters comes back as [`PasswordVerificationResult::OkObsolete`] with a
+/// new hash to save.
+pub(crate) fn verify(hash: &PasswordHash, password: &Password) -> PasswordVerificationResult {
+    let Ok(phc) = PhcHash::new(hash.as_str()) else {
+        return PasswordVerificationResult::Invalid;
+    };
+    let matches = if phc.algorithm == BCRYPT_IDENT {
+        verify_bcrypt(&phc, password)
+    } else {
+        let algorithms: &[&dyn PasswordVerifier] = &[&Argon2::default(), &Pbkdf2, &Scrypt];
+        phc.verify_password(algorithms, password.as_str()).is_ok()
+    };
+
+    if !matches {
+        PasswordVerificationResult::Invalid
+    } else if is_current(&phc) {
+        PasswordVerificationResult::Ok
+    } else {
+        PasswordVerificationResult::OkObsolete(self::hash(password))
+    }
+}
+
+/// Whether `hash` should be replaced the next time its password is known,
+/// because it isn't in the configured algorithm with its current parameters.
+pub(crate) fn is_obsolete(hash: &PasswordHash) -> bool {
+    PhcHash::new(hash.as_str()).map_or(true, |phc| !is_current(&phc))
+}
+
+fn is_current(phc: &PhcHash<'_>) -> bool {
+    let (algorithm, params) = match app_config().password_hash_algorithm {
+        PasswordHashAlgorithm::Argon2id => (
+            argon2::Algorithm::Argon2id.ident(),
+            argon2::Params::default().try_into(),
+        ),
+        PasswordHashAlgorithm::Scrypt => (scrypt::ALG_ID, scrypt::Params::default().try_into()),
+        PasswordHashAlgorithm::Pbkdf2 => (
+            pbkdf2::Algorithm::default().ident(),
+            pbkdf2::Params::default().try_into(),
+        ),
+        PasswordHashAlgorithm::Bcrypt => (BCRYPT_IDENT, Ok(bcrypt_params(BCRYPT_COST))),
+    };
+    let params: ParamsString = params.expect("the default params are valid");
+    phc.algorithm == algorithm && phc.params == params
+}
+
+fn verify_bcrypt(phc: &PhcHash<'_>, password: &Password) -> bool {
+    let Some(cost) = phc
+        .params
+        .get_decimal("cost")
+        .filter(|cost| (4..32).contains(cost))
+    else {
+        return false;
+    };
+    let mut salt = [0; 16];
+    let (Some(stored_salt), Some(stored_output)) = (phc.salt, phc.hash) else {
+        return false;
+    };
+    if stored_salt.decode_b64(&mut salt).map(<[u8]>::len) != Ok(salt.len()) {
+        return false;
+    }
+    // `Output` compares in constant time
+    bcrypt_output(cost, salt, password) == stored_output
+}
+
+fn bcrypt_params(cost: u32) -> ParamsString {
+    let mut params = ParamsString::new();
+    params
+        .add_decimal("cost", cost)
+        .expect("one short param fits");
+    params
+}
+
+fn bcrypt_output(cost: u32, salt: [u8; 16], password: &Password) -> Output {
+    let mut input = password.as_str().as_bytes().to_vec();
+    input.push(0);
+    input.truncate(BCRYPT_MAX_INPUT_LEN);
+    let output = bcrypt::bcrypt(cost, salt, &input);
+    Output::new(&output[..BCRYPT_OUTPUT_LEN]).expect("23 bytes make a valid output")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::User;
+    use crate::config::{AppConfig, set_test_app_config};
+    use crate::test_utils::{PASSWORD, TestApp};
+
+    const ALGORITHMS: [PasswordHashAlgorithm; 4] = [
+        PasswordHashAlgorithm::Argon2id,
+        PasswordHashAlgorithm::Scrypt,
+        PasswordHashAlgorithm::Pbkdf2,
+        PasswordHashAlgorithm::Bcrypt,
+    ];
+
+    fn use_algorithm(password_hash_algorithm: PasswordHashAlgorithm) {
+        set_test_app_config(AppConfig {
+            password_hash_algorithm,
+            ..AppConfig::default()
+        });
+    }
+
+    #[test]
+    fn hashes_verify_with_the_algorithm_that_made_them() {
+        let password = Password::new(PASSWORD);
+        for algorithm in ALGORITHMS {
+            use_algorithm(algorithm);
+            let hash = hash(&password);
+
+            assert!(
+                matches!(verify(&hash, &password), PasswordVerificationResult::Ok),
+                "{algorithm:?}: {}",
+                hash.as_str()
+            );
+            assert!(
+                matches!(
+                    verify(&hash, &Password::new("not-the-password")),
+                    PasswordVerificationResult::Invalid
+                ),
+                "{algorithm:?}"
+            );
+            assert!(!is_obsolete(&hash), "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn hashes_from_another_algorithm_verify_and_are_replaced() {
+        let password = Password::new(PASSWORD);
+        use_algorithm(PasswordHashAlgorithm::Argon2id);
+        for made_with in [PasswordHashAlgorithm::Bcrypt, PasswordHashAlgorithm::Scrypt] {
+            let old_hash = hash_with(made_with, &password);
+            assert!(is_obsolete(&old_hash), "{made_with:?}");
+
+            let PasswordVerificationResult::OkObsolete(new_hash) = verify(&old_hash, &password)
+            else {
+                panic!("a {made_with:?} hash should verify and be obsolete");
+            };
+            assert!(new_hash.as_str().starts_with("$argon2id$"));
+            assert!(matches!(
+                verify(&new_hash, &password),
+                PasswordVerificationResult::Ok
+            ));
+        }
+    }
+
+    #[test]
+    fn bcrypt_hashes_with_another_cost_are_obsolete() {
+        let password = Password::new(PASSWORD);
+        use_algorithm(PasswordHashAlgorithm::Bcrypt);
+        let salt = [7; 16];
+        let hash = PhcHash {
+            algorithm: BCRYPT_IDENT,
+            version: None,
+            params: bcrypt_params(4),
+            salt: Some(SaltString::encode_b64(&salt).unwrap().as_salt()),
+            hash: Some(bcrypt_output(4, salt, &password)),
+        }
+        .to_string();
+        let hash = PasswordHash::new(hash).unwrap();
+
+        assert!(matches!(
+            verify(&hash, &password),
+            PasswordVerificationResult::OkObsolete(_)
+        ));
+    }
+
+    #[test]
+    fn bcrypt_only_reads_the_first_72_bytes() {
+        let long = "x".repeat(BCRYPT_MAX_INPUT_LEN);
+        let hash = hash_with(PasswordHashAlgorithm::Bcrypt, &Password::new(&long));
+
+        assert!(!matches!(
+            verify(&hash, &Password::new(format!("{long}and more"))),
+            PasswordVerificationResult::Invalid
+        ));
+    }
+
+    #[cot::test]
+    async fn logging_in_rehashes_with_the_configured_algorithm() {
+        let mut app = TestApp::with_config(|config| {
+            config.password_hash_algorithm = PasswordHashAlgorithm::Bcrypt;
+        })
+        .await;
+        let user = app.create_user("rehash_on_login", PASSWORD).await;
+        assert!(user.password_hash().as_str().starts_with("$bcrypt$"));
+        set_test_app_config(AppConfig {
+            password_hash_algorithm: PasswordHashAlgorithm::Argon2id,
+            ..app_config().clone()
+        });
+        assert!(user.has_obsolete_password_hash());
+
+        let response = app.login("rehash_on_login", PASSWORD).await;
+
+        assert_eq!(response.location(), Some("/home"));
+        let user = User::get_by_id(app.db(), user.id().unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(user.password_hash().as_str().starts_with("$argon2id$"));
+        assert!(!user.has_obsolete_password_hash());
+    }
+}
@@ -0,0 +1,175 @@
+//! Remembering users' previous passwords so they can't be reused.
+//!
+//! Only hashes are stored: one row per replaced password, pruned to the
+//! configured depth. The current password, kept on the user, counts towards
+//! that depth.
+
+use crate::auth::User;
+use crate::config::app_config;
+use crate::password_hashing;
+use crate::telemetry::time_password_hash;
+use chrono::{DateTime, FixedOffset, Utc};
+use cot::auth::{PasswordHash, PasswordVerificationResult};
+use cot::common_types::Password;
+use cot::db::{Auto, Database, Model, model, query};
+
+/// The error shown when a new password is one of the remembered ones.
+pub(crate) const REUSED_PASSWORD: &str =
+    "you have used this password recently, please choose another.";
+
+#[derive(Debug, Clone)]
+#[model]
+pub(crate) struct PasswordHistory {
+    #[model(primary_key)]
+    id: Auto<i64>,
+    user_id: i64,
+    password: PasswordHash,
+    created_at: DateTime<FixedOffset>,
+}
+
+/// How many old passwords besides the current one are kept.
+fn kept_entries() -> usize {
+    app_config().password_history_depth.saturating_sub(1) as usize
+}
+
+/// The user's remembered previous passwords, newest first.
+async fn previous(db: &Database, user_id: i64) -> cot::Result<Vec<PasswordHistory>> {
+    let mut entries = query!(PasswordHistory, $user_id == user_id).all(db).await?;
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.created_at));
+    Ok(entries)
+}
+
+/// Whether `password` is the user's current password or one of the previous
+/// ones still remembered.
+pub(crate) async fn is_recent(
+    db: &Database,
+    user: &User,
+    password: &Password,
+) -> cot::Result<bool> {
+    if app_config().password_history_depth == 0 {
+        return Ok(false);
+    }
+    if user.check_password(password) {
+        return Ok(true);
+    }
+    let Some(user_id) = user.id() else {
+        return Ok(false);
+    };
+
+    let matches = |hash: &PasswordHash| {
+        !matches!(
+            time_password_hash("verify", || password_hashing::verify(hash, password)),
+            PasswordVerificationResult::Invalid
+        )
+    };
+    Ok(previous(db, user_id)
+        .await?
+        .iter()
+        .take(kept_entries())
+        .any(|entry| matches(&entry.password)))
+}
+
+/// Sets and saves a new password for `user`, remembering the one it replaces.
+pub(crate) async fn replace_password(
+    db: &Database,
+    user: &mut User,
+    password: &Password,
+) -> cot::Result<()> {
+    let old_hash = user
+        .has_usable_password()
+        .then(|| user.password_hash().clone());
+    user.set_password(password).save(db).await?;
+
+    let (Some(user_id), Some(old_hash)) = (user.id(), old_hash) else {
+        return Ok(());
+    };
+    if kept_entries() == 0 {
+        return Ok(());
+    }
+    PasswordHistory {
+        id: Auto::auto(),
+        user_id,
+        password: old_hash,
+        created_at: Utc::now().fixed_offset(),
+    }
+    .save(db)
+    .await?;
+
+    for stale in previous(db, user_id).await?.iter().skip(kept_entries()) {
+        let id = stale.id;
+        query!(PasswordHistory, $id == id).delete(db).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp};
+
+    const PASSWORDS: [&str; 4] = [
+        PASSWORD,
+        "Str0ng!Passphrase-one",
+        "Str0ng!Passphrase-two",
+        "Str0ng!Passphrase-three",
+    ];
+
+    /// Saves `username` with each of `PASSWORDS` in turn.
+    async fn user_with_history(app: &TestApp, username: &str) -> User {
+        let mut user = app.create_user(username, PASSWORDS[0]).await;
+        for &password in &PASSWORDS[1..] {
+            replace_password(app.db(), &mut user, &Password::new(password))
+                .await
+                .unwrap();
+        }
+        user
+    }
+
+    #[cot::test]
+    async fn the_last_passwords_up_to_the_depth_are_refused() {
+        let app = TestApp::with_config(|config| config.password_history_depth = 3).await;
+        let user = user_with_history(&app, "history_depth").await;
+
+        for &password in &PASSWORDS[1..] {
+            let recent = is_recent(app.db(), &user, &Password::new(password)).await;
+            assert!(recent.unwrap(), "{password}");
+        }
+        let oldest = is_recent(app.db(), &user, &Password::new(PASSWORDS[0])).await;
+        assert!(!oldest.unwrap());
+        assert_eq!(
+            previous(app.db(), user.id().unwrap()).await.unwrap().len(),
+            2
+        );
+    }
+
+    #[cot::test]
+    async fn a_depth_of_zero_remembers_nothing() {
+        let app = TestApp::with_config(|config| config.password_history_depth = 0).await;
+        let user = user_with_history(&app, "history_off").await;
+
+        for password in PASSWORDS {
+            let recent = is_recent(app.db(), &user, &Password::new(password)).await;
+            assert!(!recent.unwrap(), "{password}");
+        }
+    }
+
+    #[cot::test]
+    async fn changing_to_a_recent_password_is_refused() {
+        let mut app = TestApp::new().await;
+        user_with_history(&app, "history_change").await;
+        app.login("history_change", PASSWORDS[3]).await;
+
+        let response = app
+            .post(
+                "/change-password",
+                &[
+                    ("current_password", PASSWORDS[3]),
+                    ("password1", PASSWORDS[1]),
+                    ("password2", PASSWORDS[1]),
+                ],
+            )
+            .await;
+
+        assert!(response.body.contains(REUSED_PASSWORD));
+    }
+}
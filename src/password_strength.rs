@@ -0,0 +1,166 @@
+//! Estimating how guessable a password is from its length and the kinds of
+//! characters in it.
+//!
+//! This is a heuristic, not a cracking model: it catches repetitive and
+//! sequential passwords like `aaaaaaaa` or `12345678` that pass a plain length
+//! rule. Common passwords are left to the breach check.
+
+use crate::config::app_config;
+use cot::common_types::Password;
+use cot::form::FormFieldValidationError;
+use serde::Serialize;
+
+/// Lower bounds in bits for scores 1 to 4.
+const SCORE_THRESHOLDS: [f64; 4] = [28.0, 36.0, 60.0, 128.0];
+/// How many characters of each kind [`entropy_bits`] assumes: lowercase,
+/// uppercase, digits, other ASCII and everything else.
+const POOL_SIZES: [u32; 5] = [26, 26, 10, 33, 100];
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+pub(crate) struct Strength {
+    /// Estimated entropy in bits.
+    pub(crate) bits: f64,
+    /// From 0 (trivial to guess) to 4 (very strong).
+    pub(crate) score: u8,
+}
+
+pub(crate) fn estimate(password: &str) -> Strength {
+    let bits = entropy_bits(password);
+    let score = SCORE_THRESHOLDS
+        .iter()
+        .take_while(|&&threshold| bits >= threshold)
+        .count();
+    Strength {
+        bits,
+        score: u8::try_from(score).expect("there are only four thresholds"),
+    }
+}
+
+/// Rejects `password` if its estimated entropy is below
+/// `AppConfig::min_password_entropy_bits`.
+pub(crate) fn validate_entropy(password: &Password) -> Result<(), FormFieldValidationError> {
+    if estimate(password.as_str()).bits < app_config().min_password_entropy_bits {
+        return Err(FormFieldValidationError::from_static(
+            "this password is too easy to guess, try a longer one mixing different kinds of characters.",
+        ));
+    }
+    Ok(())
+}
+
+/// The fewest characters a password needs to reach
+/// `AppConfig::min_password_entropy_bits`, even drawing on every kind of
+/// character. Passwords this long can still be rejected; shorter ones always
+/// are.
+pub(crate) fn min_length() -> usize {
+    let max_bits_per_char = f64::from(POOL_SIZES.iter().sum::<u32>()).log2();
+    // a negative or NaN setting saturates to 0
+    (app_config().min_password_entropy_bits / max_bits_per_char).ceil() as usize
+}
+
+/// `log2` of the character pool size, times the number of characters that
+/// don't just repeat the previous one or continue a run like `abc` or `321`.
+fn entropy_bits(password: &str) -> f64 {
+    let (mut lower, mut upper, mut digit, mut symbol, mut other) =
+        (false, false, false, false, false);
+    let mut effective_len = 0_u32;
+    let mut previous: Option<(char, Option<i64>)> = None;
+
+    for c in password.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digit = true,
+            c if c.is_ascii() => symbol = true,
+            _ => other = true,
+        }
+
+        let step = previous.map(|(prev, _)| i64::from(u32::from(c)) - i64::from(u32::from(prev)));
+        let predictable = match (step, previous.and_then(|(_, prev_step)| prev_step)) {
+            (Some(0), _) => true,
+            (Some(step), Some(prev_step)) => step.abs() == 1 && step == prev_step,
+            _ => false,
+        };
+        if !predictable {
+            effective_len += 1;
+        }
+        previous = Some((c, step));
+    }
+
+    let pool: u32 = [lower, upper, digit, symbol, other]
+        .iter()
+        .zip(POOL_SIZES)
+        .filter(|(present, _)| **present)
+        .map(|(_, size)| size)
+        .sum();
+    if pool == 0 {
+        return 0.0;
+    }
+    f64::from(effective_len) * f64::from(pool).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{self, AppConfig};
+
+    fn validate(password: &str) -> bool {
+        validate_entropy(&Password::new(password)).is_ok()
+    }
+
+    #[test]
+    fn rejects_repetitive_and_sequential_passwords_by_default() {
+        config::set_test_app_config(AppConfig::default());
+
+        for password in [
+            "aaaaaaaa",
+            "12345678",
+            "abcdefgh",
+            "aaaaaaaaaaaaaaaaaaaa",
+            "zyxwvuts",
+        ] {
+            assert!(!validate(password), "{password} was accepted");
+        }
+    }
+
+    #[test]
+    fn accepts_high_entropy_passwords_by_default() {
+        config::set_test_app_config(AppConfig::default());
+
+        for password in [
+            "Str0ng!Passphrase-xyz",
+            "qzmxkwvj",
+            "correct horse battery staple",
+        ] {
+            assert!(validate(password), "{password} was rejected");
+        }
+    }
+
+    #[test]
+    fn a_zero_threshold_turns_the_check_off() {
+        config::set_test_app_config(AppConfig {
+            min_password_entropy_bits: 0.0,
+            ..AppConfig::default()
+        });
+
+        assert!(validate("aaaaaaaa"));
+        assert_eq!(min_length(), 0);
+    }
+
+    #[test]
+    fn scores_grow_with_entropy() {
+        assert_eq!(estimate("").score, 0);
+        assert_eq!(estimate("aaaaaaaa").score, 0);
+        assert_eq!(estimate("qzmxkwvj").score, 2);
+        assert_eq!(estimate("Str0ng!Passphrase-xyz").score, 3);
+    }
+
+    #[test]
+    fn min_length_rejects_passwords_too_short_to_ever_pass() {
+        config::set_test_app_config(AppConfig::default());
+        let min_length = min_length();
+
+        // even with every kind of character, one short of the minimum fails
+        let short: String = "aB3!é".chars().cycle().take(min_length - 1).collect();
+        assert!(!validate(&short));
+    }
+}
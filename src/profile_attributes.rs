@@ -0,0 +1,258 @@
+//! Optional profile details, such as a time zone or a short bio, stored as
+//! key/value rows next to the user so new ones don't need a change to the
+//! `User` table.
+//!
+//! Each user has at most one value per [`Attribute`], checked by
+//! [`Attribute::validate`] before it's stored; clearing a value deletes its
+//! row.
+
+use chrono_tz::Tz;
+use cot::db::{Auto, Database, LimitedString, Model, model, query};
+use cot::form::FormFieldValidationError;
+use std::collections::HashMap;
+use url::Url;
+
+/// The longest bio accepted, in characters.
+pub(crate) const MAX_BIO_LENGTH: usize = 500;
+/// The longest avatar URL accepted, in bytes.
+const MAX_URL_LENGTH: usize = 2048;
+
+#[derive(Debug, Clone)]
+#[model]
+pub(crate) struct UserAttribute {
+    #[model(primary_key)]
+    id: Auto<i64>,
+    user_id: i64,
+    /// One of the [`Attribute::key`]s.
+    key: LimitedString<32>,
+    value: String,
+}
+
+/// A profile detail a user can fill in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Attribute {
+    /// An IANA time zone name, such as `Europe/Paris`.
+    Timezone,
+    Bio,
+    /// An `https` link to a picture of the user.
+    AvatarUrl,
+}
+
+impl Attribute {
+    pub(crate) const ALL: [Attribute; 3] =
+        [Attribute::Timezone, Attribute::Bio, Attribute::AvatarUrl];
+
+    /// The name the attribute is stored under, also used as its form field.
+    pub(crate) fn key(self) -> &'static str {
+        match self {
+            Attribute::Timezone => "timezone",
+            Attribute::Bio => "bio",
+            Attribute::AvatarUrl => "avatar_url",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|attribute| attribute.key() == key)
+    }
+
+    /// Checks `value` and returns it in the form it's stored in.
+    pub(crate) fn validate(self, value: &str) -> Result<String, FormFieldValidationError> {
+        match self {
+            // matched regardless of case, as in `europe/paris`
+            Attribute::Timezone => chrono_tz::TZ_VARIANTS
+                .iter()
+                .find(|tz| tz.name().eq_ignore_ascii_case(value))
+                .map(|tz| tz.name().to_owned())
+                .ok_or_else(|| {
+                    FormFieldValidationError::from_static(
+                        "enter a time zone name, such as Europe/Paris.",
+                    )
+                }),
+            Attribute::Bio => {
+                if value.chars().count() > MAX_BIO_LENGTH {
+                    return Err(FormFieldValidationError::from_static(
+                        "keep the bio to 500 characters or fewer.",
+                    ));
+                }
+                Ok(value.to_owned())
+            }
+            Attribute::AvatarUrl => {
+                let invalid = || {
+                    FormFieldValidationError::from_static("enter a full https:// link to an image.")
+                };
+                if value.len() > MAX_URL_LENGTH {
+                    return Err(invalid());
+                }
+                let url = Url::parse(value).map_err(|_| invalid())?;
+                if url.scheme() != "https" || url.host().is_none() {
+                    return Err(invalid());
+                }
+                Ok(url.into())
+            }
+        }
+    }
+}
+
+/// The profile details a user has filled in.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Profile {
+    values: HashMap<Attribute, String>,
+}
+
+impl Profile {
+    pub(crate) async fn load(db: &Database, user_id: i64) -> cot::Result<Self> {
+        let values = query!(UserAttribute, $user_id == user_id)
+            .all(db)
+            .await?
+            .into_iter()
+            // rows for attributes that have since been removed are ignored
+            .filter_map(|row| Some((Attribute::from_key(&row.key)?, row.value)))
+            .collect();
+        Ok(Self { values })
+    }
+
+    /// The stored value of `attribute`, as text.
+    #[must_use]
+    pub(crate) fn get(&self, attribute: Attribute) -> Option<&str> {
+        self.values.get(&attribute).map(String::as_str)
+    }
+
+    #[must_use]
+    pub(crate) fn timezone(&self) -> Option<Tz> {
+        self.get(Attribute::Timezone)?.parse().ok()
+    }
+
+    #[must_use]
+    pub(crate) fn bio(&self) -> Option<&str> {
+        self.get(Attribute::Bio)
+    }
+
+    #[must_use]
+    pub(crate) fn avatar_url(&self) -> Option<Url> {
+        Url::parse(self.get(Attribute::AvatarUrl)?).ok()
+    }
+}
+
+/// Stores `value` as the user's `attribute`, or clears it if `value` is
+/// `None`.
+///
+/// `value` is expected to have been through [`Attribute::validate`].
+pub(crate) async fn set(
+    db: &Database,
+    user_id: i64,
+    attribute: Attribute,
+    value: Option<String>,
+) -> cot::Result<()> {
+    let key = LimitedString::new(attribute.key()).expect("attribute keys are short");
+    let row_key = key.clone();
+    match query!(UserAttribute, $user_id == user_id && $key == row_key)
+        .get(db)
+        .await?
+    {
+        Some(mut row) => match value {
+            Some(value) => {
+                row.value = value;
+                row.save(db).await?;
+            }
+            None => {
+                let id = row.id;
+                query!(UserAttribute, $id == id).delete(db).await?;
+            }
+        },
+        None => {
+            if let Some(value) = value {
+                UserAttribute {
+                    id: Auto::auto(),
+                    user_id,
+                    key,
+                    value,
+                }
+                .save(db)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes every profile detail of the user.
+pub(crate) async fn clear_all(db: &Database, user_id: i64) -> cot::Result<()> {
+    query!(UserAttribute, $user_id == user_id)
+        .delete(db)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{PASSWORD, TestApp};
+
+    #[test]
+    fn values_are_checked_and_normalized() {
+        assert_eq!(
+            Attribute::Timezone.validate("europe/paris").unwrap(),
+            "Europe/Paris"
+        );
+        assert!(Attribute::Timezone.validate("Mars/Olympus_Mons").is_err());
+        assert!(Attribute::Bio.validate(&"é".repeat(MAX_BIO_LENGTH)).is_ok());
+        assert!(
+            Attribute::Bio
+                .validate(&"é".repeat(MAX_BIO_LENGTH + 1))
+                .is_err()
+        );
+        assert_eq!(
+            Attribute::AvatarUrl
+                .validate("https://example.com/me.png")
+                .unwrap(),
+            "https://example.com/me.png"
+        );
+        let too_long = format!("https://example.com/{}", "a".repeat(MAX_URL_LENGTH));
+        for url in ["http://example.com/me.png", "me.png", &too_long] {
+            assert!(Attribute::AvatarUrl.validate(url).is_err(), "{url}");
+        }
+    }
+
+    #[cot::test]
+    async fn attributes_are_set_read_and_cleared() {
+        let app = TestApp::new().await;
+        let user_id = app.create_user("attributes", PASSWORD).await.id().unwrap();
+        let db = app.db();
+        assert!(Profile::load(db, user_id).await.unwrap().bio().is_none());
+
+        set(
+            db,
+            user_id,
+            Attribute::Timezone,
+            Some("Europe/Paris".to_owned()),
+        )
+        .await
+        .unwrap();
+        set(db, user_id, Attribute::Bio, Some("first".to_owned()))
+            .await
+            .unwrap();
+        set(db, user_id, Attribute::Bio, Some("second".to_owned()))
+            .await
+            .unwrap();
+
+        let profile = Profile::load(db, user_id).await.unwrap();
+        assert_eq!(profile.timezone(), Some(chrono_tz::Europe::Paris));
+        assert_eq!(profile.bio(), Some("second"));
+        assert_eq!(profile.avatar_url(), None);
+        let rows = query!(UserAttribute, $user_id == user_id)
+            .all(db)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 2);
+
+        set(db, user_id, Attribute::Bio, None).await.unwrap();
+
+        let profile = Profile::load(db, user_id).await.unwrap();
+        assert_eq!(profile.bio(), None);
+        assert_eq!(profile.timezone(), Some(chrono_tz::Europe::Paris));
+        clear_all(db, user_id).await.unwrap();
+        assert_eq!(Profile::load(db, user_id).await.unwrap().timezone(), None);
+    }
+}
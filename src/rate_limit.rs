@@ -0,0 +1,518 @@
+//! Fixed-window counters keyed by an arbitrary string, shared by every
+//! throttle in the app.
+//!
+//! Counters live in process memory by default. Setting
+//! `app.rate_limit_backend = "database"` keeps them in the database instead,
+//! so they survive restarts and are shared by every server process.
+
+use crate::client_ip::ClientIp;
+use crate::config::{RateLimitBackend, app_config};
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+use cot::common_types::Email;
+use cot::db::{Auto, Database, LimitedString, model, query};
+use cot::http;
+use cot::http::{HeaderMap, HeaderValue};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[async_trait]
+pub(crate) trait RateLimiter: Send + Sync {
+    /// Records a hit for `key`.
+    ///
+    /// Returns the time left until the window resets if the limit has already
+    /// been reached.
+    async fn check(&self, db: &Database, key: &str) -> cot::Result<Result<(), Duration>>;
+
+    /// Returns the time left until the window resets if the limit for `key` has
+    /// been reached, without recording a hit.
+    async fn retry_after(&self, db: &Database, key: &str) -> cot::Result<Option<Duration>>;
+
+    /// Forgets all hits recorded for `key`.
+    async fn reset(&self, db: &Database, key: &str) -> cot::Result<()>;
+
+    /// How many hits a key gets per window.
+    fn limit(&self) -> u32;
+}
+
+/// Adds the headers telling a throttled client when it can try again:
+/// `Retry-After`, and the `X-RateLimit-*` set with the limit, the hits left
+/// (none) and the seconds until the window resets.
+pub(crate) fn add_throttle_headers(headers: &mut HeaderMap, limit: u32, retry_after: Duration) {
+    // round up so a client waiting exactly this long isn't still throttled
+    let secs = (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).max(1);
+    headers.insert(http::header::RETRY_AFTER, HeaderValue::from(secs));
+    headers.insert("x-ratelimit-limit", HeaderValue::from(limit));
+    headers.insert("x-ratelimit-remaining", HeaderValue::from(0));
+    headers.insert("x-ratelimit-reset", HeaderValue::from(secs));
+}
+
+/// Makes a limiter allowing `limit` hits per `window` on the configured
+/// backend. `scope` tells the database backend's counters for different
+/// limiters apart.
+pub(crate) fn from_config(
+    scope: &'static str,
+    limit: u32,
+    window: Duration,
+) -> Box<dyn RateLimiter> {
+    match app_config().rate_limit_backend {
+        RateLimitBackend::Memory => Box::new(InMemoryRateLimiter::new(limit, window)),
+        RateLimitBackend::Database => Box::new(DbRateLimiter::new(scope, limit, window)),
+    }
+}
+
+/// The throttles of a form that emails an account a link.
+///
+/// Each account gets a budget of emails, so the form can't be used to flood
+/// someone's inbox, and each client address a budget of requests, so one
+/// client can't work through many accounts. Running out of the account budget
+/// must not show in the response, or the form would tell which accounts
+/// exist; the client budget can, as it's the same for every account.
+pub(crate) struct EmailThrottle {
+    per_account: Box<dyn RateLimiter>,
+    per_client: Box<dyn RateLimiter>,
+}
+
+impl EmailThrottle {
+    pub(crate) fn new(per_account: Box<dyn RateLimiter>, per_client: Box<dyn RateLimiter>) -> Self {
+        Self {
+            per_account,
+            per_client,
+        }
+    }
+
+    /// Records a request from `client_ip`, returning the time left until it
+    /// can make another if it's out of requests. Unknown addresses aren't
+    /// counted, as they would all share one budget.
+    pub(crate) async fn check_client(
+        &self,
+        db: &Database,
+        client_ip: ClientIp,
+    ) -> cot::Result<Result<(), Duration>> {
+        match client_ip.0 {
+            Some(ip) => self.per_client.check(db, &ip.to_string()).await,
+            None => Ok(Ok(())),
+        }
+    }
+
+    /// Records an email to the account's address `to`, returning the time
+    /// left until another can be sent if it's out of emails.
+    pub(crate) async fn check_account(
+        &self,
+        db: &Database,
+        to: &Email,
+    ) -> cot::Result<Result<(), Duration>> {
+        self.per_account.check(db, to.as_str()).await
+    }
+
+    /// How many emails an account gets per window.
+    pub(crate) fn account_limit(&self) -> u32 {
+        self.per_account.limit()
+    }
+
+    /// How many requests a client gets per window.
+    pub(crate) fn client_limit(&self) -> u32 {
+        self.per_client.limit()
+    }
+}
+
+/// A [`RateLimiter`] keeping its counters in a map in process memory.
+#[derive(Debug)]
+pub(crate) struct InMemoryRateLimiter {
+    limit: u32,
+    window: Duration,
+    hits: Mutex<Hits>,
+}
+
+#[derive(Debug)]
+struct Hits {
+    /// When each key's window started, and its hits so far.
+    counts: HashMap<String, (Instant, u32)>,
+    /// When finished windows were last dropped from `counts`.
+    swept_at: Instant,
+}
+
+impl InMemoryRateLimiter {
+    pub(crate) fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            hits: Mutex::new(Hits {
+                counts: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn hits(&self) -> std::sync::MutexGuard<'_, Hits> {
+        self.hits
+            .lock()
+            .unwrap_or_else(|poison_error| poison_error.into_inner())
+    }
+}
+
+#[async_trait]
+impl RateLimiter for InMemoryRateLimiter {
+    async fn check(&self, _db: &Database, key: &str) -> cot::Result<Result<(), Duration>> {
+        let now = Instant::now();
+        let mut hits = self.hits();
+        // finished windows are dropped once per window rather than on every
+        // check, so a check doesn't take longer the more keys there are
+        if now.duration_since(hits.swept_at) >= self.window {
+            hits.counts
+                .retain(|_, (started, _)| now.duration_since(*started) < self.window);
+            hits.swept_at = now;
+        }
+
+        let (started, count) = hits.counts.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*started) >= self.window {
+            *started = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return Ok(Err(self.window - now.duration_since(*started)));
+        }
+        *count += 1;
+
+        Ok(Ok(()))
+    }
+
+    async fn retry_after(&self, _db: &Database, key: &str) -> cot::Result<Option<Duration>> {
+        let now = Instant::now();
+        let retry_after = match self.hits().counts.get(key) {
+            Some((started, count))
+                if *count >= self.limit && now.duration_since(*started) < self.window =>
+            {
+                Some(self.window - now.duration_since(*started))
+            }
+            _ => None,
+        };
+        Ok(retry_after)
+    }
+
+    async fn reset(&self, _db: &Database, key: &str) -> cot::Result<()> {
+        self.hits().counts.remove(key);
+        Ok(())
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit
+    }
+}
+
+#[derive(Debug, Clone)]
+#[model]
+pub(crate) struct RateLimitBucket {
+    #[model(primary_key)]
+    id: Auto<i64>,
+    scope: LimitedString<32>,
+    /// SHA-256 of the scope and the limiter key, so usernames and addresses
+    /// used as keys aren't stored as they are.
+    #[model(unique)]
+    key: LimitedString<64>,
+    window_started_at: DateTime<FixedOffset>,
+    hits: i64,
+}
+
+/// Records a hit in one statement, so concurrent checks, from this process or
+/// others, can't get past the limit.
+///
+/// A bucket whose window started before the cutoff starts a new one; one at
+/// the limit is left alone, and then no row is changed. The parameters are the
+/// scope, the hashed key and the time now, then the cutoff three times and the
+/// limit.
+const RECORD_HIT: &str = r#"INSERT INTO "auth__rate_limit_bucket" ("scope", "key", "window_started_at", "hits")
+VALUES (?, ?, ?, 1)
+ON CONFLICT ("key") DO UPDATE SET
+    "window_started_at" = CASE WHEN "window_started_at" <= ? THEN "excluded"."window_started_at" ELSE "window_started_at" END,
+    "hits" = CASE WHEN "window_started_at" <= ? THEN 1 ELSE "hits" + 1 END
+WHERE "window_started_at" <= ? OR "hits" < ?"#;
+
+/// A [`RateLimiter`] keeping its counters in the database.
+#[derive(Debug)]
+pub(crate) struct DbRateLimiter {
+    scope: &'static str,
+    limit: u32,
+    window: Duration,
+    /// When this limiter's finished windows were last deleted.
+    swept_at: Mutex<Instant>,
+}
+
+impl DbRateLimiter {
+    pub(crate) fn new(scope: &'static str, limit: u32, window: Duration) -> Self {
+        Self {
+            scope,
+            limit,
+            window,
+            swept_at: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn key(&self, key: &str) -> LimitedString<64> {
+        let digest = Sha256::new()
+            .chain_update(self.scope)
+            .chain_update([0])
+            .chain_update(key)
+            .finalize();
+        LimitedString::new(hex::encode(digest)).expect("a SHA-256 hex digest is 64 characters")
+    }
+
+    fn scope(&self) -> LimitedString<32> {
+        LimitedString::new(self.scope).expect("limiter scopes are short")
+    }
+
+    fn window(&self) -> TimeDelta {
+        TimeDelta::from_std(self.window).unwrap_or(TimeDelta::MAX)
+    }
+
+    /// Whether it's been a window since the last sweep, marking one as done
+    /// if so.
+    fn sweep_due(&self) -> bool {
+        let mut swept_at = self
+            .swept_at
+            .lock()
+            .unwrap_or_else(|poison_error| poison_error.into_inner());
+        if swept_at.elapsed() < self.window {
+            return false;
+        }
+        *swept_at = Instant::now();
+        true
+    }
+
+    /// The bucket for `key`, if its window is still running.
+    async fn current(
+        &self,
+        db: &Database,
+        key: &LimitedString<64>,
+        now: DateTime<FixedOffset>,
+    ) -> cot::Result<Option<RateLimitBucket>> {
+        let key = key.clone();
+        let bucket = query!(RateLimitBucket, $key == key).get(db).await?;
+        Ok(bucket.filter(|bucket| now - bucket.window_started_at < self.window()))
+    }
+
+    fn remaining(&self, bucket: &RateLimitBucket, now: DateTime<FixedOffset>) -> Duration {
+        (bucket.window_started_at + self.window() - now)
+            .to_std()
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl RateLimiter for DbRateLimiter {
+    async fn check(&self, db: &Database, key: &str) -> cot::Result<Result<(), Duration>> {
+        let now = Utc::now().fixed_offset();
+        let cutoff = now - self.window();
+        let key = self.key(key);
+
+        // drop this limiter's finished windows now and then, so the table
+        // doesn't keep a row for every key ever seen
+        if self.sweep_due() {
+            let scope = self.scope();
+            query!(RateLimitBucket, $scope == scope && $window_started_at <= cutoff)
+                .delete(db)
+                .await?;
+        }
+
+        let recorded = db
+            .raw_with(
+                RECORD_HIT,
+                &[
+                    &self.scope(),
+                    &key,
+                    &now,
+                    &cutoff,
+                    &cutoff,
+                    &cutoff,
+                    &i64::from(self.limit),
+                ],
+            )
+            .await?;
+        if recorded.rows_affected().0 > 0 {
+            return Ok(Ok(()));
+        }
+        let remaining = self
+            .current(db, &key, now)
+            .await?
+            .map(|bucket| self.remaining(&bucket, now))
+            .unwrap_or_default();
+        Ok(Err(remaining))
+    }
+
+    async fn retry_after(&self, db: &Database, key: &str) -> cot::Result<Option<Duration>> {
+        let now = Utc::now().fixed_offset();
+        let retry_after = self
+            .current(db, &self.key(key), now)
+            .await?
+            .filter(|bucket| bucket.hits >= i64::from(self.limit))
+            .map(|bucket| self.remaining(&bucket, now));
+        Ok(retry_after)
+    }
+
+    async fn reset(&self, db: &Database, key: &str) -> cot::Result<()> {
+        let key = self.key(key);
+        query!(RateLimitBucket, $key == key).delete(db).await?;
+        Ok(())
+    }
+
+    fn limit(&self) -> u32 {
+        self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::migrate;
+    use cot::test::TestDatabase;
+
+    const LIMIT: u32 = 3;
+    const WINDOW: Duration = Duration::from_millis(300);
+
+    async fn database() -> TestDatabase {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        migrate(&test_db.database()).await.unwrap();
+        test_db
+    }
+
+    fn limiters() -> [Box<dyn RateLimiter>; 2] {
+        [
+            Box::new(InMemoryRateLimiter::new(LIMIT, WINDOW)),
+            Box::new(DbRateLimiter::new("test", LIMIT, WINDOW)),
+        ]
+    }
+
+    #[cot::test]
+    async fn refuses_hits_past_the_limit() {
+        let test_db = database().await;
+        let db = test_db.database();
+        for limiter in limiters() {
+            for _ in 0..LIMIT {
+                assert_eq!(limiter.check(&db, "key").await.unwrap(), Ok(()));
+            }
+            let retry_after = limiter.check(&db, "key").await.unwrap().unwrap_err();
+            assert!(retry_after <= WINDOW);
+            assert!(limiter.retry_after(&db, "key").await.unwrap().is_some());
+            // other keys have budgets of their own
+            assert_eq!(limiter.check(&db, "other").await.unwrap(), Ok(()));
+            assert_eq!(limiter.retry_after(&db, "other").await.unwrap(), None);
+        }
+    }
+
+    #[cot::test]
+    async fn a_new_window_starts_when_the_last_one_ends() {
+        let test_db = database().await;
+        let db = test_db.database();
+        let limiters = limiters();
+        for limiter in &limiters {
+            for _ in 0..LIMIT {
+                limiter.check(&db, "key").await.unwrap().unwrap();
+            }
+            assert!(limiter.check(&db, "key").await.unwrap().is_err());
+        }
+
+        tokio::time::sleep(WINDOW + Duration::from_millis(50)).await;
+
+        for limiter in &limiters {
+            assert_eq!(limiter.retry_after(&db, "key").await.unwrap(), None);
+            for _ in 0..LIMIT {
+                assert_eq!(limiter.check(&db, "key").await.unwrap(), Ok(()));
+            }
+            assert!(limiter.check(&db, "key").await.unwrap().is_err());
+        }
+    }
+
+    #[cot::test]
+    async fn reset_forgets_the_hits() {
+        let test_db = database().await;
+        let db = test_db.database();
+        for limiter in limiters() {
+            for _ in 0..LIMIT {
+                limiter.check(&db, "key").await.unwrap().unwrap();
+            }
+
+            limiter.reset(&db, "key").await.unwrap();
+
+            assert_eq!(limiter.retry_after(&db, "key").await.unwrap(), None);
+            assert_eq!(limiter.check(&db, "key").await.unwrap(), Ok(()));
+        }
+    }
+
+    #[cot::test]
+    async fn database_counters_are_scoped() {
+        let test_db = database().await;
+        let db = test_db.database();
+        let first = DbRateLimiter::new("first", 1, WINDOW);
+        let second = DbRateLimiter::new("second", 1, WINDOW);
+
+        first.check(&db, "key").await.unwrap().unwrap();
+
+        assert!(first.check(&db, "key").await.unwrap().is_err());
+        assert_eq!(second.check(&db, "key").await.unwrap(), Ok(()));
+    }
+
+    #[cot::test]
+    async fn concurrent_hits_do_not_get_past_the_limit() {
+        let test_db = database().await;
+        let db = test_db.database();
+        for limiter in limiters() {
+            let results =
+                futures::future::join_all((0..20).map(|_| limiter.check(&db, "key"))).await;
+
+            let allowed = results
+                .into_iter()
+                .filter(|result| matches!(result, Ok(Ok(()))))
+                .count();
+            assert_eq!(allowed, LIMIT as usize);
+        }
+    }
+
+    #[cot::test]
+    async fn a_database_bucket_starts_over_before_it_is_swept() {
+        let test_db = database().await;
+        let db = test_db.database();
+        let window = Duration::from_secs(1);
+        let limiter = DbRateLimiter::new("test", 1, window);
+        tokio::time::sleep(window / 2).await;
+        limiter.check(&db, "key").await.unwrap().unwrap();
+
+        // sweeps, while the bucket's window is still running
+        tokio::time::sleep(window * 11 / 20).await;
+        limiter.check(&db, "other").await.unwrap().unwrap();
+        assert!(limiter.check(&db, "key").await.unwrap().is_err());
+
+        // the bucket's window is over, and the next sweep isn't due yet
+        tokio::time::sleep(window * 3 / 5).await;
+        assert_eq!(limiter.check(&db, "key").await.unwrap(), Ok(()));
+        assert!(limiter.check(&db, "key").await.unwrap().is_err());
+    }
+
+    #[cot::test]
+    async fn finished_windows_are_dropped_from_memory() {
+        let test_db = database().await;
+        let db = test_db.database();
+        let limiter = InMemoryRateLimiter::new(LIMIT, WINDOW);
+        limiter.check(&db, "first").await.unwrap().unwrap();
+
+        tokio::time::sleep(WINDOW + Duration::from_millis(50)).await;
+        limiter.check(&db, "second").await.unwrap().unwrap();
+
+        let hits = limiter.hits();
+        assert!(!hits.counts.contains_key("first"));
+        assert!(hits.counts.contains_key("second"));
+    }
+
+    #[test]
+    fn throttle_headers_round_up() {
+        let mut headers = HeaderMap::new();
+
+        add_throttle_headers(&mut headers, 5, Duration::from_millis(1500));
+
+        assert_eq!(headers["retry-after"], "2");
+        assert_eq!(headers["x-ratelimit-limit"], "5");
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        assert_eq!(headers["x-ratelimit-reset"], "2");
+    }
+}
@@ -0,0 +1,218 @@
+//! Turning rendered templates into responses.
+
+use cot::form::{FormContext, FormErrorTarget};
+use cot::http::HeaderMap;
+use cot::json::Json;
+use cot::response::{IntoResponse, Response, ResponseExt};
+use cot::{Body, StatusCode, Template, http};
+use serde::Serialize;
+use tracing::error;
+
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+/// What is sent instead of a page whose template failed to render. It's
+/// plain HTML, so it can't fail itself, and it says nothing about the
+/// failure or the status, which the error page handler may change.
+const FALLBACK_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="UTF-8">
+  <title>Error</title>
+</head>
+<body>
+  <h1>Error</h1>
+  <p>Something went wrong while showing this page. Please try again later.</p>
+</body>
+</html>
+"#;
+
+/// Renders `template` into a `200 OK` HTML response.
+///
+/// The page is rendered in full before it's sent, as Askama templates render
+/// synchronously into one buffer; Cot's streamed bodies would only hand that
+/// buffer over in pieces.
+///
+/// If the template fails to render, the error is logged and a `500` with
+/// [`FALLBACK_PAGE`] is sent instead, so template internals never reach the
+/// client.
+pub(crate) fn render<T: Template>(template: &T) -> cot::Result<Response> {
+    let rendered = match template.render() {
+        Ok(rendered) => rendered,
+        Err(err) => {
+            error!(
+                event = "template_render_failed",
+                template = std::any::type_name::<T>(),
+                error = %err,
+                "failed to render a template"
+            );
+            return Ok(fallback_page());
+        }
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, HTML_CONTENT_TYPE)
+        .body(Body::fixed(rendered))
+        .unwrap();
+    Ok(response)
+}
+
+fn fallback_page() -> Response {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .header(http::header::CONTENT_TYPE, HTML_CONTENT_TYPE)
+        .body(Body::fixed(FALLBACK_PAGE))
+        .expect("the status and header are valid")
+}
+
+/// Renders a form page for browsers, or the form's errors as JSON for clients
+/// whose `Accept` header prefers JSON.
+///
+/// The HTML page is always sent as `200 OK`; `error_status` is what a JSON
+/// client gets when the form has errors.
+pub(crate) fn render_form<T: Template>(
+    headers: &HeaderMap,
+    template: &T,
+    form: &dyn FormContext,
+    error_status: StatusCode,
+) -> cot::Result<Response> {
+    if !prefers_json(headers) {
+        return render(template);
+    }
+
+    let errors = form_errors(form);
+    let status = if errors.is_empty() {
+        StatusCode::OK
+    } else {
+        error_status
+    };
+    Json(FormErrors { errors })
+        .with_status(status)
+        .into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct FormErrors<'a> {
+    errors: Vec<FormErrorEntry<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct FormErrorEntry<'a> {
+    /// The field the error is about, or `None` for form-wide errors.
+    field: Option<&'a str>,
+    message: String,
+}
+
+fn form_errors(form: &dyn FormContext) -> Vec<FormErrorEntry<'_>> {
+    let form_wide = form
+        .errors_for(FormErrorTarget::Form)
+        .iter()
+        .map(|error| FormErrorEntry {
+            field: None,
+            message: error.to_string(),
+        });
+    let per_field = form.fields().flat_map(|field| {
+        let id = field.dyn_id();
+        form.errors_for(FormErrorTarget::Field(id))
+            .iter()
+            .map(move |error| FormErrorEntry {
+                field: Some(id),
+                message: error.to_string(),
+            })
+    });
+    form_wide.chain(per_field).collect()
+}
+
+/// Whether the `Accept` header ranks `application/json` above `text/html`.
+///
+/// Only exact media types count, so wildcards like `*/*` keep getting HTML.
+pub(crate) fn prefers_json(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers
+        .get(http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    let quality_of = |media_type: &str| {
+        accept
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';').map(str::trim);
+                if !parts.next()?.eq_ignore_ascii_case(media_type) {
+                    return None;
+                }
+                parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+            })
+            .fold(0.0_f32, f32::max)
+    };
+    quality_of("application/json") > quality_of("text/html")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::{Display, Formatter};
+
+    #[derive(Debug, Template)]
+    #[template(source = "<p>{{ value }}</p>", ext = "html")]
+    struct ValueTemplate<T: Display> {
+        value: T,
+    }
+
+    /// Exists in both `templates/` and `theme/`, like a page being restyled.
+    #[derive(Debug, Template)]
+    #[template(path = "tests/theme_override.html")]
+    struct ThemedTemplate {
+        value: &'static str,
+    }
+
+    struct FailsToFormat;
+
+    impl Display for FailsToFormat {
+        fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
+            Err(std::fmt::Error)
+        }
+    }
+
+    async fn body_of(response: Response) -> String {
+        let bytes = response.into_body().into_bytes().await.expect("readable");
+        String::from_utf8(bytes.to_vec()).expect("UTF-8")
+    }
+
+    #[cot::test]
+    async fn renders_the_whole_page() {
+        let value = "x".repeat(100 * 1024);
+
+        let response = render(&ValueTemplate {
+            value: value.as_str(),
+        })
+        .expect("renders");
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            HTML_CONTENT_TYPE
+        );
+        assert_eq!(body_of(response).await, format!("<p>{value}</p>"));
+    }
+
+    #[cot::test]
+    async fn a_theme_template_replaces_the_bundled_one() {
+        let response = render(&ThemedTemplate { value: "page" }).expect("renders");
+
+        assert_eq!(body_of(response).await, "<p>themed page</p>");
+    }
+
+    #[cot::test]
+    async fn sends_the_fallback_page_when_rendering_fails() {
+        let response = render(&ValueTemplate {
+            value: FailsToFormat,
+        })
+        .expect("the failure is handled");
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body_of(response).await, FALLBACK_PAGE);
+    }
+}
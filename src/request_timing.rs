@@ -0,0 +1,209 @@
+//! Recording how long requests take, and warning about slow ones.
+//!
+//! Durations go to the [`REQUEST_SECONDS`] histogram per route, and requests
+//! or auth queries taking longer than `AppConfig::slow_request_threshold_ms`
+//! are logged as warnings.
+
+use crate::AuthApp;
+use crate::config::app_config;
+use crate::telemetry::REQUEST_SECONDS;
+use cot::App;
+use cot::request::Request;
+use cot::response::Response;
+use futures::future::BoxFuture;
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::Service;
+use tracing::warn;
+
+/// The label for requests whose path matches no named route.
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// The app's named routes as path segments, with `None` for parameters.
+static ROUTES: LazyLock<Vec<(Vec<Option<String>>, String)>> = LazyLock::new(|| {
+    AuthApp
+        .router()
+        .routes()
+        .iter()
+        .filter_map(|route| {
+            let segments = route
+                .url()
+                .split('/')
+                .map(|segment| {
+                    (!(segment.starts_with('{') && segment.ends_with('}')))
+                        .then(|| segment.to_owned())
+                })
+                .collect();
+            Some((segments, route.name()?.to_owned()))
+        })
+        .collect()
+});
+
+/// The name of the route `path` is served by.
+///
+/// Cot only resolves the route inside the router, after every middleware has
+/// run, so it's matched here again. Logging the name rather than the path
+/// also keeps tokens in reset and magic links out of the logs.
+fn route_name(path: &str) -> Option<&'static str> {
+    let segments: Vec<&str> = path.split('/').collect();
+    ROUTES.iter().find_map(|(pattern, name)| {
+        let matches = pattern.len() == segments.len()
+            && pattern
+                .iter()
+                .zip(&segments)
+                .all(|(expected, segment)| match expected {
+                    Some(expected) => expected == segment,
+                    None => !segment.is_empty(),
+                });
+        matches.then_some(name.as_str())
+    })
+}
+
+fn slow_threshold() -> Option<Duration> {
+    match app_config().slow_request_threshold_ms {
+        0 => None,
+        threshold => Some(Duration::from_millis(threshold)),
+    }
+}
+
+/// Logs a warning if auth query `operation`, started at `start`, took longer
+/// than the slow threshold.
+pub(crate) fn warn_if_slow_query(operation: &'static str, start: Instant) {
+    let elapsed = start.elapsed();
+    if slow_threshold().is_some_and(|threshold| elapsed > threshold) {
+        warn!(
+            event = "slow_query",
+            operation,
+            duration_ms = elapsed.as_millis() as u64,
+            "slow auth query"
+        );
+    }
+}
+
+/// Times every request, including the time spent in the middlewares inside
+/// it, so it should be the outermost one.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct RequestTimingMiddleware;
+
+impl<S> tower::Layer<S> for RequestTimingMiddleware {
+    type Service = RequestTimingService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestTimingService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RequestTimingService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for RequestTimingService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let route = route_name(req.uri().path()).unwrap_or(UNMATCHED_ROUTE);
+            let method = req.method().clone();
+            let start = Instant::now();
+            let result = inner.call(req).await;
+            let elapsed = start.elapsed();
+
+            metrics::histogram!(REQUEST_SECONDS, "route" => route).record(elapsed.as_secs_f64());
+            if slow_threshold().is_some_and(|threshold| elapsed > threshold) {
+                warn!(
+                    event = "slow_request",
+                    route,
+                    method = %method,
+                    status = match &result {
+                        Ok(response) => response.status(),
+                        Err(error) => error.status_code(),
+                    }
+                    .as_u16(),
+                    duration_ms = elapsed.as_millis() as u64,
+                    "slow request"
+                );
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, LogFormat, set_test_app_config};
+    use crate::telemetry::log_subscriber;
+    use crate::test_utils::{CapturedLogs, TestApp};
+    use cot::html::Html;
+    use cot::router::{Route, Router};
+    use tracing_subscriber::EnvFilter;
+
+    async fn slow() -> cot::Result<Html> {
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        Ok(Html::new("done"))
+    }
+
+    async fn fast() -> cot::Result<Html> {
+        Ok(Html::new("done"))
+    }
+
+    #[test]
+    fn paths_are_named_after_their_route() {
+        assert_eq!(route_name("/login"), Some("login"));
+        assert_eq!(route_name("/magic/some.token/7"), Some("magic_login"));
+        assert_eq!(route_name("/magic/some.token/"), None);
+        assert_eq!(route_name("/no/such/page"), None);
+    }
+
+    #[cot::test]
+    async fn only_requests_over_the_threshold_are_logged_as_slow() {
+        let mut app = TestApp::with_routes(
+            Router::with_urls([
+                Route::with_handler_and_name("/slow", slow, "slow"),
+                Route::with_handler_and_name("/fast", fast, "fast"),
+            ]),
+            |_| {},
+        )
+        .await;
+        set_test_app_config(AppConfig {
+            slow_request_threshold_ms: 30,
+            ..app_config().clone()
+        });
+        let captured = CapturedLogs::default();
+        let writer = captured.clone();
+        let subscriber = log_subscriber(LogFormat::Json, EnvFilter::new("auth=warn"), move || {
+            writer.clone()
+        });
+
+        let guard = tracing::subscriber::set_default(subscriber);
+        app.get("/test/fast").await;
+        app.get("/test/slow").await;
+        drop(guard);
+
+        let output = captured.text();
+        let lines: Vec<&str> = output
+            .lines()
+            .filter(|line| line.contains(r#""event":"slow_request""#))
+            .collect();
+        assert_eq!(lines.len(), 1, "{output}");
+        assert!(lines[0].contains(r#""method":"GET""#), "{}", lines[0]);
+        assert!(lines[0].contains(r#""status":200"#), "{}", lines[0]);
+        assert!(lines[0].contains(r#""duration_ms":"#), "{}", lines[0]);
+    }
+}
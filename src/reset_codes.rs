@@ -0,0 +1,262 @@
+//! Short numeric codes texted to users who reset their password by SMS.
+//!
+//! Each user has at most one code at a time. Only an HMAC of it, keyed with
+//! the secret key, is stored: the codes are too short for a plain hash to hide
+//! them. A code expires after `app.sms_reset.code_ttl_secs`.
+//!
+//! Wrong guesses are counted per account rather than per code, so asking for
+//! a new code doesn't give an attacker a fresh set of guesses. After
+//! `app.sms_reset.max_attempts` of them, the account is locked out for
+//! `app.sms_reset.lockout_secs`: no code is sent or accepted until then.
+
+use crate::config::app_config;
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+use cot::db::{Auto, Database, LimitedString, Model, model, query};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// The range `app.sms_reset.code_length` is kept to: shorter codes are too
+/// easy to guess, and longer ones overflow a `u64`.
+const CODE_LENGTHS: std::ops::RangeInclusive<u32> = 4..=12;
+
+#[derive(Debug, Clone)]
+#[model]
+pub(crate) struct ResetCode {
+    #[model(primary_key)]
+    id: Auto<i64>,
+    #[model(unique)]
+    user_id: i64,
+    /// HMAC-SHA256 of the user id and the code, hex-encoded.
+    code_hash: LimitedString<64>,
+    /// Wrong guesses since the last lockout, across all the codes sent.
+    failed_attempts: i64,
+    expires_at: DateTime<FixedOffset>,
+    locked_until: Option<DateTime<FixedOffset>>,
+}
+
+impl ResetCode {
+    fn is_locked(&self) -> bool {
+        self.locked_until.is_some_and(|until| until > Utc::now())
+    }
+}
+
+/// Makes the read and the write of [`issue`] and [`check`] happen as one step,
+/// so guesses sent in parallel can't get past the attempt limit. Other server
+/// processes aren't covered.
+static CHECK_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+fn hash_code(secret: &[u8], user_id: i64, code: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC can take any key length");
+    mac.update(format!("{user_id}:{code}").as_bytes());
+    mac
+}
+
+/// Replaces any code `user_id` has with a new one and returns it, or returns
+/// `None` if the account is locked out.
+pub(crate) async fn issue(
+    db: &Database,
+    secret: &[u8],
+    user_id: i64,
+) -> cot::Result<Option<String>> {
+    let config = &app_config().sms_reset;
+    let length = config
+        .code_length
+        .clamp(*CODE_LENGTHS.start(), *CODE_LENGTHS.end());
+    let code = format!(
+        "{:0width$}",
+        rand::random_range(0..10u64.pow(length)),
+        width = length as usize
+    );
+    let code_hash = LimitedString::new(hex::encode(
+        hash_code(secret, user_id, &code).finalize().into_bytes(),
+    ))
+    .expect("a SHA-256 hex digest is 64 characters");
+    let expires_at = (Utc::now() + Duration::seconds(config.code_ttl_secs)).fixed_offset();
+
+    let _guard = CHECK_LOCK.lock().await;
+    let mut reset_code = match query!(ResetCode, $user_id == user_id).get(db).await? {
+        Some(reset_code) if reset_code.is_locked() => return Ok(None),
+        Some(mut reset_code) => {
+            // the count carries over to the new code
+            reset_code.code_hash = code_hash;
+            reset_code.expires_at = expires_at;
+            reset_code.locked_until = None;
+            reset_code
+        }
+        None => ResetCode {
+            id: Auto::auto(),
+            user_id,
+            code_hash,
+            failed_attempts: 0,
+            expires_at,
+            locked_until: None,
+        },
+    };
+    reset_code.save(db).await?;
+    Ok(Some(code))
+}
+
+/// Whether `code` is the unexpired code `user_id` was sent, and the account
+/// isn't locked out. A wrong guess counts against the attempt limit; the code
+/// stays usable after a right one until [`consume`] is called.
+pub(crate) async fn check(
+    db: &Database,
+    secret: &[u8],
+    user_id: i64,
+    code: &str,
+) -> cot::Result<bool> {
+    let _guard = CHECK_LOCK.lock().await;
+    let Some(mut reset_code) = query!(ResetCode, $user_id == user_id).get(db).await? else {
+        return Ok(false);
+    };
+    // an expired code is kept, as its row holds the count of wrong guesses
+    let now = Utc::now();
+    if reset_code.is_locked() || reset_code.expires_at < now {
+        return Ok(false);
+    }
+
+    let stored = hex::decode(reset_code.code_hash.as_str()).unwrap_or_default();
+    if hash_code(secret, user_id, code)
+        .verify_slice(&stored)
+        .is_ok()
+    {
+        return Ok(true);
+    }
+    reset_code.failed_attempts += 1;
+    let config = &app_config().sms_reset;
+    if reset_code.failed_attempts >= i64::from(config.max_attempts) {
+        // the code is spent, and the next one starts a new count
+        reset_code.failed_attempts = 0;
+        reset_code.expires_at = now.fixed_offset();
+        reset_code.locked_until =
+            Some((now + Duration::seconds(config.lockout_secs)).fixed_offset());
+    }
+    reset_code.save(db).await?;
+    Ok(false)
+}
+
+/// Deletes the code `user_id` has, if any, along with the count of wrong
+/// guesses, once it has been used.
+pub(crate) async fn consume(db: &Database, user_id: i64) -> cot::Result<()> {
+    query!(ResetCode, $user_id == user_id).delete(db).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, SmsResetConfig, set_test_app_config};
+    use crate::test_utils::migrate;
+    use cot::test::TestDatabase;
+
+    const SECRET: &[u8] = b"secret";
+
+    async fn database(sms_reset: SmsResetConfig) -> TestDatabase {
+        set_test_app_config(AppConfig {
+            sms_reset,
+            ..AppConfig::default()
+        });
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        migrate(&test_db.database()).await.unwrap();
+        test_db
+    }
+
+    fn three_attempts() -> SmsResetConfig {
+        SmsResetConfig {
+            max_attempts: 3,
+            ..SmsResetConfig::default()
+        }
+    }
+
+    /// A code other than `code` of the same length.
+    fn wrong(code: &str) -> String {
+        let first = if code.starts_with('0') { '1' } else { '0' };
+        format!("{first}{}", &code[1..])
+    }
+
+    async fn guess_wrong(db: &Database, code: &str, times: usize) {
+        for _ in 0..times {
+            assert!(!check(db, SECRET, 1, &wrong(code)).await.unwrap());
+        }
+    }
+
+    #[cot::test]
+    async fn accepts_the_code_until_it_expires() {
+        let test_db = database(SmsResetConfig::default()).await;
+        let db = test_db.database();
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+
+        assert!(check(&db, SECRET, 1, &code).await.unwrap());
+        assert!(!check(&db, SECRET, 2, &code).await.unwrap());
+        assert!(!check(&db, b"other secret", 1, &code).await.unwrap());
+
+        let test_db = database(SmsResetConfig {
+            code_ttl_secs: 0,
+            ..SmsResetConfig::default()
+        })
+        .await;
+        let db = test_db.database();
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+        assert!(!check(&db, SECRET, 1, &code).await.unwrap());
+    }
+
+    #[cot::test]
+    async fn locks_the_account_out_after_too_many_wrong_codes() {
+        let test_db = database(three_attempts()).await;
+        let db = test_db.database();
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+
+        guess_wrong(&db, &code, 3).await;
+
+        assert!(!check(&db, SECRET, 1, &code).await.unwrap());
+        assert_eq!(issue(&db, SECRET, 1).await.unwrap(), None);
+        // other accounts aren't affected
+        assert!(issue(&db, SECRET, 2).await.unwrap().is_some());
+    }
+
+    #[cot::test]
+    async fn keeps_counting_wrong_codes_across_new_ones() {
+        let test_db = database(three_attempts()).await;
+        let db = test_db.database();
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+        guess_wrong(&db, &code, 2).await;
+
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+        guess_wrong(&db, &code, 1).await;
+
+        assert!(!check(&db, SECRET, 1, &code).await.unwrap());
+        assert_eq!(issue(&db, SECRET, 1).await.unwrap(), None);
+    }
+
+    #[cot::test]
+    async fn starts_a_new_count_once_the_lockout_is_over() {
+        let test_db = database(SmsResetConfig {
+            lockout_secs: 0,
+            ..three_attempts()
+        })
+        .await;
+        let db = test_db.database();
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+        guess_wrong(&db, &code, 3).await;
+
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+        guess_wrong(&db, &code, 2).await;
+
+        assert!(check(&db, SECRET, 1, &code).await.unwrap());
+    }
+
+    #[cot::test]
+    async fn forgets_wrong_codes_once_one_is_used() {
+        let test_db = database(three_attempts()).await;
+        let db = test_db.database();
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+        guess_wrong(&db, &code, 2).await;
+        assert!(check(&db, SECRET, 1, &code).await.unwrap());
+        consume(&db, 1).await.unwrap();
+
+        let code = issue(&db, SECRET, 1).await.unwrap().unwrap();
+        guess_wrong(&db, &code, 2).await;
+
+        assert!(check(&db, SECRET, 1, &code).await.unwrap());
+    }
+}
@@ -0,0 +1,231 @@
+//! Tracking which sessions a user is logged in with, so they can be ended from
+//! elsewhere.
+//!
+//! Every login records a row keyed by a random value that is also stored in
+//! the session, and [`SessionLimitMiddleware`] logs out any session whose row
+//! is gone. Rows are deleted when logging in past
+//! `AppConfig::max_sessions_per_user` (the oldest ones) and when the user logs
+//! out everywhere.
+//!
+//! The same middleware also ends sessions of users who have been deactivated
+//! since they logged in, which Cot itself doesn't check.
+
+use crate::config::app_config;
+use chrono::{DateTime, FixedOffset, Utc};
+use cot::auth::Auth;
+use cot::db::{Auto, Database, LimitedString, Model, model, query};
+use cot::request::{Request, RequestExt};
+use cot::response::Response;
+use cot::session::Session;
+use futures::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower::Service;
+use tracing::info;
+
+/// The session entry holding the key of the session's [`UserSession`] row.
+const SESSION_KEY: &str = "user_session_key";
+
+#[derive(Debug, Clone)]
+#[model]
+pub(crate) struct UserSession {
+    #[model(primary_key)]
+    id: Auto<i64>,
+    user_id: i64,
+    #[model(unique)]
+    key: LimitedString<64>,
+    created_at: DateTime<FixedOffset>,
+}
+
+fn max_sessions() -> Option<usize> {
+    match app_config().max_sessions_per_user {
+        0 => None,
+        max => Some(max as usize),
+    }
+}
+
+async fn session_key(session: &Session) -> cot::Result<Option<LimitedString<64>>> {
+    let key: Option<String> = session.get(SESSION_KEY).await.map_err(cot::Error::wrap)?;
+    Ok(key.and_then(|key| LimitedString::new(key).ok()))
+}
+
+/// Records a fresh login of `user_id` in `session`, evicting the user's oldest
+/// sessions if that takes them past the limit.
+pub(crate) async fn register(session: &Session, db: &Database, user_id: i64) -> cot::Result<()> {
+    let key = hex::encode(rand::random::<[u8; 16]>());
+    UserSession {
+        id: Auto::auto(),
+        user_id,
+        key: LimitedString::new(key.clone()).expect("32 hex characters fit"),
+        created_at: Utc::now().fixed_offset(),
+    }
+    .save(db)
+    .await?;
+    session
+        .insert(SESSION_KEY, key)
+        .await
+        .map_err(cot::Error::wrap)?;
+
+    let Some(max) = max_sessions() else {
+        return Ok(());
+    };
+    let mut sessions = query!(UserSession, $user_id == user_id).all(db).await?;
+    if sessions.len() > max {
+        sessions.sort_by_key(|user_session| user_session.created_at);
+        let evicted = sessions.len() - max;
+        for user_session in &sessions[..evicted] {
+            let key = user_session.key.clone();
+            query!(UserSession, $key == key).delete(db).await?;
+        }
+        info!(user_id, evicted, "evicted sessions over the per-user limit");
+    }
+    Ok(())
+}
+
+/// Drops the row of a session that is being logged out.
+pub(crate) async fn forget(session: &Session, db: &Database) -> cot::Result<()> {
+    if let Some(key) = session_key(session).await? {
+        query!(UserSession, $key == key).delete(db).await?;
+    }
+    Ok(())
+}
+
+/// Ends every session `user_id` is logged in with. Sessions that predate
+/// session tracking carry no key and aren't affected.
+pub(crate) async fn forget_all(db: &Database, user_id: i64) -> cot::Result<()> {
+    query!(UserSession, $user_id == user_id).delete(db).await?;
+    Ok(())
+}
+
+/// Drops the rows of every session `user_id` is logged in with other than
+/// `session`.
+///
+/// Changing the password already logs those sessions out, as their auth hash
+/// no longer matches; this keeps [`count`] and the per-user limit from still
+/// counting them.
+pub(crate) async fn forget_others(
+    session: &Session,
+    db: &Database,
+    user_id: i64,
+) -> cot::Result<()> {
+    match session_key(session).await? {
+        Some(key) => {
+            query!(UserSession, $user_id == user_id && $key != key)
+                .delete(db)
+                .await?;
+        }
+        None => forget_all(db, user_id).await?,
+    }
+    Ok(())
+}
+
+/// How many sessions `user_id` is logged in with.
+pub(crate) async fn count(db: &Database, user_id: i64) -> cot::Result<u64> {
+    Ok(query!(UserSession, $user_id == user_id).count(db).await?)
+}
+
+/// Logs out sessions that were evicted by a newer login or ended by logging
+/// out everywhere, or whose user has been deactivated.
+///
+/// Must be placed inside [`cot::middleware::AuthMiddleware`] so the request
+/// already carries the session and the logged-in user.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct SessionLimitMiddleware;
+
+impl<S> tower::Layer<S> for SessionLimitMiddleware {
+    type Service = SessionLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SessionLimitService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SessionLimitService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for SessionLimitService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let auth = req
+                .extensions()
+                .get::<Auth>()
+                .expect("SessionLimitMiddleware must run inside AuthMiddleware");
+            let user = auth.user();
+            if user.is_authenticated() && !user.is_active() {
+                forget(Session::from_request(&req), req.context().database()).await?;
+                auth.logout().await?;
+            } else if user.is_authenticated()
+                // sessions that predate session tracking carry no key and stay
+                // valid
+                && let Some(key) = session_key(Session::from_request(&req)).await?
+                && !query!(UserSession, $key == key)
+                    .exists(req.context().database())
+                    .await?
+            {
+                auth.logout().await?;
+            }
+
+            inner.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{PASSWORD, TestApp};
+    use cot::StatusCode;
+    use std::collections::BTreeMap;
+
+    #[cot::test]
+    async fn logging_in_past_the_limit_ends_the_oldest_session() {
+        let mut app = TestApp::with_config(|config| config.max_sessions_per_user = 2).await;
+        app.create_user("session_limit", PASSWORD).await;
+        app.login("session_limit", PASSWORD).await;
+        let oldest = app.swap_cookies(BTreeMap::new());
+        app.login("session_limit", PASSWORD).await;
+        let middle = app.swap_cookies(BTreeMap::new());
+
+        app.login("session_limit", PASSWORD).await;
+
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+        let newest = app.swap_cookies(middle);
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+        app.swap_cookies(oldest);
+        assert_eq!(app.get("/home").await.location(), Some("/login"));
+        app.swap_cookies(newest);
+        assert_eq!(app.get("/home").await.status, StatusCode::OK);
+    }
+
+    #[cot::test]
+    async fn no_limit_keeps_every_session() {
+        let mut app = TestApp::new().await;
+        app.create_user("session_no_limit", PASSWORD).await;
+        let mut browsers = Vec::new();
+        for _ in 0..3 {
+            app.login("session_no_limit", PASSWORD).await;
+            browsers.push(app.swap_cookies(BTreeMap::new()));
+        }
+
+        for cookies in browsers {
+            app.swap_cookies(cookies);
+            assert_eq!(app.get("/home").await.status, StatusCode::OK);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Sending text messages, for password reset codes.
+//!
+//! Messages go through an [`SmsSender`]. The only one so far is
+//! [`ConsoleSmsSender`], which logs them instead of sending them;
+//! a gateway can be added by implementing the trait and returning it from
+//! [`SMS_SENDER`].
+
+use async_trait::async_trait;
+use std::sync::LazyLock;
+use tracing::info;
+
+/// The most digits an E.164 number has, country code included.
+const MAX_PHONE_DIGITS: usize = 15;
+/// The fewest digits a number can have and still be routable. The shortest
+/// numbers in use, with their country code, have 8.
+const MIN_PHONE_DIGITS: usize = 8;
+
+static SMS_SENDER: LazyLock<Box<dyn SmsSender>> = LazyLock::new(|| Box::new(ConsoleSmsSender));
+
+/// Sends `body` to the E.164 number `to`.
+pub(crate) async fn send(to: &str, body: &str) -> cot::Result<()> {
+    #[cfg(test)]
+    crate::test_utils::record_sms(to, body);
+    SMS_SENDER.send(to, body).await.map_err(cot::Error::wrap)
+}
+
+#[async_trait]
+pub(crate) trait SmsSender: Send + Sync {
+    async fn send(&self, to: &str, body: &str) -> Result<(), SmsError>;
+}
+
+pub(crate) type SmsError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Logs messages at `info` level instead of sending them, so they end up
+/// with the rest of the app's output and its log format.
+pub(crate) struct ConsoleSmsSender;
+
+#[async_trait]
+impl SmsSender for ConsoleSmsSender {
+    async fn send(&self, to: &str, body: &str) -> Result<(), SmsError> {
+        info!(event = "sms_sent", to, body, "SMS sent to the console");
+        Ok(())
+    }
+}
+
+/// Puts `phone` in E.164 form (`+` and digits only), or returns `None` if it
+/// isn't an international number.
+///
+/// Spaces, dots, dashes and parentheses people write numbers with are
+/// dropped. A leading `00` is read as the international prefix.
+pub(crate) fn normalize_phone(phone: &str) -> Option<String> {
+    let compact: String = phone
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '.' | '-' | '(' | ')'))
+        .collect();
+    let digits = compact
+        .strip_prefix('+')
+        .or_else(|| compact.strip_prefix("00"))?;
+    let valid = (MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS).contains(&digits.len())
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && !digits.starts_with('0');
+    if !valid {
+        return None;
+    }
+    Some(format!("+{digits}"))
+}
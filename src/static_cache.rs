@@ -0,0 +1,228 @@
+//! Letting browsers cache static files: an `ETag` so they can revalidate
+//! cheaply, and `immutable` for content-hashed URLs so they don't have to.
+
+use crate::config::app_config;
+use cot::http::{HeaderMap, HeaderValue, header};
+use cot::request::{Request, RequestExt};
+use cot::response::Response;
+use cot::{Body, StatusCode};
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use std::task::{Context, Poll};
+use tower::Service;
+
+/// The query parameter `static_files.rewrite = "query_param"` puts the
+/// content hash in.
+const VERSION_PARAM: &str = "v";
+
+/// A strong `ETag` for `content`.
+fn etag(content: &[u8]) -> HeaderValue {
+    let digest = hex::encode(&Sha256::digest(content)[..16]);
+    HeaderValue::from_str(&format!("\"{digest}\"")).expect("a quoted hex digest is a valid value")
+}
+
+/// Whether `If-None-Match` in `headers` lists `etag`. Weak tags match too, as
+/// only the contents are compared.
+fn matches_if_none_match(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+fn is_versioned(req: &Request) -> bool {
+    req.uri().query().is_some_and(|query| {
+        query
+            .split('&')
+            .any(|pair| pair.split('=').next() == Some(VERSION_PARAM))
+    })
+}
+
+/// Adds `ETag` and revalidation to responses served by
+/// `StaticFilesMiddleware`, and `immutable` to the `Cache-Control` of
+/// content-hashed URLs; see [`crate::config::StaticCacheConfig`].
+///
+/// It has to be the next middleware out from `StaticFilesMiddleware`, so it
+/// sees the files before anything else changes them.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct StaticCacheMiddleware;
+
+impl<S> tower::Layer<S> for StaticCacheMiddleware {
+    type Service = StaticCacheService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        StaticCacheService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StaticCacheService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for StaticCacheService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let config = &app_config().static_cache;
+            let is_static = req
+                .uri()
+                .path()
+                .starts_with(&req.context().config().static_files.url);
+            if !is_static {
+                return inner.call(req).await;
+            }
+            let versioned = is_versioned(&req);
+            let request_headers = req.headers().clone();
+            let response = inner.call(req).await?;
+            if response.status() != StatusCode::OK {
+                return Ok(response);
+            }
+            let (mut parts, mut body) = response.into_parts();
+
+            if config.immutable
+                && versioned
+                && let Some(cache_control) = parts
+                    .headers
+                    .get(header::CACHE_CONTROL)
+                    .and_then(|value| value.to_str().ok())
+            {
+                let cache_control = format!("{cache_control}, immutable");
+                parts.headers.insert(
+                    header::CACHE_CONTROL,
+                    HeaderValue::from_str(&cache_control).expect("the value was valid already"),
+                );
+            }
+            if config.etag {
+                // static files are small and already in memory, so reading
+                // them whole costs little
+                let content = body.into_bytes().await?;
+                let etag = etag(&content);
+                if matches_if_none_match(&request_headers, &etag) {
+                    parts.status = StatusCode::NOT_MODIFIED;
+                    parts.headers.remove(header::CONTENT_LENGTH);
+                    body = Body::empty();
+                } else {
+                    body = Body::fixed(content);
+                }
+                parts.headers.insert(header::ETAG, etag);
+            }
+            Ok(Response::from_parts(parts, body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, StaticCacheConfig, set_test_app_config};
+    use crate::test_utils::{TestApp, TestResponse};
+    use cot::config::StaticFilesPathRewriteMode;
+    use cot::http::Method;
+    use std::time::Duration;
+
+    async fn hashed_app() -> TestApp {
+        TestApp::with_project_config(|config| {
+            config.static_files.rewrite = StaticFilesPathRewriteMode::QueryParam;
+            config.static_files.cache_timeout = Some(Duration::from_secs(3600));
+        })
+        .await
+    }
+
+    /// The content-hashed URL the login page links its stylesheet with.
+    async fn stylesheet_url(app: &mut TestApp) -> String {
+        let body = app.get("/login").await.body;
+        let url = body
+            .split("href=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("the login page links its stylesheet");
+        url.replace("&amp;", "&")
+    }
+
+    async fn get_if_none_match(app: &mut TestApp, url: &str, tag: &str) -> TestResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(tag).unwrap());
+        app.send(Method::GET, url, headers, Body::empty()).await
+    }
+
+    #[cot::test]
+    async fn hashed_stylesheets_are_immutable_and_revalidate_by_etag() {
+        let mut app = hashed_app().await;
+        let url = stylesheet_url(&mut app).await;
+        assert!(url.contains(&format!("?{VERSION_PARAM}=")), "{url}");
+
+        let response = app.get(&url).await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            response.headers[header::CACHE_CONTROL],
+            "max-age=3600, immutable"
+        );
+        let tag = response.headers[header::ETAG].to_str().unwrap().to_owned();
+        assert_eq!(tag, etag(response.body.as_bytes()));
+
+        let revalidated = get_if_none_match(&mut app, &url, &tag).await;
+        assert_eq!(revalidated.status, StatusCode::NOT_MODIFIED);
+        assert!(revalidated.body.is_empty());
+        assert_eq!(revalidated.headers[header::ETAG], tag.as_str());
+        let weak = get_if_none_match(&mut app, &url, &format!("\"other\", W/{tag}")).await;
+        assert_eq!(weak.status, StatusCode::NOT_MODIFIED);
+        let changed = get_if_none_match(&mut app, &url, "\"other\"").await;
+        assert_eq!(changed.status, StatusCode::OK);
+        assert_eq!(changed.body, response.body);
+    }
+
+    #[cot::test]
+    async fn unhashed_urls_revalidate_but_are_not_immutable() {
+        let mut app = hashed_app().await;
+        let url = stylesheet_url(&mut app).await;
+        let (path, _) = url.split_once('?').unwrap();
+
+        let response = app.get(path).await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers[header::CACHE_CONTROL], "max-age=3600");
+        assert!(response.headers.contains_key(header::ETAG));
+    }
+
+    #[cot::test]
+    async fn etags_can_be_turned_off() {
+        let mut app = hashed_app().await;
+        let url = stylesheet_url(&mut app).await;
+        let tag = app.get(&url).await.headers[header::ETAG].clone();
+        set_test_app_config(AppConfig {
+            static_cache: StaticCacheConfig {
+                etag: false,
+                immutable: true,
+            },
+            ..app_config().clone()
+        });
+
+        let response = get_if_none_match(&mut app, &url, tag.to_str().unwrap()).await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(!response.headers.contains_key(header::ETAG));
+    }
+}
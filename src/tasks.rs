@@ -0,0 +1,866 @@
+use crate::auth::{User, normalize_email, normalize_username, validate_username};
+use crate::breach::validate_not_breached;
+use crate::forms::fields::clean_person_name;
+use crate::password_strength::validate_entropy;
+use crate::sessions;
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
+use cot::Bootstrapper;
+use cot::cli::CliTask;
+use cot::cli::clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use cot::common_types::{Email, Password};
+use cot::db::{Database, Model};
+use cot::project::WithConfig;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+const REHASH_PASSWORDS_SUBCOMMAND: &str = "rehash-passwords";
+const DISABLE_STALE_ACCOUNTS_SUBCOMMAND: &str = "disable-stale-accounts";
+const FORCE_PASSWORD_RESET_SUBCOMMAND: &str = "force-password-reset";
+const SET_STAFF_SUBCOMMAND: &str = "set-staff";
+const FORCE_LOGOUT_SUBCOMMAND: &str = "force-logout";
+const IMPORT_USERS_SUBCOMMAND: &str = "import-users";
+const DUPLICATE_EMAILS_SUBCOMMAND: &str = "duplicate-emails";
+const NORMALIZE_USERNAMES_SUBCOMMAND: &str = "normalize-usernames";
+const VERBOSE_PARAM: &str = "verbose";
+const DAYS_PARAM: &str = "days";
+const DRY_RUN_PARAM: &str = "dry-run";
+const USERNAME_PARAM: &str = "username";
+const REVOKE_PARAM: &str = "revoke";
+const SUPERUSER_PARAM: &str = "superuser";
+const CSV_PARAM: &str = "csv";
+
+/// Audits stored password hashes and reports the ones using outdated
+/// parameters.
+///
+/// Hashes can't be upgraded without the plaintext password, so this only
+/// reports them; each one is rehashed on the user's next successful login.
+pub(crate) struct RehashPasswords;
+
+impl RehashPasswords {
+    /// The usernames of the users with outdated hashes, and how many users
+    /// there are in all.
+    async fn outdated(db: &Database) -> cot::Result<(Vec<String>, usize)> {
+        let users = User::all(db).await?;
+        let outdated = users
+            .iter()
+            .filter(|user| user.has_obsolete_password_hash())
+            .map(|user| user.username().to_owned())
+            .collect();
+        Ok((outdated, users.len()))
+    }
+}
+
+#[async_trait(?Send)]
+impl CliTask for RehashPasswords {
+    fn subcommand(&self) -> Command {
+        Command::new(REHASH_PASSWORDS_SUBCOMMAND)
+            .about("Reports users whose password hashes use outdated parameters")
+            .arg(
+                Arg::new(VERBOSE_PARAM)
+                    .help("List the usernames of the affected users")
+                    .short('v')
+                    .long(VERBOSE_PARAM)
+                    .action(ArgAction::SetTrue),
+            )
+    }
+
+    async fn execute(
+        &mut self,
+        matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let verbose = matches.get_flag(VERBOSE_PARAM);
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        let (outdated, total) = Self::outdated(db).await?;
+
+        if verbose {
+            for username in &outdated {
+                println!("{username}");
+            }
+        }
+        println!(
+            "{} of {total} users have outdated password hashes; they will be upgraded on next login",
+            outdated.len(),
+        );
+
+        Ok(())
+    }
+}
+
+/// Deactivates accounts that haven't been used for a given number of days.
+pub(crate) struct DisableStaleAccounts;
+
+impl DisableStaleAccounts {
+    /// Deactivates the active accounts last used before `cutoff`, or with
+    /// `dry_run` only finds them, and returns their usernames.
+    async fn disable(
+        db: &Database,
+        cutoff: DateTime<FixedOffset>,
+        dry_run: bool,
+    ) -> cot::Result<Vec<String>> {
+        let mut stale: Vec<User> = User::all(db)
+            .await?
+            .into_iter()
+            .filter(|user| user.is_active() && user.is_stale(cutoff))
+            .collect();
+
+        if !dry_run {
+            for user in &mut stale {
+                user.deactivate().save(db).await?;
+            }
+        }
+        Ok(stale
+            .iter()
+            .map(|user| user.username().to_owned())
+            .collect())
+    }
+}
+
+#[async_trait(?Send)]
+impl CliTask for DisableStaleAccounts {
+    fn subcommand(&self) -> Command {
+        Command::new(DISABLE_STALE_ACCOUNTS_SUBCOMMAND)
+            .about("Deactivates accounts that haven't logged in for a number of days")
+            .arg(
+                Arg::new(DAYS_PARAM)
+                    .help("Accounts idle for longer than this many days are deactivated")
+                    .long(DAYS_PARAM)
+                    .required(true)
+                    .value_parser(value_parser!(u32)),
+            )
+            .arg(
+                Arg::new(DRY_RUN_PARAM)
+                    .help("Only report the affected accounts without changing them")
+                    .long(DRY_RUN_PARAM)
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(VERBOSE_PARAM)
+                    .help("List the usernames of the affected users")
+                    .short('v')
+                    .long(VERBOSE_PARAM)
+                    .action(ArgAction::SetTrue),
+            )
+    }
+
+    async fn execute(
+        &mut self,
+        matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let days = *matches
+            .get_one::<u32>(DAYS_PARAM)
+            .expect("--days is a required argument");
+        let dry_run = matches.get_flag(DRY_RUN_PARAM);
+        let verbose = matches.get_flag(VERBOSE_PARAM);
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        let cutoff = (Utc::now() - TimeDelta::days(i64::from(days))).fixed_offset();
+        let stale = Self::disable(db, cutoff, dry_run).await?;
+
+        if verbose {
+            for username in &stale {
+                println!("{username}");
+            }
+        }
+        if dry_run {
+            println!(
+                "{} accounts have been idle for more than {days} days and would be deactivated",
+                stale.len()
+            );
+        } else {
+            println!(
+                "Deactivated {} accounts idle for more than {days} days",
+                stale.len()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Makes users pick a new password the next time they log in.
+pub(crate) struct ForcePasswordReset;
+
+#[async_trait(?Send)]
+impl CliTask for ForcePasswordReset {
+    fn subcommand(&self) -> Command {
+        Command::new(FORCE_PASSWORD_RESET_SUBCOMMAND)
+            .about("Requires users to change their password on next login")
+            .arg(
+                Arg::new(USERNAME_PARAM)
+                    .help("The users to flag")
+                    .required(true)
+                    .num_args(1..),
+            )
+    }
+
+    async fn execute(
+        &mut self,
+        matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        for username in matches
+            .get_many::<String>(USERNAME_PARAM)
+            .expect("username is a required argument")
+        {
+            match User::get_by_username(db, username).await? {
+                Some(mut user) => {
+                    user.set_must_change_password(true).save(db).await?;
+                    println!("{username} must change their password on next login");
+                }
+                None => println!("No such user: {username}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Logs users out of every session they have.
+pub(crate) struct ForceLogout;
+
+#[async_trait(?Send)]
+impl CliTask for ForceLogout {
+    fn subcommand(&self) -> Command {
+        Command::new(FORCE_LOGOUT_SUBCOMMAND)
+            .about("Ends every session of the given users")
+            .arg(
+                Arg::new(USERNAME_PARAM)
+                    .help("The users to log out")
+                    .required(true)
+                    .num_args(1..),
+            )
+    }
+
+    async fn execute(
+        &mut self,
+        matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        for username in matches
+            .get_many::<String>(USERNAME_PARAM)
+            .expect("username is a required argument")
+        {
+            match User::get_by_username(db, username).await? {
+                Some(mut user) => {
+                    user.rotate_sessions().save(db).await?;
+                    let id = user.id().expect("user loaded from the database has an id");
+                    sessions::forget_all(db, id).await?;
+                    println!("{username} has been logged out everywhere");
+                }
+                None => println!("No such user: {username}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Grants or revokes staff status, or with `--superuser`, superuser status.
+///
+/// Superusers can only be made here, as the admin endpoint for staff changes
+/// is limited to them.
+pub(crate) struct SetStaff;
+
+#[async_trait(?Send)]
+impl CliTask for SetStaff {
+    fn subcommand(&self) -> Command {
+        Command::new(SET_STAFF_SUBCOMMAND)
+            .about("Makes users staff, or with --revoke, regular users again")
+            .arg(
+                Arg::new(USERNAME_PARAM)
+                    .help("The users to update")
+                    .required(true)
+                    .num_args(1..),
+            )
+            .arg(
+                Arg::new(REVOKE_PARAM)
+                    .help("Revoke staff status instead of granting it")
+                    .long(REVOKE_PARAM)
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new(SUPERUSER_PARAM)
+                    .help(
+                        "Grant or revoke superuser status, which lets staff change \
+                         other users' staff status",
+                    )
+                    .long(SUPERUSER_PARAM)
+                    .action(ArgAction::SetTrue),
+            )
+    }
+
+    async fn execute(
+        &mut self,
+        matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let grant = !matches.get_flag(REVOKE_PARAM);
+        let superuser = matches.get_flag(SUPERUSER_PARAM);
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        for username in matches
+            .get_many::<String>(USERNAME_PARAM)
+            .expect("username is a required argument")
+        {
+            match User::get_by_username(db, username).await? {
+                Some(mut user) => {
+                    if superuser {
+                        user.set_superuser(grant);
+                    } else {
+                        user.set_staff_unaudited(grant);
+                    }
+                    user.save(db).await?;
+                    let role = if user.is_superuser() {
+                        "superuser"
+                    } else {
+                        user.role()
+                    };
+                    println!("{username} is now a {role}");
+                }
+                None => println!("No such user: {username}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A row of the CSV file read by [`ImportUsers`].
+#[derive(Deserialize)]
+struct ImportRow {
+    username: String,
+    email: String,
+    #[serde(default)]
+    name: String,
+    /// Left empty for accounts whose users should set their password through
+    /// the reset flow.
+    #[serde(default)]
+    password: String,
+}
+
+/// What [`ImportUsers`] did with each row of its CSV file: the line it
+/// started on, and the username created or why the row was skipped.
+#[derive(Debug, Default, PartialEq)]
+struct ImportedUsers {
+    rows: Vec<(u64, Result<String, String>)>,
+}
+
+impl ImportedUsers {
+    fn created(&self) -> usize {
+        self.rows
+            .iter()
+            .filter(|(_, result)| result.is_ok())
+            .count()
+    }
+}
+
+/// Creates users from a CSV file with a `username,email,name,password`
+/// header, checking each row like the signup form does.
+///
+/// A bad row is reported and skipped; the rows around it are still imported.
+pub(crate) struct ImportUsers;
+
+impl ImportUsers {
+    /// Imports every row of the CSV in `input`.
+    async fn import(db: &Database, input: impl std::io::Read) -> cot::Result<ImportedUsers> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::Headers)
+            // the name and password columns can be left off the end of a row
+            .flexible(true)
+            .from_reader(input);
+        let headers = reader.headers().map_err(cot::Error::wrap)?.clone();
+
+        let mut imported = ImportedUsers::default();
+        for record in reader.records() {
+            let position = match &record {
+                Ok(record) => record.position(),
+                Err(err) => err.position(),
+            };
+            let line = position.map_or(0, csv::Position::line);
+            let row = record.and_then(|mut record| {
+                while record.len() < headers.len() {
+                    record.push_field("");
+                }
+                record.deserialize::<ImportRow>(Some(&headers))
+            });
+            let result = match row {
+                Ok(row) => Self::import_row(db, row).await?,
+                Err(err) => Err(err.to_string()),
+            };
+            imported.rows.push((line, result));
+        }
+        Ok(imported)
+    }
+
+    /// Creates the user described by `row`, or says why it can't be.
+    async fn import_row(db: &Database, row: ImportRow) -> cot::Result<Result<String, String>> {
+        let username = normalize_username(row.username.trim());
+        if let Err(err) = validate_username(&username) {
+            return Ok(Err(err.to_string()));
+        }
+        if User::exists_by_username(db, &username).await? {
+            return Ok(Err("this username is already taken.".to_string()));
+        }
+        let Ok(email) = Email::new(row.email.trim()) else {
+            return Ok(Err("the email address is invalid.".to_string()));
+        };
+        if User::exists_by_email(db, &email).await? {
+            return Ok(Err(
+                "an account with this email address already exists.".to_string()
+            ));
+        }
+
+        // a row can leave the name out, but not give one that's all blanks
+        let name = match clean_person_name(&row.name) {
+            Some(name) => name,
+            None if row.name.is_empty() => String::new(),
+            None => return Ok(Err("the name has no visible characters.".to_string())),
+        };
+
+        let mut builder = User::builder().username(username).email(email).name(name);
+        if row.password.is_empty() {
+            builder = builder.unusable_password();
+        } else {
+            let password = Password::new(row.password);
+            if let Err(err) = validate_entropy(&password) {
+                return Ok(Err(err.to_string()));
+            }
+            if let Err(err) = validate_not_breached(&password).await {
+                return Ok(Err(err.to_string()));
+            }
+            builder = builder.password(&password);
+        }
+        let mut user = match builder.build() {
+            Ok(user) => user,
+            Err(err) => return Ok(Err(err.to_string())),
+        };
+        user.save(db).await?;
+        Ok(Ok(user.username().to_owned()))
+    }
+}
+
+#[async_trait(?Send)]
+impl CliTask for ImportUsers {
+    fn subcommand(&self) -> Command {
+        Command::new(IMPORT_USERS_SUBCOMMAND)
+            .about("Creates users from a CSV file of username,email,name,password rows")
+            .arg(
+                Arg::new(CSV_PARAM)
+                    .help("The CSV file to read; rows without a password get an unusable one")
+                    .long(CSV_PARAM)
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            )
+    }
+
+    async fn execute(
+        &mut self,
+        matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let path = matches
+            .get_one::<PathBuf>(CSV_PARAM)
+            .expect("--csv is a required argument");
+        let file = std::fs::File::open(path).map_err(cot::Error::wrap)?;
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        let imported = Self::import(db, file).await?;
+        for (line, result) in &imported.rows {
+            match result {
+                Ok(username) => println!("line {line}: created {username}"),
+                Err(reason) => println!("line {line}: skipped: {reason}"),
+            }
+        }
+        println!(
+            "Imported {} of {} users",
+            imported.created(),
+            imported.rows.len()
+        );
+
+        Ok(())
+    }
+}
+
+/// Lists the email addresses shared by more than one account, which keep
+/// `m_0020_user_email_unique` from making addresses unique.
+///
+/// Addresses are compared the way new ones are stored, so accounts from
+/// before normalization whose addresses differ only in case are listed too.
+/// Nothing is changed: which account keeps an address is for its owners to
+/// say.
+pub(crate) struct DuplicateEmails;
+
+impl DuplicateEmails {
+    /// The usernames of the accounts sharing each address used more than once.
+    fn shared_addresses(users: &[User]) -> BTreeMap<String, Vec<&str>> {
+        let mut accounts: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for user in users {
+            accounts
+                .entry(normalize_email(user.email()).as_str().to_owned())
+                .or_default()
+                .push(user.username());
+        }
+        accounts.retain(|_, usernames| usernames.len() > 1);
+        accounts
+    }
+}
+
+#[async_trait(?Send)]
+impl CliTask for DuplicateEmails {
+    fn subcommand(&self) -> Command {
+        Command::new(DUPLICATE_EMAILS_SUBCOMMAND)
+            .about("Lists email addresses used by more than one account")
+    }
+
+    async fn execute(
+        &mut self,
+        _matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        let users = User::all(db).await?;
+        let shared = Self::shared_addresses(&users);
+        for (address, usernames) in &shared {
+            println!("{address}: {}", usernames.join(", "));
+        }
+        println!(
+            "{} addresses are used by more than one account",
+            shared.len()
+        );
+
+        Ok(())
+    }
+}
+
+/// Applies the NFKC normalization new usernames get to the ones stored before
+/// it, so their owners can still log in: logins look up the normalized name.
+///
+/// Names that would end up the same as another account's, like `ﬁona` next to
+/// an existing `fiona`, are only reported; which account keeps the name is for
+/// the people behind them to decide.
+pub(crate) struct NormalizeUsernames;
+
+/// What [`NormalizeUsernames`] did, or would do with `--dry-run`.
+#[derive(Debug, Default, PartialEq)]
+struct NormalizedUsernames {
+    /// The stored and normalized name of each renamed account.
+    renamed: Vec<(String, String)>,
+    /// The stored names of the accounts sharing each normalized name, for
+    /// names at least one of them doesn't have in normalized form yet.
+    collisions: BTreeMap<String, Vec<String>>,
+    /// Names left as they are because the normalized form doesn't fit the
+    /// column.
+    too_long: Vec<String>,
+}
+
+impl NormalizeUsernames {
+    async fn normalize(db: &Database, dry_run: bool) -> cot::Result<NormalizedUsernames> {
+        let mut users = User::all(db).await?;
+
+        let mut accounts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for user in &users {
+            accounts
+                .entry(normalize_username(user.username()))
+                .or_default()
+                .push(user.username().to_owned());
+        }
+        accounts.retain(|normalized, usernames| {
+            usernames.len() > 1 && usernames.iter().any(|username| username != normalized)
+        });
+
+        let mut outcome = NormalizedUsernames {
+            collisions: accounts,
+            ..NormalizedUsernames::default()
+        };
+        for user in &mut users {
+            if outcome
+                .collisions
+                .contains_key(&normalize_username(user.username()))
+            {
+                continue;
+            }
+            let stored = user.username().to_owned();
+            match user.normalize_stored_username() {
+                Ok(false) => {}
+                Ok(true) => {
+                    if !dry_run {
+                        user.save(db).await?;
+                    }
+                    outcome.renamed.push((stored, user.username().to_owned()));
+                }
+                Err(_) => outcome.too_long.push(stored),
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[async_trait(?Send)]
+impl CliTask for NormalizeUsernames {
+    fn subcommand(&self) -> Command {
+        Command::new(NORMALIZE_USERNAMES_SUBCOMMAND)
+            .about("Normalizes stored usernames and lists the ones that would collide")
+            .arg(
+                Arg::new(DRY_RUN_PARAM)
+                    .help("Only report the affected accounts without changing them")
+                    .long(DRY_RUN_PARAM)
+                    .action(ArgAction::SetTrue),
+            )
+    }
+
+    async fn execute(
+        &mut self,
+        matches: &ArgMatches,
+        bootstrapper: Bootstrapper<WithConfig>,
+    ) -> cot::Result<()> {
+        let dry_run = matches.get_flag(DRY_RUN_PARAM);
+        let bootstrapper = bootstrapper.boot().await?;
+        let db = bootstrapper.context().database();
+
+        let outcome = Self::normalize(db, dry_run).await?;
+        for (stored, normalized) in &outcome.renamed {
+            println!("{stored} -> {normalized}");
+        }
+        for (normalized, usernames) in &outcome.collisions {
+            println!("{normalized}: shared by {}", usernames.join(", "));
+        }
+        for username in &outcome.too_long {
+            println!("{username}: too long once normalized");
+        }
+        println!(
+            "{} usernames {}normalized; {} names would collide and {} are too long, left as they are",
+            outcome.renamed.len(),
+            if dry_run { "would be " } else { "" },
+            outcome.collisions.len(),
+            outcome.too_long.len()
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, PasswordHashAlgorithm, set_test_app_config};
+    use crate::test_utils::migrate;
+    use cot::test::TestDatabase;
+
+    fn user(username: &str, email: &str) -> User {
+        User::builder()
+            .username(username)
+            .unusable_password()
+            .email(Email::new(email).expect("valid"))
+            .build()
+            .expect("every part is given")
+    }
+
+    #[test]
+    fn lists_addresses_shared_by_several_accounts() {
+        let users = [
+            user("alice", "alice@example.com"),
+            user("alice2", "alice@example.com"),
+            user("bob", "bob@example.com"),
+        ];
+
+        let shared = DuplicateEmails::shared_addresses(&users);
+
+        assert_eq!(
+            shared,
+            BTreeMap::from([("alice@example.com".to_owned(), vec!["alice", "alice2"])])
+        );
+    }
+
+    /// Saves a user, then stores `stored_username` for it as it is, as
+    /// accounts created before normalization have it.
+    async fn legacy_user(db: &Database, username: &str, stored_username: &str) {
+        let mut user = user(username, &format!("{username}@example.com"));
+        user.save(db).await.unwrap();
+        db.raw_with(
+            r#"UPDATE "auth__user" SET "username" = ? WHERE "username" = ?"#,
+            &[&stored_username, &username],
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn usernames(db: &Database) -> Vec<String> {
+        let mut usernames: Vec<String> = User::all(db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|user| user.username().to_owned())
+            .collect();
+        usernames.sort();
+        usernames
+    }
+
+    #[cot::test]
+    async fn normalizes_stored_usernames_and_reports_collisions() {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        migrate(&db).await.unwrap();
+        // the ligature, and the fullwidth letters, both normalize to ASCII
+        legacy_user(&db, "legacy_fiona", "\u{fb01}ona").await;
+        legacy_user(&db, "legacy_bob", "\u{ff42}\u{ff4f}\u{ff42}").await;
+        user("fiona", "fiona@example.com").save(&db).await.unwrap();
+        user("carol", "carol@example.com").save(&db).await.unwrap();
+
+        let outcome = NormalizeUsernames::normalize(&db, false).await.unwrap();
+
+        assert_eq!(
+            outcome,
+            NormalizedUsernames {
+                renamed: vec![("\u{ff42}\u{ff4f}\u{ff42}".to_owned(), "bob".to_owned())],
+                collisions: BTreeMap::from([(
+                    "fiona".to_owned(),
+                    vec!["\u{fb01}ona".to_owned(), "fiona".to_owned()]
+                )]),
+                too_long: vec![],
+            }
+        );
+        assert_eq!(
+            usernames(&db).await,
+            ["bob", "carol", "fiona", "\u{fb01}ona"]
+        );
+        assert!(User::get_by_username(&db, "bob").await.unwrap().is_some());
+    }
+
+    #[cot::test]
+    async fn a_dry_run_changes_nothing() {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        migrate(&db).await.unwrap();
+        legacy_user(&db, "legacy_bob", "\u{ff42}\u{ff4f}\u{ff42}").await;
+
+        let outcome = NormalizeUsernames::normalize(&db, true).await.unwrap();
+
+        assert_eq!(outcome.renamed.len(), 1);
+        assert_eq!(usernames(&db).await, ["\u{ff42}\u{ff4f}\u{ff42}"]);
+    }
+
+    #[cot::test]
+    async fn imports_the_good_rows_and_reports_the_bad_ones() {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        migrate(&db).await.unwrap();
+        let csv = "username,email,name,password\n\
+                   imported_ada,ada@example.com,Ada Lovelace\n\
+                   imported_blank,blank@example.com,\" \t \"\n";
+
+        let imported = ImportUsers::import(&db, csv.as_bytes()).await.unwrap();
+
+        assert_eq!(
+            imported.rows,
+            [
+                (2, Ok("imported_ada".to_owned())),
+                (3, Err("the name has no visible characters.".to_owned())),
+            ]
+        );
+        assert_eq!(imported.created(), 1);
+        assert_eq!(usernames(&db).await, ["imported_ada"]);
+    }
+
+    fn hash_with(password_hash_algorithm: PasswordHashAlgorithm) {
+        set_test_app_config(AppConfig {
+            password_hash_algorithm,
+            ..AppConfig::default()
+        });
+    }
+
+    #[cot::test]
+    async fn reports_only_the_users_with_outdated_hashes() {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        migrate(&db).await.unwrap();
+        hash_with(PasswordHashAlgorithm::Bcrypt);
+        user("outdated_hash", "outdated_hash@example.com")
+            .save(&db)
+            .await
+            .unwrap();
+        hash_with(PasswordHashAlgorithm::Argon2id);
+        user("current_hash", "current_hash@example.com")
+            .save(&db)
+            .await
+            .unwrap();
+
+        let (outdated, total) = RehashPasswords::outdated(&db).await.unwrap();
+
+        assert_eq!(outdated, ["outdated_hash"]);
+        assert_eq!(total, 2);
+    }
+
+    /// Saves a user who last logged in `days_ago` days ago.
+    async fn last_seen(db: &Database, username: &str, days_ago: i64) {
+        user(username, &format!("{username}@example.com"))
+            .save(db)
+            .await
+            .unwrap();
+        let last_login = (Utc::now() - TimeDelta::days(days_ago)).fixed_offset();
+        db.raw_with(
+            r#"UPDATE "auth__user" SET "last_login" = ? WHERE "username" = ?"#,
+            &[&last_login, &username],
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn is_active(db: &Database, username: &str) -> bool {
+        User::get_by_username(db, username)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_active()
+    }
+
+    #[cot::test]
+    async fn only_accounts_idle_past_the_cutoff_are_deactivated() {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        migrate(&db).await.unwrap();
+        last_seen(&db, "idle", 100).await;
+        last_seen(&db, "recent", 1).await;
+        let cutoff = (Utc::now() - TimeDelta::days(30)).fixed_offset();
+
+        let disabled = DisableStaleAccounts::disable(&db, cutoff, false)
+            .await
+            .unwrap();
+
+        assert_eq!(disabled, ["idle"]);
+        assert!(!is_active(&db, "idle").await);
+        assert!(is_active(&db, "recent").await);
+    }
+
+    #[cot::test]
+    async fn a_dry_run_deactivates_nobody() {
+        let test_db = TestDatabase::new_sqlite().await.unwrap();
+        let db = test_db.database();
+        migrate(&db).await.unwrap();
+        last_seen(&db, "idle", 100).await;
+        let cutoff = (Utc::now() - TimeDelta::days(30)).fixed_offset();
+
+        let disabled = DisableStaleAccounts::disable(&db, cutoff, true)
+            .await
+            .unwrap();
+
+        assert_eq!(disabled, ["idle"]);
+        assert!(is_active(&db, "idle").await);
+    }
+}
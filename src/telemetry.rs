@@ -0,0 +1,221 @@
+//! Prometheus metrics for the auth flows, served at `/metrics`, and the log
+//! subscriber setup.
+
+use crate::config::{LogFormat, app_config};
+use crate::utils::constant_time_eq;
+use cot::error::NotFound;
+use cot::http::{HeaderMap, header};
+use cot::request::Request;
+use cot::response::{IntoResponse, Response, ResponseExt};
+use cot::{Body, StatusCode};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+use std::time::Instant;
+use tracing::Subscriber;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub(crate) const LOGINS: &str = "auth_logins_total";
+pub(crate) const SIGNUPS: &str = "auth_signups_total";
+pub(crate) const RESET_REQUESTS: &str = "auth_password_reset_requests_total";
+pub(crate) const RESETS: &str = "auth_password_resets_total";
+/// Breach checks that couldn't reach the API and let the password through.
+pub(crate) const BREACH_CHECK_FAILURES: &str = "auth_breach_check_failures_total";
+pub(crate) const PASSWORD_HASH_SECONDS: &str = "auth_password_hash_seconds";
+pub(crate) const REQUEST_SECONDS: &str = "auth_request_duration_seconds";
+
+/// Buckets for password hashing, which takes tens of milliseconds by design.
+const PASSWORD_HASH_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the global metrics recorder, unless it already is.
+pub(crate) fn install() {
+    PROMETHEUS.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(PASSWORD_HASH_SECONDS.to_string()),
+                PASSWORD_HASH_BUCKETS,
+            )
+            .expect("bucket list is not empty")
+            .install_recorder()
+            .expect("no other metrics recorder is installed")
+    });
+}
+
+/// Installs the global log subscriber, writing events in `format`.
+///
+/// The filter comes from `RUST_LOG`, falling back to `info`.
+pub(crate) fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    // let the test harness capture the output of passing tests
+    #[cfg(test)]
+    let writer = tracing_subscriber::fmt::TestWriter::new();
+    #[cfg(not(test))]
+    let writer = std::io::stdout;
+    // tasks and tests may load the config more than once; the first
+    // subscriber stays
+    let _ = log_subscriber(format, filter, writer).try_init();
+}
+
+/// A subscriber writing the events `filter` lets through to `writer`, in
+/// `format`.
+pub(crate) fn log_subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().flatten_event(true).finish()),
+    }
+}
+
+/// Runs `f`, recording how long it took under [`PASSWORD_HASH_SECONDS`].
+pub(crate) fn time_password_hash<T>(operation: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    metrics::histogram!(PASSWORD_HASH_SECONDS, "operation" => operation)
+        .record(start.elapsed().as_secs_f64());
+    result
+}
+
+/// Serves the metrics in the Prometheus text format, when
+/// [`MetricsConfig::enabled`] and the request has the configured bearer token.
+///
+/// [`MetricsConfig::enabled`]: crate::config::MetricsConfig::enabled
+pub(crate) async fn metrics_endpoint(request: Request) -> cot::Result<Response> {
+    let config = &app_config().metrics;
+    if !config.enabled {
+        return Err(NotFound::new().into());
+    }
+    if !config.bearer_token.is_empty() && !has_bearer_token(request.headers(), &config.bearer_token)
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .body(Body::empty())
+            .expect("the response is valid"));
+    }
+
+    let body = match PROMETHEUS.get() {
+        Some(handle) => {
+            handle.run_upkeep();
+            handle.render()
+        }
+        None => String::new(),
+    };
+
+    Body::fixed(body)
+        .with_content_type("text/plain; version=0.0.4; charset=utf-8")
+        .into_response()
+}
+
+fn has_bearer_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|sent| constant_time_eq(sent.as_bytes(), token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{CapturedLogs, PASSWORD, TestApp};
+    use cot::http::{HeaderValue, Method};
+
+    /// Reads the value of the sample named exactly `sample` from a scrape.
+    fn sample_value(scrape: &str, sample: &str) -> f64 {
+        scrape
+            .lines()
+            .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' '))
+            .map_or(0.0, |value| value.parse().expect("samples are numbers"))
+    }
+
+    #[cot::test]
+    async fn metrics_are_not_served_unless_enabled() {
+        let mut app = TestApp::new().await;
+
+        assert_eq!(app.get("/metrics").await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[cot::test]
+    async fn scraping_after_a_login_shows_it_counted() {
+        install();
+        let mut app = TestApp::with_config(|config| config.metrics.enabled = true).await;
+        app.create_user("metrics_login", PASSWORD).await;
+        let logins = r#"auth_logins_total{outcome="success"}"#;
+        let before = sample_value(&app.get("/metrics").await.body, logins);
+
+        app.login("metrics_login", PASSWORD).await;
+        let response = app.get("/metrics").await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(sample_value(&response.body, logins) > before);
+    }
+
+    #[cot::test]
+    async fn metrics_need_the_configured_bearer_token() {
+        let mut app = TestApp::with_config(|config| {
+            config.metrics.enabled = true;
+            config.metrics.bearer_token = "scrape-token".to_owned();
+        })
+        .await;
+        let scrape = async |app: &mut TestApp, authorization: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(authorization) = authorization {
+                headers.insert(
+                    header::AUTHORIZATION,
+                    HeaderValue::from_static(authorization),
+                );
+            }
+            app.send(Method::GET, "/metrics", headers, Body::empty())
+                .await
+                .status
+        };
+
+        for authorization in [None, Some("Bearer wrong-token"), Some("scrape-token")] {
+            assert_eq!(
+                scrape(&mut app, authorization).await,
+                StatusCode::UNAUTHORIZED,
+                "{authorization:?}"
+            );
+        }
+        assert_eq!(
+            scrape(&mut app, Some("Bearer scrape-token")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[cot::test]
+    async fn json_logs_keep_event_fields_as_keys() {
+        let mut app = TestApp::new().await;
+        let captured = CapturedLogs::default();
+        let writer = captured.clone();
+        let subscriber = log_subscriber(LogFormat::Json, EnvFilter::new("auth=info"), move || {
+            writer.clone()
+        });
+
+        let guard = tracing::subscriber::set_default(subscriber);
+        app.login("json_logged", "wrong password").await;
+        drop(guard);
+
+        let output = captured.text();
+        let line = output
+            .lines()
+            .find(|line| line.contains(r#""message":"failed login""#))
+            .expect("the failed login was logged");
+        assert!(line.starts_with('{') && line.ends_with('}'), "{line}");
+        assert!(line.contains(r#""event":"login""#), "{line}");
+        assert!(line.contains(r#""outcome":"failure""#), "{line}");
+        assert!(line.contains(r#""username":"json_logged""#), "{line}");
+    }
+}
@@ -0,0 +1,433 @@
+//! Shared setup for the tests.
+//!
+//! [`TestApp`] boots the whole project, middlewares included, with
+//! `config/test.toml` and a fresh, migrated SQLite database, and keeps the
+//! cookies it's sent like a browser would. Emails and text messages the app
+//! sends are kept on the side for the test to read back with [`sent_emails`]
+//! and [`sent_sms`].
+//!
+//! A new test usually starts like this:
+//!
+//! ```ignore
+//! #[cot::test]
+//! async fn logs_in() {
+//!     let mut app = TestApp::new().await;
+//!     app.create_user("alice", PASSWORD).await;
+//!     let response = app.login("alice", PASSWORD).await;
+//!     assert_eq!(response.status, StatusCode::SEE_OTHER);
+//! }
+//! ```
+//!
+//! Settings from the `[app]` table can be changed for a single test with
+//! [`TestApp::with_config`]. They are kept per thread, which `#[cot::test]`
+//! runs each test on. Throttles read their limits once per process, so tests
+//! of them should use usernames no other test does.
+
+use crate::AuthProject;
+use crate::auth::User;
+use crate::config::{self, AppConfig};
+use crate::migrations;
+use cot::auth::AuthBackend;
+use cot::cli::CliMetadata;
+use cot::common_types::{Email, Password};
+use cot::config::ProjectConfig;
+use cot::db::migrations::{MigrationEngine, wrap_migrations};
+use cot::db::{Database, Model};
+use cot::email::EmailMessage;
+use cot::error::handler::DynErrorPageHandler;
+use cot::http::{HeaderMap, header};
+use cot::project::{
+    AuthBackendContext, MiddlewareContext, RootHandler, RootHandlerBuilder, WithConfig,
+};
+use cot::router::Router;
+use cot::test::Client;
+use cot::{App, AppBuilder, Body, Method, Project, ProjectContext, StatusCode};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A password that passes every default check.
+pub(crate) const PASSWORD: &str = "Str0ng!Passphrase-xyz";
+
+thread_local! {
+    static SENT_EMAILS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static SENT_SMS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Called by `mail_queue::send` for every email the app sends.
+pub(crate) fn record_email(message: &EmailMessage) {
+    // the message's parts are private to Cot; its debug output has them all
+    SENT_EMAILS.with_borrow_mut(|sent| sent.push(format!("{message:?}")));
+}
+
+/// Takes the emails sent since the last call, as the debug output of each
+/// message.
+pub(crate) fn sent_emails() -> Vec<String> {
+    SENT_EMAILS.take()
+}
+
+/// Called by `sms::send` for every text message the app sends.
+pub(crate) fn record_sms(to: &str, body: &str) {
+    SENT_SMS.with_borrow_mut(|sent| sent.push((to.to_owned(), body.to_owned())));
+}
+
+/// Takes the text messages sent since the last call, as the number each was
+/// sent to and its body.
+pub(crate) fn sent_sms() -> Vec<(String, String)> {
+    SENT_SMS.take()
+}
+
+/// The path of the first link in `text` whose path starts with `prefix`,
+/// e.g. the reset link in an email.
+pub(crate) fn find_link(text: &str, prefix: &str) -> Option<String> {
+    let base_url = &config::app_config().base_url;
+    let start = text.find(&format!("{base_url}{prefix}"))? + base_url.len();
+    Some(
+        text[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || "/-_.~%=?&".contains(*c))
+            .collect(),
+    )
+}
+
+/// Collects what a log subscriber writes, for tests of what gets logged.
+#[derive(Clone, Default)]
+pub(crate) struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    /// Everything written so far.
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).expect("logs are UTF-8")
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Headers saying the request came from `ip`, as a trusted proxy would, for
+/// tests with `behind_trusted_proxy` on.
+pub(crate) fn from_ip(ip: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", ip.parse().expect("the address is valid"));
+    headers
+}
+
+/// [`AuthProject`] with the test config and the database at `database_url`.
+struct TestProject {
+    database_url: String,
+    configure: Box<dyn Fn(&mut ProjectConfig) + Send + Sync>,
+    /// Extra routes served under `/test`, next to the project's own.
+    routes: Option<Router>,
+}
+
+/// The app serving the routes a test adds with [`TestApp::with_routes`].
+struct TestRoutes(Router);
+
+impl App for TestRoutes {
+    fn name(&self) -> &'static str {
+        "test_routes"
+    }
+
+    fn router(&self) -> Router {
+        self.0.clone()
+    }
+}
+
+impl Project for TestProject {
+    fn cli_metadata(&self) -> CliMetadata {
+        AuthProject.cli_metadata()
+    }
+
+    fn config(&self, config_name: &str) -> cot::Result<ProjectConfig> {
+        let mut config = AuthProject.config(config_name)?;
+        config.database.url = Some(self.database_url.as_str().into());
+        (self.configure)(&mut config);
+        Ok(config)
+    }
+
+    fn register_apps(&self, apps: &mut AppBuilder, context: &ProjectContext<WithConfig>) {
+        AuthProject.register_apps(apps, context);
+        if let Some(routes) = &self.routes {
+            apps.register_with_views(TestRoutes(routes.clone()), "/test");
+        }
+    }
+
+    fn auth_backend(&self, context: &AuthBackendContext) -> Arc<dyn AuthBackend> {
+        AuthProject.auth_backend(context)
+    }
+
+    fn error_handler(&self) -> DynErrorPageHandler {
+        AuthProject.error_handler()
+    }
+
+    fn middlewares(&self, handler: RootHandlerBuilder, context: &MiddlewareContext) -> RootHandler {
+        AuthProject.middlewares(handler, context)
+    }
+}
+
+/// Applies the app's migrations to `db`, as `cot run` does on startup.
+pub(crate) async fn migrate(db: &Database) -> cot::Result<()> {
+    MigrationEngine::new(wrap_migrations(migrations::MIGRATIONS))?
+        .run(db)
+        .await?;
+    Ok(())
+}
+
+/// A response, with its body read into a string.
+#[derive(Debug)]
+pub(crate) struct TestResponse {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: String,
+    /// The body as it was sent, for bodies that aren't text, such as
+    /// compressed ones.
+    pub(crate) raw_body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// Where the response redirects to.
+    pub(crate) fn location(&self) -> Option<&str> {
+        self.headers
+            .get(header::LOCATION)
+            .and_then(|value| value.to_str().ok())
+    }
+}
+
+/// The project running in-process for one test.
+pub(crate) struct TestApp {
+    client: Client,
+    db: Database,
+    db_path: PathBuf,
+    cookies: BTreeMap<String, String>,
+}
+
+impl TestApp {
+    pub(crate) async fn new() -> Self {
+        Self::with_config(|_| {}).await
+    }
+
+    /// Boots the project with `configure` applied to the `[app]` settings from
+    /// `config/test.toml`.
+    pub(crate) async fn with_config(configure: impl FnOnce(&mut AppConfig)) -> Self {
+        Self::boot(configure, Box::new(|_| {}), None).await
+    }
+
+    /// Boots the project with `configure` applied to the project settings from
+    /// `config/test.toml`, such as `debug`.
+    pub(crate) async fn with_project_config(
+        configure: impl Fn(&mut ProjectConfig) + Send + Sync + 'static,
+    ) -> Self {
+        Self::boot(|_| {}, Box::new(configure), None).await
+    }
+
+    /// Boots the project with the routes of `router` added under `/test`, for
+    /// testing the pieces handlers are built from, such as extractors, with
+    /// its config changed by `configure`.
+    pub(crate) async fn with_routes(
+        router: Router,
+        configure: impl Fn(&mut ProjectConfig) + Send + Sync + 'static,
+    ) -> Self {
+        Self::boot(|_| {}, Box::new(configure), Some(router)).await
+    }
+
+    async fn boot(
+        configure_app: impl FnOnce(&mut AppConfig),
+        configure_project: Box<dyn Fn(&mut ProjectConfig) + Send + Sync>,
+        routes: Option<Router>,
+    ) -> Self {
+        let config_content = config::read_config_file("test").expect("config/test.toml exists");
+        let mut app_config =
+            config::parse_app_config(&config_content).expect("config/test.toml is valid");
+        configure_app(&mut app_config);
+        config::set_test_app_config(app_config);
+
+        static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+        let db_path = std::env::temp_dir().join(format!(
+            "cot-auth-test-{}-{}.sqlite3",
+            std::process::id(),
+            NEXT_DB.fetch_add(1, Ordering::Relaxed)
+        ));
+        let database_url = format!("sqlite://{}?mode=rwc", db_path.display());
+        let db = Database::new(database_url.as_str())
+            .await
+            .expect("the test database can be created");
+        migrate(&db).await.expect("the migrations apply");
+
+        let client = Client::new(TestProject {
+            database_url,
+            configure: configure_project,
+            routes,
+        })
+        .await;
+        Self {
+            client,
+            db,
+            db_path,
+            cookies: BTreeMap::new(),
+        }
+    }
+
+    /// The project's database.
+    pub(crate) fn db(&self) -> &Database {
+        &self.db
+    }
+
+    /// Swaps the cookies collected so far for `cookies` and returns the old
+    /// ones, so the next requests come from another browser. Passing the
+    /// returned cookies back switches to the first browser again.
+    pub(crate) fn swap_cookies(
+        &mut self,
+        cookies: BTreeMap<String, String>,
+    ) -> BTreeMap<String, String> {
+        std::mem::replace(&mut self.cookies, cookies)
+    }
+
+    /// Saves an active user named `username`, with the address
+    /// `{username}@example.com` and `password`.
+    pub(crate) async fn create_user(&self, username: &str, password: &str) -> User {
+        let mut user = User::builder()
+            .username(username)
+            .password(&Password::new(password))
+            .email(Email::new(format!("{username}@example.com")).expect("the address is valid"))
+            .build()
+            .expect("every part is given");
+        user.save(&self.db).await.expect("the user can be saved");
+        user
+    }
+
+    /// Posts the login form.
+    pub(crate) async fn login(&mut self, username: &str, password: &str) -> TestResponse {
+        self.post("/login", &[("username", username), ("password", password)])
+            .await
+    }
+
+    pub(crate) async fn get(&mut self, path: &str) -> TestResponse {
+        self.send(Method::GET, path, HeaderMap::new(), Body::empty())
+            .await
+    }
+
+    /// Posts `form` URL-encoded, as a browser submits a form.
+    pub(crate) async fn post(&mut self, path: &str, form: &[(&str, &str)]) -> TestResponse {
+        self.post_with_headers(path, form, HeaderMap::new()).await
+    }
+
+    /// Posts `form` like [`TestApp::post`], with `headers` added to the
+    /// request.
+    pub(crate) async fn post_with_headers(
+        &mut self,
+        path: &str,
+        form: &[(&str, &str)],
+        mut headers: HeaderMap,
+    ) -> TestResponse {
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(form)
+            .finish();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded".parse().expect("valid"),
+        );
+        self.send(Method::POST, path, headers, Body::fixed(body))
+            .await
+    }
+
+    /// Sends a request with the cookies collected so far, and keeps the ones
+    /// the response sets.
+    pub(crate) async fn send(
+        &mut self,
+        method: Method,
+        path: &str,
+        headers: HeaderMap,
+        body: Body,
+    ) -> TestResponse {
+        let mut request = cot::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .body(body)
+            .expect("the request is valid");
+        *request.headers_mut() = headers;
+        if !self.cookies.is_empty() {
+            let cookies = self
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join("; ");
+            request.headers_mut().insert(
+                header::COOKIE,
+                cookies.parse().expect("cookies are valid header values"),
+            );
+        }
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .expect("the request is handled");
+        let (head, body) = response.into_parts();
+        for set_cookie in head.headers.get_all(header::SET_COOKIE) {
+            let set_cookie = set_cookie.to_str().expect("cookies are ASCII");
+            let pair = set_cookie.split(';').next().unwrap_or_default();
+            let Some((name, value)) = pair.split_once('=') else {
+                continue;
+            };
+            if value.is_empty() || set_cookie.contains("Max-Age=0") {
+                self.cookies.remove(name);
+            } else {
+                self.cookies.insert(name.to_owned(), value.to_owned());
+            }
+        }
+        let body = body
+            .into_bytes()
+            .await
+            .expect("the response body can be read");
+        TestResponse {
+            status: head.status,
+            headers: head.headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            raw_body: body.to_vec(),
+        }
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.db_path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cot::test]
+async fn serves_the_login_page() {
+    let mut app = TestApp::new().await;
+
+    let response = app.get("/").await;
+
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("name=\"username\""));
+}
+
+#[cot::test]
+async fn logs_in_with_the_cookie_it_was_given() {
+    let mut app = TestApp::new().await;
+    app.create_user("smoke_test", PASSWORD).await;
+
+    let response = app.login("smoke_test", PASSWORD).await;
+    assert_eq!(response.location(), Some("/home"));
+
+    let response = app.get("/home").await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.body.contains("smoke_test"));
+}
@@ -0,0 +1,134 @@
+//! Redirecting paths with a trailing slash to the same path without it, as
+//! every route is registered without one.
+
+use crate::config::{TrailingSlashPolicy, app_config};
+use crate::utils::is_safe_redirect;
+use cot::http::{HeaderValue, header};
+use cot::request::Request;
+use cot::response::{Response, ResponseExt};
+use cot::{Body, Method, StatusCode};
+use futures::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower::Service;
+
+/// Where a request for `path` should be sent instead, or `None` if it can be
+/// served as it is.
+///
+/// Paths that would turn into something browsers read as another host, like
+/// `//evil.example/`, are left alone.
+fn stripped_path(path: &str) -> Option<&str> {
+    if path == "/" || !path.ends_with('/') {
+        return None;
+    }
+    match path.trim_end_matches('/') {
+        "" => Some("/"),
+        stripped => Some(stripped).filter(|stripped| is_safe_redirect(stripped)),
+    }
+}
+
+/// Applies `AppConfig::trailing_slash`.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct TrailingSlashMiddleware;
+
+impl<S> tower::Layer<S> for TrailingSlashMiddleware {
+    type Service = TrailingSlashService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TrailingSlashService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TrailingSlashService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for TrailingSlashService<S>
+where
+    S: Service<Request, Response = Response, Error = cot::Error> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = cot::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let location = match app_config().trailing_slash {
+            TrailingSlashPolicy::Strip => {
+                stripped_path(req.uri().path()).map(|path| match req.uri().query() {
+                    Some(query) => format!("{path}?{query}"),
+                    None => path.to_owned(),
+                })
+            }
+            TrailingSlashPolicy::Off => None,
+        };
+        if let Some(location) = location {
+            // 308 keeps the method and body, so form posts to a slashed URL
+            // still go through
+            let status = if req.method() == Method::GET || req.method() == Method::HEAD {
+                StatusCode::MOVED_PERMANENTLY
+            } else {
+                StatusCode::PERMANENT_REDIRECT
+            };
+            let response = HeaderValue::try_from(location)
+                .map_err(cot::Error::wrap)
+                .map(|location| {
+                    Response::builder()
+                        .status(status)
+                        .header(header::LOCATION, location)
+                        .body(Body::empty())
+                        .expect("the status and header are valid")
+                });
+            return Box::pin(async move { response });
+        }
+
+        // use the clone that was polled ready, as `AuthService` does
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move { inner.call(req).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestApp;
+
+    #[test]
+    fn strips_trailing_slashes_but_keeps_other_hosts_out() {
+        assert_eq!(stripped_path("/login/"), Some("/login"));
+        assert_eq!(stripped_path("/admin/invites//"), Some("/admin/invites"));
+        assert_eq!(stripped_path("//"), Some("/"));
+        assert_eq!(stripped_path("/login"), None);
+        assert_eq!(stripped_path("/"), None);
+        assert_eq!(stripped_path("//evil.example/"), None);
+    }
+
+    #[cot::test]
+    async fn slashed_paths_redirect_to_the_unslashed_route() {
+        let mut app = TestApp::new().await;
+
+        let slashed = app.get("/login/?next=%2Fhome").await;
+        let plain = app.get("/login").await;
+        let posted = app.post("/login/", &[]).await;
+
+        assert_eq!(slashed.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(slashed.location(), Some("/login?next=%2Fhome"));
+        assert_eq!(plain.status, StatusCode::OK);
+        assert_eq!(posted.status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(posted.location(), Some("/login"));
+    }
+
+    #[cot::test]
+    async fn slashed_paths_are_not_found_with_the_policy_off() {
+        let mut app =
+            TestApp::with_config(|config| config.trailing_slash = TrailingSlashPolicy::Off).await;
+
+        assert_eq!(app.get("/login/").await.status, StatusCode::NOT_FOUND);
+        assert_eq!(app.get("/login").await.status, StatusCode::OK);
+    }
+}
@@ -7,7 +7,6 @@ pub const BASE36_RADIX: u32 = 36;
 pub struct Base36;
 
 impl Base36 {
-    #[expect(unused)]
     pub fn decode(s: &str) -> Result<Option<u64>, ParseBigIntError> {
         Ok(BigUint::from_str_radix(s, BASE36_RADIX)?.to_u64())
     }
@@ -16,3 +15,42 @@ impl Base36 {
         BigUint::from(num).to_str_radix(BASE36_RADIX)
     }
 }
+
+/// Compares `a` and `b` in time that depends only on their lengths, for
+/// secrets a timing difference could leak.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Whether `target` is safe to redirect to: a path on this site, as opposed to
+/// an absolute or protocol-relative URL that could send users elsewhere.
+pub fn is_safe_redirect(target: &str) -> bool {
+    target.starts_with('/')
+        && !target.starts_with("//")
+        && !target.starts_with("/\\")
+        && !target.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_paths_on_this_site_are_safe_redirects() {
+        for target in ["/", "/profile", "/reset/abc/MQ?x=1", "/a//b"] {
+            assert!(is_safe_redirect(target), "{target}");
+        }
+        for target in [
+            "",
+            "profile",
+            "https://evil.example/",
+            "javascript:alert(1)",
+            "//evil.example/",
+            "/\\evil.example/",
+            "\\\\evil.example/",
+            "/profile\nSet-Cookie: x=1",
+        ] {
+            assert!(!is_safe_redirect(target), "{target:?}");
+        }
+    }
+}